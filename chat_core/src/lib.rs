@@ -1,8 +1,13 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use sqlx::FromRow;
+use sqlx::{
+    postgres::{PgHasArrayType, PgTypeInfo},
+    FromRow,
+};
 
+pub mod mention;
 pub mod middlewares;
+pub mod typing;
 pub mod utils;
 
 #[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
@@ -14,6 +19,13 @@ pub struct User {
     #[sqlx(default)]
     #[serde(skip)]
     pub password_hash: Option<String>,
+    #[sqlx(default)]
+    #[serde(default)]
+    pub is_bot: bool,
+    // scopes granted by an api token, None for interactive sessions
+    #[sqlx(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scopes: Option<Vec<TokenScope>>,
     pub created_at: DateTime<Utc>,
 }
 
@@ -25,9 +37,36 @@ impl User {
             fullname: fullname.to_string(),
             email: email.to_string(),
             password_hash: None,
+            is_bot: false,
+            scopes: None,
             created_at: Utc::now(),
         }
     }
+
+    /// Sessions signed in with a password or sso may do anything, api tokens only what they were granted
+    pub fn has_scope(&self, scope: TokenScope) -> bool {
+        match &self.scopes {
+            Some(scopes) => scopes.contains(&scope),
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case", type_name = "token_scope")]
+pub enum TokenScope {
+    ReadChats,
+    WriteChats,
+    PostMessages,
+    ReadFiles,
+    UploadFiles,
+}
+
+impl PgHasArrayType for TokenScope {
+    fn array_type_info() -> PgTypeInfo {
+        PgTypeInfo::with_name("_token_scope")
+    }
 }
 
 #[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
@@ -43,6 +82,55 @@ pub struct ChatUser {
     pub id: i64,
     pub fullname: String,
     pub email: String,
+    // presence and status are only loaded for the workspace user list
+    #[sqlx(default)]
+    #[serde(default)]
+    pub presence: PresenceStatus,
+    #[sqlx(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_text: Option<String>,
+    #[sqlx(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_emoji: Option<String>,
+    #[sqlx(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_expires_at: Option<DateTime<Utc>>,
+    #[sqlx(default)]
+    #[serde(default)]
+    pub dnd: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case", type_name = "presence_status")]
+pub enum PresenceStatus {
+    Online,
+    Away,
+    #[default]
+    Offline,
+}
+
+/// Which messages of a chat are pushed to a member, the rest only sync silently
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case", type_name = "notify_level")]
+pub enum NotifyLevel {
+    #[default]
+    All,
+    Mentions,
+    None,
+}
+
+/// A row of the `user_presence` view, what workspace members see of each other
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct UserPresence {
+    pub user_id: i64,
+    pub presence: PresenceStatus,
+    pub status_text: Option<String>,
+    pub status_emoji: Option<String>,
+    pub status_expires_at: Option<DateTime<Utc>>,
+    // inside the do-not-disturb window right now
+    pub dnd: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, PartialOrd, sqlx::Type)]
@@ -62,6 +150,9 @@ pub struct Chat {
     pub name: Option<String>,
     pub r#type: ChatType,
     pub members: Vec<i64>,
+    #[sqlx(default)]
+    #[serde(default)]
+    pub topic: Option<String>,
     pub created_at: DateTime<Utc>,
 }
 
@@ -72,5 +163,42 @@ pub struct Message {
     pub sender_id: i64,
     pub content: String,
     pub files: Vec<String>,
+    #[sqlx(default)]
+    #[serde(default)]
+    pub is_bot: bool,
+    /// Metadata of `files`, filled in when messages are loaded
+    #[sqlx(skip)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
     pub created_at: DateTime<Utc>,
 }
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Attachment {
+    pub id: i64,
+    pub url: String,
+    pub filename: String,
+    pub size: i64,
+    pub mime: String,
+    /// Link usable without a token, minted for the user who loaded the message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signed_url: Option<String>,
+    // the fields below are only set for images once their previews are ready
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blurhash: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub thumbnails: Vec<Thumbnail>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Thumbnail {
+    /// Longest side in pixels
+    pub size: u32,
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signed_url: Option<String>,
+}
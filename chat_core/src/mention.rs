@@ -0,0 +1,41 @@
+use std::collections::HashSet;
+
+/// `@alice` and `@alice@acme.org` in the content, lowercased with trailing punctuation removed
+pub fn mentions(content: &str) -> HashSet<String> {
+    content
+        .split_whitespace()
+        .filter_map(|word| word.strip_prefix('@'))
+        .map(|handle| {
+            handle
+                .trim_end_matches(|c: char| !c.is_alphanumeric())
+                .to_lowercase()
+        })
+        .filter(|handle| !handle.is_empty())
+        .collect()
+}
+
+/// Whether the handles name the user by email or by the part before the `@`
+pub fn is_mentioned(handles: &HashSet<String>, email: &str) -> bool {
+    let email = email.to_lowercase();
+    let local = email.split('@').next().unwrap_or_default();
+    handles.contains(&email) || handles.contains(local)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mentions_should_match_whole_handles() {
+        let handles = mentions("hey @Alice, ask @bob@acme.org! @ email@");
+        assert!(is_mentioned(&handles, "alice@acme.org"));
+        assert!(is_mentioned(&handles, "bob@acme.org"));
+        assert!(!is_mentioned(&handles, "tchen@acme.org"));
+
+        assert!(!is_mentioned(&mentions("ping @alicebob"), "alice@acme.org"));
+        assert!(!is_mentioned(
+            &mentions("mail alice@acme.org"),
+            "alice@acme.org"
+        ));
+    }
+}
@@ -1,6 +1,6 @@
 use axum::{
     extract::{FromRequestParts, Query, Request, State},
-    http::StatusCode,
+    http::{header::SEC_WEBSOCKET_PROTOCOL, request::Parts, StatusCode},
     middleware::Next,
     response::{IntoResponse, Response},
 };
@@ -10,7 +10,7 @@ use axum_extra::{
 };
 use serde::Deserialize;
 
-use super::TokenVerify;
+use super::{TokenVerify, WS_TOKEN_PREFIX};
 
 #[derive(Deserialize, Debug)]
 struct Params {
@@ -25,24 +25,25 @@ where
     let token =
         match TypedHeader::<Authorization<Bearer>>::from_request_parts(&mut parts, &state).await {
             Ok(auth) => auth.0.token().to_string(),
-            Err(e) => {
+            Err(e) if e.is_missing() => match ws_protocol_token(&parts) {
+                // browsers can't set headers on websockets, the token rides along as a subprotocol
+                Some(token) => token,
                 // If the Authorization header is missing, try to parse the query params
-                if e.is_missing() {
-                    match Query::<Params>::from_request_parts(&mut parts, &state).await {
-                        Ok(params) => params.access_token.clone(),
-                        Err(e) => {
-                            let msg = format!("parse query params failed: {}", e);
-                            return (StatusCode::UNAUTHORIZED, msg).into_response();
-                        }
+                None => match Query::<Params>::from_request_parts(&mut parts, &state).await {
+                    Ok(params) => params.access_token.clone(),
+                    Err(e) => {
+                        let msg = format!("parse query params failed: {}", e);
+                        return (StatusCode::UNAUTHORIZED, msg).into_response();
                     }
-                } else {
-                    let msg = format!("parse Authorization header failed: {}", e);
-                    return (StatusCode::BAD_REQUEST, msg).into_response();
-                }
+                },
+            },
+            Err(e) => {
+                let msg = format!("parse Authorization header failed: {}", e);
+                return (StatusCode::BAD_REQUEST, msg).into_response();
             }
         };
 
-    let req = match state.verify(&token) {
+    let req = match state.verify(&token).await {
         Ok(user) => {
             let mut req = Request::from_parts(parts, body);
             req.extensions_mut().insert(user);
@@ -58,6 +59,18 @@ where
     next.run(req).await
 }
 
+// e.g. `Sec-WebSocket-Protocol: chat, access_token.<jwt>`
+fn ws_protocol_token(parts: &Parts) -> Option<String> {
+    parts
+        .headers
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .find_map(|p| p.trim().strip_prefix(WS_TOKEN_PREFIX))
+        .map(str::to_string)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -89,7 +102,7 @@ mod tests {
     impl TokenVerify for AppState {
         type Error = jwt_simple::Error;
 
-        fn verify(&self, token: &str) -> Result<crate::User, Self::Error> {
+        async fn verify(&self, token: &str) -> Result<crate::User, Self::Error> {
             self.0.dk.verify(token)
         }
     }
@@ -129,6 +142,16 @@ mod tests {
         let res = app.clone().oneshot(req).await?;
         assert_eq!(res.status(), StatusCode::OK);
 
+        let req = Request::builder()
+            .uri("/")
+            .header(
+                "Sec-WebSocket-Protocol",
+                format!("chat, {}{}", WS_TOKEN_PREFIX, token),
+            )
+            .body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
         let req = Request::builder().uri("/").body(Body::empty())?;
         let res = app.clone().oneshot(req).await?;
         assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
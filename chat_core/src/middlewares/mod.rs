@@ -1,4 +1,5 @@
 use core::fmt;
+use std::future::Future;
 
 mod auth;
 mod request_id;
@@ -17,12 +18,17 @@ use tracing::Level;
 
 const REQUEST_ID_HEADER: &str = "X-Request-ID";
 
+/// Subprotocol a websocket client asks for, next to one carrying its token
+pub const WS_PROTOCOL: &str = "chat";
+/// Prefix of the subprotocol carrying the token, `access_token.<jwt>`
+pub const WS_TOKEN_PREFIX: &str = "access_token.";
+
 #[allow(unused)]
 const SERVER_TIME_HEADER: &str = "X-Server-Time";
 
 pub trait TokenVerify {
     type Error: fmt::Debug;
-    fn verify(&self, token: &str) -> Result<crate::User, Self::Error>;
+    fn verify(&self, token: &str) -> impl Future<Output = Result<crate::User, Self::Error>> + Send;
 }
 
 pub fn set_layer(app: Router) -> Router {
@@ -0,0 +1,27 @@
+use sqlx::PgPool;
+
+/// Typing is published through postgres so every notify_server instance sees it
+pub const TYPING_CHANNEL: &str = "chat_typing";
+
+/// Announce that the user is typing, false when they aren't a member of the chat.
+/// Only the ids are sent, notify_server looks up the members: a member list of a large
+/// channel goes over the 8000 byte limit of pg_notify
+pub async fn publish_typing(
+    pool: &PgPool,
+    chat_id: i64,
+    user_id: i64,
+) -> Result<bool, sqlx::Error> {
+    let sent = sqlx::query(
+        r#"
+        SELECT pg_notify($3, json_build_object('chat_id', id, 'user_id', $2::bigint)::text)
+        FROM chats
+        WHERE id = $1 AND $2 = ANY(members)
+        "#,
+    )
+    .bind(chat_id)
+    .bind(user_id)
+    .bind(TYPING_CHANNEL)
+    .fetch_optional(pool)
+    .await?;
+    Ok(sent.is_some())
+}
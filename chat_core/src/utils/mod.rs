@@ -1,3 +1,5 @@
 mod jwt;
+mod net;
 
 pub use jwt::{DecodingKey, EncodingKey};
+pub use net::{check_public_url, is_public_ip, public_client};
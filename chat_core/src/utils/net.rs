@@ -0,0 +1,158 @@
+use std::{
+    error::Error,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect, Client, Url,
+};
+
+/// Check a url the server will call on a user's behalf, like a webhook or push endpoint.
+/// It must be https and its host must not be or resolve to a loopback, private or link-local
+/// address. `allow_private` lifts both rules so tests can point at a local listener.
+pub async fn check_public_url(url: &str, allow_private: bool) -> Result<Url, String> {
+    let parsed = Url::parse(url).map_err(|_| format!("Invalid url: {url}"))?;
+    if allow_private {
+        return match parsed.scheme() {
+            "http" | "https" => Ok(parsed),
+            _ => Err(format!("Invalid url: {url}")),
+        };
+    }
+    if parsed.scheme() != "https" {
+        return Err(format!("Url must use https: {url}"));
+    }
+
+    let host = parsed.host_str().unwrap_or_default();
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return match is_public_ip(ip) {
+            true => Ok(parsed),
+            false => Err(format!("Url must not point at a private address: {url}")),
+        };
+    }
+    if host.is_empty() || host == "localhost" || host.ends_with(".localhost") {
+        return Err(format!("Url must not point at a private address: {url}"));
+    }
+
+    // the resolver of `public_client` checks again on every connect, this only fails early
+    let port = parsed.port_or_known_default().unwrap_or(443);
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|_| format!("Can't resolve url host: {url}"))?
+        .collect();
+    if addrs.is_empty() || addrs.iter().any(|addr| !is_public_ip(addr.ip())) {
+        return Err(format!("Url must not point at a private address: {url}"));
+    }
+    Ok(parsed)
+}
+
+/// http client for user supplied urls. Every resolved address is checked, so a host
+/// re-pointed at an internal address after `check_public_url` still can't be reached,
+/// and redirects aren't followed.
+pub fn public_client(allow_private: bool) -> Client {
+    let builder = Client::builder().redirect(redirect::Policy::none());
+    let builder = match allow_private {
+        true => builder,
+        false => builder.dns_resolver(Arc::new(PublicResolver)),
+    };
+    builder.build().expect("http client should build")
+}
+
+/// Whether the address is routable on the public internet
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || a == 0
+                // carrier grade nat, 100.64.0.0/10
+                || (a == 100 && (b & 0xc0) == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_ip(ip.into()),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    // unique local fc00::/7 and link-local fe80::/10
+                    || (first & 0xfe00) == 0xfc00
+                    || (first & 0xffc0) == 0xfe80)
+            }
+        },
+    }
+}
+
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(resolve_public(name))
+    }
+}
+
+async fn resolve_public(name: Name) -> Result<Addrs, Box<dyn Error + Send + Sync>> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+        .await?
+        .filter(|addr| is_public_ip(addr.ip()))
+        .collect();
+    if addrs.is_empty() {
+        return Err(format!("{} has no public address", name.as_str()).into());
+    }
+    Ok(Box::new(addrs.into_iter()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_public_ip_should_work() {
+        for ip in ["8.8.8.8", "2606:4700::1111", "::ffff:1.1.1.1"] {
+            assert!(is_public_ip(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[tokio::test]
+    async fn check_public_url_should_work() {
+        for url in [
+            "http://example.com/hook",
+            "https://127.0.0.1/hook",
+            "https://[::1]:8080/hook",
+            "https://169.254.169.254/latest/meta-data",
+            "https://localhost/hook",
+            "ftp://example.com",
+        ] {
+            assert!(check_public_url(url, false).await.is_err(), "{url}");
+        }
+        assert!(check_public_url("https://1.1.1.1/hook", false)
+            .await
+            .is_ok());
+
+        assert!(check_public_url("http://127.0.0.1:3000/hook", true)
+            .await
+            .is_ok());
+        assert!(check_public_url("ftp://127.0.0.1", true).await.is_err());
+    }
+}
@@ -1,10 +1,10 @@
 -- insert 3 workspaces
 INSERT INTO
-  workspaces(name, owner_id)
+  workspaces(name, owner_id, domain)
 VALUES
-  ('acme', 0),
-  ('foo', 0),
-  ('bar', 0);
+  ('acme', 0, 'acme.org'),
+  ('foo', 0, NULL),
+  ('bar', 0, NULL);
 
 -- insert 5 users, all with hashed password '123456'
 INSERT INTO
@@ -0,0 +1,411 @@
+use chat_core::ChatType;
+use chrono::{Duration, Utc};
+
+use crate::{AppState, CreateMessage};
+
+use super::{CommandContext, CommandFuture, CommandRegistry, MessageOutput, SlashCommand};
+
+// reminders and mutes further out than this are rejected
+const MAX_DURATION_DAYS: i64 = 365;
+
+pub(super) fn register_all(registry: &mut CommandRegistry) {
+    registry.register(Me);
+    registry.register(Topic);
+    registry.register(Invite);
+    registry.register(Leave);
+    registry.register(Mute);
+    registry.register(Remind);
+}
+
+struct Me;
+struct Topic;
+struct Invite;
+struct Leave;
+struct Mute;
+struct Remind;
+
+impl SlashCommand for Me {
+    fn name(&self) -> &'static str {
+        "me"
+    }
+
+    fn usage(&self) -> &'static str {
+        "/me <action>"
+    }
+
+    fn run<'a>(&'a self, state: &'a AppState, ctx: CommandContext<'a>) -> CommandFuture<'a> {
+        Box::pin(async move {
+            if ctx.args.is_empty() {
+                return Ok(usage(self));
+            }
+            let input = CreateMessage {
+                content: format!("_{} {}_", ctx.user.fullname, ctx.args),
+                files: vec![],
+            };
+            let msg = state
+                .create_message(input, ctx.chat.id as _, ctx.user.id as _)
+                .await?;
+            Ok(MessageOutput::Message(msg))
+        })
+    }
+}
+
+impl SlashCommand for Topic {
+    fn name(&self) -> &'static str {
+        "topic"
+    }
+
+    fn usage(&self) -> &'static str {
+        "/topic <text>, or /topic clear"
+    }
+
+    fn run<'a>(&'a self, state: &'a AppState, ctx: CommandContext<'a>) -> CommandFuture<'a> {
+        Box::pin(async move {
+            let (topic, content) = match ctx.args {
+                "" => return Ok(usage(self)),
+                "clear" => (None, format!("_{} cleared the topic_", ctx.user.fullname)),
+                topic if topic.chars().count() > 250 => {
+                    return Ok(MessageOutput::ephemeral(
+                        "The topic can't be longer than 250 characters",
+                    ))
+                }
+                topic => (
+                    Some(topic),
+                    format!("_{} set the topic: {}_", ctx.user.fullname, topic),
+                ),
+            };
+
+            state.set_chat_topic(ctx.chat.id as _, topic).await?;
+            let input = CreateMessage {
+                content,
+                files: vec![],
+            };
+            let msg = state
+                .create_message(input, ctx.chat.id as _, ctx.user.id as _)
+                .await?;
+            Ok(MessageOutput::Message(msg))
+        })
+    }
+}
+
+impl SlashCommand for Invite {
+    fn name(&self) -> &'static str {
+        "invite"
+    }
+
+    fn usage(&self) -> &'static str {
+        "/invite @user [@user...]"
+    }
+
+    fn run<'a>(&'a self, state: &'a AppState, ctx: CommandContext<'a>) -> CommandFuture<'a> {
+        Box::pin(async move {
+            if ctx.chat.r#type == ChatType::Single {
+                return Ok(MessageOutput::ephemeral(
+                    "People can't be invited into a direct message",
+                ));
+            }
+            let handles: Vec<_> = ctx.args.split_whitespace().collect();
+            if handles.is_empty() || handles.iter().any(|h| !h.starts_with('@')) {
+                return Ok(usage(self));
+            }
+
+            let mut invited = vec![];
+            for handle in handles {
+                match state.find_user_by_handle(ctx.chat.ws_id, handle).await? {
+                    Some(user) if ctx.chat.members.contains(&user.id) => {
+                        return Ok(MessageOutput::ephemeral(format!(
+                            "{} is already in this chat",
+                            user.fullname
+                        )))
+                    }
+                    Some(user) => invited.push(user),
+                    None => {
+                        return Ok(MessageOutput::ephemeral(format!(
+                            "No one in this workspace matches {handle}"
+                        )))
+                    }
+                }
+            }
+
+            let ids: Vec<_> = invited.iter().map(|u| u.id).collect();
+            state.add_chat_members(ctx.chat.id as _, &ids).await?;
+            let names: Vec<_> = invited.iter().map(|u| u.fullname.as_str()).collect();
+            Ok(MessageOutput::ephemeral(format!(
+                "Invited {}",
+                names.join(", ")
+            )))
+        })
+    }
+}
+
+impl SlashCommand for Leave {
+    fn name(&self) -> &'static str {
+        "leave"
+    }
+
+    fn usage(&self) -> &'static str {
+        "/leave"
+    }
+
+    fn run<'a>(&'a self, state: &'a AppState, ctx: CommandContext<'a>) -> CommandFuture<'a> {
+        Box::pin(async move {
+            if ctx.chat.r#type == ChatType::Single {
+                return Ok(MessageOutput::ephemeral("You can't leave a direct message"));
+            }
+            state
+                .remove_chat_member(ctx.chat.id as _, ctx.user.id as _)
+                .await?;
+            let name = ctx.chat.name.as_deref().unwrap_or("the chat");
+            Ok(MessageOutput::ephemeral(format!("You left {name}")))
+        })
+    }
+}
+
+impl SlashCommand for Mute {
+    fn name(&self) -> &'static str {
+        "mute"
+    }
+
+    fn usage(&self) -> &'static str {
+        "/mute [duration like 30m, 8h or 2d], or /mute off"
+    }
+
+    fn run<'a>(&'a self, state: &'a AppState, ctx: CommandContext<'a>) -> CommandFuture<'a> {
+        Box::pin(async move {
+            let (chat_id, user_id) = (ctx.chat.id as u64, ctx.user.id as u64);
+            let reply = match ctx.args {
+                "off" => {
+                    state.unmute_chat(chat_id, user_id).await?;
+                    "Notifications for this chat are back on".to_string()
+                }
+                "" => {
+                    state.mute_chat(chat_id, user_id, None).await?;
+                    "Muted this chat until you turn it back on with /mute off".to_string()
+                }
+                args => {
+                    let Some(duration) = parse_duration(args) else {
+                        return Ok(usage(self));
+                    };
+                    state
+                        .mute_chat(chat_id, user_id, Some(Utc::now() + duration))
+                        .await?;
+                    format!("Muted this chat for {args}")
+                }
+            };
+            Ok(MessageOutput::ephemeral(reply))
+        })
+    }
+}
+
+impl SlashCommand for Remind {
+    fn name(&self) -> &'static str {
+        "remind"
+    }
+
+    fn usage(&self) -> &'static str {
+        "/remind [in] <duration like 30m, 8h or 2d> <text>"
+    }
+
+    fn run<'a>(&'a self, state: &'a AppState, ctx: CommandContext<'a>) -> CommandFuture<'a> {
+        Box::pin(async move {
+            let args = ctx
+                .args
+                .strip_prefix("in ")
+                .unwrap_or(ctx.args)
+                .trim_start();
+            let (when, content) = args
+                .split_once(char::is_whitespace)
+                .map(|(when, content)| (when, content.trim()))
+                .unwrap_or((args, ""));
+            let (Some(duration), false) = (parse_duration(when), content.is_empty()) else {
+                return Ok(usage(self));
+            };
+
+            state
+                .create_reminder(ctx.user, ctx.chat.id as _, content, Utc::now() + duration)
+                .await?;
+            Ok(MessageOutput::ephemeral(format!(
+                "I will remind you in {when}"
+            )))
+        })
+    }
+}
+
+fn usage(command: &dyn SlashCommand) -> MessageOutput {
+    MessageOutput::ephemeral(format!("Usage: {}", command.usage()))
+}
+
+/// Parse durations like `45s`, `10m`, `1h30m` or `2d`
+fn parse_duration(s: &str) -> Option<Duration> {
+    let mut total = Duration::zero();
+    let mut num = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            num.push(c);
+            continue;
+        }
+        let n: i64 = num.parse().ok()?;
+        num.clear();
+        let d = match c {
+            's' => Duration::try_seconds(n)?,
+            'm' => Duration::try_minutes(n)?,
+            'h' => Duration::try_hours(n)?,
+            'd' => Duration::try_days(n)?,
+            _ => return None,
+        };
+        total = total.checked_add(&d)?;
+        if total > Duration::days(MAX_DURATION_DAYS) {
+            return None;
+        }
+    }
+    (num.is_empty() && total > Duration::zero()).then_some(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        commands::tests::{ephemeral_text, text},
+        UpdateNotificationSettings,
+    };
+    use anyhow::Result;
+    use chat_core::NotifyLevel;
+
+    #[test]
+    fn parse_duration_should_work() {
+        assert_eq!(parse_duration("45s"), Some(Duration::seconds(45)));
+        assert_eq!(parse_duration("1h30m"), Some(Duration::minutes(90)));
+        assert_eq!(parse_duration("2d"), Some(Duration::days(2)));
+        assert_eq!(parse_duration("10"), None);
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(parse_duration("0m"), None);
+        assert_eq!(parse_duration("10w"), None);
+        assert_eq!(parse_duration("400d"), None);
+        assert_eq!(parse_duration("100000000000d100000000000d"), None);
+    }
+
+    #[tokio::test]
+    async fn me_and_topic_should_post_messages() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+
+        let output = state.send_message(text("/me waves"), 1, &user).await?;
+        let MessageOutput::Message(msg) = output else {
+            panic!("/me should post a message");
+        };
+        assert_eq!(msg.content, "_Tyr Chen waves_");
+
+        let output = state.send_message(text("/me"), 1, &user).await?;
+        assert_eq!(ephemeral_text(output), "Usage: /me <action>");
+
+        state
+            .send_message(text("/topic Q3 planning"), 1, &user)
+            .await?;
+        let chat = state.get_chat_by_id(1).await?.unwrap();
+        assert_eq!(chat.topic.as_deref(), Some("Q3 planning"));
+
+        state.send_message(text("/topic clear"), 1, &user).await?;
+        let chat = state.get_chat_by_id(1).await?.unwrap();
+        assert_eq!(chat.topic, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invite_and_leave_should_change_members() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+
+        // private channel with members 1, 2, 3
+        let output = state
+            .send_message(text("/invite @charlie @tester@example.com"), 2, &user)
+            .await?;
+        assert_eq!(ephemeral_text(output), "Invited Charlie Chen, tester");
+        let chat = state.get_chat_by_id(2).await?.unwrap();
+        assert_eq!(chat.members, vec![1, 2, 3, 4, 5]);
+
+        let output = state.send_message(text("/invite @alice"), 2, &user).await?;
+        assert_eq!(ephemeral_text(output), "Alice Chen is already in this chat");
+        let output = state
+            .send_message(text("/invite @nobody"), 2, &user)
+            .await?;
+        assert_eq!(
+            ephemeral_text(output),
+            "No one in this workspace matches @nobody"
+        );
+
+        let output = state.send_message(text("/leave"), 2, &user).await?;
+        assert_eq!(ephemeral_text(output), "You left private");
+        assert!(!state.is_chat_member(2, 1).await?);
+
+        // direct messages can't be changed
+        let output = state.send_message(text("/leave"), 3, &user).await?;
+        assert_eq!(ephemeral_text(output), "You can't leave a direct message");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mute_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+
+        state.send_message(text("/mute 8h"), 1, &user).await?;
+        assert!(state.is_chat_muted(1, 1).await?);
+        assert!(!state.is_chat_muted(1, 2).await?);
+
+        state.send_message(text("/mute off"), 1, &user).await?;
+        assert!(!state.is_chat_muted(1, 1).await?);
+
+        // notify_server reads the same settings, a chosen level outlives the mute
+        let input = UpdateNotificationSettings {
+            level: Some(NotifyLevel::Mentions),
+            muted_until: None,
+        };
+        state.update_notification_settings(1, &user, input).await?;
+        state.send_message(text("/mute"), 1, &user).await?;
+        let settings = state.get_notification_settings(1, &user).await?;
+        assert_eq!(settings.effective_level, NotifyLevel::None);
+        state.send_message(text("/mute off"), 1, &user).await?;
+        let settings = state.get_notification_settings(1, &user).await?;
+        assert_eq!(settings.effective_level, NotifyLevel::Mentions);
+
+        let output = state.send_message(text("/mute soon"), 1, &user).await?;
+        assert!(ephemeral_text(output).starts_with("Usage:"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn remind_should_message_the_requester_when_due() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+
+        let output = state
+            .send_message(text("/remind in 1s stand up"), 1, &user)
+            .await?;
+        assert_eq!(ephemeral_text(output), "I will remind you in 1s");
+        assert_eq!(state.process_due_reminders().await?, 0);
+
+        tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+        assert_eq!(state.process_due_reminders().await?, 1);
+        assert_eq!(state.process_due_reminders().await?, 0);
+
+        let input = crate::ListMessage {
+            last_id: None,
+            limit: 1,
+        };
+        // nothing is posted into the chat the reminder was set in
+        let messages = state.list_message(input.clone(), 1).await?;
+        assert_ne!(messages[0].content, "Reminder: stand up");
+
+        let chats = state.fetch_chats(user.ws_id as _).await?;
+        let dm = chats
+            .iter()
+            .filter(|c| c.r#type == ChatType::Single && c.members.contains(&user.id))
+            .max_by_key(|c| c.id)
+            .unwrap();
+        assert_eq!(dm.members.len(), 2);
+        let messages = state.list_message(input, dm.id as _).await?;
+        assert_eq!(messages[0].content, "Reminder: stand up");
+        assert!(messages[0].is_bot);
+        Ok(())
+    }
+}
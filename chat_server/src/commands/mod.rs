@@ -0,0 +1,285 @@
+mod builtin;
+
+use std::{collections::HashMap, future::Future, pin::Pin};
+
+use chat_core::{Chat, Message, User};
+use serde::{Deserialize, Serialize};
+
+use crate::{error::AppError, AppState, CreateMessage};
+
+pub(crate) type CommandFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<MessageOutput, AppError>> + Send + 'a>>;
+
+/// What sending a message or running a command gives back to the sender
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum MessageOutput {
+    // stored in the chat and seen by every member
+    Message(Message),
+    // only shown to the sender, never stored
+    Ephemeral(EphemeralReply),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct EphemeralReply {
+    pub ephemeral: bool,
+    pub text: String,
+}
+
+pub(crate) struct CommandContext<'a> {
+    pub user: &'a User,
+    pub chat: &'a Chat,
+    // everything after the command name, trimmed
+    pub args: &'a str,
+}
+
+pub(crate) trait SlashCommand: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn usage(&self) -> &'static str;
+
+    fn run<'a>(&'a self, state: &'a AppState, ctx: CommandContext<'a>) -> CommandFuture<'a>;
+}
+
+/// Commands handled in process, workspace commands registered over http are looked up after these
+pub(crate) struct CommandRegistry {
+    commands: HashMap<&'static str, Box<dyn SlashCommand>>,
+}
+
+impl CommandRegistry {
+    pub fn new() -> Self {
+        Self {
+            commands: HashMap::new(),
+        }
+    }
+
+    pub fn register(&mut self, command: impl SlashCommand + 'static) {
+        self.commands.insert(command.name(), Box::new(command));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn SlashCommand> {
+        self.commands.get(name).map(|c| c.as_ref())
+    }
+}
+
+impl Default for CommandRegistry {
+    fn default() -> Self {
+        let mut registry = Self::new();
+        builtin::register_all(&mut registry);
+        registry
+    }
+}
+
+impl MessageOutput {
+    pub fn ephemeral(text: impl Into<String>) -> Self {
+        Self::Ephemeral(EphemeralReply {
+            ephemeral: true,
+            text: text.into(),
+        })
+    }
+}
+
+impl AppState {
+    /// Entry point for messages sent by users, slash commands are run instead of stored
+    pub async fn send_message(
+        &self,
+        input: CreateMessage,
+        chat_id: u64,
+        user: &User,
+    ) -> Result<MessageOutput, AppError> {
+        let Some((name, args)) = parse_command(&input.content) else {
+            let msg = self.create_message(input, chat_id, user.id as _).await?;
+            return Ok(MessageOutput::Message(msg));
+        };
+        if !input.files.is_empty() {
+            return Err(AppError::CommandError(
+                "Slash commands can't carry files".to_string(),
+            ));
+        }
+        let Some(chat) = self.get_chat_by_id(chat_id).await? else {
+            return Err(AppError::NotFound(format!("chat id {chat_id}")));
+        };
+
+        let name = name.to_lowercase();
+        if let Some(command) = self.commands.get(&name) {
+            let ctx = CommandContext {
+                user,
+                chat: &chat,
+                args,
+            };
+            return command.run(self, ctx).await;
+        }
+
+        match self.run_external_command(&name, args, user, &chat).await? {
+            Some(output) => Ok(output),
+            None => Ok(MessageOutput::ephemeral(format!("Unknown command /{name}"))),
+        }
+    }
+}
+
+/// Split `/name args` into the name and the trimmed args, None for plain messages.
+/// A path like `/usr/bin` isn't a valid name so it is sent as a message.
+pub(crate) fn parse_command(content: &str) -> Option<(&str, &str)> {
+    let rest = content.strip_prefix('/')?;
+    let (name, args) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    is_valid_command_name(name).then(|| (name, args.trim()))
+}
+
+pub(crate) fn is_valid_command_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AppConfig, CreateSlashCommand};
+    use anyhow::Result;
+    use axum::{routing::post, Json, Router};
+    use serde_json::{json, Value};
+    use tokio::net::TcpListener;
+
+    pub(super) fn text(content: &str) -> CreateMessage {
+        CreateMessage {
+            content: content.to_string(),
+            files: vec![],
+        }
+    }
+
+    pub(super) fn ephemeral_text(output: MessageOutput) -> String {
+        match output {
+            MessageOutput::Ephemeral(reply) => reply.text,
+            MessageOutput::Message(msg) => panic!("expected an ephemeral reply, got {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn parse_command_should_work() {
+        assert_eq!(parse_command("/me waves"), Some(("me", "waves")));
+        assert_eq!(parse_command("/leave"), Some(("leave", "")));
+        assert_eq!(
+            parse_command("/remind  10m  stretch"),
+            Some(("remind", "10m  stretch"))
+        );
+        assert_eq!(parse_command("/usr/bin is a path"), None);
+        assert_eq!(parse_command("/"), None);
+        assert_eq!(parse_command(" /me"), None);
+        assert_eq!(parse_command("hello /me"), None);
+    }
+
+    #[tokio::test]
+    async fn unknown_command_should_reply_ephemeral() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+        let before = state
+            .list_message(
+                crate::ListMessage {
+                    last_id: None,
+                    limit: 100,
+                },
+                1,
+            )
+            .await?;
+
+        let output = state.send_message(text("/nope x"), 1, &user).await?;
+        assert_eq!(ephemeral_text(output), "Unknown command /nope");
+
+        let after = state
+            .list_message(
+                crate::ListMessage {
+                    last_id: None,
+                    limit: 100,
+                },
+                1,
+            )
+            .await?;
+        assert_eq!(before.len(), after.len());
+
+        // incoming webhooks, in-channel slash command replies, reminders and builtins call
+        // create_message directly and post literal content, api tokens go through send_message
+        let msg = state.create_message(text("/var/log is full"), 1, 1).await?;
+        assert_eq!(msg.content, "/var/log is full");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn external_command_should_be_forwarded() -> Result<()> {
+        async fn reply(Json(body): Json<Value>) -> Json<Value> {
+            let response_type = if body["text"] == "public" {
+                "in_channel"
+            } else {
+                "ephemeral"
+            };
+            Json(json!({
+                "text": format!("{} ran {}", body["user_name"].as_str().unwrap(), body["command"].as_str().unwrap()),
+                "response_type": response_type,
+            }))
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/cmd", listener.local_addr()?);
+        let app = Router::new().route("/cmd", post(reply));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state.update_workspace_owner(1, 1).await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+
+        let input = CreateSlashCommand {
+            name: "/me".to_string(),
+            url: url.clone(),
+            description: String::new(),
+        };
+        let ret = state.create_slash_command(input, &user).await;
+        assert!(matches!(ret, Err(AppError::CommandError(_))));
+
+        let input = CreateSlashCommand {
+            name: "/Deploy".to_string(),
+            url,
+            description: "ship it".to_string(),
+        };
+        let command = state.create_slash_command(input.clone(), &user).await?;
+        assert_eq!(command.info.name, "deploy");
+
+        // a taken name doesn't leave a bot behind
+        let bots = state.fetch_bots(1).await?.len();
+        let ret = state.create_slash_command(input, &user).await;
+        assert!(matches!(ret, Err(AppError::CommandError(_))));
+        assert_eq!(state.fetch_bots(1).await?.len(), bots);
+
+        let output = state.send_message(text("/deploy"), 1, &user).await?;
+        assert_eq!(ephemeral_text(output), "Tyr Chen ran /deploy");
+
+        let output = state.send_message(text("/deploy public"), 1, &user).await?;
+        let MessageOutput::Message(msg) = output else {
+            panic!("expected an in channel reply");
+        };
+        assert!(msg.is_bot);
+        assert_eq!(msg.sender_id, command.info.bot_id);
+
+        state
+            .delete_slash_command(command.info.id as _, &user)
+            .await?;
+        assert_eq!(state.fetch_bots(1).await?.len(), bots - 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn external_command_should_reject_private_urls() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test_with_config(AppConfig::load()?).await?;
+        state.update_workspace_owner(1, 1).await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+
+        for url in ["http://example.com/cmd", "https://127.0.0.1/cmd"] {
+            let input = CreateSlashCommand {
+                name: "/deploy".to_string(),
+                url: url.to_string(),
+                description: String::new(),
+            };
+            let ret = state.create_slash_command(input, &user).await;
+            assert!(matches!(ret, Err(AppError::CommandError(_))), "{url}");
+        }
+        Ok(())
+    }
+}
@@ -1,5 +1,5 @@
 use axum::{
-    http::StatusCode,
+    http::{header::RETRY_AFTER, StatusCode},
     response::{IntoResponse, Response},
 };
 use serde::{Deserialize, Serialize};
@@ -47,6 +47,63 @@ pub enum AppError {
 
     #[error("Not found: {0}")]
     NotFound(String),
+
+    #[error("oidc error: {0}")]
+    OidcError(String),
+
+    #[error("http client error: {0}")]
+    HttpClientError(#[from] reqwest::Error),
+
+    #[error("permission denied: {0}")]
+    PermissionDenied(String),
+
+    #[error("api token error: {0}")]
+    ApiTokenError(String),
+
+    #[error("bot already exists: {0}")]
+    BotAlreadyExists(String),
+
+    #[error("webhook error: {0}")]
+    WebhookError(String),
+
+    #[error("payload too large: {0}")]
+    PayloadTooLarge(String),
+
+    #[error("unsupported media type: {0}")]
+    UnsupportedMediaType(String),
+
+    #[error("upload conflict: {0}")]
+    UploadConflict(String),
+
+    #[error("upload expired: {0}")]
+    UploadExpired(String),
+
+    #[error("unsupported tus version: {0}")]
+    TusVersionMismatch(String),
+
+    #[error("storage error: {0}")]
+    StorageError(String),
+
+    #[error("command error: {0}")]
+    CommandError(String),
+
+    #[error("rate limited, retry after {0}s")]
+    RateLimited(u64),
+
+    #[error("image error: {0}")]
+    ImageError(#[from] image::ImageError),
+
+    #[error("storage quota exceeded: {0}")]
+    QuotaExceeded(String),
+
+    #[error("file quarantined: {0}")]
+    FileQuarantined(String),
+
+    #[error("status error: {0}")]
+    StatusError(String),
+
+    #[error("email error: {0}")]
+    EmailError(String),
 }
 
 impl ErrorOutput {
@@ -72,7 +129,34 @@ impl IntoResponse for AppError {
             Self::NotFound(_) => StatusCode::NOT_FOUND,
             AppError::WorkspaceAlreadyExists(_) => StatusCode::CONFLICT,
             AppError::PasswordHashError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::OidcError(_) => StatusCode::UNAUTHORIZED,
+            AppError::HttpClientError(_) => StatusCode::BAD_GATEWAY,
+            AppError::PermissionDenied(_) => StatusCode::FORBIDDEN,
+            AppError::ApiTokenError(_) => StatusCode::BAD_REQUEST,
+            AppError::BotAlreadyExists(_) => StatusCode::CONFLICT,
+            AppError::WebhookError(_) => StatusCode::BAD_REQUEST,
+            AppError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            AppError::UploadConflict(_) => StatusCode::CONFLICT,
+            AppError::UploadExpired(_) => StatusCode::GONE,
+            AppError::TusVersionMismatch(_) => StatusCode::PRECONDITION_FAILED,
+            AppError::StorageError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::CommandError(_) => StatusCode::BAD_REQUEST,
+            AppError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::ImageError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::QuotaExceeded(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::FileQuarantined(_) => StatusCode::LOCKED,
+            AppError::StatusError(_) => StatusCode::BAD_REQUEST,
+            AppError::EmailError(_) => StatusCode::BAD_GATEWAY,
         };
-        (status, axum::response::Json(self.to_string())).into_response()
+        let retry_after = match self {
+            AppError::RateLimited(secs) => Some(secs),
+            _ => None,
+        };
+        let mut res = (status, axum::response::Json(self.to_string())).into_response();
+        if let Some(secs) = retry_after {
+            res.headers_mut().insert(RETRY_AFTER, secs.into());
+        }
+        res
     }
 }
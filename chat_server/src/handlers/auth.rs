@@ -1,4 +1,9 @@
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Redirect},
+    Json,
+};
 use serde::{Deserialize, Serialize};
 
 use crate::{
@@ -43,3 +48,53 @@ pub(crate) async fn signup_handler(
     let body = Json(AuthOutput { token });
     Ok((StatusCode::CREATED, body))
 }
+
+#[derive(Debug, Deserialize)]
+pub struct OidcCallback {
+    code: Option<String>,
+    state: String,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+// Path: chat_server/src/handlers/auth.rs
+// 单点登录: 跳转到身份提供方
+pub(crate) async fn oidc_login_handler(
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let Some(oidc) = &state.oidc else {
+        return Err(AppError::NotFound(
+            "oidc sign-in is not enabled".to_string(),
+        ));
+    };
+    let url = oidc.authorize_url().await?;
+    Ok(Redirect::to(&url))
+}
+
+// Path: chat_server/src/handlers/auth.rs
+// 单点登录: 身份提供方回调, 签发 token
+pub(crate) async fn oidc_callback_handler(
+    State(state): State<AppState>,
+    Query(input): Query<OidcCallback>,
+) -> Result<impl IntoResponse, AppError> {
+    let Some(oidc) = &state.oidc else {
+        return Err(AppError::NotFound(
+            "oidc sign-in is not enabled".to_string(),
+        ));
+    };
+    let code = match (input.code, input.error) {
+        (Some(code), None) => code,
+        (_, error) => {
+            let msg = input
+                .error_description
+                .or(error)
+                .unwrap_or_else(|| "missing authorization code".to_string());
+            return Err(AppError::OidcError(msg));
+        }
+    };
+
+    let identity = oidc.exchange(&code, &input.state).await?;
+    let user = state.find_or_provision_sso_user(&identity).await?;
+    let token = state.ek.sign(user)?;
+    Ok((StatusCode::OK, Json(AuthOutput { token })))
+}
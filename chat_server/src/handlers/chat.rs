@@ -6,7 +6,7 @@ use axum::{
 };
 use chat_core::User;
 
-use crate::{AppError, AppState, CreateChat};
+use crate::{AppError, AppState, CreateChat, UpdateNotificationSettings};
 
 pub(crate) async fn list_chat_handler(
     Extension(user): Extension<User>,
@@ -43,3 +43,26 @@ pub(crate) async fn get_chat_handler(
         None => Err(AppError::NotFound(format!("chat id {id}"))),
     }
 }
+
+// Path: chat_server/src/handlers/chat.rs
+// 当前用户在该聊天的通知设置, 未设置时沿用工作区默认
+pub(crate) async fn get_notification_settings_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let settings = state.get_notification_settings(id, &user).await?;
+    Ok(Json(settings))
+}
+
+// Path: chat_server/src/handlers/chat.rs
+// 设置通知级别 (all / mentions / none) 和静音截止时间
+pub(crate) async fn update_notification_settings_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<UpdateNotificationSettings>,
+) -> Result<impl IntoResponse, AppError> {
+    let settings = state.update_notification_settings(id, &user, input).await?;
+    Ok(Json(settings))
+}
@@ -0,0 +1,35 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::User;
+
+use crate::{AppError, AppState, CreateSlashCommand};
+
+pub(crate) async fn list_slash_commands_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let commands = state.fetch_slash_commands(user.ws_id as _).await?;
+    Ok(Json(commands))
+}
+
+pub(crate) async fn create_slash_command_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<CreateSlashCommand>,
+) -> Result<impl IntoResponse, AppError> {
+    let command = state.create_slash_command(input, &user).await?;
+    Ok((StatusCode::CREATED, Json(command)))
+}
+
+pub(crate) async fn delete_slash_command_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.delete_slash_command(id, &user).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,511 @@
+use std::{io, ops::Range, str::FromStr};
+
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use bytes::Bytes;
+use chat_core::User;
+use futures::{stream, StreamExt, TryStreamExt};
+use mime_guess::mime;
+
+use crate::{
+    storage::bytes_stream, utils::random_string, AppError, AppState, ChatFile, SignedFileParams,
+};
+
+// the url is the hash of the content, a cached copy is never stale
+const CACHE_CONTROL: &str = "private, max-age=31536000, immutable";
+// uploads are untrusted, nothing served from them may run script on the app origin
+const CONTENT_SECURITY_POLICY: &str = "sandbox";
+// more ranges than this aren't worth the overhead, the whole file is sent instead
+const MAX_RANGES: usize = 16;
+
+#[derive(Debug, PartialEq)]
+enum RangeRequest {
+    Full,
+    Ranges(Vec<Range<u64>>),
+    Unsatisfiable,
+}
+
+// Path: chat_server/src/handlers/file.rs
+// 支持 ETag / If-None-Match / Range, 文件内容流式返回
+pub(crate) async fn file_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((ws_id, path)): Path<(i64, String)>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let url = format!("/files/{ws_id}/{path}");
+    if user.ws_id != ws_id || !state.can_access_file(user.id, &url).await? {
+        return Err(AppError::NotFound(
+            "File doesn't exist or you don't have permission".to_string(),
+        ));
+    }
+    serve_file(&state, ws_id, &path, &headers).await
+}
+
+// Path: chat_server/src/handlers/file.rs
+// 签名链接不需要 token, 可以直接用在 <img> / <video> 里
+pub(crate) async fn signed_file_handler(
+    State(state): State<AppState>,
+    Path((ws_id, path)): Path<(i64, String)>,
+    Query(params): Query<SignedFileParams>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    state.verify_file_url(&format!("/files/{ws_id}/{path}"), &params)?;
+    serve_file(&state, ws_id, &path, &headers).await
+}
+
+async fn serve_file(
+    state: &AppState,
+    ws_id: i64,
+    path: &str,
+    headers: &HeaderMap,
+) -> Result<Response, AppError> {
+    let key = format!("{}/{}", ws_id, path);
+    let url = format!("/files/{key}");
+    let Ok(file) = ChatFile::from_str(&url) else {
+        return Err(AppError::NotFound("File doesn't exist".to_string()));
+    };
+    // nobody gets the content before the scanner cleared it
+    state.ensure_file_clean(&url).await?;
+    let etag = format!("\"{}\"", file.hash);
+
+    let mut res_headers = HeaderMap::new();
+    res_headers.insert(header::ETAG, etag.parse().unwrap());
+    res_headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static(CACHE_CONTROL),
+    );
+    res_headers.insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+    res_headers.insert(
+        header::CONTENT_SECURITY_POLICY,
+        HeaderValue::from_static(CONTENT_SECURITY_POLICY),
+    );
+    if none_match(headers, &etag) {
+        return Ok((StatusCode::NOT_MODIFIED, res_headers).into_response());
+    }
+
+    let Some(meta) = state.store.head(&key).await? else {
+        return Err(AppError::NotFound("File doesn't exist".to_string()));
+    };
+    let size = meta.size;
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    res_headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    // files are stored by hash, the original name only lives in the metadata
+    let filename = match state.find_file_meta_by_url(&url).await? {
+        Some(meta) => meta.filename,
+        None => path.rsplit('/').next().unwrap_or(path).to_string(),
+    };
+    let disposition = content_disposition(&mime, &filename);
+    res_headers.insert(header::CONTENT_DISPOSITION, disposition.parse().unwrap());
+
+    let range = match headers.get(header::RANGE).and_then(|v| v.to_str().ok()) {
+        Some(range) if if_range_matches(headers, &etag) => parse_range(range, size),
+        _ => RangeRequest::Full,
+    };
+    let not_found = || AppError::NotFound("File doesn't exist".to_string());
+    match range {
+        RangeRequest::Full => {
+            let obj = state.store.get(&key).await?.ok_or_else(not_found)?;
+            res_headers.insert(header::CONTENT_TYPE, mime.to_string().parse().unwrap());
+            res_headers.insert(header::CONTENT_LENGTH, obj.meta.size.into());
+            Ok((res_headers, Body::from_stream(obj.body)).into_response())
+        }
+        RangeRequest::Unsatisfiable => {
+            let content_range = format!("bytes */{size}");
+            res_headers.insert(header::CONTENT_RANGE, content_range.parse().unwrap());
+            Ok((StatusCode::RANGE_NOT_SATISFIABLE, res_headers).into_response())
+        }
+        RangeRequest::Ranges(ranges) if ranges.len() == 1 => {
+            let range = ranges[0].clone();
+            let content_range = format!("bytes {}-{}/{size}", range.start, range.end - 1);
+            let len = range.end - range.start;
+            let obj = state
+                .store
+                .get_range(&key, range)
+                .await?
+                .ok_or_else(not_found)?;
+            res_headers.insert(header::CONTENT_TYPE, mime.to_string().parse().unwrap());
+            res_headers.insert(header::CONTENT_RANGE, content_range.parse().unwrap());
+            res_headers.insert(header::CONTENT_LENGTH, len.into());
+            Ok((
+                StatusCode::PARTIAL_CONTENT,
+                res_headers,
+                Body::from_stream(obj.body),
+            )
+                .into_response())
+        }
+        RangeRequest::Ranges(ranges) => {
+            let boundary = random_string(24);
+            let content_type = format!("multipart/byteranges; boundary={boundary}");
+            let parts: Vec<_> = ranges
+                .into_iter()
+                .map(|range| {
+                    let head = format!(
+                        "\r\n--{boundary}\r\nContent-Type: {mime}\r\nContent-Range: bytes {}-{}/{size}\r\n\r\n",
+                        range.start,
+                        range.end - 1
+                    );
+                    (Bytes::from(head), range)
+                })
+                .collect();
+            let tail = Bytes::from(format!("\r\n--{boundary}--\r\n"));
+            let len = parts
+                .iter()
+                .map(|(head, range)| head.len() as u64 + range.end - range.start)
+                .sum::<u64>()
+                + tail.len() as u64;
+
+            // every part is fetched from the store only when the client gets to it
+            let store = state.store.clone();
+            let body = stream::iter(parts)
+                .then(move |(head, range)| {
+                    let store = store.clone();
+                    let key = key.clone();
+                    async move {
+                        let obj = store
+                            .get_range(&key, range)
+                            .await
+                            .map_err(io::Error::other)?
+                            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+                        Ok::<_, io::Error>(bytes_stream(head).chain(obj.body))
+                    }
+                })
+                .try_flatten()
+                .chain(bytes_stream(tail));
+
+            res_headers.insert(header::CONTENT_TYPE, content_type.parse().unwrap());
+            res_headers.insert(header::CONTENT_LENGTH, len.into());
+            Ok((
+                StatusCode::PARTIAL_CONTENT,
+                res_headers,
+                Body::from_stream(body),
+            )
+                .into_response())
+        }
+    }
+}
+
+pub(crate) async fn file_meta_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    match state.get_file_meta(id, user.ws_id as _).await? {
+        Some(meta) if state.can_access_file(user.id, &meta.url).await? => Ok(Json(meta)),
+        _ => Err(AppError::NotFound(format!("file id {id}"))),
+    }
+}
+
+// If-None-Match uses the weak comparison, `W/` prefixes are ignored
+fn none_match(headers: &HeaderMap, etag: &str) -> bool {
+    let Some(value) = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return false;
+    };
+    value
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag)
+}
+
+// a Range is only honored if If-Range still names this content. Dates aren't supported,
+// such a request gets the full body which is always correct.
+fn if_range_matches(headers: &HeaderMap, etag: &str) -> bool {
+    match headers.get(header::IF_RANGE).and_then(|v| v.to_str().ok()) {
+        Some(value) => value.trim() == etag,
+        None => true,
+    }
+}
+
+// `bytes=0-99,200-,-50`. Invalid headers are ignored as RFC 9110 allows,
+// ranges past the end are dropped and clamped.
+fn parse_range(value: &str, size: u64) -> RangeRequest {
+    let Some(specs) = value.trim().strip_prefix("bytes=") else {
+        return RangeRequest::Full;
+    };
+    let specs: Vec<_> = specs.split(',').map(str::trim).collect();
+    if specs.len() > MAX_RANGES {
+        return RangeRequest::Full;
+    }
+
+    let mut ranges = vec![];
+    for spec in specs {
+        let Some((start, end)) = spec.split_once('-') else {
+            return RangeRequest::Full;
+        };
+        let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+            // the last `end` bytes
+            (Err(_), Ok(len)) if start.is_empty() => size.saturating_sub(len)..size,
+            (Ok(start), Err(_)) if end.is_empty() => start..size,
+            (Ok(start), Ok(end)) if start <= end => start..size.min(end + 1),
+            _ => return RangeRequest::Full,
+        };
+        if range.start < range.end {
+            ranges.push(range);
+        }
+    }
+
+    if ranges.is_empty() {
+        RangeRequest::Unsatisfiable
+    } else {
+        RangeRequest::Ranges(ranges)
+    }
+}
+
+// raster images, video and audio are shown in the browser, everything else is downloaded.
+// svg is a document that can carry script, so it's downloaded too.
+// `filename` is an ascii fallback, `filename*` carries the real name (RFC 6266)
+fn content_disposition(mime: &mime::Mime, filename: &str) -> String {
+    let media = matches!(mime.type_(), mime::IMAGE | mime::VIDEO | mime::AUDIO);
+    let kind = match media && mime.suffix() != Some(mime::XML) {
+        true => "inline",
+        false => "attachment",
+    };
+    let fallback: String = filename
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+    let mut encoded = String::new();
+    for b in filename.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{b:02X}")),
+        }
+    }
+    format!("{kind}; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::get_router;
+    use anyhow::Result;
+    use axum::{http::Request, Router};
+    use tower::ServiceExt;
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn parse_range_should_work() {
+        use RangeRequest::*;
+        assert_eq!(parse_range("bytes=0-4", 10), Ranges(vec![0..5]));
+        assert_eq!(parse_range("bytes=5-", 10), Ranges(vec![5..10]));
+        assert_eq!(parse_range("bytes=-3", 10), Ranges(vec![7..10]));
+        assert_eq!(parse_range("bytes=8-100", 10), Ranges(vec![8..10]));
+        assert_eq!(
+            parse_range("bytes=0-1, 20-30, 4-5", 10),
+            Ranges(vec![0..2, 4..6])
+        );
+        assert_eq!(parse_range("bytes=10-", 10), Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 10), Unsatisfiable);
+        assert_eq!(parse_range("bytes=5-1", 10), Full);
+        assert_eq!(parse_range("items=0-1", 10), Full);
+        assert_eq!(parse_range("bytes=abc", 10), Full);
+        let many = format!("bytes={}", vec!["0-0"; MAX_RANGES + 1].join(","));
+        assert_eq!(parse_range(&many, 10), Full);
+    }
+
+    #[test]
+    fn content_disposition_should_work() {
+        assert_eq!(
+            content_disposition(&mime::IMAGE_PNG, "cat.png"),
+            "inline; filename=\"cat.png\"; filename*=UTF-8''cat.png"
+        );
+        assert_eq!(
+            content_disposition(&mime::IMAGE_SVG, "cat.svg"),
+            "attachment; filename=\"cat.svg\"; filename*=UTF-8''cat.svg"
+        );
+        assert_eq!(
+            content_disposition(&mime::APPLICATION_PDF, "résumé \"v2\".pdf"),
+            "attachment; filename=\"r_sum_ _v2_.pdf\"; filename*=UTF-8''r%C3%A9sum%C3%A9%20%22v2%22.pdf"
+        );
+    }
+
+    async fn setup() -> Result<(sqlx_db_tester::TestPg, Router, String, crate::FileMeta)> {
+        let (tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+        let token = format!("Bearer {}", state.ek.sign(user.clone())?);
+        let body = stream::iter([Ok("hello world".into())]);
+        let meta = state
+            .save_upload(&user, "report.txt", body, &mut 1024, &Default::default())
+            .await?;
+        Ok((tdb, get_router(state).await?, token, meta))
+    }
+
+    async fn body_text(res: Response) -> Result<String> {
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await?;
+        Ok(String::from_utf8(body.to_vec())?)
+    }
+
+    #[tokio::test]
+    async fn file_meta_and_download_should_work() -> Result<()> {
+        let (_tdb, app, token, meta) = setup().await?;
+        let get = |uri: String| {
+            Request::builder()
+                .uri(uri)
+                .header("Authorization", &token)
+                .body(Body::empty())
+        };
+
+        let res = app
+            .clone()
+            .oneshot(get(format!("/api/files/{}/meta", meta.id))?)
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let ret: crate::FileMeta = serde_json::from_str(&body_text(res).await?)?;
+        assert_eq!(ret, meta);
+
+        let res = app
+            .clone()
+            .oneshot(get(format!("/api{}", meta.url))?)
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers()[header::CONTENT_DISPOSITION],
+            "attachment; filename=\"report.txt\"; filename*=UTF-8''report.txt"
+        );
+        assert_eq!(res.headers()[header::CACHE_CONTROL], CACHE_CONTROL);
+        assert_eq!(
+            res.headers()[header::ETAG],
+            "\"2aae6c35c94fcfb415dbe95f408b9ce91ee846ed\""
+        );
+        assert_eq!(body_text(res).await?, "hello world");
+
+        let res = app.oneshot(get("/api/files/999/meta".to_string())?).await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn svg_should_be_downloaded_not_rendered() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+        let svg = r#"<svg xmlns="http://www.w3.org/2000/svg"><script>alert(1)</script></svg>"#;
+        let body = stream::iter([Ok(svg.into())]);
+        let meta = state
+            .save_upload(&user, "cat.svg", body, &mut 1024, &Default::default())
+            .await?;
+        let signed = state.sign_file_url(&meta.url, user.id);
+        let app = get_router(state).await?;
+
+        let req = Request::builder().uri(signed).body(Body::empty())?;
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "image/svg+xml");
+        assert!(res.headers()[header::CONTENT_DISPOSITION]
+            .to_str()?
+            .starts_with("attachment;"));
+        assert_eq!(res.headers()[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert_eq!(
+            res.headers()[header::CONTENT_SECURITY_POLICY],
+            CONTENT_SECURITY_POLICY
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signed_file_url_should_work_without_token() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+        let body = stream::iter([Ok("hello world".into())]);
+        let meta = state
+            .save_upload(&user, "report.txt", body, &mut 1024, &Default::default())
+            .await?;
+        let signed = state.sign_file_url(&meta.url, user.id);
+        assert_eq!(signed, state.sign_file_url(&meta.url, user.id));
+        let other = ChatFile::new(1, "other.txt", b"other").url();
+        let app = get_router(state).await?;
+        let get = |uri: String| Request::builder().uri(uri).body(Body::empty());
+
+        let res = app.clone().oneshot(get(signed.clone())?).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body_text(res).await?, "hello world");
+
+        // the signature covers the file, the user and the expiry
+        let (_, query) = signed.split_once('?').unwrap();
+        for uri in [
+            format!("/api/signed{other}?{query}"),
+            signed.replace("uid=1", "uid=2"),
+            signed.replace("&exp=", "&exp=9"),
+        ] {
+            let res = app.clone().oneshot(get(uri)?).await?;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_handler_should_support_conditional_and_ranges() -> Result<()> {
+        let (_tdb, app, token, meta) = setup().await?;
+        let etag = "\"2aae6c35c94fcfb415dbe95f408b9ce91ee846ed\"";
+        let get = |name: &str, value: &str| {
+            Request::builder()
+                .uri(format!("/api{}", meta.url))
+                .header("Authorization", &token)
+                .header(name, value)
+                .body(Body::empty())
+        };
+
+        let res = app
+            .clone()
+            .oneshot(get("If-None-Match", &format!("\"other\", W/{etag}"))?)
+            .await?;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers()[header::ETAG], etag);
+
+        let res = app.clone().oneshot(get("Range", "bytes=6-")?).await?;
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(res.headers()[header::CONTENT_RANGE], "bytes 6-10/11");
+        assert_eq!(res.headers()[header::CONTENT_LENGTH], "5");
+        assert_eq!(body_text(res).await?, "world");
+
+        let res = app.clone().oneshot(get("Range", "bytes=20-")?).await?;
+        assert_eq!(res.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(res.headers()[header::CONTENT_RANGE], "bytes */11");
+
+        let res = app.clone().oneshot(get("Range", "bytes=0-1,-2")?).await?;
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        let content_type = res.headers()[header::CONTENT_TYPE].to_str()?.to_string();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap()
+            .to_string();
+        let len: usize = res.headers()[header::CONTENT_LENGTH].to_str()?.parse()?;
+        let body = body_text(res).await?;
+        assert_eq!(body.len(), len);
+        assert_eq!(
+            body,
+            format!(
+                "\r\n--{boundary}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/11\r\n\r\nhe\
+                 \r\n--{boundary}\r\nContent-Type: text/plain\r\nContent-Range: bytes 9-10/11\r\n\r\nld\
+                 \r\n--{boundary}--\r\n"
+            )
+        );
+
+        // a stale If-Range gets the whole file
+        let req = Request::builder()
+            .uri(format!("/api{}", meta.url))
+            .header("Authorization", &token)
+            .header("Range", "bytes=0-1")
+            .header("If-Range", "\"other\"")
+            .body(Body::empty())?;
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body_text(res).await?, "hello world");
+        Ok(())
+    }
+}
@@ -1,13 +1,13 @@
 use axum::{
-    extract::{Multipart, Path, Query, State},
-    http::{HeaderMap, StatusCode},
+    extract::{multipart::MultipartError, Multipart, Path, Query, State},
+    http::StatusCode,
     response::IntoResponse,
     Extension, Json,
 };
 use chat_core::User;
-use tokio::fs;
+use futures::TryStreamExt;
 
-use crate::{AppError, AppState, ChatFile, CreateMessage, ListMessage};
+use crate::{AppError, AppState, CreateMessage, ListMessage, MessageOutput, UploadOptions};
 
 pub(crate) async fn send_message_handler(
     Extension(user): Extension<User>,
@@ -15,71 +15,72 @@ pub(crate) async fn send_message_handler(
     Path(chat_id): Path<u64>,
     Json(input): Json<CreateMessage>,
 ) -> Result<impl IntoResponse, AppError> {
-    let msg = state.create_message(input, chat_id, user.id as _).await?;
-    Ok((StatusCode::CREATED, Json(msg)))
+    let mut output = state.send_message(input, chat_id, &user).await?;
+    let status = match &mut output {
+        MessageOutput::Message(message) => {
+            state.sign_attachments(std::slice::from_mut(message), user.id);
+            StatusCode::CREATED
+        }
+        MessageOutput::Ephemeral(_) => StatusCode::OK,
+    };
+    Ok((status, Json(output)))
+}
+
+// Path: chat_server/src/handlers/messages.rs
+// 标记当前用户正在输入, 由 notify_server 推送给其他成员, 几秒后自动过期
+pub(crate) async fn typing_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(chat_id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.set_typing(chat_id, user.id as _).await?;
+    Ok(StatusCode::NO_CONTENT)
 }
 
 pub(crate) async fn list_message_handler(
+    Extension(user): Extension<User>,
     State(state): State<AppState>,
     Path(id): Path<u64>,
     Query(input): Query<ListMessage>,
 ) -> Result<impl IntoResponse, AppError> {
-    let messages = state.list_message(input, id).await?;
+    let mut messages = state.list_message(input, id).await?;
+    state.sign_attachments(&mut messages, user.id);
     Ok(Json(messages))
 }
 
 pub(crate) async fn upload_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
+    Query(options): Query<UploadOptions>,
     mut multipart: Multipart,
 ) -> Result<impl IntoResponse, AppError> {
-    let ws_id = user.ws_id as u64;
-    let base_dir = &state.config.server.base_dir;
+    let mut budget = state.config.upload.max_request_size;
     let mut files = vec![];
 
-    while let Some(field) = multipart.next_field().await.map_err(|e| {
-        tracing::error!("Failed to read multipart field: {:?}", e);
-        AppError::UploadFileError("Failed to read multipart field".to_string())
-    })? {
-        let filename = field.file_name().map(|name| name.to_string());
-        let (Some(filename), Ok(data)) = (filename, field.bytes().await) else {
-            tracing::warn!("Failed to read multipart field");
+    while let Some(field) = multipart.next_field().await.map_err(multipart_error)? {
+        let Some(filename) = field.file_name().map(|name| name.to_string()) else {
+            tracing::warn!("Skip multipart field without a file name");
             continue;
         };
-        let file = ChatFile::new(ws_id, &filename, &data);
-        let path = file.path(base_dir);
-
-        if path.exists() {
-            tracing::info!("File {} already exists: {:?}", filename, path);
-        } else {
-            fs::create_dir_all(path.parent().expect("file path parent should exists")).await?;
-            fs::write(path, data).await?;
-        }
-        files.push(file.url());
+        let meta = state
+            .save_upload(
+                &user,
+                &filename,
+                field.map_err(multipart_error),
+                &mut budget,
+                &options,
+            )
+            .await?;
+        files.push(meta.url);
     }
     Ok(Json(files))
 }
 
-pub(crate) async fn file_handler(
-    Extension(user): Extension<User>,
-    State(state): State<AppState>,
-    Path((ws_id, path)): Path<(i64, String)>,
-) -> Result<impl IntoResponse, AppError> {
-    if user.ws_id != ws_id {
-        return Err(AppError::NotFound(
-            "File doesn't exist or you don't have permission".to_string(),
-        ));
-    }
-
-    let base_dir = state.config.server.base_dir.join(ws_id.to_string());
-    let path = base_dir.join(path);
-    if !path.exists() {
-        return Err(AppError::NotFound("File doesn't exist".to_string()));
+// the body limit layer surfaces as a multipart error too
+fn multipart_error(e: MultipartError) -> AppError {
+    if e.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        return AppError::PayloadTooLarge("Upload exceeds the request size limit".to_string());
     }
-
-    let mime = mime_guess::from_path(&path).first_or_octet_stream();
-    let body = fs::read(path).await?;
-    let mut headers = HeaderMap::new();
-    headers.insert("content-type", mime.to_string().parse().unwrap());
-    Ok((headers, body))
+    tracing::error!("Failed to read multipart field: {:?}", e);
+    AppError::UploadFileError("Failed to read multipart field".to_string())
 }
@@ -1,13 +1,23 @@
 mod auth;
 mod chat;
+mod command;
+mod file;
 mod messages;
+mod token;
+mod tus;
+mod webhook;
 mod workspace;
 
 use axum::response::IntoResponse;
 
 pub(crate) use auth::*;
 pub(crate) use chat::*;
+pub(crate) use command::*;
+pub(crate) use file::*;
 pub(crate) use messages::*;
+pub(crate) use token::*;
+pub(crate) use tus::*;
+pub(crate) use webhook::*;
 pub(crate) use workspace::*;
 
 pub(crate) async fn index_handler() -> impl IntoResponse {
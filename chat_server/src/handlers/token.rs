@@ -0,0 +1,52 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::User;
+
+use crate::{AppError, AppState, CreateApiToken, CreateBot};
+
+pub(crate) async fn list_bots_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let bots = state.fetch_bots(user.ws_id as _).await?;
+    Ok(Json(bots))
+}
+
+pub(crate) async fn create_bot_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<CreateBot>,
+) -> Result<impl IntoResponse, AppError> {
+    let bot = state.create_bot(&input, &user).await?;
+    Ok((StatusCode::CREATED, Json(bot)))
+}
+
+pub(crate) async fn list_api_tokens_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let tokens = state.fetch_api_tokens(&user).await?;
+    Ok(Json(tokens))
+}
+
+pub(crate) async fn create_api_token_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<CreateApiToken>,
+) -> Result<impl IntoResponse, AppError> {
+    let token = state.create_api_token(input, &user).await?;
+    Ok((StatusCode::CREATED, Json(token)))
+}
+
+pub(crate) async fn revoke_api_token_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.revoke_api_token(id, &user).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
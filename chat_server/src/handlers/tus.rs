@@ -0,0 +1,236 @@
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::IntoResponse,
+    Extension,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chat_core::User;
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+
+use crate::{models::TusUpload, AppError, AppState};
+
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,expiration,termination";
+const OFFSET_CONTENT_TYPE: &str = "application/offset+octet-stream";
+// tus has no header for it, clients need it to reference the file in a message
+const FILE_URL_HEADER: &str = "X-Chat-File-Url";
+
+// Path: chat_server/src/handlers/tus.rs
+// 服务端能力探测, 不需要登录
+pub(crate) async fn tus_options_handler(State(state): State<AppState>) -> impl IntoResponse {
+    let mut headers = tus_headers();
+    headers.insert("Tus-Version", HeaderValue::from_static(TUS_VERSION));
+    headers.insert("Tus-Extension", HeaderValue::from_static(TUS_EXTENSIONS));
+    headers.insert("Tus-Max-Size", state.config.upload.max_file_size.into());
+    (StatusCode::NO_CONTENT, headers)
+}
+
+pub(crate) async fn tus_create_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    check_version(&headers)?;
+    let Some(length) = header_u64(&headers, "Upload-Length") else {
+        return Err(AppError::UploadFileError(
+            "Upload-Length header is required".to_string(),
+        ));
+    };
+    let Some(filename) = headers
+        .get("Upload-Metadata")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| metadata_value(v, "filename"))
+    else {
+        return Err(AppError::UploadFileError(
+            "Upload-Metadata must contain a filename".to_string(),
+        ));
+    };
+
+    let upload = state.create_tus_upload(&user, &filename, length).await?;
+    let mut headers = upload_headers(&upload);
+    let location = format!("/api/tus/{}", upload.id);
+    headers.insert(header::LOCATION, location.parse().unwrap());
+    Ok((StatusCode::CREATED, headers))
+}
+
+pub(crate) async fn tus_head_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let upload = state.get_tus_upload(&id, &user).await?;
+    let mut headers = upload_headers(&upload);
+    headers.insert("Upload-Length", upload.upload_length.into());
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    Ok((StatusCode::OK, headers))
+}
+
+pub(crate) async fn tus_patch_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<impl IntoResponse, AppError> {
+    check_version(&headers)?;
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok());
+    if content_type != Some(OFFSET_CONTENT_TYPE) {
+        return Err(AppError::UnsupportedMediaType(format!(
+            "Content-Type must be {OFFSET_CONTENT_TYPE}"
+        )));
+    }
+    let Some(offset) = header_u64(&headers, "Upload-Offset") else {
+        return Err(AppError::UploadFileError(
+            "Upload-Offset header is required".to_string(),
+        ));
+    };
+
+    let body = body
+        .into_data_stream()
+        .map_err(|e| AppError::UploadFileError(format!("Failed to read body: {e}")));
+    let upload = state.append_tus_upload(&id, &user, offset, body).await?;
+    Ok((StatusCode::NO_CONTENT, upload_headers(&upload)))
+}
+
+pub(crate) async fn tus_delete_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    check_version(&headers)?;
+    state.delete_tus_upload(&id, &user).await?;
+    Ok((StatusCode::NO_CONTENT, tus_headers()))
+}
+
+fn tus_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert("Tus-Resumable", HeaderValue::from_static(TUS_VERSION));
+    headers
+}
+
+fn upload_headers(upload: &TusUpload) -> HeaderMap {
+    let mut headers = tus_headers();
+    headers.insert("Upload-Offset", upload.upload_offset.into());
+    match &upload.file_url {
+        Some(url) => {
+            headers.insert(FILE_URL_HEADER, url.parse().unwrap());
+        }
+        None => {
+            headers.insert(
+                "Upload-Expires",
+                http_date(upload.expires_at).parse().unwrap(),
+            );
+        }
+    }
+    headers
+}
+
+fn check_version(headers: &HeaderMap) -> Result<(), AppError> {
+    match headers.get("Tus-Resumable").and_then(|v| v.to_str().ok()) {
+        Some(TUS_VERSION) => Ok(()),
+        version => Err(AppError::TusVersionMismatch(
+            version.unwrap_or("none").to_string(),
+        )),
+    }
+}
+
+fn header_u64(headers: &HeaderMap, name: &str) -> Option<u64> {
+    headers.get(name)?.to_str().ok()?.parse().ok()
+}
+
+// Upload-Metadata is `key base64,key base64`, values are optional
+fn metadata_value(metadata: &str, key: &str) -> Option<String> {
+    metadata.split(',').find_map(|pair| {
+        let mut parts = pair.trim().splitn(2, ' ');
+        if parts.next()? != key {
+            return None;
+        }
+        let value = STANDARD.decode(parts.next()?.trim()).ok()?;
+        String::from_utf8(value).ok()
+    })
+}
+
+fn http_date(dt: DateTime<Utc>) -> String {
+    dt.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::get_router;
+    use anyhow::Result;
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    #[test]
+    fn metadata_value_should_work() {
+        let metadata = "filetype dGV4dC9wbGFpbg==,filename bm90ZXMudHh0,is_confidential";
+        assert_eq!(
+            metadata_value(metadata, "filename").as_deref(),
+            Some("notes.txt")
+        );
+        assert_eq!(metadata_value(metadata, "is_confidential"), None);
+        assert_eq!(metadata_value(metadata, "missing"), None);
+    }
+
+    #[tokio::test]
+    async fn tus_protocol_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+        let token = format!("Bearer {}", state.ek.sign(user)?);
+        let app = get_router(state).await?;
+
+        let req = Request::builder()
+            .method("POST")
+            .uri("/api/tus")
+            .header("Authorization", &token)
+            .header("Tus-Resumable", TUS_VERSION)
+            .header("Upload-Length", "11")
+            .header("Upload-Metadata", "filename bm90ZXMudHh0")
+            .body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let location = res.headers()[header::LOCATION].to_str()?.to_string();
+
+        let patch = |offset: &str, data: &'static str| {
+            Request::builder()
+                .method("PATCH")
+                .uri(&location)
+                .header("Authorization", &token)
+                .header("Tus-Resumable", TUS_VERSION)
+                .header(header::CONTENT_TYPE, OFFSET_CONTENT_TYPE)
+                .header("Upload-Offset", offset)
+                .body(Body::from(data))
+        };
+        let res = app.clone().oneshot(patch("0", "hello")?).await?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(res.headers()["Upload-Offset"], "5");
+
+        let res = app.clone().oneshot(patch("3", "lo world")?).await?;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        let req = Request::builder()
+            .method("HEAD")
+            .uri(&location)
+            .header("Authorization", &token)
+            .header("Tus-Resumable", TUS_VERSION)
+            .body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.headers()["Upload-Offset"], "5");
+        assert_eq!(res.headers()["Upload-Length"], "11");
+
+        let res = app.clone().oneshot(patch("5", " world")?).await?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            res.headers()[FILE_URL_HEADER],
+            "/files/1/2aa/e6c/35c94fcfb415dbe95f408b9ce91ee846ed.txt"
+        );
+        Ok(())
+    }
+}
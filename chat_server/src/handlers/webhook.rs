@@ -0,0 +1,93 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::User;
+
+use crate::{
+    AppError, AppState, CreateIncomingWebhook, CreateOutgoingWebhook, ListDeliveries,
+    WebhookPayload,
+};
+
+pub(crate) async fn list_incoming_webhooks_handler(
+    State(state): State<AppState>,
+    Path(chat_id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let hooks = state.fetch_incoming_webhooks(chat_id).await?;
+    Ok(Json(hooks))
+}
+
+pub(crate) async fn create_incoming_webhook_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(chat_id): Path<u64>,
+    Json(input): Json<CreateIncomingWebhook>,
+) -> Result<impl IntoResponse, AppError> {
+    let hook = state.create_incoming_webhook(chat_id, input, &user).await?;
+    Ok((StatusCode::CREATED, Json(hook)))
+}
+
+pub(crate) async fn delete_incoming_webhook_handler(
+    State(state): State<AppState>,
+    Path((chat_id, hook_id)): Path<(u64, u64)>,
+) -> Result<impl IntoResponse, AppError> {
+    state.delete_incoming_webhook(chat_id, hook_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// called by external systems, the secret token in the url is the credential
+pub(crate) async fn incoming_webhook_handler(
+    State(state): State<AppState>,
+    Path((id, token)): Path<(u64, String)>,
+    Json(payload): Json<WebhookPayload>,
+) -> Result<impl IntoResponse, AppError> {
+    let msg = state.post_incoming_webhook(id, &token, payload).await?;
+    Ok((StatusCode::CREATED, Json(msg)))
+}
+
+pub(crate) async fn list_outgoing_webhooks_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let hooks = state.fetch_outgoing_webhooks(&user).await?;
+    Ok(Json(hooks))
+}
+
+pub(crate) async fn create_outgoing_webhook_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<CreateOutgoingWebhook>,
+) -> Result<impl IntoResponse, AppError> {
+    let hook = state.create_outgoing_webhook(input, &user).await?;
+    Ok((StatusCode::CREATED, Json(hook)))
+}
+
+pub(crate) async fn delete_outgoing_webhook_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.delete_outgoing_webhook(id, &user).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn list_webhook_deliveries_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Query(input): Query<ListDeliveries>,
+) -> Result<impl IntoResponse, AppError> {
+    let deliveries = state.fetch_webhook_deliveries(id, input, &user).await?;
+    Ok(Json(deliveries))
+}
+
+pub(crate) async fn retry_webhook_delivery_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, delivery_id)): Path<(u64, u64)>,
+) -> Result<impl IntoResponse, AppError> {
+    state.retry_webhook_delivery(id, delivery_id, &user).await?;
+    Ok(StatusCode::ACCEPTED)
+}
@@ -1,7 +1,7 @@
 use axum::{extract::State, response::IntoResponse, Extension, Json};
 use chat_core::User;
 
-use crate::{AppError, AppState};
+use crate::{AppError, AppState, SetStatus, WorkspaceNotificationSettings};
 
 pub(crate) async fn list_chat_users_handler(
     Extension(user): Extension<User>,
@@ -10,3 +10,33 @@ pub(crate) async fn list_chat_users_handler(
     let users = state.fetch_chat_users(user.ws_id as _).await?;
     Ok(Json(users))
 }
+
+pub(crate) async fn storage_usage_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let usage = state.storage_usage(&user).await?;
+    Ok(Json(usage))
+}
+
+// Path: chat_server/src/handlers/workspace.rs
+// 设置当前用户的状态文字 / emoji 和免打扰时段, 在线状态由 notify_server 的连接决定
+pub(crate) async fn set_status_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<SetStatus>,
+) -> Result<impl IntoResponse, AppError> {
+    let presence = state.set_status(user.id as _, input).await?;
+    Ok(Json(presence))
+}
+
+// Path: chat_server/src/handlers/workspace.rs
+// 工作区默认通知级别, 仅工作区所有者可修改
+pub(crate) async fn update_workspace_notify_level_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<WorkspaceNotificationSettings>,
+) -> Result<impl IntoResponse, AppError> {
+    let settings = state.update_workspace_notify_level(&user, input).await?;
+    Ok(Json(settings))
+}
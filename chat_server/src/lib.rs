@@ -1,29 +1,56 @@
+mod commands;
 mod config;
 mod error;
 mod handlers;
+mod mailer;
 mod middlewares;
 mod models;
+mod oidc;
+mod scanner;
+mod storage;
+mod utils;
 
 use anyhow::{Context, Result};
+use commands::CommandRegistry;
 use core::fmt;
-use middlewares::verify_chat;
+use middlewares::{deny_api_tokens, verify_chat, RequireScope};
 use std::{ops::Deref, sync::Arc};
 use tokio::fs;
 
 use axum::{
-    middleware::from_fn_with_state,
-    routing::{get, post},
+    extract::DefaultBodyLimit,
+    handler::Handler,
+    middleware::{from_fn, from_fn_with_state},
+    routing::{delete, get, head, options, post, put},
     Router,
 };
 use chat_core::{
     middlewares::{set_layer, verify_token, TokenVerify},
-    utils::{DecodingKey, EncodingKey},
+    utils::{public_client, DecodingKey, EncodingKey},
+    TokenScope,
 };
 
-pub use config::AppConfig;
+pub use commands::{EphemeralReply, MessageOutput};
+pub use config::{AppConfig, EmailConfig, S3Config, ScannerConfig, StorageConfig, UploadConfig};
 pub use error::{AppError, ErrorOutput};
 use handlers::*;
-pub use models::{ChatFile, CreateChat, CreateMessage, CreateUser, ListMessage, SigninUser};
+use mailer::build_mailer;
+pub use mailer::{Email, Mailer, SmtpMailer};
+pub use models::{
+    ApiToken, ChatFile, ChatNotificationSettings, CreateApiToken, CreateBot, CreateChat,
+    CreateIncomingWebhook, CreateMessage, CreateOutgoingWebhook, CreateSlashCommand, CreateUser,
+    CreatedApiToken, CreatedIncomingWebhook, CreatedOutgoingWebhook, CreatedSlashCommand,
+    DeliveryStatus, FileMeta, IncomingWebhook, ListDeliveries, ListMessage, OutgoingWebhook,
+    Reminder, ResponseType, ScanStatus, SetStatus, SignedFileParams, SigninUser, SlashCommand,
+    SlashCommandResponse, StorageUsage, TusUpload, UpdateNotificationSettings, UploadOptions,
+    UploaderUsage, WebhookDelivery, WebhookMatch, WebhookPayload, WorkspaceNotificationSettings,
+};
+use oidc::OidcClient;
+use scanner::build_scanner;
+pub use scanner::{ClamdScanner, FileScanner, ScanOutcome};
+use storage::build_store;
+pub use storage::{ByteStream, FileStore, LocalStore, ObjectMeta, S3Store, StoredObject};
+use utils::RateLimiter;
 
 #[derive(Debug, Clone)]
 pub struct AppState {
@@ -35,29 +62,129 @@ pub struct AppStateInner {
     pub(crate) dk: DecodingKey,
     pub(crate) ek: EncodingKey,
     pub(crate) pool: sqlx::PgPool,
+    pub(crate) oidc: Option<OidcClient>,
+    pub(crate) hook_limiter: RateLimiter,
+    pub(crate) http: reqwest::Client,
+    pub(crate) commands: CommandRegistry,
+    pub(crate) store: Arc<dyn FileStore>,
+    pub(crate) scanner: Option<Arc<dyn FileScanner>>,
+    pub(crate) mailer: Option<Arc<dyn Mailer>>,
 }
 
 pub async fn get_router(state: AppState) -> Result<Router, AppError> {
+    let read_chats = RequireScope(TokenScope::ReadChats);
+    let write_chats = RequireScope(TokenScope::WriteChats);
+    // multipart framing on top of the files themselves
+    let upload_limit = state.config.upload.max_request_size as usize + 64 * 1024;
+
     let chat = Router::new()
         .route(
             "/:id",
-            get(get_chat_handler)
-                .patch(update_chat_handler)
-                .delete(delete_chat_handler)
-                .post(send_message_handler),
+            get(get_chat_handler.layer(read_chats))
+                .patch(update_chat_handler.layer(write_chats))
+                .delete(delete_chat_handler.layer(write_chats))
+                .post(send_message_handler.layer(RequireScope(TokenScope::PostMessages))),
+        )
+        .route("/:id/messages", get(list_message_handler.layer(read_chats)))
+        .route(
+            "/:id/notifications",
+            get(get_notification_settings_handler.layer(read_chats))
+                .put(update_notification_settings_handler.layer(write_chats)),
+        )
+        .route(
+            "/:id/typing",
+            post(typing_handler.layer(RequireScope(TokenScope::PostMessages))),
+        )
+        .route(
+            "/:id/webhooks",
+            get(list_incoming_webhooks_handler.layer(write_chats))
+                .post(create_incoming_webhook_handler.layer(write_chats)),
+        )
+        .route(
+            "/:id/webhooks/:hook_id",
+            delete(delete_incoming_webhook_handler.layer(write_chats)),
         )
-        .route("/:id/messages", get(list_message_handler))
         .layer(from_fn_with_state(state.clone(), verify_chat))
-        .route("/", get(list_chat_handler).post(create_chat_handler));
+        .route(
+            "/",
+            get(list_chat_handler.layer(read_chats)).post(create_chat_handler.layer(write_chats)),
+        );
+
+    // every route here needs a session, api tokens only reach the scoped routes below
+    let session = Router::new()
+        .route("/users/me/status", put(set_status_handler))
+        .route("/workspace/usage", get(storage_usage_handler))
+        .route(
+            "/workspace/notifications",
+            put(update_workspace_notify_level_handler),
+        )
+        .route("/bots", get(list_bots_handler).post(create_bot_handler))
+        .route(
+            "/tokens",
+            get(list_api_tokens_handler).post(create_api_token_handler),
+        )
+        .route("/tokens/:id", delete(revoke_api_token_handler))
+        .route_layer(from_fn(deny_api_tokens));
 
+    let upload_files = RequireScope(TokenScope::UploadFiles);
     let api = Router::new()
-        .route("/users", get(list_chat_users_handler))
+        .route("/users", get(list_chat_users_handler.layer(read_chats)))
         .nest("/chats", chat)
-        .route("/upload", post(upload_handler))
-        .route("/files/:ws_id/*path", get(file_handler))
+        .route(
+            "/upload",
+            post(upload_handler.layer(upload_files)).layer(DefaultBodyLimit::max(upload_limit)),
+        )
+        .route("/tus", post(tus_create_handler.layer(upload_files)))
+        .route(
+            "/tus/:id",
+            head(tus_head_handler.layer(upload_files))
+                .patch(tus_patch_handler.layer(upload_files))
+                .delete(tus_delete_handler.layer(upload_files)),
+        )
+        // `:id` is the workspace id for downloads and the file id for metadata
+        .route(
+            "/files/:id/*path",
+            get(file_handler.layer(RequireScope(TokenScope::ReadFiles))),
+        )
+        .route(
+            "/files/:id/meta",
+            get(file_meta_handler.layer(RequireScope(TokenScope::ReadFiles))),
+        )
+        .route(
+            "/commands",
+            get(list_slash_commands_handler.layer(write_chats))
+                .post(create_slash_command_handler.layer(write_chats)),
+        )
+        .route(
+            "/commands/:id",
+            delete(delete_slash_command_handler.layer(write_chats)),
+        )
+        .route(
+            "/webhooks/outgoing",
+            get(list_outgoing_webhooks_handler.layer(write_chats))
+                .post(create_outgoing_webhook_handler.layer(write_chats)),
+        )
+        .route(
+            "/webhooks/outgoing/:id",
+            delete(delete_outgoing_webhook_handler.layer(write_chats)),
+        )
+        .route(
+            "/webhooks/outgoing/:id/deliveries",
+            get(list_webhook_deliveries_handler.layer(write_chats)),
+        )
+        .route(
+            "/webhooks/outgoing/:id/deliveries/:delivery_id/retry",
+            post(retry_webhook_delivery_handler.layer(write_chats)),
+        )
+        .merge(session)
         .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
         .route("/signin", post(signin_handler))
-        .route("/signup", post(signup_handler));
+        .route("/signup", post(signup_handler))
+        .route("/tus", options(tus_options_handler))
+        .route("/oidc/login", get(oidc_login_handler))
+        .route("/oidc/callback", get(oidc_callback_handler))
+        .route("/hooks/:id/:token", post(incoming_webhook_handler))
+        .route("/signed/files/:ws_id/*path", get(signed_file_handler));
 
     let app = Router::new()
         .route("/", get(index_handler))
@@ -78,7 +205,11 @@ impl Deref for AppState {
 
 impl TokenVerify for AppState {
     type Error = AppError;
-    fn verify(&self, token: &str) -> std::result::Result<chat_core::User, Self::Error> {
+    // api tokens are opaque and looked up in the db, everything else is a jwt
+    async fn verify(&self, token: &str) -> std::result::Result<chat_core::User, Self::Error> {
+        if token.starts_with(models::API_TOKEN_PREFIX) {
+            return self.verify_api_token(token).await;
+        }
         Ok(self.dk.verify(token)?)
     }
 }
@@ -93,12 +224,24 @@ impl AppState {
         let pool = sqlx::PgPool::connect(&config.server.db_url)
             .await
             .context("connect to db failed")?;
+        let oidc = config.oidc.clone().map(OidcClient::new);
+        let store = build_store(&config);
+        let scanner = build_scanner(&config);
+        let mailer = build_mailer(&config)?;
+        let http = public_client(config.webhook.allow_private_urls);
         Ok(Self {
             inner: Arc::new(AppStateInner {
                 config,
                 dk,
                 ek,
                 pool,
+                oidc,
+                hook_limiter: RateLimiter::default(),
+                http,
+                commands: CommandRegistry::default(),
+                store,
+                scanner,
+                mailer,
             }),
         })
     }
@@ -120,18 +263,38 @@ mod test_util {
 
     impl AppState {
         pub async fn new_for_test() -> Result<(TestPg, Self), AppError> {
-            let config = AppConfig::load()?;
+            let mut config = AppConfig::load()?;
+            // webhooks and commands in tests call local listeners
+            config.webhook.allow_private_urls = true;
+            Self::new_for_test_with_config(config).await
+        }
+
+        pub async fn new_for_test_with_config(
+            config: AppConfig,
+        ) -> Result<(TestPg, Self), AppError> {
             let dk = DecodingKey::load(&config.auth.pk).context("load pk failed")?;
             let ek = EncodingKey::load(&config.auth.sk).context("load sk failed")?;
             let post = config.server.db_url.rfind('/').expect("invalid db_url");
             let server_url = &config.server.db_url[..post];
             let (tdb, pool) = get_test_pool(Some(server_url)).await;
+            let oidc = config.oidc.clone().map(OidcClient::new);
+            let store = build_store(&config);
+            let scanner = build_scanner(&config);
+            let mailer = build_mailer(&config)?;
+            let http = public_client(config.webhook.allow_private_urls);
             let state = Self {
                 inner: Arc::new(AppStateInner {
                     config,
                     ek,
                     dk,
                     pool,
+                    oidc,
+                    hook_limiter: RateLimiter::default(),
+                    http,
+                    commands: CommandRegistry::default(),
+                    store,
+                    scanner,
+                    mailer,
                 }),
             };
             Ok((tdb, state))
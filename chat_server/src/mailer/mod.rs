@@ -0,0 +1,33 @@
+mod smtp;
+
+use std::{future::Future, pin::Pin, sync::Arc};
+
+use crate::{AppConfig, AppError};
+
+pub use smtp::SmtpMailer;
+
+#[cfg(test)]
+pub(crate) use smtp::tests::start_smtp_sink;
+
+pub type MailFuture<'a> = Pin<Box<dyn Future<Output = Result<(), AppError>> + Send + 'a>>;
+
+/// A plain text mail to a single recipient
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Email {
+    pub to_name: String,
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Delivers mail on behalf of the server
+pub trait Mailer: Send + Sync {
+    fn send<'a>(&'a self, email: &'a Email) -> MailFuture<'a>;
+}
+
+pub(crate) fn build_mailer(config: &AppConfig) -> Result<Option<Arc<dyn Mailer>>, AppError> {
+    let Some(email) = config.email.as_ref() else {
+        return Ok(None);
+    };
+    Ok(Some(Arc::new(SmtpMailer::new(email)?)))
+}
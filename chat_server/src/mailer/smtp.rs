@@ -0,0 +1,195 @@
+use std::time::Duration;
+
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+
+use crate::{config::EmailConfig, AppError};
+
+use super::{Email, MailFuture, Mailer};
+
+/// Submits mail to an SMTP relay, a new connection for every mail
+#[derive(Debug, Clone)]
+pub struct SmtpMailer {
+    from: Mailbox,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpMailer {
+    pub fn new(config: &EmailConfig) -> Result<Self, AppError> {
+        let from = config.from.parse().map_err(email_error)?;
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::from_url(&config.smtp)
+            .map_err(email_error)?
+            .timeout(Some(Duration::from_secs(config.timeout_secs)))
+            .build();
+        Ok(Self { from, transport })
+    }
+
+    async fn deliver(&self, email: &Email) -> Result<(), AppError> {
+        let to = Mailbox::new(
+            Some(email.to_name.clone()),
+            email.to.parse().map_err(email_error)?,
+        );
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(&email.subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(email.body.clone())
+            .map_err(email_error)?;
+        self.transport.send(message).await.map_err(email_error)?;
+        Ok(())
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send<'a>(&'a self, email: &'a Email) -> MailFuture<'a> {
+        Box::pin(self.deliver(email))
+    }
+}
+
+fn email_error(e: impl std::fmt::Display) -> AppError {
+    AppError::EmailError(e.to_string())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use anyhow::Result;
+    use std::sync::{Arc, Mutex};
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+    };
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub(crate) struct SinkMail {
+        pub from: String,
+        pub to: Vec<String>,
+        pub data: String,
+    }
+
+    /// Accepts every mail and keeps it in memory
+    #[derive(Debug, Clone)]
+    pub(crate) struct SmtpSink {
+        pub url: String,
+        mails: Arc<Mutex<Vec<SinkMail>>>,
+    }
+
+    impl SmtpSink {
+        pub(crate) fn mails(&self) -> Vec<SinkMail> {
+            self.mails.lock().unwrap().clone()
+        }
+    }
+
+    pub(crate) async fn start_smtp_sink() -> Result<SmtpSink> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let sink = SmtpSink {
+            url: format!("smtp://{}", listener.local_addr()?),
+            mails: Default::default(),
+        };
+        let mails = sink.mails.clone();
+        tokio::spawn(async move {
+            while let Ok((conn, _)) = listener.accept().await {
+                tokio::spawn(serve_smtp(conn, mails.clone()));
+            }
+        });
+        Ok(sink)
+    }
+
+    async fn serve_smtp(conn: TcpStream, mails: Arc<Mutex<Vec<SinkMail>>>) -> Result<()> {
+        let (read, mut write) = conn.into_split();
+        let mut lines = BufReader::new(read).lines();
+        let mut mail = SinkMail {
+            from: String::new(),
+            to: vec![],
+            data: String::new(),
+        };
+        write.write_all(b"220 sink ESMTP\r\n").await?;
+        while let Some(line) = lines.next_line().await? {
+            let upper = line.to_ascii_uppercase();
+            let reply: &[u8] = if upper.starts_with("EHLO") || upper.starts_with("HELO") {
+                b"250 sink\r\n"
+            } else if upper.starts_with("MAIL FROM:") {
+                mail.from = angle_addr(&line);
+                b"250 ok\r\n"
+            } else if upper.starts_with("RCPT TO:") {
+                mail.to.push(angle_addr(&line));
+                b"250 ok\r\n"
+            } else if upper == "DATA" {
+                write.write_all(b"354 go ahead\r\n").await?;
+                while let Some(line) = lines.next_line().await? {
+                    if line == "." {
+                        break;
+                    }
+                    let line = line.strip_prefix('.').unwrap_or(&line);
+                    mail.data.push_str(line);
+                    mail.data.push('\n');
+                }
+                mails.lock().unwrap().push(mail.clone());
+                mail.to.clear();
+                mail.data.clear();
+                b"250 queued\r\n"
+            } else if upper == "QUIT" {
+                write.write_all(b"221 bye\r\n").await?;
+                break;
+            } else {
+                b"250 ok\r\n"
+            };
+            write.write_all(reply).await?;
+        }
+        Ok(())
+    }
+
+    fn angle_addr(line: &str) -> String {
+        let start = line.find('<').map_or(0, |i| i + 1);
+        let end = line.rfind('>').unwrap_or(line.len());
+        line[start..end].to_string()
+    }
+
+    #[tokio::test]
+    async fn smtp_mailer_should_deliver_to_sink() -> Result<()> {
+        let sink = start_smtp_sink().await?;
+        let mailer = SmtpMailer::new(&EmailConfig {
+            smtp: sink.url.clone(),
+            from: "Chat <chat@acme.org>".to_string(),
+            base_url: "http://localhost".to_string(),
+            digest_delay_secs: 60,
+            digest_interval_secs: 60,
+            timeout_secs: 5,
+        })?;
+        let email = Email {
+            to_name: "Alice".to_string(),
+            to: "alice@acme.org".to_string(),
+            subject: "Hello".to_string(),
+            body: "first line\n.leading dot".to_string(),
+        };
+        mailer.send(&email).await?;
+
+        let mails = sink.mails();
+        assert_eq!(mails.len(), 1);
+        assert_eq!(mails[0].from, "chat@acme.org");
+        assert_eq!(mails[0].to, vec!["alice@acme.org"]);
+        assert!(mails[0].data.contains("Subject: Hello"));
+        assert!(mails[0].data.contains("To: Alice <alice@acme.org>"));
+        assert!(mails[0].data.contains("first line\n.leading dot"));
+        Ok(())
+    }
+
+    #[test]
+    fn smtp_mailer_should_reject_bad_sender() {
+        let config = EmailConfig {
+            smtp: "smtp://localhost:25".to_string(),
+            from: "not an address".to_string(),
+            base_url: "http://localhost".to_string(),
+            digest_delay_secs: 60,
+            digest_interval_secs: 60,
+            timeout_secs: 5,
+        };
+        assert!(matches!(
+            SmtpMailer::new(&config),
+            Err(AppError::EmailError(_))
+        ));
+    }
+}
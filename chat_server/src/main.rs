@@ -13,6 +13,12 @@ async fn main() -> Result<()> {
     let addr = format!("0.0.0.0:{}", config.server.port);
 
     let state = AppState::try_new(config).await?;
+    tokio::spawn(state.clone().run_webhook_dispatcher());
+    tokio::spawn(state.clone().run_reminders());
+    tokio::spawn(state.clone().run_upload_janitor());
+    tokio::spawn(state.clone().run_file_gc());
+    tokio::spawn(state.clone().run_file_scans());
+    tokio::spawn(state.clone().run_email_digests());
     let app = get_router(state).await?;
     let listener = TcpListener::bind(&addr).await?;
     info!("Listening on: {}", addr);
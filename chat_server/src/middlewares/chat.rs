@@ -10,14 +10,17 @@ use crate::{error::AppError, AppState};
 pub async fn verify_chat(State(state): State<AppState>, req: Request, next: Next) -> Response {
     let (mut parts, body) = req.into_parts();
 
-    let path = Path::<u64>::from_request_parts(&mut parts, &state).await;
+    // nested routes may carry more params than the chat id
+    let chat_id = Path::<Vec<(String, String)>>::from_request_parts(&mut parts, &state)
+        .await
+        .ok()
+        .and_then(|Path(params)| params.into_iter().find(|(k, _)| k == "id"))
+        .and_then(|(_, v)| v.parse::<u64>().ok());
 
-    if path.is_err() {
+    let Some(chat_id) = chat_id else {
         return AppError::ParseUrlPathError("chat_id should be a number".to_string())
             .into_response();
-    }
-
-    let Path(chat_id) = path.unwrap();
+    };
 
     let user = parts.extensions.get::<User>().unwrap();
 
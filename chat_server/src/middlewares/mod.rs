@@ -1,3 +1,5 @@
 mod chat;
+mod scope;
 
 pub use chat::verify_chat;
+pub use scope::{deny_api_tokens, RequireScope};
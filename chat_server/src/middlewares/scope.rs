@@ -0,0 +1,153 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use axum::{
+    extract::Request,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chat_core::{TokenScope, User};
+use tower::{Layer, Service};
+
+use crate::error::AppError;
+
+/// Reject requests whose api token wasn't granted the scope, must run after verify_token
+#[derive(Debug, Clone, Copy)]
+pub struct RequireScope(pub TokenScope);
+
+impl<S> Layer<S> for RequireScope {
+    type Service = RequireScopeMiddleware<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequireScopeMiddleware {
+            inner,
+            scope: self.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequireScopeMiddleware<S> {
+    inner: S,
+    scope: TokenScope,
+}
+
+impl<S> Service<Request> for RequireScopeMiddleware<S>
+where
+    S: Service<Request, Response = Response> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+
+    type Error = S::Error;
+
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let allowed = req
+            .extensions()
+            .get::<User>()
+            .is_some_and(|user| user.has_scope(self.scope));
+
+        if !allowed {
+            let err = AppError::PermissionDenied(format!("token lacks the {:?} scope", self.scope));
+            return Box::pin(async move { Ok(err.into_response()) });
+        }
+
+        Box::pin(self.inner.call(req))
+    }
+}
+
+/// Let only interactive sessions through, routes reachable with an api token must use
+/// `RequireScope` instead
+pub async fn deny_api_tokens(req: Request, next: Next) -> Response {
+    let allowed = req
+        .extensions()
+        .get::<User>()
+        .is_some_and(|user| user.scopes.is_none());
+
+    if !allowed {
+        return AppError::PermissionDenied("api tokens can't use this endpoint".to_string())
+            .into_response();
+    }
+
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use axum::{
+        body::Body,
+        handler::Handler,
+        http::StatusCode,
+        middleware::{from_fn, from_fn_with_state},
+        routing::{get, put},
+        Router,
+    };
+    use chat_core::middlewares::verify_token;
+    use tower::ServiceExt;
+
+    use crate::{AppState, CreateApiToken};
+
+    async fn handler() -> impl IntoResponse {
+        (StatusCode::OK, "ok")
+    }
+
+    #[tokio::test]
+    async fn require_scope_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        let jwt = state.ek.sign(user.clone())?;
+        let input = CreateApiToken {
+            name: "reader".to_string(),
+            scopes: vec![TokenScope::ReadChats],
+            expires_in_days: None,
+            bot_id: None,
+        };
+        let pat = state.create_api_token(input, &user).await?.token;
+
+        let app = Router::new()
+            .route(
+                "/",
+                get(handler.layer(RequireScope(TokenScope::ReadChats)))
+                    .post(handler.layer(RequireScope(TokenScope::PostMessages))),
+            )
+            .merge(
+                Router::new()
+                    .route("/session", put(handler))
+                    .route_layer(from_fn(deny_api_tokens)),
+            )
+            .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
+            .with_state(state);
+
+        let cases = [
+            ("GET", "/", &jwt, StatusCode::OK),
+            ("POST", "/", &jwt, StatusCode::OK),
+            ("PUT", "/session", &jwt, StatusCode::OK),
+            ("GET", "/", &pat, StatusCode::OK),
+            ("POST", "/", &pat, StatusCode::FORBIDDEN),
+            ("PUT", "/session", &pat, StatusCode::FORBIDDEN),
+        ];
+        for (method, uri, token, status) in cases {
+            let req = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())?;
+            let res = app.clone().oneshot(req).await?;
+            assert_eq!(res.status(), status, "{} {} with {}", method, uri, token);
+        }
+
+        Ok(())
+    }
+}
@@ -1,4 +1,5 @@
-use chat_core::{Chat, ChatType};
+use chat_core::{typing::publish_typing, Chat, ChatType};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
 use crate::{error::AppError, AppState};
@@ -49,7 +50,7 @@ impl AppState {
             r#"
             INSERT INTO chats (ws_id, name, type, members)
             VALUES ($1, $2, $3, $4)
-            RETURNING id, ws_id, name, type, members, created_at"#,
+            RETURNING id, ws_id, name, type, members, topic, created_at"#,
         )
         .bind(ws_id as i64)
         .bind(input.name)
@@ -64,7 +65,7 @@ impl AppState {
     pub async fn fetch_chats(&self, ws_id: u64) -> Result<Vec<Chat>, AppError> {
         let chats = sqlx::query_as(
             r#"
-            SELECT id, ws_id, name, type, members, created_at
+            SELECT id, ws_id, name, type, members, topic, created_at
             FROM chats
             WHERE ws_id = $1
             "#,
@@ -79,7 +80,7 @@ impl AppState {
     pub async fn get_chat_by_id(&self, id: u64) -> Result<Option<Chat>, AppError> {
         let chat = sqlx::query_as(
             r#"
-            SELECT id, ws_id, name, type, members, created_at
+            SELECT id, ws_id, name, type, members, topic, created_at
             FROM chats
             WHERE id = $1
             "#,
@@ -106,6 +107,111 @@ impl AppState {
 
         Ok(is_member.is_some())
     }
+
+    /// Announce that the user is typing, notify_server fans it out and expires it.
+    /// Nothing is stored, a user outside the chat is rejected
+    pub async fn set_typing(&self, chat_id: u64, user_id: u64) -> Result<(), AppError> {
+        if publish_typing(&self.pool, chat_id as _, user_id as _).await? {
+            return Ok(());
+        }
+        Err(AppError::PermissionDenied(format!(
+            "user {user_id} is not a member of chat {chat_id}"
+        )))
+    }
+
+    pub async fn set_chat_topic(&self, chat_id: u64, topic: Option<&str>) -> Result<(), AppError> {
+        sqlx::query("UPDATE chats SET topic = $2 WHERE id = $1")
+            .bind(chat_id as i64)
+            .bind(topic)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    // Append the users that aren't members yet, keeping the order of the existing ones
+    pub async fn add_chat_members(&self, chat_id: u64, user_ids: &[i64]) -> Result<Chat, AppError> {
+        let chat = sqlx::query_as(
+            r#"
+            UPDATE chats
+            SET members = members || ARRAY(
+              SELECT u FROM unnest($2::bigint[]) WITH ORDINALITY AS t(u, i)
+              WHERE u <> ALL(members)
+              ORDER BY i)
+            WHERE id = $1
+            RETURNING id, ws_id, name, type, members, topic, created_at
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_ids)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        chat.ok_or_else(|| AppError::NotFound(format!("chat id {chat_id}")))
+    }
+
+    pub async fn remove_chat_member(&self, chat_id: u64, user_id: u64) -> Result<(), AppError> {
+        sqlx::query("UPDATE chats SET members = array_remove(members, $2) WHERE id = $1")
+            .bind(chat_id as i64)
+            .bind(user_id as i64)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Mute the chat for the user, `until` of None mutes it until unmuted.
+    /// The notification level the user picked for the chat is kept
+    pub async fn mute_chat(
+        &self,
+        chat_id: u64,
+        user_id: u64,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            INSERT INTO chat_notification_settings (chat_id, user_id, muted_until)
+            VALUES ($1, $2, COALESCE($3, '9999-12-31 23:59:59+00'::timestamptz))
+            ON CONFLICT (chat_id, user_id) DO UPDATE
+            SET muted_until = EXCLUDED.muted_until, updated_at = CURRENT_TIMESTAMP
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .bind(until)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn unmute_chat(&self, chat_id: u64, user_id: u64) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            UPDATE chat_notification_settings
+            SET muted_until = NULL, updated_at = CURRENT_TIMESTAMP
+            WHERE chat_id = $1 AND user_id = $2
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn is_chat_muted(&self, chat_id: u64, user_id: u64) -> Result<bool, AppError> {
+        let muted = sqlx::query(
+            r#"
+            SELECT 1
+            FROM chat_notification_settings
+            WHERE chat_id = $1 AND user_id = $2 AND muted_until > CURRENT_TIMESTAMP
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(muted.is_some())
+    }
 }
 
 #[cfg(test)]
@@ -123,6 +229,7 @@ impl CreateChat {
 mod tests {
     use super::*;
     use anyhow::Result;
+    use chat_core::typing::TYPING_CHANNEL;
 
     #[tokio::test]
     async fn create_single_chat_should_work() -> Result<()> {
@@ -199,4 +306,24 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn set_typing_should_notify_members_only() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let mut listener = sqlx::postgres::PgListener::connect_with(&state.pool).await?;
+        listener.listen(TYPING_CHANNEL).await?;
+
+        state.set_typing(2, 1).await?;
+        let payload: serde_json::Value = serde_json::from_str(listener.recv().await?.payload())?;
+        assert_eq!(payload, serde_json::json!({"chat_id": 2, "user_id": 1}));
+
+        // user 4 is not a member of chat 2
+        let ret = state.set_typing(2, 4).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let messages: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM messages WHERE chat_id = 2")
+            .fetch_one(&state.pool)
+            .await?;
+        assert_eq!(messages, 0);
+        Ok(())
+    }
 }
@@ -0,0 +1,327 @@
+use std::time::Duration;
+
+use chat_core::{
+    mention::{is_mentioned, mentions},
+    ChatType,
+};
+use chrono::{DateTime, Utc};
+use sqlx::FromRow;
+
+use crate::{config::EmailConfig, error::AppError, mailer::Email, AppState};
+
+// messages listed in a single digest, the rest are only counted
+const MAX_DIGEST_MESSAGES: usize = 20;
+const MAX_EXCERPT_CHARS: usize = 140;
+// older messages never make it into a digest, e.g. the history of a chat someone joins
+const MAX_DIGEST_AGE_SECS: f64 = 7.0 * 24.0 * 60.0 * 60.0;
+
+#[derive(Debug, Clone, FromRow)]
+struct UnreadMessage {
+    user_id: i64,
+    fullname: String,
+    email: String,
+    // newest message already mailed to the user
+    since: i64,
+    id: i64,
+    chat_id: i64,
+    chat_name: Option<String>,
+    chat_type: ChatType,
+    sender: String,
+    content: String,
+    created_at: DateTime<Utc>,
+}
+
+impl AppState {
+    /// Mail every user one digest of the mentions and direct messages they left unread,
+    /// returning how many digests were sent
+    pub async fn send_digests(&self) -> Result<usize, AppError> {
+        let (Some(mailer), Some(config)) = (self.mailer.as_ref(), self.config.email.as_ref())
+        else {
+            return Ok(0);
+        };
+        let unread = self.unread_for_digest(config.digest_delay_secs).await?;
+
+        let mut sent = 0;
+        for messages in unread.chunk_by(|a, b| a.user_id == b.user_id) {
+            let first = &messages[0];
+            let last_id = messages.iter().map(|m| m.id).max().unwrap_or_default();
+            if !self
+                .claim_digest(first.user_id, first.since, last_id)
+                .await?
+            {
+                continue;
+            }
+            let email = digest_email(config, messages);
+            if let Err(e) = mailer.send(&email).await {
+                // hand the messages back so the next run retries them
+                self.release_digest(first.user_id, first.since, last_id)
+                    .await?;
+                tracing::warn!("Send digest to {} failed: {}", first.email, e);
+                continue;
+            }
+            sent += 1;
+        }
+        Ok(sent)
+    }
+
+    /// Send digests until the process exits
+    pub async fn run_email_digests(self) {
+        let Some(config) = self.config.email.as_ref() else {
+            return;
+        };
+        let interval = Duration::from_secs(config.digest_interval_secs.max(1));
+        loop {
+            if let Err(e) = self.send_digests().await {
+                tracing::warn!("Send email digests failed: {}", e);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    // unread direct messages and mentions older than the delay, grouped by user
+    async fn unread_for_digest(&self, delay_secs: u64) -> Result<Vec<UnreadMessage>, AppError> {
+        let rows: Vec<UnreadMessage> = sqlx::query_as(
+            r#"
+            SELECT u.id AS user_id, u.fullname, u.email,
+              COALESCE(d.last_message_id, 0) AS since, m.id, m.chat_id,
+              c.name AS chat_name, c.type AS chat_type, s.fullname AS sender, m.content,
+              m.created_at
+            FROM users u
+            JOIN workspaces w ON w.id = u.ws_id
+            JOIN chats c ON u.id = ANY(c.members)
+            JOIN messages m ON m.chat_id = c.id
+            JOIN users s ON s.id = m.sender_id
+            LEFT JOIN chat_reads r ON r.chat_id = c.id AND r.user_id = u.id
+            LEFT JOIN chat_notification_settings n ON n.chat_id = c.id AND n.user_id = u.id
+            LEFT JOIN email_digests d ON d.user_id = u.id
+            WHERE NOT u.is_bot
+              AND m.sender_id <> u.id
+              AND m.id > COALESCE(r.last_read_id, 0)
+              AND m.id > COALESCE(d.last_message_id, 0)
+              AND m.created_at <= NOW() - make_interval(secs => $1)
+              AND m.created_at > NOW() - make_interval(secs => $2)
+              -- answering in the chat reads everything before the answer
+              AND NOT EXISTS (
+                SELECT 1 FROM messages o
+                WHERE o.chat_id = c.id AND o.sender_id = u.id AND o.id > m.id)
+              AND COALESCE(n.level, w.notify_level) <> 'none'
+              AND (n.muted_until IS NULL OR n.muted_until <= NOW())
+              AND (c.type IN ('single', 'group')
+                OR m.content ILIKE ('%@' || split_part(u.email, '@', 1) || '%'))
+            ORDER BY u.id, m.id
+            "#,
+        )
+        .bind(delay_secs as f64)
+        .bind(MAX_DIGEST_AGE_SECS)
+        .fetch_all(&self.pool)
+        .await?;
+
+        // the ILIKE above only narrows channels down, a mention needs the whole handle
+        Ok(rows
+            .into_iter()
+            .filter(|m| {
+                matches!(m.chat_type, ChatType::Single | ChatType::Group)
+                    || is_mentioned(&mentions(&m.content), &m.email)
+            })
+            .collect())
+    }
+
+    // move the user's watermark forward, false if another instance got there first
+    async fn claim_digest(&self, user_id: i64, since: i64, last_id: i64) -> Result<bool, AppError> {
+        let claimed = sqlx::query(
+            r#"
+            INSERT INTO email_digests (user_id, last_message_id)
+            VALUES ($1, $3)
+            ON CONFLICT (user_id) DO UPDATE
+            SET last_message_id = EXCLUDED.last_message_id, sent_at = NOW()
+            WHERE email_digests.last_message_id = $2
+            "#,
+        )
+        .bind(user_id)
+        .bind(since)
+        .bind(last_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(claimed.rows_affected() == 1)
+    }
+
+    async fn release_digest(&self, user_id: i64, since: i64, last_id: i64) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            UPDATE email_digests SET last_message_id = $2
+            WHERE user_id = $1 AND last_message_id = $3
+            "#,
+        )
+        .bind(user_id)
+        .bind(since)
+        .bind(last_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
+
+fn digest_email(config: &EmailConfig, messages: &[UnreadMessage]) -> Email {
+    let first = &messages[0];
+    let subject = match messages.len() {
+        1 => "You have 1 unread message".to_string(),
+        n => format!("You have {} unread messages", n),
+    };
+    let base_url = config.base_url.trim_end_matches('/');
+
+    let mut body = format!("Hi {},\n\n{}:\n", first.fullname, subject);
+    let mut chat_id = None;
+    for m in messages.iter().take(MAX_DIGEST_MESSAGES) {
+        if chat_id != Some(m.chat_id) {
+            chat_id = Some(m.chat_id);
+            body.push_str(&format!("\n{}\n", chat_title(m)));
+        }
+        body.push_str(&format!(
+            "  {} at {}: {}\n  {}/chats/{}?message={}\n",
+            m.sender,
+            m.created_at.format("%Y-%m-%d %H:%M UTC"),
+            excerpt(&m.content),
+            base_url,
+            m.chat_id,
+            m.id
+        ));
+    }
+    if messages.len() > MAX_DIGEST_MESSAGES {
+        body.push_str(&format!(
+            "\n...and {} more.\n",
+            messages.len() - MAX_DIGEST_MESSAGES
+        ));
+    }
+
+    Email {
+        to_name: first.fullname.clone(),
+        to: first.email.clone(),
+        subject,
+        body,
+    }
+}
+
+fn chat_title(m: &UnreadMessage) -> String {
+    match (&m.chat_type, &m.chat_name) {
+        (ChatType::Single, _) => format!("Direct message from {}", m.sender),
+        (ChatType::Group, Some(name)) => name.clone(),
+        (ChatType::Group, None) => "Group message".to_string(),
+        (_, name) => format!("#{}", name.as_deref().unwrap_or_default()),
+    }
+}
+
+// the content on one line, cut at a char boundary
+fn excerpt(content: &str) -> String {
+    let flat = content.split_whitespace().collect::<Vec<_>>().join(" ");
+    match flat.char_indices().nth(MAX_EXCERPT_CHARS) {
+        Some((i, _)) => format!("{}...", &flat[..i]),
+        None => flat,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mailer::start_smtp_sink, AppConfig, CreateMessage};
+    use anyhow::Result;
+
+    async fn post(state: &AppState, chat_id: u64, user_id: u64, content: &str) -> Result<i64> {
+        let input = CreateMessage {
+            content: content.to_string(),
+            files: vec![],
+        };
+        let message = state.create_message(input, chat_id, user_id).await?;
+        // old enough to be past the digest delay
+        sqlx::query("UPDATE messages SET created_at = NOW() - INTERVAL '2 hours' WHERE id = $1")
+            .bind(message.id)
+            .execute(&state.pool)
+            .await?;
+        Ok(message.id)
+    }
+
+    #[test]
+    fn excerpt_should_flatten_and_truncate() {
+        assert_eq!(excerpt("hello\n  world"), "hello world");
+        let long = "é".repeat(MAX_EXCERPT_CHARS + 10);
+        assert_eq!(
+            excerpt(&long),
+            format!("{}...", "é".repeat(MAX_EXCERPT_CHARS))
+        );
+    }
+
+    #[tokio::test]
+    async fn digests_should_be_sent_once_per_message() -> Result<()> {
+        let sink = start_smtp_sink().await?;
+        let mut config = AppConfig::load()?;
+        config.email = Some(EmailConfig {
+            smtp: sink.url.clone(),
+            from: "Chat <chat@acme.org>".to_string(),
+            base_url: "https://chat.acme.org/".to_string(),
+            digest_delay_secs: 3600,
+            digest_interval_secs: 60,
+            timeout_secs: 5,
+        });
+        let (_tdb, state) = AppState::new_for_test_with_config(config).await?;
+
+        // alice (2) gets a dm, bob (3) a mention, the fixture messages are too recent
+        let dm = post(&state, 3, 1, "are you around?").await?;
+        let mention = post(&state, 1, 1, "@bob can you review this?").await?;
+        post(&state, 1, 2, "@bobby is someone else").await?;
+        assert_eq!(state.send_digests().await?, 2);
+
+        let mails = sink.mails();
+        assert_eq!(mails.len(), 2);
+        let alice = mails.iter().find(|m| m.to == ["alice@acme.org"]).unwrap();
+        assert!(alice.data.contains("Direct message from Tyr Chen"));
+        assert!(alice.data.contains("are you around?"));
+        assert!(alice
+            .data
+            .contains(&format!("https://chat.acme.org/chats/3?message={}", dm)));
+        let bob = mails.iter().find(|m| m.to == ["bob@acme.org"]).unwrap();
+        assert!(bob.data.contains("#general"));
+        assert!(bob.data.contains(&format!(
+            "https://chat.acme.org/chats/1?message={}",
+            mention
+        )));
+        assert!(!bob.data.contains("@bobby"));
+
+        // nothing new, nothing sent
+        assert_eq!(state.send_digests().await?, 0);
+
+        // only the new dm goes out, tchen already read alice's reply
+        let second = post(&state, 3, 1, "never mind").await?;
+        post(&state, 3, 2, "sorry, was out").await?;
+        sqlx::query("INSERT INTO chat_reads (chat_id, user_id, last_read_id) VALUES (3, 1, $1)")
+            .bind(second + 1)
+            .execute(&state.pool)
+            .await?;
+        assert_eq!(state.send_digests().await?, 1);
+        let mails = sink.mails();
+        assert_eq!(mails.len(), 3);
+        assert_eq!(mails[2].to, ["alice@acme.org"]);
+        assert!(mails[2].data.contains("never mind"));
+        assert!(!mails[2].data.contains("are you around?"));
+
+        // replying counts as reading, old history is never mailed
+        post(&state, 3, 2, "still there?").await?;
+        let reply = post(&state, 3, 1, "yes").await?;
+        sqlx::query("INSERT INTO chat_reads (chat_id, user_id, last_read_id) VALUES (3, 2, $1)")
+            .bind(reply)
+            .execute(&state.pool)
+            .await?;
+        let old = post(&state, 1, 2, "@tchen from last month").await?;
+        sqlx::query("UPDATE messages SET created_at = NOW() - INTERVAL '30 days' WHERE id = $1")
+            .bind(old)
+            .execute(&state.pool)
+            .await?;
+        assert_eq!(state.send_digests().await?, 0);
+
+        // a workspace that turned notifications off gets no digests either
+        sqlx::query("UPDATE workspaces SET notify_level = 'none' WHERE id = 1")
+            .execute(&state.pool)
+            .await?;
+        post(&state, 3, 1, "anyone?").await?;
+        assert_eq!(state.send_digests().await?, 0);
+        Ok(())
+    }
+}
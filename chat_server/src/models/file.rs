@@ -1,12 +1,27 @@
 use std::{
     path::{Path, PathBuf},
+    pin::pin,
     str::FromStr,
 };
 
+use bytes::Bytes;
+use chat_core::{Attachment, Message, Thumbnail, User};
+use chrono::{DateTime, Utc};
+use futures::{Stream, TryStreamExt};
+use hmac::{Hmac, Mac};
+use img_parts::{DynImage, ImageEXIF};
 use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use sqlx::{types::Json, FromRow, Postgres, Transaction};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncWriteExt},
+};
+
+use crate::{error::AppError, utils::random_string, AppState};
 
-use crate::error::AppError;
+use super::ScanStatus;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ChatFile {
@@ -15,13 +30,70 @@ pub struct ChatFile {
     pub hash: String,
 }
 
+/// Query of a signed file link, `/api/signed/files/...?uid=1&exp=1700003600&sig=...`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedFileParams {
+    pub uid: i64,
+    pub exp: i64,
+    pub sig: String,
+}
+
+/// One upload of a file. Identical content shares the url but each upload keeps its own name.
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct FileMeta {
+    pub id: i64,
+    pub ws_id: i64,
+    pub uploader_id: i64,
+    pub url: String,
+    pub filename: String,
+    pub size: i64,
+    pub mime: String,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub blurhash: Option<String>,
+    pub thumbnails: Json<Vec<Thumbnail>>,
+    pub scan_status: ScanStatus,
+    // the signature found or why scanning failed
+    pub scan_detail: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Query of `/api/upload`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UploadOptions {
+    // drop EXIF (camera, GPS...) from jpeg, png and webp images before they are stored
+    #[serde(default)]
+    pub strip_exif: bool,
+}
+
+impl FileMeta {
+    pub fn attachment(&self) -> Attachment {
+        Attachment {
+            id: self.id,
+            url: self.url.clone(),
+            filename: self.filename.clone(),
+            size: self.size,
+            mime: self.mime.clone(),
+            signed_url: None,
+            width: self.width,
+            height: self.height,
+            blurhash: self.blurhash.clone(),
+            thumbnails: self.thumbnails.0.clone(),
+        }
+    }
+}
+
 impl ChatFile {
     pub fn new(ws_id: u64, filename: &str, data: &[u8]) -> Self {
-        let hash = Sha1::digest(data);
+        Self::from_hash(ws_id, filename, hex::encode(Sha1::digest(data)))
+    }
+
+    // for content that was hashed while it streamed in
+    pub fn from_hash(ws_id: u64, filename: &str, hash: String) -> Self {
         Self {
             ws_id,
-            ext: filename.split('.').last().unwrap_or("txt").to_string(),
-            hash: hex::encode(hash),
+            ext: filename.split('.').next_back().unwrap_or("txt").to_string(),
+            hash,
         }
     }
 
@@ -29,8 +101,26 @@ impl ChatFile {
         format!("/files/{}", self.hash_to_path())
     }
 
-    pub fn path(&self, base_dir: &Path) -> PathBuf {
-        base_dir.join(self.hash_to_path())
+    // key of the file in the FileStore
+    pub fn key(&self) -> String {
+        self.hash_to_path()
+    }
+
+    /// Derived from the image, stored next to it as `{hash}-{size}.jpg` or `.png`
+    pub fn thumbnail(&self, size: u32) -> ChatFile {
+        ChatFile {
+            ws_id: self.ws_id,
+            ext: self.thumbnail_ext().to_string(),
+            hash: format!("{}-{}", self.hash, size),
+        }
+    }
+
+    // jpeg stays jpeg, anything else may have transparency and becomes png
+    pub(crate) fn thumbnail_ext(&self) -> &'static str {
+        match self.ext.to_lowercase().as_str() {
+            "jpg" | "jpeg" => "jpg",
+            _ => "png",
+        }
     }
 
     fn hash_to_path(&self) -> String {
@@ -40,6 +130,380 @@ impl ChatFile {
     }
 }
 
+impl AppState {
+    /// Stream one uploaded file to a temp file while hashing it, then move it into the store.
+    /// `budget` is what is left of the per request limit and shrinks by the file size.
+    pub async fn save_upload(
+        &self,
+        user: &User,
+        filename: &str,
+        body: impl Stream<Item = Result<Bytes, AppError>>,
+        budget: &mut u64,
+        options: &UploadOptions,
+    ) -> Result<FileMeta, AppError> {
+        let config = &self.config.upload;
+        self.ensure_mime_allowed(filename)?;
+
+        let dir = self.config.server.base_dir.join(".tmp");
+        fs::create_dir_all(&dir).await?;
+        let tmp = TempFile(dir.join(random_string(16)));
+        let mut file = fs::File::create(&tmp.0).await?;
+        let mut hasher = Sha1::new();
+        let mut size = 0u64;
+
+        let mut body = pin!(body);
+        while let Some(chunk) = body.try_next().await? {
+            size += chunk.len() as u64;
+            if size > config.max_file_size {
+                return Err(AppError::PayloadTooLarge(format!(
+                    "{} is larger than {} bytes",
+                    filename, config.max_file_size
+                )));
+            }
+            if size > *budget {
+                return Err(AppError::PayloadTooLarge(
+                    "Upload exceeds the request size limit".to_string(),
+                ));
+            }
+            hasher.update(&chunk);
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        drop(file);
+        *budget -= size;
+
+        let mut hash = hex::encode(hasher.finalize());
+        if options.strip_exif {
+            if let Some(data) = strip_exif(&tmp.0, size, config.max_strip_exif_size).await? {
+                hash = hex::encode(Sha1::digest(&data));
+                size = data.len() as u64;
+            }
+        }
+
+        let chat_file = ChatFile::from_hash(user.ws_id as _, filename, hash);
+        // known content takes no extra room, so the quota can only be checked once it is hashed
+        if self
+            .find_file_meta_by_url(&chat_file.url())
+            .await?
+            .is_none()
+            && self
+                .storage_remaining(user.ws_id)
+                .await?
+                .is_some_and(|left| size > left)
+        {
+            return Err(AppError::QuotaExceeded(format!(
+                "workspace {} has no room for {}",
+                user.ws_id, filename
+            )));
+        }
+        let lock = self.lock_file_url(&chat_file.url()).await?;
+        self.store_local_file(&chat_file, &tmp.0).await?;
+        let meta = self
+            .create_file_meta(&chat_file, user.id, filename, size)
+            .await?;
+        lock.commit().await?;
+        Ok(meta)
+    }
+
+    pub(crate) async fn create_file_meta(
+        &self,
+        file: &ChatFile,
+        uploader_id: i64,
+        filename: &str,
+        size: u64,
+    ) -> Result<FileMeta, AppError> {
+        let mime = mime_guess::from_path(filename).first_or_octet_stream();
+        // identical content keeps the verdict it already got
+        let meta: FileMeta = sqlx::query_as(
+            r#"
+            INSERT INTO files (ws_id, uploader_id, url, filename, size, mime, scan_status, scan_detail)
+            SELECT $1, $2, $3, $4, $5, $6, COALESCE(o.scan_status, $7), o.scan_detail
+            FROM (SELECT 1) t
+            LEFT JOIN LATERAL (
+              SELECT scan_status, scan_detail FROM files
+              WHERE url = $3 AND scan_status IN ('clean', 'infected')
+              ORDER BY id DESC
+              LIMIT 1
+            ) o ON true
+            RETURNING id, ws_id, uploader_id, url, filename, size, mime, width, height, blurhash,
+              thumbnails, scan_status, scan_detail, created_at
+            "#,
+        )
+        .bind(file.ws_id as i64)
+        .bind(uploader_id)
+        .bind(file.url())
+        .bind(filename)
+        .bind(size as i64)
+        .bind(mime.essence_str())
+        .bind(if self.scanner.is_some() {
+            ScanStatus::Pending
+        } else {
+            ScanStatus::Clean
+        })
+        .fetch_one(&self.pool)
+        .await?;
+        match meta.scan_status {
+            ScanStatus::Clean => self.spawn_previews(&meta),
+            ScanStatus::Pending => self.spawn_scan(&meta),
+            _ => {}
+        }
+        Ok(meta)
+    }
+
+    pub async fn get_file_meta(&self, id: u64, ws_id: u64) -> Result<Option<FileMeta>, AppError> {
+        let meta = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, uploader_id, url, filename, size, mime, width, height, blurhash,
+              thumbnails, scan_status, scan_detail, created_at
+            FROM files
+            WHERE id = $1 AND ws_id = $2
+            "#,
+        )
+        .bind(id as i64)
+        .bind(ws_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(meta)
+    }
+
+    /// The uploader always has access, anyone else must be in a chat the file was posted in.
+    /// Thumbnails follow the image they were made from.
+    pub async fn can_access_file(&self, user_id: i64, url: &str) -> Result<bool, AppError> {
+        let url = self.source_file_url(url).await?;
+        let (allowed,): (bool,) = sqlx::query_as(
+            r#"
+            SELECT EXISTS (SELECT 1 FROM files WHERE url = $1 AND uploader_id = $2)
+              OR EXISTS (
+                SELECT 1 FROM message_files mf
+                JOIN chats c ON c.id = mf.chat_id
+                WHERE mf.url = $1 AND $2 = ANY(c.members)
+              )
+            "#,
+        )
+        .bind(url)
+        .bind(user_id)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(allowed)
+    }
+
+    // thumbnails are the only files with a `-` in their hash
+    pub(crate) async fn source_file_url(&self, url: &str) -> Result<String, AppError> {
+        let is_thumbnail = ChatFile::from_str(url).is_ok_and(|file| file.hash.contains('-'));
+        if !is_thumbnail {
+            return Ok(url.to_string());
+        }
+        let source: Option<(String,)> =
+            sqlx::query_as("SELECT url FROM files WHERE thumbnails @> $1 LIMIT 1")
+                .bind(Json(serde_json::json!([{ "url": url }])))
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(source.map_or_else(|| url.to_string(), |(url,)| url))
+    }
+
+    /// Latest upload of a url, its name is what downloads are saved as
+    pub async fn find_file_meta_by_url(&self, url: &str) -> Result<Option<FileMeta>, AppError> {
+        let meta = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, uploader_id, url, filename, size, mime, width, height, blurhash,
+              thumbnails, scan_status, scan_detail, created_at
+            FROM files
+            WHERE url = $1
+            ORDER BY id DESC
+            LIMIT 1
+            "#,
+        )
+        .bind(url)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(meta)
+    }
+
+    /// Resolve `files` of each message into attachments. When the same content was uploaded
+    /// several times the sender's own upload wins. Files uploaded before metadata existed are left out.
+    pub(crate) async fn attach_files(&self, messages: &mut [Message]) -> Result<(), AppError> {
+        let urls: Vec<&str> = messages
+            .iter()
+            .flat_map(|m| m.files.iter().map(|f| f.as_str()))
+            .collect();
+        if urls.is_empty() {
+            return Ok(());
+        }
+
+        let metas: Vec<FileMeta> = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, uploader_id, url, filename, size, mime, width, height, blurhash,
+              thumbnails, scan_status, scan_detail, created_at
+            FROM files
+            WHERE url = ANY($1)
+            ORDER BY id DESC
+            "#,
+        )
+        .bind(&urls)
+        .fetch_all(&self.pool)
+        .await?;
+
+        for message in messages.iter_mut() {
+            message.attachments = message
+                .files
+                .iter()
+                .filter_map(|url| {
+                    let mut uploads = metas.iter().filter(|m| &m.url == url);
+                    uploads
+                        .clone()
+                        .find(|m| m.uploader_id == message.sender_id)
+                        .or_else(|| uploads.next())
+                })
+                .map(FileMeta::attachment)
+                .collect();
+        }
+        Ok(())
+    }
+
+    /// A link to the chat file `url` that works without a token, for `<img>` and `<video>`.
+    /// The expiry is rounded up so repeated calls give the same link and browsers can cache it.
+    pub fn sign_file_url(&self, url: &str, user_id: i64) -> String {
+        let ttl = self.config.file_url.ttl_secs.max(1) as i64;
+        let exp = (Utc::now().timestamp() / ttl + 2) * ttl;
+        let sig = hex::encode(self.file_url_mac(url, user_id, exp).finalize().into_bytes());
+        format!("/api/signed{url}?uid={user_id}&exp={exp}&sig={sig}")
+    }
+
+    /// Checked against the key alone, no database lookup
+    pub fn verify_file_url(&self, url: &str, params: &SignedFileParams) -> Result<(), AppError> {
+        let sig = hex::decode(&params.sig)
+            .map_err(|_| AppError::PermissionDenied("invalid file link signature".to_string()))?;
+        self.file_url_mac(url, params.uid, params.exp)
+            .verify_slice(&sig)
+            .map_err(|_| AppError::PermissionDenied("invalid file link signature".to_string()))?;
+        if params.exp < Utc::now().timestamp() {
+            return Err(AppError::PermissionDenied("file link expired".to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn sign_attachments(&self, messages: &mut [Message], user_id: i64) {
+        for attachment in messages.iter_mut().flat_map(|m| m.attachments.iter_mut()) {
+            attachment.signed_url = Some(self.sign_file_url(&attachment.url, user_id));
+            for thumbnail in &mut attachment.thumbnails {
+                thumbnail.signed_url = Some(self.sign_file_url(&thumbnail.url, user_id));
+            }
+        }
+    }
+
+    fn file_url_mac(&self, url: &str, user_id: i64, exp: i64) -> Hmac<Sha256> {
+        // a key of its own so a link signature can't be replayed anywhere else
+        let mut key = Hmac::<Sha256>::new_from_slice(self.config.auth.sk.as_bytes())
+            .expect("hmac accepts any key size");
+        key.update(b"chat file url");
+        let mut mac = Hmac::<Sha256>::new_from_slice(&key.finalize().into_bytes())
+            .expect("hmac accepts any key size");
+        mac.update(format!("{url}\n{user_id}\n{exp}").as_bytes());
+        mac
+    }
+
+    // files are served with the mime of their extension, so that is what gets checked
+    pub(crate) fn ensure_mime_allowed(&self, filename: &str) -> Result<(), AppError> {
+        let mime = mime_guess::from_path(filename).first_or_octet_stream();
+        if mime_allowed(&self.config.upload.allowed_mime, mime.essence_str()) {
+            Ok(())
+        } else {
+            Err(AppError::UnsupportedMediaType(format!(
+                "{} ({}) is not allowed",
+                filename, mime
+            )))
+        }
+    }
+
+    // move a fully received file into the store, content addressing makes duplicates free
+    // uploads hold this from storing the object until its row exists, so the file gc
+    // can't delete the object in between
+    pub(crate) async fn lock_file_url(
+        &self,
+        url: &str,
+    ) -> Result<Transaction<'static, Postgres>, AppError> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("SELECT pg_advisory_xact_lock(hashtext($1))")
+            .bind(url)
+            .execute(&mut *tx)
+            .await?;
+        Ok(tx)
+    }
+
+    pub(crate) async fn store_local_file(
+        &self,
+        file: &ChatFile,
+        path: &Path,
+    ) -> Result<(), AppError> {
+        let key = file.key();
+        if self.store.exists(&key).await? {
+            tracing::info!("File {} already exists", key);
+            fs::remove_file(path).await?;
+        } else {
+            self.store.put_file(&key, path).await?;
+        }
+        Ok(())
+    }
+}
+
+// rewrites the file without EXIF, returns the new content if anything was removed.
+// Anything that isn't a jpeg, png or webp is kept as is.
+async fn strip_exif(path: &Path, size: u64, max_size: u64) -> Result<Option<Bytes>, AppError> {
+    // the first bytes tell whether it's an image at all, before anything is read into memory
+    let mut head = Vec::with_capacity(12);
+    fs::File::open(path)
+        .await?
+        .take(12)
+        .read_to_end(&mut head)
+        .await?;
+    if !has_exif_format(&head) {
+        return Ok(None);
+    }
+    if size > max_size {
+        return Err(AppError::PayloadTooLarge(format!(
+            "EXIF can only be stripped from images up to {max_size} bytes"
+        )));
+    }
+
+    let data = Bytes::from(fs::read(path).await?);
+    let Ok(Some(mut image)) = DynImage::from_bytes(data) else {
+        return Ok(None);
+    };
+    if image.exif().is_none() {
+        return Ok(None);
+    }
+    image.set_exif(None);
+    let data = image.encoder().bytes();
+    fs::write(path, &data).await?;
+    Ok(Some(data))
+}
+
+// jpeg, png or webp
+fn has_exif_format(head: &[u8]) -> bool {
+    head.starts_with(&[0xff, 0xd8, 0xff])
+        || head.starts_with(b"\x89PNG\r\n\x1a\n")
+        || (head.starts_with(b"RIFF") && head.get(8..12) == Some(&b"WEBP"[..]))
+}
+
+// removes the temp file unless it was moved into the store
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+fn mime_allowed(allowed: &[String], mime: &str) -> bool {
+    allowed.is_empty()
+        || allowed
+            .iter()
+            .any(|pattern| match pattern.strip_suffix("/*") {
+                Some(prefix) => mime.split('/').next() == Some(prefix),
+                None => pattern == mime,
+            })
+}
+
 impl FromStr for ChatFile {
     type Err = AppError;
 
@@ -87,6 +551,133 @@ impl FromStr for ChatFile {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::AppConfig;
+    use anyhow::Result;
+    use axum::extract::Query;
+    use futures::stream;
+
+    fn chunks(parts: &[&'static str]) -> impl Stream<Item = Result<Bytes, AppError>> {
+        stream::iter(
+            parts
+                .iter()
+                .map(|p| Ok(Bytes::from_static(p.as_bytes())))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    fn tmp_files(state: &AppState) -> usize {
+        std::fs::read_dir(state.config.server.base_dir.join(".tmp"))
+            .map(|dir| dir.count())
+            .unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn save_upload_should_hash_while_streaming() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+        let mut budget = 1024;
+        let meta = state
+            .save_upload(
+                &user,
+                "test.txt",
+                chunks(&["hello", " ", "world"]),
+                &mut budget,
+                &UploadOptions::default(),
+            )
+            .await?;
+        let file = ChatFile::new(1, "test.txt", b"hello world");
+        assert_eq!(meta.url, file.url());
+        assert_eq!(budget, 1024 - 11);
+        assert_eq!(
+            state.store.get_bytes(&file.key()).await?.unwrap(),
+            "hello world"
+        );
+
+        // the metadata keeps what was uploaded
+        assert_eq!(meta.filename, "test.txt");
+        assert_eq!(meta.size, 11);
+        assert_eq!(meta.mime, "text/plain");
+        assert_eq!(meta.uploader_id, 1);
+        assert_eq!(
+            state.get_file_meta(meta.id as _, 1).await?,
+            Some(meta.clone())
+        );
+        assert_eq!(state.get_file_meta(meta.id as _, 2).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn save_upload_should_enforce_limits() -> Result<()> {
+        let mut config = AppConfig::load()?;
+        config.server.base_dir = std::env::temp_dir().join(format!("chat-{}", random_string(8)));
+        config.upload.max_file_size = 8;
+        config.upload.allowed_mime = vec!["image/*".to_string(), "text/plain".to_string()];
+        let (_tdb, state) = AppState::new_for_test_with_config(config).await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+
+        let mut budget = 100;
+        let ret = state
+            .save_upload(
+                &user,
+                "big.txt",
+                chunks(&["hello", " world"]),
+                &mut budget,
+                &UploadOptions::default(),
+            )
+            .await;
+        assert!(matches!(ret, Err(AppError::PayloadTooLarge(_))));
+        assert_eq!(tmp_files(&state), 0);
+
+        let mut budget = 4;
+        let ret = state
+            .save_upload(
+                &user,
+                "a.png",
+                chunks(&["abc", "de"]),
+                &mut budget,
+                &UploadOptions::default(),
+            )
+            .await;
+        assert!(matches!(ret, Err(AppError::PayloadTooLarge(_))));
+
+        let ret = state
+            .save_upload(
+                &user,
+                "run.exe",
+                chunks(&["MZ"]),
+                &mut budget,
+                &UploadOptions::default(),
+            )
+            .await;
+        assert!(matches!(ret, Err(AppError::UnsupportedMediaType(_))));
+        assert_eq!(tmp_files(&state), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verify_file_url_should_reject_expired_links() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let url = "/files/1/2aa/e6c/35c94f.txt";
+        let signed = state.sign_file_url(url, 1);
+        let Query(params) = Query::<SignedFileParams>::try_from_uri(&signed.parse()?)?;
+        assert!(state.verify_file_url(url, &params).is_ok());
+
+        let exp = Utc::now().timestamp() - 1;
+        let sig = hex::encode(state.file_url_mac(url, 1, exp).finalize().into_bytes());
+        let params = SignedFileParams { uid: 1, exp, sig };
+        let ret = state.verify_file_url(url, &params);
+        assert!(matches!(ret, Err(AppError::PermissionDenied(msg)) if msg == "file link expired"));
+        Ok(())
+    }
+
+    #[test]
+    fn mime_allowed_should_work() {
+        let allowed = vec!["image/*".to_string(), "application/pdf".to_string()];
+        assert!(mime_allowed(&allowed, "image/png"));
+        assert!(mime_allowed(&allowed, "application/pdf"));
+        assert!(!mime_allowed(&allowed, "application/zip"));
+        assert!(mime_allowed(&[], "application/zip"));
+    }
 
     #[test]
     fn chat_file_new_should_work() {
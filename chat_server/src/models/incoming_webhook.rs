@@ -0,0 +1,445 @@
+use std::time::Duration;
+
+use chat_core::{Message, User};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::FromRow;
+
+use crate::{error::AppError, utils::random_string, AppState};
+
+use super::{
+    token::{deactivate_bot_user, hash_token, insert_bot_user},
+    CreateMessage,
+};
+
+const DEFAULT_RATE_LIMIT: u32 = 60;
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct IncomingWebhook {
+    pub id: i64,
+    pub chat_id: i64,
+    pub bot_id: i64,
+    pub name: String,
+    pub rate_limit: i32,
+    pub created_by: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateIncomingWebhook {
+    pub name: String,
+    // messages per minute
+    #[serde(default)]
+    pub rate_limit: Option<u32>,
+}
+
+/// A freshly created webhook, the only time the secret url is returned
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreatedIncomingWebhook {
+    #[serde(flatten)]
+    pub info: IncomingWebhook,
+    pub url: String,
+}
+
+/// Body accepted by the webhook url, either `{"text": ...}` or a slack compatible payload
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WebhookPayload {
+    #[serde(default)]
+    pub text: Option<String>,
+    #[serde(default)]
+    pub attachments: Vec<SlackAttachment>,
+    // slack block kit is open ended, only the text parts are kept
+    #[serde(default)]
+    pub blocks: Vec<Value>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SlackAttachment {
+    pub fallback: Option<String>,
+    pub pretext: Option<String>,
+    pub author_name: Option<String>,
+    pub title: Option<String>,
+    pub title_link: Option<String>,
+    pub text: Option<String>,
+    #[serde(default)]
+    pub fields: Vec<SlackField>,
+    pub footer: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SlackField {
+    pub title: Option<String>,
+    pub value: Option<String>,
+}
+
+impl AppState {
+    pub async fn create_incoming_webhook(
+        &self,
+        chat_id: u64,
+        input: CreateIncomingWebhook,
+        user: &User,
+    ) -> Result<CreatedIncomingWebhook, AppError> {
+        let name = input.name.trim();
+        if name.is_empty() || name.len() > 64 {
+            return Err(AppError::WebhookError(
+                "Webhook name must be 1 to 64 characters".to_string(),
+            ));
+        }
+        let rate_limit = input.rate_limit.unwrap_or(DEFAULT_RATE_LIMIT);
+        let rate_limit = match i32::try_from(rate_limit) {
+            Ok(limit) if limit > 0 => limit,
+            _ => {
+                return Err(AppError::WebhookError(format!(
+                    "Rate limit must be between 1 and {}",
+                    i32::MAX
+                )))
+            }
+        };
+
+        // every webhook posts as its own integration bot
+        let mut tx = self.pool.begin().await?;
+        let email = format!("hook-{}@bots.local", random_string(16).to_lowercase());
+        let bot = insert_bot_user(&mut *tx, user.ws_id, name, &email).await?;
+
+        let token = random_string(32);
+        let info: IncomingWebhook = sqlx::query_as(
+            r#"
+            INSERT INTO incoming_webhooks (chat_id, bot_id, name, token_hash, rate_limit, created_by)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING id, chat_id, bot_id, name, rate_limit, created_by, created_at
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(bot.id)
+        .bind(name)
+        .bind(hash_token(&token))
+        .bind(rate_limit)
+        .bind(user.id)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        let url = format!("/api/hooks/{}/{}", info.id, token);
+        Ok(CreatedIncomingWebhook { info, url })
+    }
+
+    pub async fn fetch_incoming_webhooks(
+        &self,
+        chat_id: u64,
+    ) -> Result<Vec<IncomingWebhook>, AppError> {
+        let hooks = sqlx::query_as(
+            r#"
+            SELECT id, chat_id, bot_id, name, rate_limit, created_by, created_at
+            FROM incoming_webhooks
+            WHERE chat_id = $1
+            ORDER BY id
+            "#,
+        )
+        .bind(chat_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(hooks)
+    }
+
+    pub async fn delete_incoming_webhook(&self, chat_id: u64, id: u64) -> Result<(), AppError> {
+        let mut tx = self.pool.begin().await?;
+        let bot_id: Option<i64> = sqlx::query_scalar(
+            "DELETE FROM incoming_webhooks WHERE id = $1 AND chat_id = $2 RETURNING bot_id",
+        )
+        .bind(id as i64)
+        .bind(chat_id as i64)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let Some(bot_id) = bot_id else {
+            return Err(AppError::NotFound(format!("webhook id {id}")));
+        };
+        deactivate_bot_user(&mut tx, bot_id).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Turn a webhook call into a message of the chat, sent by the webhook bot
+    pub async fn post_incoming_webhook(
+        &self,
+        id: u64,
+        token: &str,
+        payload: WebhookPayload,
+    ) -> Result<Message, AppError> {
+        let hook: Option<(i64, i64, i32)> = sqlx::query_as(
+            "SELECT chat_id, bot_id, rate_limit FROM incoming_webhooks WHERE id = $1 AND token_hash = $2",
+        )
+        .bind(id as i64)
+        .bind(hash_token(token))
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let Some((chat_id, bot_id, rate_limit)) = hook else {
+            return Err(AppError::NotFound(
+                "Webhook doesn't exist or the token is invalid".to_string(),
+            ));
+        };
+
+        if let Err(wait) = self
+            .hook_limiter
+            .check(id as _, rate_limit as _, RATE_LIMIT_WINDOW)
+        {
+            return Err(AppError::RateLimited(wait.as_secs().max(1)));
+        }
+
+        let input = CreateMessage {
+            content: payload.to_text(),
+            files: vec![],
+        };
+        self.create_message(input, chat_id as _, bot_id as _).await
+    }
+}
+
+impl WebhookPayload {
+    pub fn new(text: impl Into<String>) -> Self {
+        Self {
+            text: Some(text.into()),
+            ..Default::default()
+        }
+    }
+
+    /// Downgrade the payload to plain message content.
+    /// With blocks present `text` is only the notification fallback, like in slack.
+    pub fn to_text(&self) -> String {
+        let mut lines = vec![];
+        if self.blocks.is_empty() {
+            lines.extend(self.text.clone());
+        } else {
+            lines.extend(self.blocks.iter().filter_map(block_to_text));
+        }
+        lines.extend(self.attachments.iter().filter_map(SlackAttachment::to_text));
+
+        lines
+            .into_iter()
+            .filter(|line| !line.trim().is_empty())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+impl SlackAttachment {
+    fn to_text(&self) -> Option<String> {
+        let mut lines = vec![];
+        lines.extend(self.pretext.clone());
+        lines.extend(self.author_name.clone());
+        match (&self.title, &self.title_link) {
+            (Some(title), Some(link)) => lines.push(format!("{} ({})", title, link)),
+            (Some(title), None) => lines.push(title.clone()),
+            _ => {}
+        }
+        lines.extend(self.text.clone());
+        for field in &self.fields {
+            match (&field.title, &field.value) {
+                (Some(title), Some(value)) => lines.push(format!("{}: {}", title, value)),
+                (None, Some(value)) => lines.push(value.clone()),
+                (Some(title), None) => lines.push(title.clone()),
+                (None, None) => {}
+            }
+        }
+        lines.extend(self.footer.clone());
+
+        if lines.is_empty() {
+            return self.fallback.clone();
+        }
+        Some(lines.join("\n"))
+    }
+}
+
+fn block_to_text(block: &Value) -> Option<String> {
+    let text_of = |v: &Value| v.get("text").and_then(Value::as_str).map(str::to_string);
+
+    match block.get("type")?.as_str()? {
+        "header" => block.get("text").and_then(text_of),
+        "section" => {
+            let mut lines = vec![];
+            lines.extend(block.get("text").and_then(text_of));
+            if let Some(fields) = block.get("fields").and_then(Value::as_array) {
+                lines.extend(fields.iter().filter_map(text_of));
+            }
+            Some(lines.join("\n"))
+        }
+        "context" => {
+            let elements = block.get("elements")?.as_array()?;
+            let parts: Vec<_> = elements
+                .iter()
+                .filter_map(|e| text_of(e).or_else(|| alt_text(e)))
+                .collect();
+            Some(parts.join(" "))
+        }
+        "image" => alt_text(block),
+        "divider" => Some("---".to_string()),
+        "rich_text" => {
+            let mut out = String::new();
+            collect_rich_text(block, &mut out);
+            Some(out)
+        }
+        _ => None,
+    }
+}
+
+fn alt_text(v: &Value) -> Option<String> {
+    let alt = v.get("alt_text").and_then(Value::as_str)?;
+    match v.get("image_url").and_then(Value::as_str) {
+        Some(url) => Some(format!("{} ({})", alt, url)),
+        None => Some(alt.to_string()),
+    }
+}
+
+// rich text nests sections and lists of text/link elements
+fn collect_rich_text(v: &Value, out: &mut String) {
+    match v.get("type").and_then(Value::as_str) {
+        Some("text") | Some("emoji") => {
+            if let Some(text) = v.get("text").and_then(Value::as_str) {
+                out.push_str(text);
+            } else if let Some(name) = v.get("name").and_then(Value::as_str) {
+                out.push_str(&format!(":{}:", name));
+            }
+        }
+        Some("link") => {
+            let url = v.get("url").and_then(Value::as_str).unwrap_or_default();
+            match v.get("text").and_then(Value::as_str) {
+                Some(text) => out.push_str(&format!("{} ({})", text, url)),
+                None => out.push_str(url),
+            }
+        }
+        _ => {
+            if let Some(elements) = v.get("elements").and_then(Value::as_array) {
+                for e in elements {
+                    collect_rich_text(e, out);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use serde_json::json;
+
+    #[test]
+    fn slack_payload_should_downgrade_to_text() -> Result<()> {
+        let payload: WebhookPayload = serde_json::from_value(json!({
+            "text": "fallback only",
+            "blocks": [
+                {"type": "header", "text": {"type": "plain_text", "text": "Deploy finished"}},
+                {"type": "section", "text": {"type": "mrkdwn", "text": "*prod* is live"},
+                 "fields": [{"type": "mrkdwn", "text": "version: 1.2.0"}]},
+                {"type": "divider"},
+                {"type": "context", "elements": [{"type": "mrkdwn", "text": "by ci"}]},
+                {"type": "actions", "elements": []}
+            ],
+            "attachments": [{
+                "fallback": "ignored",
+                "title": "Build #42",
+                "title_link": "https://ci.example.com/42",
+                "fields": [{"title": "Status", "value": "passed"}]
+            }]
+        }))?;
+
+        assert_eq!(
+            payload.to_text(),
+            "Deploy finished\n*prod* is live\nversion: 1.2.0\n---\nby ci\nBuild #42 (https://ci.example.com/42)\nStatus: passed"
+        );
+
+        let payload: WebhookPayload = serde_json::from_value(json!({"text": "hello"}))?;
+        assert_eq!(payload.to_text(), "hello");
+
+        let payload: WebhookPayload =
+            serde_json::from_value(json!({"attachments": [{"fallback": "only fallback"}]}))?;
+        assert_eq!(payload.to_text(), "only fallback");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn incoming_webhook_should_post_message_as_bot() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+        let input = CreateIncomingWebhook {
+            name: "CI".to_string(),
+            rate_limit: Some(2),
+        };
+        let hook = state.create_incoming_webhook(1, input, &user).await?;
+        let token = hook.url.rsplit('/').next().unwrap();
+        assert_eq!(state.fetch_incoming_webhooks(1).await?.len(), 1);
+
+        let message = state
+            .post_incoming_webhook(
+                hook.info.id as _,
+                token,
+                WebhookPayload::new("build passed"),
+            )
+            .await?;
+        assert_eq!(message.chat_id, 1);
+        assert_eq!(message.sender_id, hook.info.bot_id);
+        assert_eq!(message.content, "build passed");
+        assert!(message.is_bot);
+
+        let ret = state
+            .post_incoming_webhook(hook.info.id as _, "bad-token", WebhookPayload::new("x"))
+            .await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        // an empty payload isn't a message
+        let ret = state
+            .post_incoming_webhook(hook.info.id as _, token, WebhookPayload::default())
+            .await;
+        assert!(matches!(ret, Err(AppError::CreateMessageError(_))));
+
+        // the limit is 2 per minute and both calls above counted
+        let ret = state
+            .post_incoming_webhook(hook.info.id as _, token, WebhookPayload::new("x"))
+            .await;
+        assert!(matches!(ret, Err(AppError::RateLimited(_))));
+
+        state.update_workspace_owner(1, 1).await?;
+        let bots = state.fetch_bots(1).await?;
+        assert!(bots.iter().any(|b| b.id == hook.info.bot_id));
+
+        state.delete_incoming_webhook(1, hook.info.id as _).await?;
+        let ret = state
+            .post_incoming_webhook(hook.info.id as _, token, WebhookPayload::new("x"))
+            .await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        // the bot keeps its messages but is gone from the workspace
+        let bots = state.fetch_bots(1).await?;
+        assert!(!bots.iter().any(|b| b.id == hook.info.bot_id));
+        let input = crate::CreateApiToken {
+            name: "ci".to_string(),
+            scopes: vec![chat_core::TokenScope::PostMessages],
+            expires_in_days: None,
+            bot_id: Some(hook.info.bot_id),
+        };
+        let ret = state.create_api_token(input, &user).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn incoming_webhook_should_reject_out_of_range_rate_limit() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+        let bots = state.fetch_bots(1).await?.len();
+
+        for rate_limit in [0, u32::MAX] {
+            let input = CreateIncomingWebhook {
+                name: "CI".to_string(),
+                rate_limit: Some(rate_limit),
+            };
+            let ret = state.create_incoming_webhook(1, input, &user).await;
+            assert!(matches!(ret, Err(AppError::WebhookError(_))));
+        }
+        assert_eq!(state.fetch_bots(1).await?.len(), bots);
+        Ok(())
+    }
+}
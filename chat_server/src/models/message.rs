@@ -26,40 +26,59 @@ impl AppState {
         chat_id: u64,
         user_id: u64,
     ) -> Result<Message, AppError> {
-        let base_dir = &self.config.server.base_dir;
-
         if input.content.is_empty() && input.files.is_empty() {
             return Err(AppError::CreateMessageError(
                 "Message content or files must not be empty".to_string(),
             ));
         }
 
-        // verify files exist
+        // verify files exist, posting a file also shares it so the sender must be able to see it
         for s in &input.files {
             let file = ChatFile::from_str(s)?;
-            if !file.path(base_dir).exists() {
+            if !self.can_access_file(user_id as _, s).await?
+                || !self.store.exists(&file.key()).await?
+            {
                 return Err(AppError::CreateMessageError(format!(
                     "File {} does not exist",
                     s
                 )));
             }
+            self.ensure_file_clean(s).await?;
         }
 
-        // Insert a new message
-        let message = sqlx::query_as(
+        // the message and its webhook deliveries are stored together
+        let mut tx = self.pool.begin().await?;
+        let message: Message = sqlx::query_as(
             r#"
-            INSERT INTO messages (chat_id, sender_id, content, files)
-            VALUES ($1, $2, $3, $4)
-            RETURNING id, chat_id, sender_id, content, files, created_at
+            INSERT INTO messages (chat_id, sender_id, content, files, is_bot)
+            VALUES ($1, $2, $3, $4, (SELECT is_bot FROM users WHERE id = $2))
+            RETURNING id, chat_id, sender_id, content, files, is_bot, created_at
             "#,
         )
         .bind(chat_id as i64)
         .bind(user_id as i64)
         .bind(input.content)
         .bind(input.files)
-        .fetch_one(&self.pool)
+        .fetch_one(&mut *tx)
         .await?;
+        sqlx::query(
+            r#"
+            INSERT INTO message_files (message_id, chat_id, url)
+            SELECT $1, $2, unnest($3::text[])
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(message.id)
+        .bind(message.chat_id)
+        .bind(&message.files)
+        .execute(&mut *tx)
+        .await?;
+        self.enqueue_webhook_deliveries(&mut tx, &message).await?;
+        tx.commit().await?;
 
+        let mut messages = [message];
+        self.attach_files(&mut messages).await?;
+        let [message] = messages;
         Ok(message)
     }
 
@@ -69,9 +88,9 @@ impl AppState {
         chat_id: u64,
     ) -> Result<Vec<Message>, AppError> {
         let last_id = input.last_id.unwrap_or(i64::MAX as _);
-        let messages = sqlx::query_as(
+        let mut messages = sqlx::query_as(
             r#"
-            SELECT id, chat_id, sender_id, content, files, created_at
+            SELECT id, chat_id, sender_id, content, files, is_bot, created_at
             FROM messages
             WHERE chat_id = $1 AND id < $2
             ORDER BY id DESC
@@ -83,6 +102,7 @@ impl AppState {
         .bind(input.limit as i64)
         .fetch_all(&self.pool)
         .await?;
+        self.attach_files(&mut messages).await?;
 
         Ok(messages)
     }
@@ -117,7 +137,7 @@ mod tests {
         let err = state.create_message(input, 1, 1).await.unwrap_err();
         assert_eq!(err.to_string(), "Invalid chat file path: 1");
 
-        let url = upload_dummy_file(&state)?;
+        let url = upload_dummy_file(&state).await?;
 
         let input = CreateMessage {
             content: "Hello".to_string(),
@@ -161,14 +181,84 @@ mod tests {
         Ok(())
     }
 
-    fn upload_dummy_file(state: &AppState) -> Result<String> {
-        let base_dir = &state.config.server.base_dir;
+    #[tokio::test]
+    async fn list_message_should_resolve_attachments() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let mut budget = 1024;
+        for (user_id, filename) in [(1, "draft.txt"), (2, "final.txt")] {
+            let user = state.find_user_by_id(user_id).await?.unwrap();
+            let body = futures::stream::iter([Ok("same bytes".into())]);
+            state
+                .save_upload(&user, filename, body, &mut budget, &Default::default())
+                .await?;
+        }
+        let url = ChatFile::new(1, "final.txt", b"same bytes").url();
+        let legacy = upload_dummy_file(&state).await?;
+
+        for sender_id in [1, 2] {
+            let input = CreateMessage {
+                content: "".to_string(),
+                files: vec![url.clone(), legacy.clone()],
+            };
+            state.create_message(input, 1, sender_id).await?;
+        }
+
+        let input = ListMessage {
+            last_id: None,
+            limit: 2,
+        };
+        let messages = state.list_message(input, 1).await?;
+        let names: Vec<_> = messages
+            .iter()
+            .map(|m| {
+                assert_eq!(m.files.len(), 2);
+                assert_eq!(m.attachments.len(), 1);
+                m.attachments[0].filename.as_str()
+            })
+            .collect();
+        assert_eq!(names, ["final.txt", "draft.txt"]);
+        assert_eq!(messages[0].attachments[0].mime, "text/plain");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn files_should_be_shared_through_chats() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(2).await?.unwrap();
+        let body = futures::stream::iter([Ok("secret".into())]);
+        let meta = state
+            .save_upload(&user, "secret.txt", body, &mut 1024, &Default::default())
+            .await?;
+        assert!(state.can_access_file(2, &meta.url).await?);
+        assert!(!state.can_access_file(3, &meta.url).await?);
+
+        // a file can't be shared by someone who can't see it
+        let input = CreateMessage {
+            content: "".to_string(),
+            files: vec![meta.url.clone()],
+        };
+        let ret = state.create_message(input.clone(), 2, 3).await;
+        assert!(matches!(ret, Err(AppError::CreateMessageError(_))));
+
+        // chat 2 is private to users 1, 2 and 3
+        state.create_message(input, 2, 2).await?;
+        assert!(state.can_access_file(3, &meta.url).await?);
+        assert!(!state.can_access_file(4, &meta.url).await?);
+        Ok(())
+    }
+
+    // stored without metadata but already posted in chat 1, like the files the migration backfilled
+    async fn upload_dummy_file(state: &AppState) -> Result<String> {
         let file_data = b"hello world";
         let file = ChatFile::new(1, "test.txt", file_data);
-        let path = file.path(base_dir);
-        std::fs::create_dir_all(path.parent().expect("file path parent should exists"))?;
-        println!("path: {:?}", path);
-        std::fs::write(&path, file_data).unwrap();
+        state
+            .store
+            .put_bytes(&file.key(), file_data.as_slice().into())
+            .await?;
+        sqlx::query("INSERT INTO message_files (message_id, chat_id, url) VALUES (1, 1, $1)")
+            .bind(file.url())
+            .execute(&state.pool)
+            .await?;
         Ok(file.url())
     }
 }
@@ -1,12 +1,43 @@
 mod chat;
+mod digest;
 mod file;
+mod incoming_webhook;
 mod message;
+mod notification;
+mod outgoing_webhook;
+mod presence;
+mod preview;
+mod reminder;
+mod scan;
+mod slash_command;
+mod token;
+mod tus;
+mod usage;
 mod user;
 mod workspace;
 
 pub use chat::CreateChat;
-pub use file::ChatFile;
+pub use file::{ChatFile, FileMeta, SignedFileParams, UploadOptions};
+pub use incoming_webhook::{
+    CreateIncomingWebhook, CreatedIncomingWebhook, IncomingWebhook, WebhookPayload,
+};
 pub use message::CreateMessage;
 pub use message::ListMessage;
+pub use notification::{
+    ChatNotificationSettings, UpdateNotificationSettings, WorkspaceNotificationSettings,
+};
+pub use outgoing_webhook::{
+    CreateOutgoingWebhook, CreatedOutgoingWebhook, DeliveryStatus, ListDeliveries, OutgoingWebhook,
+    WebhookDelivery, WebhookMatch,
+};
+pub use presence::SetStatus;
+pub use reminder::Reminder;
+pub use scan::ScanStatus;
+pub use slash_command::{
+    CreateSlashCommand, CreatedSlashCommand, ResponseType, SlashCommand, SlashCommandResponse,
+};
+pub use token::{ApiToken, CreateApiToken, CreateBot, CreatedApiToken, API_TOKEN_PREFIX};
+pub use tus::TusUpload;
+pub use usage::{StorageUsage, UploaderUsage};
 pub use user::CreateUser;
 pub use user::SigninUser;
@@ -0,0 +1,152 @@
+use chat_core::{NotifyLevel, User};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+use crate::{error::AppError, AppState};
+
+/// A member's settings for one chat
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct ChatNotificationSettings {
+    pub chat_id: i64,
+    // None follows the workspace default
+    pub level: Option<NotifyLevel>,
+    pub muted_until: Option<DateTime<Utc>>,
+    // what applies right now, taking the default and the mute into account
+    pub effective_level: NotifyLevel,
+}
+
+/// Replaces the settings, fields left out are cleared
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UpdateNotificationSettings {
+    #[serde(default)]
+    pub level: Option<NotifyLevel>,
+    #[serde(default)]
+    pub muted_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkspaceNotificationSettings {
+    pub level: NotifyLevel,
+}
+
+impl AppState {
+    pub async fn get_notification_settings(
+        &self,
+        chat_id: u64,
+        user: &User,
+    ) -> Result<ChatNotificationSettings, AppError> {
+        let settings = sqlx::query_as(
+            r#"
+            SELECT $1::bigint AS chat_id, s.level, s.muted_until,
+              CASE WHEN s.muted_until > NOW() THEN 'none'::notify_level
+                ELSE COALESCE(s.level, w.notify_level) END AS effective_level
+            FROM workspaces w
+            LEFT JOIN chat_notification_settings s ON s.chat_id = $1 AND s.user_id = $2
+            WHERE w.id = $3
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user.id)
+        .bind(user.ws_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        settings.ok_or_else(|| AppError::NotFound(format!("workspace id {}", user.ws_id)))
+    }
+
+    pub async fn update_notification_settings(
+        &self,
+        chat_id: u64,
+        user: &User,
+        input: UpdateNotificationSettings,
+    ) -> Result<ChatNotificationSettings, AppError> {
+        sqlx::query(
+            r#"
+            INSERT INTO chat_notification_settings (chat_id, user_id, level, muted_until)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (chat_id, user_id) DO UPDATE
+            SET level = EXCLUDED.level, muted_until = EXCLUDED.muted_until,
+              updated_at = CURRENT_TIMESTAMP
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user.id)
+        .bind(input.level)
+        .bind(input.muted_until)
+        .execute(&self.pool)
+        .await?;
+
+        self.get_notification_settings(chat_id, user).await
+    }
+
+    /// The level for chats a member hasn't configured, only the owner may change it
+    pub async fn update_workspace_notify_level(
+        &self,
+        user: &User,
+        input: WorkspaceNotificationSettings,
+    ) -> Result<WorkspaceNotificationSettings, AppError> {
+        self.ensure_workspace_owner(user).await?;
+        sqlx::query("UPDATE workspaces SET notify_level = $1 WHERE id = $2")
+            .bind(input.level)
+            .bind(user.ws_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use chrono::Duration;
+
+    #[tokio::test]
+    async fn notification_settings_should_fall_back_to_workspace() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state.update_workspace_owner(1, 1).await?;
+        let owner = state.find_user_by_id(1).await?.expect("user should exist");
+        let member = state.find_user_by_id(2).await?.expect("user should exist");
+
+        let settings = state.get_notification_settings(1, &member).await?;
+        assert_eq!(settings.level, None);
+        assert_eq!(settings.effective_level, NotifyLevel::All);
+
+        let level = WorkspaceNotificationSettings {
+            level: NotifyLevel::Mentions,
+        };
+        let ret = state
+            .update_workspace_notify_level(&member, level.clone())
+            .await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        state.update_workspace_notify_level(&owner, level).await?;
+        let settings = state.get_notification_settings(1, &member).await?;
+        assert_eq!(settings.effective_level, NotifyLevel::Mentions);
+
+        let input = UpdateNotificationSettings {
+            level: Some(NotifyLevel::All),
+            muted_until: None,
+        };
+        let settings = state
+            .update_notification_settings(1, &member, input)
+            .await?;
+        assert_eq!(settings.effective_level, NotifyLevel::All);
+
+        // muted for an hour
+        let input = UpdateNotificationSettings {
+            level: Some(NotifyLevel::All),
+            muted_until: Some(Utc::now() + Duration::hours(1)),
+        };
+        let settings = state
+            .update_notification_settings(1, &member, input)
+            .await?;
+        assert_eq!(settings.level, Some(NotifyLevel::All));
+        assert_eq!(settings.effective_level, NotifyLevel::None);
+
+        // the other chats are untouched
+        let settings = state.get_notification_settings(2, &member).await?;
+        assert_eq!(settings.effective_level, NotifyLevel::Mentions);
+        Ok(())
+    }
+}
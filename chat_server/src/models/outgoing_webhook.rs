@@ -0,0 +1,786 @@
+use std::time::Duration;
+
+use chat_core::{utils::check_public_url, Message, User};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use sqlx::{FromRow, PgConnection};
+
+use crate::{error::AppError, utils::random_string, AppState};
+
+pub const SIGNATURE_HEADER: &str = "X-Chat-Signature";
+pub const TIMESTAMP_HEADER: &str = "X-Chat-Timestamp";
+pub const DELIVERY_HEADER: &str = "X-Chat-Delivery";
+
+// retries never wait longer than this
+const MAX_RETRY_DELAY: u64 = 60 * 60;
+const DISPATCH_BATCH: u64 = 32;
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case", type_name = "webhook_match")]
+pub enum WebhookMatch {
+    #[default]
+    All,
+    Keyword,
+    TriggerWord,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case", type_name = "delivery_status")]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    Dead,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct OutgoingWebhook {
+    pub id: i64,
+    pub ws_id: i64,
+    pub chat_id: Option<i64>,
+    pub name: String,
+    pub url: String,
+    pub r#match: WebhookMatch,
+    pub words: Vec<String>,
+    pub created_by: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateOutgoingWebhook {
+    pub name: String,
+    pub url: String,
+    // forward messages of a single chat instead of the whole workspace
+    #[serde(default)]
+    pub chat_id: Option<i64>,
+    #[serde(default)]
+    pub r#match: WebhookMatch,
+    #[serde(default)]
+    pub words: Vec<String>,
+}
+
+/// A freshly created webhook, the only time the signing secret is returned
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreatedOutgoingWebhook {
+    #[serde(flatten)]
+    pub info: OutgoingWebhook,
+    pub secret: String,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub webhook_id: i64,
+    pub message_id: i64,
+    pub payload: Value,
+    pub status: DeliveryStatus,
+    pub attempts: i32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub last_status_code: Option<i32>,
+    pub last_error: Option<String>,
+    pub delivered_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListDeliveries {
+    #[serde(default)]
+    pub status: Option<DeliveryStatus>,
+    pub last_id: Option<u64>,
+    pub limit: u64,
+}
+
+#[derive(Debug, FromRow)]
+struct WebhookTarget {
+    id: i64,
+    ws_id: i64,
+    r#match: WebhookMatch,
+    words: Vec<String>,
+}
+
+#[derive(Debug, FromRow)]
+struct DueDelivery {
+    id: i64,
+    webhook_id: i64,
+    payload: Value,
+    attempts: i32,
+    url: String,
+    secret: String,
+}
+
+impl AppState {
+    pub async fn create_outgoing_webhook(
+        &self,
+        input: CreateOutgoingWebhook,
+        user: &User,
+    ) -> Result<CreatedOutgoingWebhook, AppError> {
+        let name = input.name.trim();
+        if name.is_empty() || name.len() > 64 {
+            return Err(AppError::WebhookError(
+                "Webhook name must be 1 to 64 characters".to_string(),
+            ));
+        }
+        check_public_url(&input.url, self.config.webhook.allow_private_urls)
+            .await
+            .map_err(AppError::WebhookError)?;
+        let words: Vec<String> = input
+            .words
+            .iter()
+            .map(|w| w.trim().to_lowercase())
+            .filter(|w| !w.is_empty())
+            .collect();
+        if input.r#match != WebhookMatch::All && words.is_empty() {
+            return Err(AppError::WebhookError(
+                "Keyword and trigger word webhooks need at least one word".to_string(),
+            ));
+        }
+
+        // chat webhooks are open to members, workspace wide ones to the owner
+        match input.chat_id {
+            Some(chat_id) => {
+                let chat = self.get_chat_by_id(chat_id as _).await?;
+                let allowed =
+                    chat.is_some_and(|c| c.ws_id == user.ws_id && c.members.contains(&user.id));
+                if !allowed {
+                    return Err(AppError::PermissionDenied(format!(
+                        "User {} is not a member of chat {chat_id}",
+                        user.id
+                    )));
+                }
+            }
+            None => self.ensure_workspace_owner(user).await?,
+        }
+
+        let secret = random_string(32);
+        let info = sqlx::query_as(
+            r#"
+            INSERT INTO outgoing_webhooks (ws_id, chat_id, name, url, secret, match, words, created_by)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            RETURNING id, ws_id, chat_id, name, url, match, words, created_by, created_at
+            "#,
+        )
+        .bind(user.ws_id)
+        .bind(input.chat_id)
+        .bind(name)
+        .bind(&input.url)
+        .bind(&secret)
+        .bind(input.r#match)
+        .bind(words)
+        .bind(user.id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(CreatedOutgoingWebhook { info, secret })
+    }
+
+    /// The workspace owner sees every webhook, everyone else only the ones they created
+    pub async fn fetch_outgoing_webhooks(
+        &self,
+        user: &User,
+    ) -> Result<Vec<OutgoingWebhook>, AppError> {
+        let is_owner = self
+            .find_workspace_by_id(user.ws_id)
+            .await?
+            .is_some_and(|ws| ws.owner_id == user.id);
+        let created_by = (!is_owner).then_some(user.id);
+        let hooks = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, chat_id, name, url, match, words, created_by, created_at
+            FROM outgoing_webhooks
+            WHERE ws_id = $1 AND ($2::bigint IS NULL OR created_by = $2)
+            ORDER BY id
+            "#,
+        )
+        .bind(user.ws_id)
+        .bind(created_by)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(hooks)
+    }
+
+    pub async fn delete_outgoing_webhook(&self, id: u64, user: &User) -> Result<(), AppError> {
+        self.ensure_outgoing_webhook_access(id, user).await?;
+        sqlx::query("DELETE FROM outgoing_webhooks WHERE id = $1")
+            .bind(id as i64)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn fetch_webhook_deliveries(
+        &self,
+        id: u64,
+        input: ListDeliveries,
+        user: &User,
+    ) -> Result<Vec<WebhookDelivery>, AppError> {
+        self.ensure_outgoing_webhook_access(id, user).await?;
+        let last_id = input.last_id.unwrap_or(i64::MAX as _);
+        let deliveries = sqlx::query_as(
+            r#"
+            SELECT id, webhook_id, message_id, payload, status, attempts, next_attempt_at,
+              last_status_code, last_error, delivered_at, created_at
+            FROM webhook_deliveries
+            WHERE webhook_id = $1 AND id < $2 AND ($3::delivery_status IS NULL OR status = $3)
+            ORDER BY id DESC
+            LIMIT $4
+            "#,
+        )
+        .bind(id as i64)
+        .bind(last_id as i64)
+        .bind(input.status)
+        .bind(input.limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(deliveries)
+    }
+
+    /// Put a dead-lettered delivery back into the queue
+    pub async fn retry_webhook_delivery(
+        &self,
+        id: u64,
+        delivery_id: u64,
+        user: &User,
+    ) -> Result<(), AppError> {
+        self.ensure_outgoing_webhook_access(id, user).await?;
+        let ret = sqlx::query(
+            r#"
+            UPDATE webhook_deliveries
+            SET status = 'pending', attempts = 0, next_attempt_at = CURRENT_TIMESTAMP
+            WHERE id = $1 AND webhook_id = $2 AND status = 'dead'
+            "#,
+        )
+        .bind(delivery_id as i64)
+        .bind(id as i64)
+        .execute(&self.pool)
+        .await?;
+
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!(
+                "dead delivery id {delivery_id}"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Queue deliveries for every webhook matching the new message.
+    /// Workspace wide webhooks only see public channels, private chats need a webhook of their own.
+    /// A chat's webhook stops once its creator is no longer a member.
+    /// Runs in the transaction inserting the message so no delivery is lost.
+    pub(crate) async fn enqueue_webhook_deliveries(
+        &self,
+        conn: &mut PgConnection,
+        message: &Message,
+    ) -> Result<(), AppError> {
+        // bot messages are never forwarded, a receiver replying through a webhook would loop
+        if message.is_bot {
+            return Ok(());
+        }
+
+        let targets: Vec<WebhookTarget> = sqlx::query_as(
+            r#"
+            SELECT w.id, w.ws_id, w.match, w.words
+            FROM outgoing_webhooks w JOIN chats c ON c.ws_id = w.ws_id
+            WHERE c.id = $1
+              AND ((w.chat_id = $1 AND w.created_by = ANY(c.members))
+                OR (w.chat_id IS NULL AND c.type = 'public_channel'))
+            "#,
+        )
+        .bind(message.chat_id)
+        .fetch_all(&mut *conn)
+        .await?;
+
+        for target in targets {
+            let Some(word) = target.matches(&message.content) else {
+                continue;
+            };
+            let payload = json!({
+                "event": "message_created",
+                "webhook_id": target.id,
+                "ws_id": target.ws_id,
+                "chat_id": message.chat_id,
+                "trigger_word": word,
+                "message": message,
+            });
+
+            sqlx::query(
+                r#"
+                INSERT INTO webhook_deliveries (webhook_id, message_id, payload)
+                VALUES ($1, $2, $3)
+                ON CONFLICT (webhook_id, message_id) DO NOTHING
+                "#,
+            )
+            .bind(target.id)
+            .bind(message.id)
+            .bind(payload)
+            .execute(&mut *conn)
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Deliver the deliveries that are due, returning how many were attempted
+    pub async fn process_webhook_deliveries(&self) -> Result<usize, AppError> {
+        let config = &self.config.webhook;
+        // lease the rows so a crashed or concurrent dispatcher doesn't send twice in a row
+        let lease = config.timeout_secs + 30;
+        let due: Vec<DueDelivery> = sqlx::query_as(
+            r#"
+            UPDATE webhook_deliveries d
+            SET attempts = d.attempts + 1,
+              next_attempt_at = CURRENT_TIMESTAMP + make_interval(secs => $2)
+            FROM outgoing_webhooks w
+            WHERE w.id = d.webhook_id AND d.id IN (
+              SELECT id FROM webhook_deliveries
+              WHERE status = 'pending' AND next_attempt_at <= CURRENT_TIMESTAMP
+              ORDER BY next_attempt_at
+              LIMIT $1
+              FOR UPDATE SKIP LOCKED)
+            RETURNING d.id, d.webhook_id, d.payload, d.attempts, w.url, w.secret
+            "#,
+        )
+        .bind(DISPATCH_BATCH as i64)
+        .bind(lease as f64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let count = due.len();
+        for delivery in due {
+            let ret = self.deliver(&delivery).await;
+            self.record_delivery(&delivery, ret).await?;
+        }
+        Ok(count)
+    }
+
+    /// Dispatch webhook deliveries until the process exits
+    pub async fn run_webhook_dispatcher(self) {
+        loop {
+            match self.process_webhook_deliveries().await {
+                Ok(n) if n > 0 => continue,
+                Ok(_) => {}
+                Err(e) => tracing::warn!("Process webhook deliveries failed: {}", e),
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    async fn deliver(&self, delivery: &DueDelivery) -> Result<u16, (Option<u16>, String)> {
+        // hooks stored before urls were checked must not reach internal hosts either
+        check_public_url(&delivery.url, self.config.webhook.allow_private_urls)
+            .await
+            .map_err(|e| (None, e))?;
+        let body = delivery.payload.to_string();
+        let timestamp = Utc::now().timestamp();
+        let signature = sign_payload(&delivery.secret, timestamp, &body);
+
+        let res = self
+            .http
+            .post(&delivery.url)
+            .timeout(Duration::from_secs(self.config.webhook.timeout_secs))
+            .header("content-type", "application/json")
+            .header(SIGNATURE_HEADER, signature)
+            .header(TIMESTAMP_HEADER, timestamp)
+            .header(DELIVERY_HEADER, delivery.id)
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| (None, e.to_string()))?;
+
+        let status = res.status();
+        if status.is_success() {
+            Ok(status.as_u16())
+        } else {
+            let text = res.text().await.unwrap_or_default();
+            let text: String = text.chars().take(512).collect();
+            Err((Some(status.as_u16()), format!("{}: {}", status, text)))
+        }
+    }
+
+    async fn record_delivery(
+        &self,
+        delivery: &DueDelivery,
+        ret: Result<u16, (Option<u16>, String)>,
+    ) -> Result<(), AppError> {
+        match ret {
+            Ok(code) => {
+                sqlx::query(
+                    r#"
+                    UPDATE webhook_deliveries
+                    SET status = 'delivered', last_status_code = $2, last_error = NULL,
+                      delivered_at = CURRENT_TIMESTAMP
+                    WHERE id = $1
+                    "#,
+                )
+                .bind(delivery.id)
+                .bind(code as i32)
+                .execute(&self.pool)
+                .await?;
+            }
+            Err((code, error)) => {
+                tracing::warn!(
+                    "Webhook {} delivery {} attempt {} failed: {}",
+                    delivery.webhook_id,
+                    delivery.id,
+                    delivery.attempts,
+                    error
+                );
+                let config = &self.config.webhook;
+                let status = if delivery.attempts as u32 >= config.max_attempts {
+                    DeliveryStatus::Dead
+                } else {
+                    DeliveryStatus::Pending
+                };
+                let delay = retry_delay(config.retry_base_secs, delivery.attempts as u32);
+                sqlx::query(
+                    r#"
+                    UPDATE webhook_deliveries
+                    SET status = $2, last_status_code = $3, last_error = $4,
+                      next_attempt_at = CURRENT_TIMESTAMP + make_interval(secs => $5)
+                    WHERE id = $1
+                    "#,
+                )
+                .bind(delivery.id)
+                .bind(status)
+                .bind(code.map(|c| c as i32))
+                .bind(error)
+                .bind(delay as f64)
+                .execute(&self.pool)
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn ensure_outgoing_webhook_access(&self, id: u64, user: &User) -> Result<(), AppError> {
+        let row: Option<(i64, i64)> =
+            sqlx::query_as("SELECT ws_id, created_by FROM outgoing_webhooks WHERE id = $1")
+                .bind(id as i64)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        match row {
+            Some((ws_id, created_by)) if ws_id == user.ws_id => {
+                if created_by != user.id {
+                    self.ensure_workspace_owner(user).await?;
+                }
+                Ok(())
+            }
+            _ => Err(AppError::NotFound(format!("webhook id {id}"))),
+        }
+    }
+}
+
+impl WebhookTarget {
+    /// None if the message doesn't match, otherwise the keyword or trigger word that matched
+    fn matches(&self, content: &str) -> Option<Option<String>> {
+        let content = content.to_lowercase();
+        let find = |pred: &dyn Fn(&str) -> bool| self.words.iter().find(|w| pred(w)).cloned();
+
+        match self.r#match {
+            WebhookMatch::All => Some(None),
+            WebhookMatch::Keyword => {
+                let tokens: Vec<_> = content
+                    .split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-'))
+                    .collect();
+                find(&|w| tokens.contains(&w)).map(Some)
+            }
+            WebhookMatch::TriggerWord => {
+                let content = content.trim_start();
+                find(&|w| {
+                    content.strip_prefix(w).is_some_and(|rest| {
+                        rest.chars().next().is_none_or(|c| !c.is_alphanumeric())
+                    })
+                })
+                .map(Some)
+            }
+        }
+    }
+}
+
+/// hex encoded hmac-sha256 over `{timestamp}.{body}`, sent as `sha256=<hex>`
+pub fn sign_payload(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts any key size");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+fn retry_delay(base: u64, attempts: u32) -> u64 {
+    let factor = 2u64.saturating_pow(attempts.saturating_sub(1));
+    base.saturating_mul(factor).min(MAX_RETRY_DELAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AppConfig, CreateMessage};
+    use anyhow::Result;
+    use axum::{
+        body::Bytes,
+        extract::State,
+        http::{HeaderMap, StatusCode},
+        routing::post,
+        Router,
+    };
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+
+    #[derive(Clone, Default)]
+    struct Receiver {
+        requests: Arc<Mutex<Vec<(HeaderMap, Bytes)>>>,
+        status: Arc<Mutex<u16>>,
+    }
+
+    async fn receive(State(rx): State<Receiver>, headers: HeaderMap, body: Bytes) -> StatusCode {
+        rx.requests.lock().unwrap().push((headers, body));
+        StatusCode::from_u16(*rx.status.lock().unwrap()).unwrap()
+    }
+
+    async fn start_receiver() -> Result<(String, Receiver)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/hook", listener.local_addr()?);
+        let rx = Receiver::default();
+        *rx.status.lock().unwrap() = 200;
+        let app = Router::new()
+            .route("/hook", post(receive))
+            .with_state(rx.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        Ok((url, rx))
+    }
+
+    fn message(content: &str) -> CreateMessage {
+        CreateMessage {
+            content: content.to_string(),
+            files: vec![],
+        }
+    }
+
+    #[test]
+    fn webhook_match_should_work() {
+        let target = |m, words: &[&str]| WebhookTarget {
+            id: 1,
+            ws_id: 1,
+            r#match: m,
+            words: words.iter().map(|w| w.to_string()).collect(),
+        };
+
+        let all = target(WebhookMatch::All, &[]);
+        assert_eq!(all.matches("anything"), Some(None));
+
+        let keyword = target(WebhookMatch::Keyword, &["deploy", "incident"]);
+        assert_eq!(
+            keyword.matches("Can we DEPLOY now?"),
+            Some(Some("deploy".to_string()))
+        );
+        assert_eq!(keyword.matches("deployment done"), None);
+
+        let trigger = target(WebhookMatch::TriggerWord, &["!build"]);
+        assert_eq!(
+            trigger.matches("  !build main"),
+            Some(Some("!build".to_string()))
+        );
+        assert_eq!(trigger.matches("please !build main"), None);
+        assert_eq!(trigger.matches("!buildall"), None);
+    }
+
+    #[test]
+    fn retry_delay_should_back_off_exponentially() {
+        assert_eq!(retry_delay(10, 1), 10);
+        assert_eq!(retry_delay(10, 2), 20);
+        assert_eq!(retry_delay(10, 4), 80);
+        assert_eq!(retry_delay(10, 30), MAX_RETRY_DELAY);
+    }
+
+    #[tokio::test]
+    async fn outgoing_webhook_should_deliver_signed_payload() -> Result<()> {
+        let (url, rx) = start_receiver().await?;
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+
+        let input = CreateOutgoingWebhook {
+            name: "deploys".to_string(),
+            url,
+            chat_id: Some(1),
+            r#match: WebhookMatch::Keyword,
+            words: vec!["Deploy".to_string()],
+        };
+        let hook = state.create_outgoing_webhook(input, &user).await?;
+
+        state.create_message(message("hello"), 1, 1).await?;
+        state
+            .create_message(message("deploy to prod"), 2, 1)
+            .await?;
+        let msg = state
+            .create_message(message("ready to deploy?"), 1, 1)
+            .await?;
+        assert_eq!(state.process_webhook_deliveries().await?, 1);
+
+        let requests = rx.requests.lock().unwrap().clone();
+        assert_eq!(requests.len(), 1);
+        let (headers, body) = &requests[0];
+        let timestamp: i64 = headers[TIMESTAMP_HEADER].to_str()?.parse()?;
+        let expected = sign_payload(&hook.secret, timestamp, std::str::from_utf8(body)?);
+        assert_eq!(headers[SIGNATURE_HEADER].to_str()?, expected);
+
+        let payload: Value = serde_json::from_slice(body)?;
+        assert_eq!(payload["trigger_word"], "deploy");
+        assert_eq!(payload["message"]["id"], msg.id);
+
+        let input = ListDeliveries {
+            status: None,
+            last_id: None,
+            limit: 10,
+        };
+        let log = state
+            .fetch_webhook_deliveries(hook.info.id as _, input, &user)
+            .await?;
+        assert_eq!(log.len(), 1);
+        assert_eq!(log[0].status, DeliveryStatus::Delivered);
+        assert_eq!(log[0].last_status_code, Some(200));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn outgoing_webhook_should_reject_private_urls() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test_with_config(AppConfig::load()?).await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+
+        for url in [
+            "http://example.com/hook",
+            "https://127.0.0.1/hook",
+            "https://169.254.169.254/latest/meta-data",
+            "https://localhost:6688/api/signin",
+        ] {
+            let input = CreateOutgoingWebhook {
+                name: "internal".to_string(),
+                url: url.to_string(),
+                chat_id: Some(1),
+                r#match: WebhookMatch::All,
+                words: vec![],
+            };
+            let ret = state.create_outgoing_webhook(input, &user).await;
+            assert!(matches!(ret, Err(AppError::WebhookError(_))), "{url}");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn outgoing_webhooks_should_only_be_listed_to_creator_and_owner() -> Result<()> {
+        let (url, _rx) = start_receiver().await?;
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state.update_workspace_owner(1, 1).await?;
+        let alice = state.find_user_by_id(2).await?.unwrap();
+        let bob = state.find_user_by_id(3).await?.unwrap();
+        let owner = state.find_user_by_id(1).await?.unwrap();
+
+        let input = CreateOutgoingWebhook {
+            name: "alice".to_string(),
+            url,
+            chat_id: Some(1),
+            r#match: WebhookMatch::All,
+            words: vec![],
+        };
+        let hook = state.create_outgoing_webhook(input, &alice).await?;
+
+        assert_eq!(
+            state.fetch_outgoing_webhooks(&alice).await?,
+            [hook.info.clone()]
+        );
+        assert_eq!(state.fetch_outgoing_webhooks(&owner).await?, [hook.info]);
+        assert!(state.fetch_outgoing_webhooks(&bob).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_delivery_should_retry_then_dead_letter() -> Result<()> {
+        let (url, rx) = start_receiver().await?;
+        *rx.status.lock().unwrap() = 500;
+
+        let mut config = AppConfig::load()?;
+        config.webhook.max_attempts = 2;
+        config.webhook.retry_base_secs = 0;
+        config.webhook.allow_private_urls = true;
+        let (_tdb, state) = AppState::new_for_test_with_config(config).await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+        state.update_workspace_owner(1, 1).await?;
+
+        let input = CreateOutgoingWebhook {
+            name: "everything".to_string(),
+            url,
+            chat_id: None,
+            r#match: WebhookMatch::All,
+            words: vec![],
+        };
+        let hook = state.create_outgoing_webhook(input, &user).await?;
+        // direct messages and private channels never leave through a workspace wide hook
+        state.create_message(message("secret"), 3, 1).await?;
+        state.create_message(message("psst"), 2, 1).await?;
+        state.create_message(message("hi"), 1, 1).await?;
+
+        let list = |status| ListDeliveries {
+            status: Some(status),
+            last_id: None,
+            limit: 10,
+        };
+
+        assert_eq!(state.process_webhook_deliveries().await?, 1);
+        let log = state
+            .fetch_webhook_deliveries(hook.info.id as _, list(DeliveryStatus::Pending), &user)
+            .await?;
+        assert_eq!(log[0].attempts, 1);
+        assert_eq!(log[0].last_status_code, Some(500));
+
+        assert_eq!(state.process_webhook_deliveries().await?, 1);
+        let dead = state
+            .fetch_webhook_deliveries(hook.info.id as _, list(DeliveryStatus::Dead), &user)
+            .await?;
+        assert_eq!(dead.len(), 1);
+        assert_eq!(state.process_webhook_deliveries().await?, 0);
+
+        // a dead delivery can be sent again once the receiver is fixed
+        *rx.status.lock().unwrap() = 204;
+        state
+            .retry_webhook_delivery(hook.info.id as _, dead[0].id as _, &user)
+            .await?;
+        assert_eq!(state.process_webhook_deliveries().await?, 1);
+        let log = state
+            .fetch_webhook_deliveries(hook.info.id as _, list(DeliveryStatus::Delivered), &user)
+            .await?;
+        assert_eq!(log.len(), 1);
+        assert_eq!(rx.requests.lock().unwrap().len(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chat_webhook_should_stop_when_creator_leaves() -> Result<()> {
+        let (url, rx) = start_receiver().await?;
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // private channel with members 1, 2, 3
+        let alice = state.find_user_by_id(2).await?.unwrap();
+
+        let input = CreateOutgoingWebhook {
+            name: "mine".to_string(),
+            url,
+            chat_id: Some(2),
+            r#match: WebhookMatch::All,
+            words: vec![],
+        };
+        state.create_outgoing_webhook(input, &alice).await?;
+        state.create_message(message("before"), 2, 1).await?;
+        assert_eq!(state.process_webhook_deliveries().await?, 1);
+
+        state.remove_chat_member(2, 2).await?;
+        state.create_message(message("after"), 2, 1).await?;
+        assert_eq!(state.process_webhook_deliveries().await?, 0);
+        assert_eq!(rx.requests.lock().unwrap().len(), 1);
+        Ok(())
+    }
+}
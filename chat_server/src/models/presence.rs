@@ -0,0 +1,196 @@
+use chat_core::UserPresence;
+use chrono::{DateTime, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{error::AppError, AppState};
+
+/// notify_server broadcasts the presence of the user id sent here
+const PRESENCE_CHANNEL: &str = "presence_changed";
+
+/// Replaces the status, fields left out are cleared
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SetStatus {
+    #[serde(default)]
+    pub status_text: Option<String>,
+    #[serde(default)]
+    pub status_emoji: Option<String>,
+    #[serde(default)]
+    pub status_expires_at: Option<DateTime<Utc>>,
+    // daily window in UTC, both or neither
+    #[serde(default)]
+    pub dnd_start: Option<NaiveTime>,
+    #[serde(default)]
+    pub dnd_end: Option<NaiveTime>,
+}
+
+impl AppState {
+    pub async fn get_presence(&self, user_id: u64) -> Result<UserPresence, AppError> {
+        let presence = sqlx::query_as(
+            r#"
+            SELECT user_id, presence, status_text, status_emoji, status_expires_at, dnd
+            FROM user_presence
+            WHERE user_id = $1
+            "#,
+        )
+        .bind(user_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        presence.ok_or_else(|| AppError::NotFound(format!("user id {user_id}")))
+    }
+
+    /// Set the status text, emoji and do-not-disturb window, workspace members are told
+    pub async fn set_status(
+        &self,
+        user_id: u64,
+        input: SetStatus,
+    ) -> Result<UserPresence, AppError> {
+        input.validate()?;
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"
+            INSERT INTO user_status (user_id, status_text, status_emoji, status_expires_at,
+              dnd_start, dnd_end)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (user_id) DO UPDATE
+            SET status_text = EXCLUDED.status_text, status_emoji = EXCLUDED.status_emoji,
+              status_expires_at = EXCLUDED.status_expires_at, dnd_start = EXCLUDED.dnd_start,
+              dnd_end = EXCLUDED.dnd_end, updated_at = CURRENT_TIMESTAMP
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(&input.status_text)
+        .bind(&input.status_emoji)
+        .bind(input.status_expires_at)
+        .bind(input.dnd_start)
+        .bind(input.dnd_end)
+        .execute(&mut *tx)
+        .await?;
+        // sent on commit
+        sqlx::query("SELECT pg_notify($1, $2::text)")
+            .bind(PRESENCE_CHANNEL)
+            .bind(user_id as i64)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        self.get_presence(user_id).await
+    }
+}
+
+impl SetStatus {
+    fn validate(&self) -> Result<(), AppError> {
+        if self
+            .status_text
+            .as_ref()
+            .is_some_and(|t| t.chars().count() > 100)
+        {
+            return Err(AppError::StatusError(
+                "status text is limited to 100 characters".to_string(),
+            ));
+        }
+        if self
+            .status_emoji
+            .as_ref()
+            .is_some_and(|e| e.chars().count() > 8)
+        {
+            return Err(AppError::StatusError(
+                "status emoji is too long".to_string(),
+            ));
+        }
+        if self.status_expires_at.is_some_and(|at| at <= Utc::now()) {
+            return Err(AppError::StatusError(
+                "status expiry must be in the future".to_string(),
+            ));
+        }
+        match (self.dnd_start, self.dnd_end) {
+            (Some(start), Some(end)) if start == end => Err(AppError::StatusError(
+                "do-not-disturb window can't be empty".to_string(),
+            )),
+            (Some(_), None) | (None, Some(_)) => Err(AppError::StatusError(
+                "do-not-disturb needs both a start and an end".to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use chat_core::PresenceStatus;
+    use chrono::Duration;
+
+    #[tokio::test]
+    async fn set_status_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let mut listener = sqlx::postgres::PgListener::connect_with(&state.pool).await?;
+        listener.listen(PRESENCE_CHANNEL).await?;
+
+        let now = Utc::now().time();
+        let input = SetStatus {
+            status_text: Some("In a meeting".to_string()),
+            status_emoji: Some("📅".to_string()),
+            status_expires_at: Some(Utc::now() + Duration::hours(1)),
+            // around now, wrapping past midnight if needed
+            dnd_start: Some(now - Duration::minutes(5)),
+            dnd_end: Some(now + Duration::minutes(5)),
+        };
+        let presence = state.set_status(1, input).await?;
+        assert_eq!(presence.presence, PresenceStatus::Offline);
+        assert_eq!(presence.status_text.as_deref(), Some("In a meeting"));
+        assert!(presence.dnd);
+        assert_eq!(listener.recv().await?.payload(), "1");
+
+        // clearing the status ends do-not-disturb as well
+        let presence = state.set_status(1, SetStatus::default()).await?;
+        assert_eq!(presence.status_text, None);
+        assert!(!presence.dnd);
+
+        // a status that expired is no longer shown
+        sqlx::query(
+            "UPDATE user_status SET status_text = 'Lunch', status_expires_at = NOW() - INTERVAL '1 minute'",
+        )
+        .execute(&state.pool)
+        .await?;
+        assert_eq!(state.get_presence(1).await?.status_text, None);
+
+        // a session from notify_server makes the user online
+        sqlx::query("INSERT INTO presence_sessions (instance, user_id) VALUES ('test', 1)")
+            .execute(&state.pool)
+            .await?;
+        assert_eq!(state.get_presence(1).await?.presence, PresenceStatus::Away);
+        sqlx::query("UPDATE user_status SET last_active_at = NOW()")
+            .execute(&state.pool)
+            .await?;
+        assert_eq!(
+            state.get_presence(1).await?.presence,
+            PresenceStatus::Online
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_status_should_reject_invalid_input() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        for input in [
+            SetStatus {
+                status_text: Some("a".repeat(101)),
+                ..Default::default()
+            },
+            SetStatus {
+                status_expires_at: Some(Utc::now() - Duration::minutes(1)),
+                ..Default::default()
+            },
+            SetStatus {
+                dnd_start: NaiveTime::from_hms_opt(22, 0, 0),
+                ..Default::default()
+            },
+        ] {
+            let ret = state.set_status(1, input).await;
+            assert!(matches!(ret, Err(AppError::StatusError(_))));
+        }
+        Ok(())
+    }
+}
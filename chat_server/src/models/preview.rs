@@ -0,0 +1,249 @@
+use std::{io::Cursor, str::FromStr};
+
+use chat_core::Thumbnail;
+use image::{
+    codecs::{jpeg::JpegEncoder, png::PngEncoder},
+    DynamicImage, GenericImageView, ImageDecoder, ImageReader, ImageResult,
+};
+use sqlx::types::Json;
+
+use crate::{error::AppError, AppState};
+
+use super::{ChatFile, FileMeta};
+
+// formats the image crate is built with
+const PREVIEW_MIME: [&str; 4] = ["image/jpeg", "image/png", "image/gif", "image/webp"];
+const JPEG_QUALITY: u8 = 80;
+
+// what gets rendered from one image, computed off the async runtime
+struct Previews {
+    width: u32,
+    height: u32,
+    blurhash: String,
+    thumbnails: Vec<(u32, Vec<u8>)>,
+}
+
+impl AppState {
+    /// Generate the previews of an uploaded image in the background
+    pub(crate) fn spawn_previews(&self, meta: &FileMeta) {
+        if !PREVIEW_MIME.contains(&meta.mime.as_str()) {
+            return;
+        }
+        let state = self.clone();
+        let meta = meta.clone();
+        tokio::spawn(async move {
+            if let Err(e) = state.generate_previews(&meta).await {
+                tracing::warn!("Generate previews for {} failed: {}", meta.url, e);
+            }
+        });
+    }
+
+    /// Dimensions, blurhash and thumbnails of an image, stored on every upload of the same content.
+    /// Thumbnails are only made for sizes smaller than the image itself.
+    pub async fn generate_previews(&self, meta: &FileMeta) -> Result<FileMeta, AppError> {
+        // identical content was uploaded before, its previews can be reused
+        let copied: Option<FileMeta> = sqlx::query_as(
+            r#"
+            UPDATE files f
+            SET width = o.width, height = o.height, blurhash = o.blurhash, thumbnails = o.thumbnails
+            FROM (
+              SELECT width, height, blurhash, thumbnails FROM files
+              WHERE url = $1 AND width IS NOT NULL
+              LIMIT 1
+            ) o
+            WHERE f.id = $2
+            RETURNING f.id, f.ws_id, f.uploader_id, f.url, f.filename, f.size, f.mime, f.width,
+              f.height, f.blurhash, f.thumbnails, f.scan_status, f.scan_detail, f.created_at
+            "#,
+        )
+        .bind(&meta.url)
+        .bind(meta.id)
+        .fetch_optional(&self.pool)
+        .await?;
+        if let Some(meta) = copied {
+            return Ok(meta);
+        }
+
+        let file = ChatFile::from_str(&meta.url)?;
+        let Some(data) = self.store.get_bytes(&file.key()).await? else {
+            return Err(AppError::NotFound(format!("file {}", meta.url)));
+        };
+        let sizes = self.config.upload.thumbnail_sizes.clone();
+        let jpeg = file.thumbnail_ext() == "jpg";
+        let previews = tokio::task::spawn_blocking(move || render_previews(&data, &sizes, jpeg))
+            .await
+            .map_err(|e| AppError::StorageError(format!("preview task failed: {e}")))??;
+
+        let mut thumbnails = vec![];
+        for (size, data) in previews.thumbnails {
+            let thumbnail = file.thumbnail(size);
+            self.store.put_bytes(&thumbnail.key(), data.into()).await?;
+            thumbnails.push(Thumbnail {
+                size,
+                url: thumbnail.url(),
+                signed_url: None,
+            });
+        }
+
+        let meta = sqlx::query_as(
+            r#"
+            UPDATE files SET width = $2, height = $3, blurhash = $4, thumbnails = $5
+            WHERE url = $1
+            RETURNING id, ws_id, uploader_id, url, filename, size, mime, width, height, blurhash,
+              thumbnails, scan_status, scan_detail, created_at
+            "#,
+        )
+        .bind(&meta.url)
+        .bind(previews.width as i32)
+        .bind(previews.height as i32)
+        .bind(previews.blurhash)
+        .bind(Json(thumbnails))
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .find(|m: &FileMeta| m.id == meta.id)
+        .ok_or_else(|| AppError::NotFound(format!("file id {}", meta.id)))?;
+        Ok(meta)
+    }
+}
+
+fn render_previews(data: &[u8], sizes: &[u32], jpeg: bool) -> Result<Previews, AppError> {
+    // the default limits refuse decompression bombs
+    let mut decoder = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()?
+        .into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    let (width, height) = image.dimensions();
+
+    let small = image.thumbnail(32, 32).to_rgba8();
+    let blurhash = blurhash::encode(4, 3, small.width(), small.height(), small.as_raw())
+        .map_err(|e| AppError::StorageError(format!("blurhash failed: {e:?}")))?;
+
+    let thumbnails = sizes
+        .iter()
+        .filter(|&&size| size < width.max(height))
+        .map(|&size| Ok((size, encode(&image.thumbnail(size, size), jpeg)?)))
+        .collect::<ImageResult<_>>()?;
+
+    Ok(Previews {
+        width,
+        height,
+        blurhash,
+        thumbnails,
+    })
+}
+
+fn encode(image: &DynamicImage, jpeg: bool) -> ImageResult<Vec<u8>> {
+    let mut buf = vec![];
+    if jpeg {
+        let encoder = JpegEncoder::new_with_quality(&mut buf, JPEG_QUALITY);
+        image.to_rgb8().write_with_encoder(encoder)?;
+    } else {
+        image.write_with_encoder(PngEncoder::new(&mut buf))?;
+    }
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AppConfig, UploadOptions};
+    use anyhow::Result;
+    use bytes::Bytes;
+    use image::{ImageFormat, RgbImage};
+    use img_parts::{jpeg::Jpeg, ImageEXIF};
+
+    fn test_image(format: ImageFormat) -> Result<Bytes> {
+        let image = RgbImage::from_fn(600, 400, |x, y| {
+            image::Rgb([(x / 3) as u8, (y / 2) as u8, 128])
+        });
+        let mut buf = Cursor::new(vec![]);
+        image.write_to(&mut buf, format)?;
+        Ok(buf.into_inner().into())
+    }
+
+    async fn upload(
+        state: &AppState,
+        name: &str,
+        data: Bytes,
+        strip_exif: bool,
+    ) -> Result<FileMeta> {
+        let user = state.find_user_by_id(1).await?.unwrap();
+        let body = futures::stream::iter([Ok(data)]);
+        let options = UploadOptions { strip_exif };
+        let mut budget = u64::MAX;
+        Ok(state
+            .save_upload(&user, name, body, &mut budget, &options)
+            .await?)
+    }
+
+    #[tokio::test]
+    async fn generate_previews_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let meta = upload(&state, "photo.png", test_image(ImageFormat::Png)?, false).await?;
+
+        let meta = state.generate_previews(&meta).await?;
+        assert_eq!((meta.width, meta.height), (Some(600), Some(400)));
+        assert!(!meta.blurhash.as_deref().unwrap_or_default().is_empty());
+        // 1024 is larger than the image, there's no point in it
+        let thumbnail = ChatFile::from_str(&meta.url)?.thumbnail(256);
+        assert_eq!(
+            meta.thumbnails.0,
+            vec![Thumbnail {
+                size: 256,
+                url: thumbnail.url(),
+                signed_url: None,
+            }]
+        );
+        let data = state.store.get_bytes(&thumbnail.key()).await?.unwrap();
+        assert_eq!(image::load_from_memory(&data)?.dimensions(), (256, 171));
+        // only those who can see the image can see its thumbnails
+        assert!(state.can_access_file(1, &thumbnail.url()).await?);
+        assert!(!state.can_access_file(2, &thumbnail.url()).await?);
+
+        // the same content uploaded again reuses the previews
+        let again = upload(&state, "copy.png", test_image(ImageFormat::Png)?, false).await?;
+        let again = state.generate_previews(&again).await?;
+        assert_eq!(again.thumbnails, meta.thumbnails);
+        assert_eq!(again.attachment().width, Some(600));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn upload_should_strip_exif_on_request() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let mut jpeg = Jpeg::from_bytes(test_image(ImageFormat::Jpeg)?)?;
+        jpeg.set_exif(Some(Bytes::from_static(b"Exif\0\0MM\0*GPS")));
+        let data = jpeg.encoder().bytes();
+
+        let kept = upload(&state, "a.jpg", data.clone(), false).await?;
+        let stripped = upload(&state, "b.jpg", data.clone(), true).await?;
+        assert_ne!(kept.url, stripped.url);
+        assert!(stripped.size < kept.size);
+
+        let key = ChatFile::from_str(&stripped.url)?.key();
+        let stored = state.store.get_bytes(&key).await?.unwrap();
+        assert_eq!(stored.len() as i64, stripped.size);
+        assert!(Jpeg::from_bytes(stored)?.exif().is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn strip_exif_should_reject_large_images() -> Result<()> {
+        let mut config = AppConfig::load()?;
+        config.upload.max_strip_exif_size = 64;
+        let (_tdb, state) = AppState::new_for_test_with_config(config).await?;
+
+        let ret = upload(&state, "a.jpg", test_image(ImageFormat::Jpeg)?, true).await;
+        let ret = ret.unwrap_err().downcast::<AppError>()?;
+        assert!(matches!(ret, AppError::PayloadTooLarge(_)));
+
+        // anything that isn't an image is stored as is, whatever its size
+        let text = Bytes::from(vec![b'a'; 128]);
+        let meta = upload(&state, "a.txt", text, true).await?;
+        assert_eq!(meta.size, 128);
+        Ok(())
+    }
+}
@@ -0,0 +1,153 @@
+use std::time::Duration;
+
+use chat_core::{Chat, User};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+use crate::{error::AppError, AppState};
+
+use super::{token::insert_bot_user, CreateChat, CreateMessage};
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct Reminder {
+    pub id: i64,
+    pub user_id: i64,
+    pub chat_id: i64,
+    pub content: String,
+    pub remind_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl AppState {
+    pub async fn create_reminder(
+        &self,
+        user: &User,
+        chat_id: u64,
+        content: &str,
+        remind_at: DateTime<Utc>,
+    ) -> Result<Reminder, AppError> {
+        let reminder = sqlx::query_as(
+            r#"
+            INSERT INTO reminders (user_id, chat_id, content, remind_at)
+            VALUES ($1, $2, $3, $4)
+            RETURNING id, user_id, chat_id, content, remind_at, created_at
+            "#,
+        )
+        .bind(user.id)
+        .bind(chat_id as i64)
+        .bind(content)
+        .bind(remind_at)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(reminder)
+    }
+
+    /// Send the reminders that are due to their requesters, returning how many were sent
+    pub async fn process_due_reminders(&self) -> Result<usize, AppError> {
+        let due: Vec<i64> = sqlx::query_scalar(
+            r#"
+            SELECT id FROM reminders
+            WHERE remind_at <= CURRENT_TIMESTAMP
+            ORDER BY remind_at
+            LIMIT 100
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut sent = 0;
+        for id in due {
+            match self.send_reminder(id).await {
+                Ok(true) => sent += 1,
+                Ok(false) => {}
+                // kept for the next round
+                Err(e) => tracing::warn!("Send reminder {} failed: {}", id, e),
+            }
+        }
+        Ok(sent)
+    }
+
+    // the row stays locked until it's delivered, so no other instance sends it twice
+    async fn send_reminder(&self, id: i64) -> Result<bool, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let reminder: Option<Reminder> = sqlx::query_as(
+            r#"
+            SELECT id, user_id, chat_id, content, remind_at, created_at
+            FROM reminders
+            WHERE id = $1
+            FOR UPDATE SKIP LOCKED
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(reminder) = reminder else {
+            return Ok(false);
+        };
+
+        let user = self.find_user_by_id(reminder.user_id).await?;
+        if let Some(user) = &user {
+            let bot = self.reminder_bot(user.ws_id).await?;
+            let chat = self.reminder_chat(&bot, user).await?;
+            let input = CreateMessage {
+                content: format!("Reminder: {}", reminder.content),
+                files: vec![],
+            };
+            self.create_message(input, chat.id as _, bot.id as _)
+                .await?;
+        }
+
+        sqlx::query("DELETE FROM reminders WHERE id = $1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(user.is_some())
+    }
+
+    /// Send due reminders until the process exits
+    pub async fn run_reminders(self) {
+        loop {
+            if let Err(e) = self.process_due_reminders().await {
+                tracing::warn!("Process reminders failed: {}", e);
+            }
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        }
+    }
+
+    // every workspace gets a single bot posting the reminders
+    async fn reminder_bot(&self, ws_id: i64) -> Result<User, AppError> {
+        let email = format!("reminders-{}@bots.local", ws_id);
+        match self.find_user_by_email(&email).await? {
+            Some(bot) => Ok(bot),
+            None => insert_bot_user(&self.pool, ws_id, "Reminders", &email).await,
+        }
+    }
+
+    // reminders only go to the requester, in a direct message with the bot
+    async fn reminder_chat(&self, bot: &User, user: &User) -> Result<Chat, AppError> {
+        let chat = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, name, type, members, topic, created_at
+            FROM chats
+            WHERE type = 'single' AND members @> ARRAY[$1, $2]::bigint[]
+            "#,
+        )
+        .bind(bot.id)
+        .bind(user.id)
+        .fetch_optional(&self.pool)
+        .await?;
+        if let Some(chat) = chat {
+            return Ok(chat);
+        }
+
+        let input = CreateChat {
+            name: None,
+            members: vec![bot.id, user.id],
+            public: false,
+        };
+        self.create_chat(input, user.ws_id as _).await
+    }
+}
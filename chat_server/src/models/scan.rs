@@ -0,0 +1,229 @@
+use std::{str::FromStr, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{error::AppError, scanner::ScanOutcome, AppState};
+
+use super::{ChatFile, FileMeta};
+
+// scans still pending after this long were lost, e.g. to a restart
+const LOST_SCAN_SECS: f64 = 5.0 * 60.0;
+const RESCAN_BATCH: i64 = 100;
+
+/// Files are only served and attached once `clean`, to their uploader too
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case", type_name = "scan_status")]
+pub enum ScanStatus {
+    Pending,
+    Clean,
+    Infected,
+    Error,
+}
+
+impl AppState {
+    /// Scan a new upload in the background
+    pub(crate) fn spawn_scan(&self, meta: &FileMeta) {
+        let state = self.clone();
+        let url = meta.url.clone();
+        tokio::spawn(async move {
+            if let Err(e) = state.scan_file(&url).await {
+                tracing::warn!("Scan {} failed: {}", url, e);
+            }
+        });
+    }
+
+    /// Run the scanner over the stored content and record the verdict on every upload of it.
+    /// Without a scanner the file is released.
+    pub async fn scan_file(&self, url: &str) -> Result<ScanStatus, AppError> {
+        let file = ChatFile::from_str(url)?;
+        let outcome = match &self.scanner {
+            None => ScanOutcome::Clean,
+            Some(scanner) => match self.store.get(&file.key()).await? {
+                Some(obj) => scanner.scan(obj.body).await,
+                None => ScanOutcome::Error("file is missing from the store".to_string()),
+            },
+        };
+        let (status, detail) = match outcome {
+            ScanOutcome::Clean => (ScanStatus::Clean, None),
+            ScanOutcome::Infected(signature) => {
+                tracing::warn!("Quarantined {}: {} found", url, signature);
+                (ScanStatus::Infected, Some(signature))
+            }
+            ScanOutcome::Error(e) => {
+                tracing::warn!("Scan {} failed: {}", url, e);
+                (ScanStatus::Error, Some(e))
+            }
+        };
+
+        let updated: Vec<FileMeta> = sqlx::query_as(
+            r#"
+            UPDATE files SET scan_status = $2, scan_detail = $3
+            WHERE url = $1 AND scan_status IN ('pending', 'error')
+            RETURNING id, ws_id, uploader_id, url, filename, size, mime, width, height, blurhash,
+              thumbnails, scan_status, scan_detail, created_at
+            "#,
+        )
+        .bind(url)
+        .bind(status)
+        .bind(detail)
+        .fetch_all(&self.pool)
+        .await?;
+        // previews are only rendered from content that passed
+        if let (ScanStatus::Clean, Some(meta)) = (status, updated.first()) {
+            self.spawn_previews(meta);
+        }
+        Ok(status)
+    }
+
+    /// Refuse files that are quarantined, thumbnails share the verdict of their image.
+    /// Files uploaded before scanning existed have no metadata and are let through.
+    pub(crate) async fn ensure_file_clean(&self, url: &str) -> Result<(), AppError> {
+        let url = self.source_file_url(url).await?;
+        let status: Option<(ScanStatus, Option<String>)> = sqlx::query_as(
+            "SELECT scan_status, scan_detail FROM files WHERE url = $1 ORDER BY id DESC LIMIT 1",
+        )
+        .bind(&url)
+        .fetch_optional(&self.pool)
+        .await?;
+        match status {
+            None | Some((ScanStatus::Clean, _)) => Ok(()),
+            Some((ScanStatus::Pending, _)) => Err(AppError::FileQuarantined(format!(
+                "{url} is still being scanned"
+            ))),
+            Some((ScanStatus::Infected, detail)) => Err(AppError::FileQuarantined(format!(
+                "{url} is infected with {}",
+                detail.unwrap_or_default()
+            ))),
+            Some((ScanStatus::Error, _)) => Err(AppError::FileQuarantined(format!(
+                "{url} couldn't be scanned, it will be retried"
+            ))),
+        }
+    }
+
+    /// Retry failed scans and pick up the ones lost to a restart, returning how many were scanned
+    pub async fn rescan_files(&self) -> Result<usize, AppError> {
+        let urls: Vec<(String,)> = sqlx::query_as(
+            r#"
+            SELECT DISTINCT url FROM files
+            WHERE scan_status = 'error'
+              OR (scan_status = 'pending'
+                AND created_at < CURRENT_TIMESTAMP - make_interval(secs => $1))
+            LIMIT $2
+            "#,
+        )
+        .bind(LOST_SCAN_SECS)
+        .bind(RESCAN_BATCH)
+        .fetch_all(&self.pool)
+        .await?;
+        for (url,) in &urls {
+            self.scan_file(url).await?;
+        }
+        Ok(urls.len())
+    }
+
+    /// Rescan quarantined files until the process exits, nothing to do without a scanner
+    pub async fn run_file_scans(self) {
+        if self.scanner.is_none() {
+            return;
+        }
+        loop {
+            match self.rescan_files().await {
+                Ok(0) => {}
+                Ok(n) => tracing::info!("Rescanned {} files", n),
+                Err(e) => tracing::warn!("Rescan files failed: {}", e),
+            }
+            tokio::time::sleep(Duration::from_secs(5 * 60)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::ScannerConfig, scanner::start_fake_clamd, AppConfig, CreateMessage};
+    use anyhow::Result;
+    use chat_core::User;
+
+    async fn upload(state: &AppState, user: &User, data: &'static str) -> Result<FileMeta> {
+        let body = futures::stream::iter([Ok(data.into())]);
+        let mut budget = u64::MAX;
+        Ok(state
+            .save_upload(user, "a.txt", body, &mut budget, &Default::default())
+            .await?)
+    }
+
+    async fn new_state() -> Result<(sqlx_db_tester::TestPg, AppState)> {
+        let mut config = AppConfig::load()?;
+        config.scanner = Some(ScannerConfig {
+            clamd: start_fake_clamd().await?,
+            timeout_secs: 5,
+        });
+        Ok(AppState::new_for_test_with_config(config).await?)
+    }
+
+    #[tokio::test]
+    async fn uploads_should_be_quarantined_until_scanned() -> Result<()> {
+        let (_tdb, state) = new_state().await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+
+        let meta = upload(&state, &user, "hello world").await?;
+        assert_eq!(meta.scan_status, ScanStatus::Pending);
+        assert_eq!(state.scan_file(&meta.url).await?, ScanStatus::Clean);
+        state.ensure_file_clean(&meta.url).await?;
+        let input = CreateMessage {
+            content: "".to_string(),
+            files: vec![meta.url],
+        };
+        state.create_message(input, 1, 1).await?;
+
+        let infected = upload(&state, &user, "X5O!P%@AP EICAR").await?;
+        assert_eq!(state.scan_file(&infected.url).await?, ScanStatus::Infected);
+        let ret = state.ensure_file_clean(&infected.url).await;
+        assert!(matches!(ret, Err(AppError::FileQuarantined(_))));
+        let input = CreateMessage {
+            content: "".to_string(),
+            files: vec![infected.url.clone()],
+        };
+        let ret = state.create_message(input, 1, 1).await;
+        assert!(matches!(ret, Err(AppError::FileQuarantined(_))));
+
+        // the same content uploaded again keeps its verdict
+        let again = upload(&state, &user, "X5O!P%@AP EICAR").await?;
+        assert_eq!(again.scan_status, ScanStatus::Infected);
+        assert_eq!(again.scan_detail.as_deref(), Some("Eicar-Test-Signature"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_scans_should_be_retried() -> Result<()> {
+        let (_tdb, state) = new_state().await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+        let meta = upload(&state, &user, "hello world").await?;
+        // let the scan started by the upload finish first
+        let scanned = async {
+            while state
+                .find_file_meta_by_url(&meta.url)
+                .await?
+                .unwrap()
+                .scan_status
+                == ScanStatus::Pending
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            Ok::<_, AppError>(())
+        };
+        tokio::time::timeout(Duration::from_secs(5), scanned).await??;
+        sqlx::query("UPDATE files SET scan_status = 'error' WHERE url = $1")
+            .bind(&meta.url)
+            .execute(&state.pool)
+            .await?;
+        let ret = state.ensure_file_clean(&meta.url).await;
+        assert!(matches!(ret, Err(AppError::FileQuarantined(_))));
+
+        assert_eq!(state.rescan_files().await?, 1);
+        state.ensure_file_clean(&meta.url).await?;
+        assert_eq!(state.rescan_files().await?, 0);
+        Ok(())
+    }
+}
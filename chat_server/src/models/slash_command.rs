@@ -0,0 +1,259 @@
+use std::time::Duration;
+
+use chat_core::{utils::check_public_url, Chat, User};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::FromRow;
+
+use crate::{
+    commands::{is_valid_command_name, MessageOutput},
+    error::AppError,
+    utils::random_string,
+    AppState,
+};
+
+use super::{
+    outgoing_webhook::{sign_payload, SIGNATURE_HEADER, TIMESTAMP_HEADER},
+    token::{deactivate_bot_user, insert_bot_user},
+    CreateMessage,
+};
+
+// external commands have to answer quickly, the sender is waiting on the reply
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct SlashCommand {
+    pub id: i64,
+    pub ws_id: i64,
+    pub name: String,
+    pub description: String,
+    pub url: String,
+    pub bot_id: i64,
+    pub created_by: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateSlashCommand {
+    pub name: String,
+    pub url: String,
+    #[serde(default)]
+    pub description: String,
+}
+
+/// A freshly registered command, the only time the signing secret is returned
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreatedSlashCommand {
+    #[serde(flatten)]
+    pub info: SlashCommand,
+    pub secret: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseType {
+    #[default]
+    Ephemeral,
+    InChannel,
+}
+
+/// What an external command answers, slack compatible
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SlashCommandResponse {
+    #[serde(default)]
+    pub text: String,
+    #[serde(default)]
+    pub response_type: ResponseType,
+}
+
+#[derive(Debug, FromRow)]
+struct CommandTarget {
+    url: String,
+    secret: String,
+    bot_id: i64,
+}
+
+impl AppState {
+    pub async fn create_slash_command(
+        &self,
+        input: CreateSlashCommand,
+        user: &User,
+    ) -> Result<CreatedSlashCommand, AppError> {
+        self.ensure_workspace_owner(user).await?;
+
+        let name = input.name.trim_start_matches('/').to_lowercase();
+        if !is_valid_command_name(&name) || name.len() > 32 {
+            return Err(AppError::CommandError(format!(
+                "Invalid command name: {}",
+                input.name
+            )));
+        }
+        if self.commands.get(&name).is_some() {
+            return Err(AppError::CommandError(format!(
+                "/{name} is a built-in command"
+            )));
+        }
+        check_public_url(&input.url, self.config.webhook.allow_private_urls)
+            .await
+            .map_err(AppError::CommandError)?;
+
+        // the bot is rolled back with the command if the name is taken
+        let mut tx = self.pool.begin().await?;
+        let email = format!("command-{}@bots.local", random_string(16).to_lowercase());
+        let bot = insert_bot_user(&mut *tx, user.ws_id, &format!("/{name}"), &email).await?;
+
+        let secret = random_string(32);
+        let info = sqlx::query_as(
+            r#"
+            INSERT INTO slash_commands (ws_id, name, description, url, secret, bot_id, created_by)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (ws_id, name) DO NOTHING
+            RETURNING id, ws_id, name, description, url, bot_id, created_by, created_at
+            "#,
+        )
+        .bind(user.ws_id)
+        .bind(&name)
+        .bind(input.description.trim())
+        .bind(&input.url)
+        .bind(&secret)
+        .bind(bot.id)
+        .bind(user.id)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let Some(info) = info else {
+            return Err(AppError::CommandError(format!("/{name} already exists")));
+        };
+        tx.commit().await?;
+        Ok(CreatedSlashCommand { info, secret })
+    }
+
+    pub async fn fetch_slash_commands(&self, ws_id: u64) -> Result<Vec<SlashCommand>, AppError> {
+        let commands = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, name, description, url, bot_id, created_by, created_at
+            FROM slash_commands
+            WHERE ws_id = $1
+            ORDER BY name
+            "#,
+        )
+        .bind(ws_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(commands)
+    }
+
+    pub async fn delete_slash_command(&self, id: u64, user: &User) -> Result<(), AppError> {
+        self.ensure_workspace_owner(user).await?;
+        let mut tx = self.pool.begin().await?;
+        let bot_id: Option<i64> = sqlx::query_scalar(
+            "DELETE FROM slash_commands WHERE id = $1 AND ws_id = $2 RETURNING bot_id",
+        )
+        .bind(id as i64)
+        .bind(user.ws_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let Some(bot_id) = bot_id else {
+            return Err(AppError::NotFound(format!("command id {id}")));
+        };
+        deactivate_bot_user(&mut tx, bot_id).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Forward a command to the url registered by the workspace.
+    /// Returns None if the workspace has no such command.
+    pub(crate) async fn run_external_command(
+        &self,
+        name: &str,
+        args: &str,
+        user: &User,
+        chat: &Chat,
+    ) -> Result<Option<MessageOutput>, AppError> {
+        let target: Option<CommandTarget> = sqlx::query_as(
+            "SELECT url, secret, bot_id FROM slash_commands WHERE ws_id = $1 AND name = $2",
+        )
+        .bind(chat.ws_id)
+        .bind(name)
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some(target) = target else {
+            return Ok(None);
+        };
+        // commands stored before urls were checked must not reach internal hosts either
+        if let Err(e) = check_public_url(&target.url, self.config.webhook.allow_private_urls).await
+        {
+            tracing::warn!("Slash command /{} refused: {}", name, e);
+            return Ok(Some(MessageOutput::ephemeral(format!(
+                "/{name} didn't respond, try again later"
+            ))));
+        }
+
+        let body = json!({
+            "command": format!("/{name}"),
+            "text": args,
+            "ws_id": chat.ws_id,
+            "chat_id": chat.id,
+            "user_id": user.id,
+            "user_name": user.fullname,
+        })
+        .to_string();
+        let timestamp = Utc::now().timestamp();
+        let ret = self
+            .http
+            .post(&target.url)
+            .timeout(COMMAND_TIMEOUT)
+            .header("content-type", "application/json")
+            .header(
+                SIGNATURE_HEADER,
+                sign_payload(&target.secret, timestamp, &body),
+            )
+            .header(TIMESTAMP_HEADER, timestamp)
+            .body(body)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status());
+
+        let res = match ret {
+            Ok(res) => res,
+            Err(e) => {
+                tracing::warn!("Slash command /{} failed: {}", name, e);
+                return Ok(Some(MessageOutput::ephemeral(format!(
+                    "/{name} didn't respond, try again later"
+                ))));
+            }
+        };
+
+        // an empty body acknowledges the command without a reply
+        let bytes = res.bytes().await?;
+        let reply: SlashCommandResponse = if bytes.is_empty() {
+            SlashCommandResponse::default()
+        } else {
+            match serde_json::from_slice(&bytes) {
+                Ok(reply) => reply,
+                Err(_) => SlashCommandResponse {
+                    text: String::from_utf8_lossy(&bytes).into_owned(),
+                    ..Default::default()
+                },
+            }
+        };
+
+        let output = match reply.response_type {
+            ResponseType::InChannel if !reply.text.is_empty() => {
+                let input = CreateMessage {
+                    content: reply.text,
+                    files: vec![],
+                };
+                let msg = self
+                    .create_message(input, chat.id as _, target.bot_id as _)
+                    .await?;
+                MessageOutput::Message(msg)
+            }
+            _ => MessageOutput::ephemeral(reply.text),
+        };
+        Ok(Some(output))
+    }
+}
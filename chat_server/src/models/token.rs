@@ -0,0 +1,409 @@
+use chat_core::{ChatUser, TokenScope, User};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, PgExecutor, Postgres, Transaction};
+
+use crate::{error::AppError, utils::random_string, AppState};
+
+pub const API_TOKEN_PREFIX: &str = "chat_pat_";
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct ApiToken {
+    pub id: i64,
+    pub user_id: i64,
+    pub name: String,
+    pub scopes: Vec<TokenScope>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateApiToken {
+    pub name: String,
+    pub scopes: Vec<TokenScope>,
+    #[serde(default)]
+    pub expires_in_days: Option<u32>,
+    // issue the token for a bot of the workspace instead of the caller
+    #[serde(default)]
+    pub bot_id: Option<i64>,
+}
+
+/// A freshly issued token, the only time the plain token is returned
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreatedApiToken {
+    #[serde(flatten)]
+    pub info: ApiToken,
+    pub token: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateBot {
+    pub name: String,
+}
+
+impl AppState {
+    // Create a bot user owned by the workspace of the caller
+    pub async fn create_bot(&self, input: &CreateBot, user: &User) -> Result<User, AppError> {
+        ensure_session(user)?;
+        self.ensure_workspace_owner(user).await?;
+
+        let name = input.name.trim();
+        if name.is_empty() || name.len() > 64 {
+            return Err(AppError::ApiTokenError(
+                "Bot name must be 1 to 64 characters".to_string(),
+            ));
+        }
+
+        // bots have no mailbox, the address only keeps them unique per workspace
+        let slug: String = name
+            .to_lowercase()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .take(32)
+            .collect();
+        let email = format!("{}-{}@bots.local", slug, user.ws_id);
+        if self.find_user_by_email(&email).await?.is_some() {
+            return Err(AppError::BotAlreadyExists(name.to_string()));
+        }
+
+        insert_bot_user(&self.pool, user.ws_id, name, &email).await
+    }
+
+    pub async fn fetch_bots(&self, ws_id: u64) -> Result<Vec<ChatUser>, AppError> {
+        let bots = sqlx::query_as(
+            r#"
+            SELECT id, fullname, email FROM users
+            WHERE ws_id = $1 AND is_bot AND deactivated_at IS NULL
+            ORDER BY id
+            "#,
+        )
+        .bind(ws_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(bots)
+    }
+
+    pub async fn create_api_token(
+        &self,
+        input: CreateApiToken,
+        user: &User,
+    ) -> Result<CreatedApiToken, AppError> {
+        ensure_session(user)?;
+
+        if input.name.is_empty() || input.name.len() > 64 {
+            return Err(AppError::ApiTokenError(
+                "Token name must be 1 to 64 characters".to_string(),
+            ));
+        }
+        if input.scopes.is_empty() {
+            return Err(AppError::ApiTokenError(
+                "Token must have at least one scope".to_string(),
+            ));
+        }
+
+        let owner_id = match input.bot_id {
+            Some(bot_id) => {
+                self.ensure_workspace_owner(user).await?;
+                let bots = self.fetch_bots(user.ws_id as _).await?;
+                match bots.iter().find(|bot| bot.id == bot_id) {
+                    Some(bot) => bot.id,
+                    None => return Err(AppError::NotFound(format!("bot id {bot_id}"))),
+                }
+            }
+            None => user.id,
+        };
+
+        let mut scopes = input.scopes;
+        scopes.sort_by_key(|s| *s as u8);
+        scopes.dedup();
+
+        let token = format!("{}{}", API_TOKEN_PREFIX, random_string(40));
+        let expires_at = input
+            .expires_in_days
+            .map(|days| Utc::now() + Duration::days(days as i64));
+
+        let info = sqlx::query_as(
+            r#"
+            INSERT INTO api_tokens (user_id, created_by, name, token_hash, scopes, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING id, user_id, name, scopes, expires_at, last_used_at, created_at
+            "#,
+        )
+        .bind(owner_id)
+        .bind(user.id)
+        .bind(&input.name)
+        .bind(hash_token(&token))
+        .bind(scopes)
+        .bind(expires_at)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(CreatedApiToken { info, token })
+    }
+
+    // List the tokens issued by the user, including the ones for bots
+    pub async fn fetch_api_tokens(&self, user: &User) -> Result<Vec<ApiToken>, AppError> {
+        ensure_session(user)?;
+
+        let tokens = sqlx::query_as(
+            r#"
+            SELECT id, user_id, name, scopes, expires_at, last_used_at, created_at
+            FROM api_tokens
+            WHERE created_by = $1 OR user_id = $1
+            ORDER BY id
+            "#,
+        )
+        .bind(user.id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(tokens)
+    }
+
+    pub async fn revoke_api_token(&self, id: u64, user: &User) -> Result<(), AppError> {
+        ensure_session(user)?;
+        let ret = sqlx::query(
+            "DELETE FROM api_tokens WHERE id = $1 AND (created_by = $2 OR user_id = $2)",
+        )
+        .bind(id as i64)
+        .bind(user.id)
+        .execute(&self.pool)
+        .await?;
+
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("api token id {id}")));
+        }
+        Ok(())
+    }
+
+    /// Resolve an opaque api token to its user, carrying the granted scopes
+    pub async fn verify_api_token(&self, token: &str) -> Result<User, AppError> {
+        let row: Option<(i64, Vec<TokenScope>)> = sqlx::query_as(
+            r#"
+            UPDATE api_tokens SET last_used_at = CURRENT_TIMESTAMP
+            WHERE token_hash = $1 AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+            RETURNING user_id, scopes
+            "#,
+        )
+        .bind(hash_token(token))
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let Some((user_id, scopes)) = row else {
+            return Err(AppError::PermissionDenied(
+                "invalid or expired api token".to_string(),
+            ));
+        };
+        let Some(mut user) = self.find_user_by_id(user_id).await? else {
+            return Err(AppError::NotFound(format!("user id {user_id}")));
+        };
+        user.scopes = Some(scopes);
+        Ok(user)
+    }
+
+    pub(crate) async fn ensure_workspace_owner(&self, user: &User) -> Result<(), AppError> {
+        match self.find_workspace_by_id(user.ws_id).await? {
+            Some(ws) if ws.owner_id == user.id => Ok(()),
+            _ => Err(AppError::PermissionDenied(
+                "only the workspace owner can do this".to_string(),
+            )),
+        }
+    }
+}
+
+/// Insert a bot user, takes a transaction where the bot belongs to another row
+pub(crate) async fn insert_bot_user(
+    executor: impl PgExecutor<'_>,
+    ws_id: i64,
+    name: &str,
+    email: &str,
+) -> Result<User, AppError> {
+    let bot = sqlx::query_as(
+        r#"
+        INSERT INTO users (ws_id, fullname, email, password_hash, is_bot)
+        VALUES ($1, $2, $3, '', TRUE)
+        RETURNING id, ws_id, fullname, email, is_bot, created_at
+        "#,
+    )
+    .bind(ws_id)
+    .bind(name)
+    .bind(email)
+    .fetch_one(executor)
+    .await?;
+
+    Ok(bot)
+}
+
+/// Retire the bot of a deleted integration, its messages still point at it
+pub(crate) async fn deactivate_bot_user(
+    tx: &mut Transaction<'_, Postgres>,
+    bot_id: i64,
+) -> Result<(), AppError> {
+    sqlx::query("UPDATE users SET deactivated_at = CURRENT_TIMESTAMP WHERE id = $1 AND is_bot")
+        .bind(bot_id)
+        .execute(&mut **tx)
+        .await?;
+    sqlx::query("DELETE FROM api_tokens WHERE user_id = $1")
+        .bind(bot_id)
+        .execute(&mut **tx)
+        .await?;
+    Ok(())
+}
+
+// api tokens must not be able to mint or revoke other tokens
+fn ensure_session(user: &User) -> Result<(), AppError> {
+    match user.scopes {
+        Some(_) => Err(AppError::PermissionDenied(
+            "api tokens can't manage tokens or bots".to_string(),
+        )),
+        None => Ok(()),
+    }
+}
+
+pub(crate) fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CreateMessage;
+    use anyhow::Result;
+
+    async fn owner(state: &AppState) -> Result<User> {
+        let mut user = state.find_user_by_id(1).await?.expect("user should exist");
+        state.update_workspace_owner(1, 1).await?;
+        user.scopes = None;
+        Ok(user)
+    }
+
+    #[tokio::test]
+    async fn bot_token_should_resolve_to_bot_user() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let owner = owner(&state).await?;
+
+        let bot = state
+            .create_bot(
+                &CreateBot {
+                    name: "CI Bot".to_string(),
+                },
+                &owner,
+            )
+            .await?;
+        assert!(bot.is_bot);
+        assert_eq!(bot.ws_id, 1);
+        assert_eq!(state.fetch_bots(1).await?.len(), 1);
+
+        let input = CreateApiToken {
+            name: "ci".to_string(),
+            scopes: vec![TokenScope::PostMessages, TokenScope::ReadChats],
+            expires_in_days: Some(30),
+            bot_id: Some(bot.id),
+        };
+        let created = state.create_api_token(input, &owner).await?;
+        assert!(created.token.starts_with(API_TOKEN_PREFIX));
+        assert_eq!(created.info.user_id, bot.id);
+
+        let user = state.verify_api_token(&created.token).await?;
+        assert_eq!(user.id, bot.id);
+        assert!(user.is_bot);
+        assert!(user.has_scope(TokenScope::PostMessages));
+        assert!(!user.has_scope(TokenScope::UploadFiles));
+
+        // api tokens can't mint new tokens
+        let input = CreateApiToken {
+            name: "nested".to_string(),
+            scopes: vec![TokenScope::ReadChats],
+            expires_in_days: None,
+            bot_id: None,
+        };
+        let ret = state.create_api_token(input, &user).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let ret = state.fetch_api_tokens(&user).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        let tokens = state.fetch_api_tokens(&owner).await?;
+        assert_eq!(tokens.len(), 1);
+        assert!(tokens[0].last_used_at.is_some());
+
+        state.revoke_api_token(created.info.id as _, &owner).await?;
+        let ret = state.verify_api_token(&created.token).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_workspace_owner_should_manage_bots() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        owner(&state).await?;
+        let member = state.find_user_by_id(2).await?.unwrap();
+
+        let ret = state
+            .create_bot(
+                &CreateBot {
+                    name: "bot".to_string(),
+                },
+                &member,
+            )
+            .await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        let input = CreateApiToken {
+            name: "bad".to_string(),
+            scopes: vec![TokenScope::ReadChats],
+            expires_in_days: None,
+            bot_id: Some(1),
+        };
+        let ret = state.create_api_token(input, &member).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn expired_token_should_be_rejected() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(2).await?.unwrap();
+        let input = CreateApiToken {
+            name: "short".to_string(),
+            scopes: vec![TokenScope::ReadChats],
+            expires_in_days: Some(0),
+            bot_id: None,
+        };
+        let created = state.create_api_token(input, &user).await?;
+        let ret = state.verify_api_token(&created.token).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn message_sent_by_bot_should_be_marked() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let owner = owner(&state).await?;
+        let bot = state
+            .create_bot(
+                &CreateBot {
+                    name: "alerts".to_string(),
+                },
+                &owner,
+            )
+            .await?;
+
+        let input = CreateMessage {
+            content: "build passed".to_string(),
+            files: vec![],
+        };
+        let message = state.create_message(input, 1, bot.id as _).await?;
+        assert!(message.is_bot);
+
+        let input = CreateMessage {
+            content: "thanks".to_string(),
+            files: vec![],
+        };
+        let message = state.create_message(input, 1, owner.id as _).await?;
+        assert!(!message.is_bot);
+        Ok(())
+    }
+}
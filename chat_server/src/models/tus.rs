@@ -0,0 +1,421 @@
+use std::{path::PathBuf, pin::pin, time::Duration};
+
+use bytes::Bytes;
+use chat_core::User;
+use chrono::{DateTime, Utc};
+use futures::{Stream, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use sqlx::FromRow;
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom},
+};
+
+use crate::{error::AppError, utils::random_string, AppState};
+
+use super::ChatFile;
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct TusUpload {
+    pub id: String,
+    pub ws_id: i64,
+    pub user_id: i64,
+    pub filename: String,
+    pub upload_length: i64,
+    pub upload_offset: i64,
+    pub file_url: Option<String>,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl AppState {
+    pub async fn create_tus_upload(
+        &self,
+        user: &User,
+        filename: &str,
+        length: u64,
+    ) -> Result<TusUpload, AppError> {
+        if length > self.config.upload.max_file_size {
+            return Err(AppError::PayloadTooLarge(format!(
+                "{} is larger than {} bytes",
+                filename, self.config.upload.max_file_size
+            )));
+        }
+        if filename.is_empty() || filename.len() > 255 {
+            return Err(AppError::UploadFileError(
+                "File name must be 1 to 255 characters".to_string(),
+            ));
+        }
+        self.ensure_mime_allowed(filename)?;
+        if self
+            .storage_remaining(user.ws_id)
+            .await?
+            .is_some_and(|left| length > left)
+        {
+            return Err(AppError::QuotaExceeded(format!(
+                "workspace {} has no room for {}",
+                user.ws_id, filename
+            )));
+        }
+
+        let id = random_string(32).to_lowercase();
+        fs::create_dir_all(self.tus_dir()).await?;
+        fs::File::create(self.tus_path(&id)).await?;
+
+        let upload = sqlx::query_as(
+            r#"
+            INSERT INTO tus_uploads (id, ws_id, user_id, filename, upload_length, expires_at)
+            VALUES ($1, $2, $3, $4, $5, CURRENT_TIMESTAMP + make_interval(secs => $6))
+            RETURNING id, ws_id, user_id, filename, upload_length, upload_offset, file_url,
+              expires_at, created_at
+            "#,
+        )
+        .bind(&id)
+        .bind(user.ws_id)
+        .bind(user.id)
+        .bind(filename)
+        .bind(length as i64)
+        .bind(self.config.upload.resumable_expiry_secs as f64)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(upload)
+    }
+
+    /// Uploads are private to the user who created them
+    pub async fn get_tus_upload(&self, id: &str, user: &User) -> Result<TusUpload, AppError> {
+        let upload: Option<TusUpload> = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, user_id, filename, upload_length, upload_offset, file_url,
+              expires_at, created_at
+            FROM tus_uploads
+            WHERE id = $1 AND user_id = $2
+            "#,
+        )
+        .bind(id)
+        .bind(user.id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match upload {
+            Some(upload) if upload.file_url.is_none() && upload.expires_at <= Utc::now() => {
+                Err(AppError::UploadExpired(format!("upload {id}")))
+            }
+            Some(upload) => Ok(upload),
+            None => Err(AppError::NotFound(format!("upload {id}"))),
+        }
+    }
+
+    /// Write a chunk at `offset`, which must be where the previous chunk stopped.
+    /// Whatever arrived before the client dropped is kept so it can resume from there.
+    pub async fn append_tus_upload(
+        &self,
+        id: &str,
+        user: &User,
+        offset: u64,
+        body: impl Stream<Item = Result<Bytes, AppError>>,
+    ) -> Result<TusUpload, AppError> {
+        // the row stays locked while the chunk is written, a second request for the same
+        // upload is turned away instead of writing the same range
+        let mut tx = self.pool.begin().await?;
+        let locked: Option<TusUpload> = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, user_id, filename, upload_length, upload_offset, file_url,
+              expires_at, created_at
+            FROM tus_uploads
+            WHERE id = $1 AND user_id = $2
+            FOR UPDATE SKIP LOCKED
+            "#,
+        )
+        .bind(id)
+        .bind(user.id)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(upload) = locked else {
+            self.get_tus_upload(id, user).await?;
+            return Err(AppError::UploadConflict(format!(
+                "upload {id} is being written by another request"
+            )));
+        };
+
+        if upload.file_url.is_some() {
+            return Err(AppError::UploadConflict(format!(
+                "upload {id} is already complete"
+            )));
+        }
+        if upload.expires_at <= Utc::now() {
+            return Err(AppError::UploadExpired(format!("upload {id}")));
+        }
+        if upload.upload_offset as u64 != offset {
+            return Err(AppError::UploadConflict(format!(
+                "upload {id} is at offset {}, not {offset}",
+                upload.upload_offset
+            )));
+        }
+
+        let remaining = (upload.upload_length - upload.upload_offset) as u64;
+        // files posted since the upload was created count against the quota too
+        let quota_left = self.storage_remaining(upload.ws_id).await?;
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .open(self.tus_path(id))
+            .await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+
+        let mut written = 0u64;
+        let mut body = pin!(body);
+        let ret: Result<(), AppError> = async {
+            while let Some(chunk) = body.try_next().await? {
+                let total = written + chunk.len() as u64;
+                if total > remaining {
+                    return Err(AppError::PayloadTooLarge(format!(
+                        "upload {id} only has {remaining} bytes left"
+                    )));
+                }
+                if quota_left.is_some_and(|left| offset + total > left) {
+                    return Err(AppError::QuotaExceeded(format!(
+                        "workspace {} has no room for {}",
+                        upload.ws_id, upload.filename
+                    )));
+                }
+                file.write_all(&chunk).await?;
+                written = total;
+            }
+            Ok(())
+        }
+        .await;
+        file.flush().await?;
+        drop(file);
+
+        let upload: TusUpload = sqlx::query_as(
+            r#"
+            UPDATE tus_uploads SET upload_offset = upload_offset + $2
+            WHERE id = $1
+            RETURNING id, ws_id, user_id, filename, upload_length, upload_offset, file_url,
+              expires_at, created_at
+            "#,
+        )
+        .bind(id)
+        .bind(written as i64)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        ret?;
+
+        if upload.upload_offset == upload.upload_length {
+            return self.complete_tus_upload(upload).await;
+        }
+        Ok(upload)
+    }
+
+    pub async fn delete_tus_upload(&self, id: &str, user: &User) -> Result<(), AppError> {
+        let ret = sqlx::query("DELETE FROM tus_uploads WHERE id = $1 AND user_id = $2")
+            .bind(id)
+            .bind(user.id)
+            .execute(&self.pool)
+            .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("upload {id}")));
+        }
+        self.remove_tus_data(id).await;
+        Ok(())
+    }
+
+    /// Drop expired uploads with their partial data, returning how many were removed
+    pub async fn purge_expired_uploads(&self) -> Result<usize, AppError> {
+        let ids: Vec<(String,)> = sqlx::query_as(
+            "DELETE FROM tus_uploads WHERE expires_at <= CURRENT_TIMESTAMP RETURNING id",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        for (id,) in &ids {
+            self.remove_tus_data(id).await;
+        }
+        Ok(ids.len())
+    }
+
+    /// Purge expired uploads until the process exits
+    pub async fn run_upload_janitor(self) {
+        loop {
+            match self.purge_expired_uploads().await {
+                Ok(0) => {}
+                Ok(n) => tracing::info!("Purged {} expired uploads", n),
+                Err(e) => tracing::warn!("Purge expired uploads failed: {}", e),
+            }
+            tokio::time::sleep(Duration::from_secs(10 * 60)).await;
+        }
+    }
+
+    async fn complete_tus_upload(&self, upload: TusUpload) -> Result<TusUpload, AppError> {
+        let path = self.tus_path(&upload.id);
+        let mut file = fs::File::open(&path).await?;
+        let mut hasher = Sha1::new();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        drop(file);
+
+        let chat_file = ChatFile::from_hash(
+            upload.ws_id as _,
+            &upload.filename,
+            hex::encode(hasher.finalize()),
+        );
+        let lock = self.lock_file_url(&chat_file.url()).await?;
+        self.store_local_file(&chat_file, &path).await?;
+        self.create_file_meta(
+            &chat_file,
+            upload.user_id,
+            &upload.filename,
+            upload.upload_length as _,
+        )
+        .await?;
+        lock.commit().await?;
+
+        let upload = sqlx::query_as(
+            r#"
+            UPDATE tus_uploads SET file_url = $2
+            WHERE id = $1
+            RETURNING id, ws_id, user_id, filename, upload_length, upload_offset, file_url,
+              expires_at, created_at
+            "#,
+        )
+        .bind(&upload.id)
+        .bind(chat_file.url())
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(upload)
+    }
+
+    async fn remove_tus_data(&self, id: &str) {
+        // completed uploads were already moved into the store
+        let _ = fs::remove_file(self.tus_path(id)).await;
+    }
+
+    fn tus_dir(&self) -> PathBuf {
+        self.config.server.base_dir.join(".tus")
+    }
+
+    fn tus_path(&self, id: &str) -> PathBuf {
+        self.tus_dir().join(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AppConfig, CreateMessage};
+    use anyhow::Result;
+    use futures::stream;
+
+    fn chunk(data: &'static str) -> impl Stream<Item = Result<Bytes, AppError>> {
+        stream::iter([Ok(Bytes::from_static(data.as_bytes()))])
+    }
+
+    #[tokio::test]
+    async fn tus_upload_should_resume_and_complete() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+
+        let upload = state.create_tus_upload(&user, "notes.txt", 11).await?;
+        assert_eq!(upload.upload_offset, 0);
+
+        let upload = state
+            .append_tus_upload(&upload.id, &user, 0, chunk("hello"))
+            .await?;
+        assert_eq!(upload.upload_offset, 5);
+        assert!(upload.file_url.is_none());
+
+        // resuming from a stale offset is rejected
+        let ret = state
+            .append_tus_upload(&upload.id, &user, 0, chunk("hello"))
+            .await;
+        assert!(matches!(ret, Err(AppError::UploadConflict(_))));
+
+        // other users can't see the upload
+        let other = state.find_user_by_id(2).await?.unwrap();
+        let ret = state.get_tus_upload(&upload.id, &other).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        let upload = state
+            .append_tus_upload(&upload.id, &user, 5, chunk(" world"))
+            .await?;
+        let url = upload.file_url.expect("upload should be complete");
+        assert_eq!(url, ChatFile::new(1, "notes.txt", b"hello world").url());
+        assert!(!state.tus_path(&upload.id).exists());
+
+        // the url is accepted by create_message and keeps the uploaded name
+        let input = CreateMessage {
+            content: "see attached".to_string(),
+            files: vec![url],
+        };
+        let message = state.create_message(input, 1, 1).await?;
+        assert_eq!(message.attachments[0].filename, "notes.txt");
+        assert_eq!(message.attachments[0].size, 11);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tus_upload_should_enforce_length_and_expire() -> Result<()> {
+        let mut config = AppConfig::load()?;
+        config.upload.resumable_expiry_secs = 0;
+        let (_tdb, state) = AppState::new_for_test_with_config(config).await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+
+        let ret = state
+            .create_tus_upload(&user, "huge.bin", u64::MAX / 2)
+            .await;
+        assert!(matches!(ret, Err(AppError::PayloadTooLarge(_))));
+
+        let upload = state.create_tus_upload(&user, "a.txt", 3).await?;
+        let ret = state.get_tus_upload(&upload.id, &user).await;
+        assert!(matches!(ret, Err(AppError::UploadExpired(_))));
+
+        assert_eq!(state.purge_expired_uploads().await?, 1);
+        assert!(!state.tus_path(&upload.id).exists());
+        let ret = state.get_tus_upload(&upload.id, &user).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tus_upload_should_lock_and_check_quota_while_writing() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+        let upload = state.create_tus_upload(&user, "notes.txt", 11).await?;
+
+        // a request already writing the upload holds its row
+        let mut tx = state.pool.begin().await?;
+        sqlx::query("SELECT 1 FROM tus_uploads WHERE id = $1 FOR UPDATE")
+            .bind(&upload.id)
+            .execute(&mut *tx)
+            .await?;
+        let ret = state
+            .append_tus_upload(&upload.id, &user, 0, chunk("hello"))
+            .await;
+        assert!(matches!(ret, Err(AppError::UploadConflict(_))));
+        tx.rollback().await?;
+
+        // the quota shrank after the upload was created
+        sqlx::query("UPDATE workspaces SET storage_quota = 8 WHERE id = 1")
+            .execute(&state.pool)
+            .await?;
+        let upload = state
+            .append_tus_upload(&upload.id, &user, 0, chunk("hello"))
+            .await?;
+        assert_eq!(upload.upload_offset, 5);
+        let ret = state
+            .append_tus_upload(&upload.id, &user, 5, chunk(" world"))
+            .await;
+        assert!(matches!(ret, Err(AppError::QuotaExceeded(_))));
+        let upload = state.get_tus_upload(&upload.id, &user).await?;
+        assert_eq!(upload.upload_offset, 5);
+        Ok(())
+    }
+}
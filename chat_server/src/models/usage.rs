@@ -0,0 +1,253 @@
+use std::{collections::HashMap, str::FromStr, time::Duration};
+
+use chat_core::{Thumbnail, User};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json, FromRow};
+
+use crate::{error::AppError, AppState};
+
+use super::ChatFile;
+
+/// Storage report of a workspace, identical content is only counted once
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct StorageUsage {
+    pub ws_id: i64,
+    // None is unlimited
+    pub quota: Option<i64>,
+    pub used: i64,
+    pub files: i64,
+    // bytes of uploads never posted in a chat, removed once their grace period is over
+    pub orphaned: i64,
+    pub uploaders: Vec<UploaderUsage>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct UploaderUsage {
+    pub user_id: i64,
+    pub fullname: String,
+    pub files: i64,
+    pub bytes: i64,
+}
+
+impl AppState {
+    pub async fn storage_usage(&self, user: &User) -> Result<StorageUsage, AppError> {
+        self.ensure_workspace_owner(user).await?;
+
+        let (files, used, orphaned): (i64, i64, i64) = sqlx::query_as(
+            r#"
+            SELECT COUNT(*),
+              COALESCE(SUM(size), 0)::bigint,
+              COALESCE(SUM(size) FILTER (
+                WHERE NOT EXISTS (SELECT 1 FROM message_files mf WHERE mf.url = f.url)
+              ), 0)::bigint
+            FROM (SELECT DISTINCT ON (url) url, size FROM files WHERE ws_id = $1) f
+            "#,
+        )
+        .bind(user.ws_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        let uploaders = sqlx::query_as(
+            r#"
+            SELECT u.id AS user_id, u.fullname, COUNT(*) AS files, SUM(f.size)::bigint AS bytes
+            FROM files f
+            JOIN users u ON u.id = f.uploader_id
+            WHERE f.ws_id = $1
+            GROUP BY u.id, u.fullname
+            ORDER BY bytes DESC, u.id
+            "#,
+        )
+        .bind(user.ws_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(StorageUsage {
+            ws_id: user.ws_id,
+            quota: self.storage_quota(user.ws_id).await?,
+            used,
+            files,
+            orphaned,
+            uploaders,
+        })
+    }
+
+    /// Bytes the workspace may still store, None if it has no quota.
+    /// Checked before the upload lands so concurrent uploads can overshoot a little.
+    pub(crate) async fn storage_remaining(&self, ws_id: i64) -> Result<Option<u64>, AppError> {
+        let Some(quota) = self.storage_quota(ws_id).await? else {
+            return Ok(None);
+        };
+        let (used,): (i64,) = sqlx::query_as(
+            r#"
+            SELECT COALESCE(SUM(size), 0)::bigint
+            FROM (SELECT DISTINCT ON (url) size FROM files WHERE ws_id = $1) f
+            "#,
+        )
+        .bind(ws_id)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(Some(quota.saturating_sub(used).max(0) as u64))
+    }
+
+    /// Remove uploads that were never posted once the grace period is over,
+    /// returning how many files were deleted from the store
+    pub async fn collect_orphaned_files(&self) -> Result<usize, AppError> {
+        let removed: Vec<(String, Json<Vec<Thumbnail>>)> = sqlx::query_as(
+            r#"
+            DELETE FROM files f
+            WHERE f.created_at < CURRENT_TIMESTAMP - make_interval(secs => $1)
+              AND NOT EXISTS (SELECT 1 FROM message_files mf WHERE mf.url = f.url)
+            RETURNING url, thumbnails
+            "#,
+        )
+        .bind(self.config.upload.orphan_grace_secs as f64)
+        .fetch_all(&self.pool)
+        .await?;
+        let removed: HashMap<_, _> = removed.into_iter().collect();
+
+        let mut deleted = 0;
+        for (url, Json(thumbnails)) in removed {
+            // another upload of the same content may still be in its grace period, or be
+            // storing it right now, it's seen once it holds the lock
+            let mut lock = self.lock_file_url(&url).await?;
+            let (in_use,): (bool,) = sqlx::query_as(
+                r#"
+                SELECT EXISTS (SELECT 1 FROM files WHERE url = $1)
+                  OR EXISTS (SELECT 1 FROM message_files WHERE url = $1)
+                "#,
+            )
+            .bind(&url)
+            .fetch_one(&mut *lock)
+            .await?;
+            if in_use {
+                continue;
+            }
+
+            let keys = std::iter::once(url.as_str())
+                .chain(thumbnails.iter().map(|t| t.url.as_str()))
+                .filter_map(|url| ChatFile::from_str(url).ok())
+                .map(|file| file.key());
+            for key in keys {
+                if let Err(e) = self.store.delete(&key).await {
+                    tracing::warn!("Delete orphaned file {} failed: {}", key, e);
+                }
+            }
+            lock.commit().await?;
+            deleted += 1;
+        }
+        Ok(deleted)
+    }
+
+    /// Collect orphaned files until the process exits
+    pub async fn run_file_gc(self) {
+        loop {
+            match self.collect_orphaned_files().await {
+                Ok(0) => {}
+                Ok(n) => tracing::info!("Removed {} orphaned files", n),
+                Err(e) => tracing::warn!("Collect orphaned files failed: {}", e),
+            }
+            tokio::time::sleep(Duration::from_secs(60 * 60)).await;
+        }
+    }
+
+    async fn storage_quota(&self, ws_id: i64) -> Result<Option<i64>, AppError> {
+        let quota: Option<(Option<i64>,)> =
+            sqlx::query_as("SELECT storage_quota FROM workspaces WHERE id = $1")
+                .bind(ws_id)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(match quota.and_then(|(quota,)| quota) {
+            Some(quota) => Some(quota),
+            None if self.config.upload.workspace_quota > 0 => {
+                Some(self.config.upload.workspace_quota as i64)
+            }
+            None => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AppConfig, CreateMessage, FileMeta};
+    use anyhow::Result;
+
+    async fn upload(
+        state: &AppState,
+        user: &User,
+        name: &str,
+        data: &'static str,
+    ) -> Result<FileMeta, AppError> {
+        let body = futures::stream::iter([Ok(data.into())]);
+        state
+            .save_upload(user, name, body, &mut 1024, &Default::default())
+            .await
+    }
+
+    #[tokio::test]
+    async fn quota_should_be_enforced_and_reported() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+        let other = state.find_user_by_id(2).await?.unwrap();
+        sqlx::query("UPDATE workspaces SET storage_quota = 16 WHERE id = 1")
+            .execute(&state.pool)
+            .await?;
+
+        let meta = upload(&state, &user, "a.txt", "hello world").await?;
+        // the same content uploaded again doesn't take more room
+        upload(&state, &other, "b.txt", "hello").await?;
+        upload(&state, &other, "c.txt", "hello world").await?;
+        let ret = upload(&state, &user, "d.txt", "too much").await;
+        assert!(matches!(ret, Err(AppError::QuotaExceeded(_))));
+        let ret = state.create_tus_upload(&user, "e.txt", 1).await;
+        assert!(matches!(ret, Err(AppError::QuotaExceeded(_))));
+
+        let ret = state.storage_usage(&user).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        state.update_workspace_owner(1, 1).await?;
+
+        let input = CreateMessage {
+            content: "".to_string(),
+            files: vec![meta.url],
+        };
+        state.create_message(input, 1, 1).await?;
+        let usage = state.storage_usage(&user).await?;
+        assert_eq!(usage.quota, Some(16));
+        assert_eq!((usage.files, usage.used, usage.orphaned), (2, 16, 5));
+        let uploaders: Vec<_> = usage
+            .uploaders
+            .iter()
+            .map(|u| (u.user_id, u.files, u.bytes))
+            .collect();
+        assert_eq!(uploaders, [(2, 2, 16), (1, 1, 11)]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn gc_should_remove_orphaned_files() -> Result<()> {
+        let mut config = AppConfig::load()?;
+        config.upload.orphan_grace_secs = 0;
+        let (_tdb, state) = AppState::new_for_test_with_config(config).await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+
+        let posted = upload(&state, &user, "a.txt", "posted").await?;
+        let orphan = upload(&state, &user, "b.txt", "orphan").await?;
+        let input = CreateMessage {
+            content: "".to_string(),
+            files: vec![posted.url.clone()],
+        };
+        state.create_message(input, 1, 1).await?;
+
+        assert_eq!(state.collect_orphaned_files().await?, 1);
+        let exists = |url: &str| {
+            let key = ChatFile::from_str(url).unwrap().key();
+            let store = state.store.clone();
+            async move { store.exists(&key).await }
+        };
+        assert!(exists(&posted.url).await?);
+        assert!(!exists(&orphan.url).await?);
+        assert_eq!(state.get_file_meta(orphan.id as _, 1).await?, None);
+        assert_eq!(state.collect_orphaned_files().await?, 0);
+        Ok(())
+    }
+}
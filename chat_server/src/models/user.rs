@@ -7,7 +7,7 @@ use argon2::{
 use chat_core::{ChatUser, User};
 use serde::{Deserialize, Serialize};
 
-use crate::{error::AppError, AppState};
+use crate::{error::AppError, oidc::OidcIdentity, AppState};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreateUser {
@@ -27,7 +27,7 @@ impl AppState {
     // Find a user by email
     pub async fn find_user_by_email(&self, email: &str) -> Result<Option<User>, AppError> {
         let user = sqlx::query_as(
-            "SELECT id, ws_id, fullname, email, created_at FROM users WHERE email = $1",
+            "SELECT id, ws_id, fullname, email, is_bot, created_at FROM users WHERE email = $1",
         )
         .bind(email)
         .fetch_optional(&self.pool)
@@ -36,10 +36,34 @@ impl AppState {
         Ok(user)
     }
 
+    // Resolve a mention like `@alice` or `@alice@acme.org` to a human of the workspace
+    pub async fn find_user_by_handle(
+        &self,
+        ws_id: i64,
+        handle: &str,
+    ) -> Result<Option<User>, AppError> {
+        let handle = handle.trim_start_matches('@').to_lowercase();
+        let user = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, fullname, email, is_bot, created_at
+            FROM users
+            WHERE ws_id = $1 AND NOT is_bot
+              AND (lower(email) = $2 OR lower(split_part(email, '@', 1)) = $2)
+            ORDER BY id
+            LIMIT 1
+            "#,
+        )
+        .bind(ws_id)
+        .bind(handle)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(user)
+    }
+
     // Find a user by id
     pub async fn find_user_by_id(&self, id: i64) -> Result<Option<User>, AppError> {
         let user = sqlx::query_as(
-            "SELECT id, ws_id, fullname, email, created_at FROM users WHERE id = $1",
+            "SELECT id, ws_id, fullname, email, is_bot, created_at FROM users WHERE id = $1",
         )
         .bind(id)
         .fetch_optional(&self.pool)
@@ -84,10 +108,75 @@ impl AppState {
         Ok(user)
     }
 
+    /// Find the user behind a verified sso identity, or provision it into the
+    /// workspace tied to the email domain
+    pub async fn find_or_provision_sso_user(
+        &self,
+        identity: &OidcIdentity,
+    ) -> Result<User, AppError> {
+        // providers hand out lowercased emails, accounts signed up with mixed case still match
+        let user: Option<User> = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, fullname, email, is_bot, created_at FROM users
+            WHERE lower(email) = lower($1)
+            ORDER BY email = $1 DESC, id
+            LIMIT 1
+            "#,
+        )
+        .bind(&identity.email)
+        .fetch_optional(&self.pool)
+        .await?;
+        if let Some(user) = user {
+            return Ok(user);
+        }
+
+        let domain = identity.email.rsplit_once('@').map(|(_, d)| d);
+        let workspace = match domain {
+            Some(domain) => self.find_workspace_by_domain(domain).await?,
+            None => None,
+        };
+        let Some(workspace) = workspace else {
+            return Err(AppError::OidcError(format!(
+                "no workspace is tied to the domain of {}",
+                identity.email
+            )));
+        };
+
+        let fullname = identity.name.clone().unwrap_or_else(|| {
+            identity
+                .email
+                .split('@')
+                .next()
+                .unwrap_or_default()
+                .to_string()
+        });
+
+        // sso users have no local password, the empty hash never verifies
+        let user: User = sqlx::query_as(
+            r#"
+            INSERT INTO users (ws_id, fullname, email, password_hash)
+            VALUES ($1, $2, $3, '')
+            RETURNING id, ws_id, fullname, email, created_at
+            "#,
+        )
+        .bind(workspace.id)
+        .bind(&fullname)
+        .bind(&identity.email)
+        .fetch_one(&self.pool)
+        .await?;
+
+        if workspace.owner_id == 0 {
+            self.update_workspace_owner(workspace.id as _, user.id as u64)
+                .await?;
+        }
+
+        Ok(user)
+    }
+
     /// Verify email and password
     pub async fn verify_user(&self, input: &SigninUser) -> Result<Option<User>, AppError> {
         let user: Option<User> = sqlx::query_as(
-            "SELECT id, ws_id, fullname, email, password_hash, created_at FROM users WHERE email = $1",
+            "SELECT id, ws_id, fullname, email, password_hash, is_bot, created_at FROM users WHERE email = $1",
         )
         .bind(&input.email)
         .fetch_optional(&self.pool)
@@ -95,9 +184,11 @@ impl AppState {
 
         match user {
             Some(mut user) => {
-                let password_hash = mem::take(&mut user.password_hash);
-                let is_valid =
-                    verify_password(&input.password, &password_hash.unwrap_or_default())?;
+                let password_hash = mem::take(&mut user.password_hash).unwrap_or_default();
+                if password_hash.is_empty() {
+                    return Ok(None);
+                }
+                let is_valid = verify_password(&input.password, &password_hash)?;
                 if is_valid {
                     Ok(Some(user))
                 } else {
@@ -118,11 +209,19 @@ impl AppState {
     }
 
     pub async fn fetch_chat_users(&self, ws_id: u64) -> Result<Vec<ChatUser>, AppError> {
-        let users =
-            sqlx::query_as("SELECT id, fullname, email FROM users WHERE ws_id = $1 ORDER BY id")
-                .bind(ws_id as i64)
-                .fetch_all(&self.pool)
-                .await?;
+        let users = sqlx::query_as(
+            r#"
+            SELECT u.id, u.fullname, u.email, p.presence, p.status_text, p.status_emoji,
+              p.status_expires_at, p.dnd
+            FROM users u
+            JOIN user_presence p ON p.user_id = u.id
+            WHERE u.ws_id = $1
+            ORDER BY u.id
+            "#,
+        )
+        .bind(ws_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
 
         Ok(users)
     }
@@ -230,6 +329,23 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn sso_user_should_match_mixed_case_email() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        sqlx::query("UPDATE users SET email = 'Alice@acme.org' WHERE id = 2")
+            .execute(&state.pool)
+            .await?;
+
+        let identity = OidcIdentity {
+            email: "alice@acme.org".to_string(),
+            name: None,
+        };
+        let user = state.find_or_provision_sso_user(&identity).await?;
+        assert_eq!(user.id, 2);
+        assert_eq!(user.email, "Alice@acme.org");
+        Ok(())
+    }
+
     #[tokio::test]
     async fn find_user_by_id_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
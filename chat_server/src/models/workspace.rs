@@ -25,6 +25,20 @@ impl AppState {
         Ok(workspace)
     }
 
+    pub async fn find_workspace_by_domain(
+        &self,
+        domain: &str,
+    ) -> Result<Option<Workspace>, AppError> {
+        let workspace = sqlx::query_as(
+            r#"SELECT id, name, owner_id, created_at FROM workspaces WHERE domain = $1"#,
+        )
+        .bind(domain)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(workspace)
+    }
+
     // Create a new workspace
     pub async fn create_workspace(&self, name: &str, user_id: u64) -> Result<Workspace, AppError> {
         let workspace = self.find_workspace_by_name(name).await?;
@@ -94,6 +108,16 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn workspace_should_find_by_domain() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ws = state.find_workspace_by_domain("acme.org").await?;
+        assert_eq!(ws.unwrap().name, "acme");
+        let ws = state.find_workspace_by_domain("example.com").await?;
+        assert!(ws.is_none());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn workspace_should_fetch_all_chat_users() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
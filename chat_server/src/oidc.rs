@@ -0,0 +1,446 @@
+use std::{collections::HashMap, time::Instant};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use dashmap::DashMap;
+use jwt_simple::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::{OnceCell, RwLock};
+
+use crate::{config::OidcConfig, error::AppError, utils::random_string};
+
+// how long a started sign-in may wait for its callback
+const PENDING_AUTH_TTL: std::time::Duration = std::time::Duration::from_secs(600);
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct IdTokenClaims {
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: bool,
+    name: Option<String>,
+}
+
+/// The identity asserted by the provider once the id_token has been verified
+#[derive(Debug, Clone, PartialEq)]
+pub struct OidcIdentity {
+    pub email: String,
+    pub name: Option<String>,
+}
+
+struct PendingAuth {
+    verifier: String,
+    nonce: String,
+    created_at: Instant,
+}
+
+/// OpenID Connect relying party using the authorization code flow with PKCE
+pub struct OidcClient {
+    config: OidcConfig,
+    http: reqwest::Client,
+    provider: OnceCell<ProviderMetadata>,
+    keys: RwLock<HashMap<String, RS256PublicKey>>,
+    pending: DashMap<String, PendingAuth>,
+}
+
+impl OidcClient {
+    pub fn new(config: OidcConfig) -> Self {
+        Self {
+            config,
+            http: reqwest::Client::new(),
+            provider: OnceCell::new(),
+            keys: RwLock::new(HashMap::new()),
+            pending: DashMap::new(),
+        }
+    }
+
+    /// Build the provider authorization url for a new sign-in.
+    /// The PKCE verifier and nonce are kept until the callback comes back with the same state.
+    pub async fn authorize_url(&self) -> Result<String, AppError> {
+        self.pending
+            .retain(|_, auth| auth.created_at.elapsed() < PENDING_AUTH_TTL);
+
+        let provider = self.provider().await?;
+        let state = random_string(32);
+        let nonce = random_string(32);
+        let verifier = random_string(64);
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+        let scope = self.config.scopes.join(" ");
+
+        let url = reqwest::Url::parse_with_params(
+            &provider.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", &self.config.client_id),
+                ("redirect_uri", &self.config.redirect_url),
+                ("scope", &scope),
+                ("state", &state),
+                ("nonce", &nonce),
+                ("code_challenge", &challenge),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .map_err(|e| AppError::OidcError(format!("invalid authorization endpoint: {}", e)))?;
+
+        self.pending.insert(
+            state,
+            PendingAuth {
+                verifier,
+                nonce,
+                created_at: Instant::now(),
+            },
+        );
+
+        Ok(url.into())
+    }
+
+    /// Exchange the authorization code and verify the returned id_token
+    pub async fn exchange(&self, code: &str, state: &str) -> Result<OidcIdentity, AppError> {
+        let Some((_, auth)) = self.pending.remove(state) else {
+            return Err(AppError::OidcError("unknown sign-in state".to_string()));
+        };
+        if auth.created_at.elapsed() >= PENDING_AUTH_TTL {
+            return Err(AppError::OidcError("sign-in state expired".to_string()));
+        }
+
+        let provider = self.provider().await?;
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.config.redirect_url),
+            ("client_id", &self.config.client_id),
+            ("code_verifier", &auth.verifier),
+        ];
+        if let Some(secret) = &self.config.client_secret {
+            form.push(("client_secret", secret));
+        }
+
+        let res = self
+            .http
+            .post(&provider.token_endpoint)
+            .form(&form)
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            let status = res.status();
+            let body = res.text().await.unwrap_or_default();
+            return Err(AppError::OidcError(format!(
+                "token endpoint returned {}: {}",
+                status, body
+            )));
+        }
+        let token: TokenResponse = res.json().await?;
+
+        let claims = self.verify_id_token(&token.id_token, &auth.nonce).await?;
+        match (claims.email, claims.email_verified) {
+            (Some(email), true) => Ok(OidcIdentity {
+                email: email.to_lowercase(),
+                name: claims.name,
+            }),
+            (Some(email), false) => Err(AppError::OidcError(format!(
+                "email {} is not verified by the identity provider",
+                email
+            ))),
+            (None, _) => Err(AppError::OidcError(
+                "id_token does not contain an email claim".to_string(),
+            )),
+        }
+    }
+
+    async fn provider(&self) -> Result<&ProviderMetadata, AppError> {
+        self.provider
+            .get_or_try_init(|| async {
+                let url = format!(
+                    "{}/.well-known/openid-configuration",
+                    self.config.issuer.trim_end_matches('/')
+                );
+                let metadata: ProviderMetadata = self
+                    .http
+                    .get(url)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+
+                if metadata.issuer != self.config.issuer {
+                    return Err(AppError::OidcError(format!(
+                        "issuer mismatch: expected {}, got {}",
+                        self.config.issuer, metadata.issuer
+                    )));
+                }
+                Ok(metadata)
+            })
+            .await
+    }
+
+    async fn verify_id_token(&self, token: &str, nonce: &str) -> Result<IdTokenClaims, AppError> {
+        let metadata = Token::decode_metadata(token)?;
+        if metadata.algorithm() != "RS256" {
+            return Err(AppError::OidcError(format!(
+                "unsupported id_token algorithm: {}",
+                metadata.algorithm()
+            )));
+        }
+        let kid = metadata.key_id().unwrap_or_default().to_string();
+
+        // the provider may have rotated its keys since we last fetched them
+        if !self.keys.read().await.contains_key(&kid) {
+            self.refresh_keys().await?;
+        }
+
+        let keys = self.keys.read().await;
+        let Some(key) = keys.get(&kid) else {
+            return Err(AppError::OidcError(format!("unknown signing key: {}", kid)));
+        };
+
+        let opts = VerificationOptions {
+            allowed_issuers: Some(HashSet::from_strings(&[&self.config.issuer])),
+            allowed_audiences: Some(HashSet::from_strings(&[&self.config.client_id])),
+            required_nonce: Some(nonce.to_string()),
+            ..Default::default()
+        };
+        let claims = key.verify_token::<IdTokenClaims>(token, Some(opts))?;
+        Ok(claims.custom)
+    }
+
+    async fn refresh_keys(&self) -> Result<(), AppError> {
+        let provider = self.provider().await?;
+        let jwks: JwkSet = self
+            .http
+            .get(&provider.jwks_uri)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let mut keys = HashMap::new();
+        for jwk in jwks.keys {
+            let (Some(n), Some(e)) = (jwk.n, jwk.e) else {
+                continue;
+            };
+            if jwk.kty != "RSA" {
+                continue;
+            }
+            let (Ok(n), Ok(e)) = (URL_SAFE_NO_PAD.decode(n), URL_SAFE_NO_PAD.decode(e)) else {
+                tracing::warn!("Skip malformed jwk {:?}", jwk.kid);
+                continue;
+            };
+            let kid = jwk.kid.unwrap_or_default();
+            let mut key = RS256PublicKey::from_components(&n, &e)?;
+            if !kid.is_empty() {
+                key = key.with_key_id(&kid);
+            }
+            keys.insert(kid, key);
+        }
+
+        *self.keys.write().await = keys;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use axum::{
+        extract::State,
+        routing::{get, post},
+        Form, Json, Router,
+    };
+    use serde_json::{json, Value};
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+
+    use crate::{config::OidcConfig, AppConfig, AppState};
+
+    const CLIENT_ID: &str = "chat";
+
+    #[derive(Clone)]
+    struct MockIdp {
+        issuer: String,
+        key: Arc<RS256KeyPair>,
+        // code_challenge and nonce seen at the authorization endpoint
+        challenge: Arc<Mutex<Option<(String, String)>>>,
+        email: String,
+        email_verified: bool,
+    }
+
+    async fn discovery(State(idp): State<MockIdp>) -> Json<Value> {
+        Json(json!({
+            "issuer": idp.issuer,
+            "authorization_endpoint": format!("{}/authorize", idp.issuer),
+            "token_endpoint": format!("{}/token", idp.issuer),
+            "jwks_uri": format!("{}/jwks", idp.issuer),
+        }))
+    }
+
+    async fn jwks(State(idp): State<MockIdp>) -> Json<Value> {
+        let components = idp.key.public_key().to_components();
+        Json(json!({
+            "keys": [{
+                "kty": "RSA",
+                "kid": "test-key",
+                "alg": "RS256",
+                "use": "sig",
+                "n": URL_SAFE_NO_PAD.encode(components.n),
+                "e": URL_SAFE_NO_PAD.encode(components.e),
+            }]
+        }))
+    }
+
+    async fn token(
+        State(idp): State<MockIdp>,
+        Form(form): Form<HashMap<String, String>>,
+    ) -> axum::response::Response {
+        use axum::{http::StatusCode, response::IntoResponse};
+
+        let (challenge, nonce) = idp.challenge.lock().unwrap().clone().unwrap();
+        let verifier = form.get("code_verifier").cloned().unwrap_or_default();
+        let expected = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+        if form.get("code").map(String::as_str) != Some("test-code") || expected != challenge {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "invalid_grant"})),
+            )
+                .into_response();
+        }
+
+        let claims = Claims::with_custom_claims(
+            IdTokenClaims {
+                email: Some(idp.email.clone()),
+                email_verified: idp.email_verified,
+                name: Some("Sso User".to_string()),
+            },
+            Duration::from_mins(5),
+        )
+        .with_issuer(&idp.issuer)
+        .with_audience(CLIENT_ID)
+        .with_subject("sso-user")
+        .with_nonce(nonce);
+        let id_token = idp.key.sign(claims).unwrap();
+        Json(json!({"access_token": "at", "token_type": "Bearer", "id_token": id_token}))
+            .into_response()
+    }
+
+    async fn start_idp(email: &str, email_verified: bool) -> Result<MockIdp> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let issuer = format!("http://{}", listener.local_addr()?);
+        let key =
+            RS256KeyPair::from_pem(include_str!("../fixtures/idp.pem"))?.with_key_id("test-key");
+        let idp = MockIdp {
+            issuer,
+            key: Arc::new(key),
+            challenge: Arc::new(Mutex::new(None)),
+            email: email.to_string(),
+            email_verified,
+        };
+
+        let app = Router::new()
+            .route("/.well-known/openid-configuration", get(discovery))
+            .route("/jwks", get(jwks))
+            .route("/token", post(token))
+            .with_state(idp.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        Ok(idp)
+    }
+
+    async fn state_for(idp: &MockIdp) -> Result<(sqlx_db_tester::TestPg, AppState)> {
+        let mut config = AppConfig::load()?;
+        config.oidc = Some(OidcConfig {
+            issuer: idp.issuer.clone(),
+            client_id: CLIENT_ID.to_string(),
+            client_secret: None,
+            redirect_url: "http://localhost:6688/api/oidc/callback".to_string(),
+            scopes: vec!["openid".into(), "email".into()],
+        });
+        Ok(AppState::new_for_test_with_config(config).await?)
+    }
+
+    // simulate the user approving the sign-in at the provider
+    fn approve(idp: &MockIdp, url: &str) -> String {
+        let url = reqwest::Url::parse(url).unwrap();
+        let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+        assert_eq!(params["code_challenge_method"], "S256");
+        assert_eq!(params["client_id"], CLIENT_ID);
+        *idp.challenge.lock().unwrap() =
+            Some((params["code_challenge"].clone(), params["nonce"].clone()));
+        params["state"].clone()
+    }
+
+    #[tokio::test]
+    async fn oidc_signin_should_provision_user_into_domain_workspace() -> Result<()> {
+        let idp = start_idp("New.Hire@acme.org", true).await?;
+        let (_tdb, state) = state_for(&idp).await?;
+        let oidc = state.oidc.as_ref().expect("oidc should be configured");
+
+        let url = oidc.authorize_url().await?;
+        assert!(url.starts_with(&format!("{}/authorize?", idp.issuer)));
+        let sign_in_state = approve(&idp, &url);
+
+        let identity = oidc.exchange("test-code", &sign_in_state).await?;
+        assert_eq!(identity.email, "new.hire@acme.org");
+
+        let user = state.find_or_provision_sso_user(&identity).await?;
+        assert_eq!(user.ws_id, 1);
+        assert_eq!(user.fullname, "Sso User");
+
+        // the same state can't be replayed
+        let ret = oidc.exchange("test-code", &sign_in_state).await;
+        assert!(matches!(ret, Err(AppError::OidcError(_))));
+
+        // signing in again maps to the same user
+        let user2 = state.find_or_provision_sso_user(&identity).await?;
+        assert_eq!(user.id, user2.id);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn oidc_signin_should_reject_bad_verifier_and_unverified_email() -> Result<()> {
+        let idp = start_idp("someone@acme.org", false).await?;
+        let (_tdb, state) = state_for(&idp).await?;
+        let oidc = state.oidc.as_ref().unwrap();
+
+        let url = oidc.authorize_url().await?;
+        let sign_in_state = approve(&idp, &url);
+        let ret = oidc.exchange("test-code", &sign_in_state).await;
+        match ret {
+            Err(AppError::OidcError(msg)) => assert!(msg.contains("not verified")),
+            _ => panic!("expect unverified email error"),
+        }
+
+        // a challenge that doesn't match the verifier is refused by the provider
+        let url = oidc.authorize_url().await?;
+        let sign_in_state = approve(&idp, &url);
+        idp.challenge.lock().unwrap().as_mut().unwrap().0 = "tampered".to_string();
+        let ret = oidc.exchange("test-code", &sign_in_state).await;
+        assert!(matches!(ret, Err(AppError::OidcError(_))));
+        Ok(())
+    }
+}
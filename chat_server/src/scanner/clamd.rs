@@ -0,0 +1,228 @@
+use std::{io, time::Duration};
+
+use futures::StreamExt;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{config::ScannerConfig, storage::ByteStream};
+
+use super::{FileScanner, ScanFuture, ScanOutcome};
+
+// clamd refuses a single chunk over StreamMaxLength anyway, keep them small
+const MAX_CHUNK: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+enum ClamdAddr {
+    Tcp(String),
+    Unix(String),
+}
+
+/// Speaks the clamd INSTREAM protocol over a TCP or Unix socket
+#[derive(Debug, Clone)]
+pub struct ClamdScanner {
+    addr: ClamdAddr,
+    timeout: Duration,
+}
+
+impl ClamdScanner {
+    pub fn new(config: &ScannerConfig) -> Self {
+        Self {
+            addr: ClamdAddr::parse(&config.clamd),
+            timeout: Duration::from_secs(config.timeout_secs),
+        }
+    }
+
+    async fn instream(&self, body: ByteStream) -> io::Result<String> {
+        match &self.addr {
+            ClamdAddr::Tcp(addr) => instream(TcpStream::connect(addr).await?, body).await,
+            #[cfg(unix)]
+            ClamdAddr::Unix(path) => {
+                instream(tokio::net::UnixStream::connect(path).await?, body).await
+            }
+            #[cfg(not(unix))]
+            ClamdAddr::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unix sockets are not supported on this platform",
+            )),
+        }
+    }
+}
+
+impl FileScanner for ClamdScanner {
+    fn scan<'a>(&'a self, body: ByteStream) -> ScanFuture<'a> {
+        Box::pin(async move {
+            match tokio::time::timeout(self.timeout, self.instream(body)).await {
+                Ok(Ok(reply)) => parse_reply(&reply),
+                Ok(Err(e)) => ScanOutcome::Error(format!("clamd: {e}")),
+                Err(_) => ScanOutcome::Error("clamd: scan timed out".to_string()),
+            }
+        })
+    }
+}
+
+impl ClamdAddr {
+    fn parse(s: &str) -> Self {
+        if let Some(addr) = s.strip_prefix("tcp://") {
+            Self::Tcp(addr.to_string())
+        } else if let Some(path) = s.strip_prefix("unix://") {
+            Self::Unix(path.to_string())
+        } else if s.starts_with('/') {
+            Self::Unix(s.to_string())
+        } else {
+            Self::Tcp(s.to_string())
+        }
+    }
+}
+
+async fn instream<S>(mut conn: S, mut body: ByteStream) -> io::Result<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    conn.write_all(b"zINSTREAM\0").await?;
+    while let Some(data) = body.next().await {
+        let data = data?;
+        // a zero length chunk ends the stream
+        for chunk in data.chunks(MAX_CHUNK).filter(|c| !c.is_empty()) {
+            conn.write_all(&(chunk.len() as u32).to_be_bytes()).await?;
+            conn.write_all(chunk).await?;
+        }
+    }
+    conn.write_all(&0u32.to_be_bytes()).await?;
+    conn.flush().await?;
+
+    let mut reply = vec![];
+    conn.read_to_end(&mut reply).await?;
+    let end = reply.iter().position(|&b| b == 0).unwrap_or(reply.len());
+    Ok(String::from_utf8_lossy(&reply[..end]).trim().to_string())
+}
+
+fn parse_reply(reply: &str) -> ScanOutcome {
+    let result = reply.strip_prefix("stream: ").unwrap_or(reply);
+    if result == "OK" {
+        ScanOutcome::Clean
+    } else if let Some(signature) = result.strip_suffix(" FOUND") {
+        ScanOutcome::Infected(signature.to_string())
+    } else {
+        ScanOutcome::Error(format!("clamd: {reply}"))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{storage::bytes_stream, utils::random_string};
+    use anyhow::Result;
+    use bytes::Bytes;
+    use tokio::net::TcpListener;
+
+    /// Answers like clamd, anything containing `EICAR` is infected
+    pub(crate) async fn start_fake_clamd() -> Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            while let Ok((conn, _)) = listener.accept().await {
+                tokio::spawn(serve_clamd(conn));
+            }
+        });
+        Ok(format!("tcp://{addr}"))
+    }
+
+    async fn serve_clamd<S>(mut conn: S) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut command = [0u8; 10];
+        conn.read_exact(&mut command).await?;
+        assert_eq!(&command, b"zINSTREAM\0");
+        let mut data = vec![];
+        loop {
+            let len = conn.read_u32().await? as usize;
+            if len == 0 {
+                break;
+            }
+            let start = data.len();
+            data.resize(start + len, 0);
+            conn.read_exact(&mut data[start..]).await?;
+        }
+        let reply: &[u8] = if data.windows(5).any(|w| w == b"EICAR") {
+            b"stream: Eicar-Test-Signature FOUND\0"
+        } else {
+            b"stream: OK\0"
+        };
+        conn.write_all(reply).await?;
+        conn.shutdown().await
+    }
+
+    fn scanner(clamd: String) -> ClamdScanner {
+        ClamdScanner::new(&ScannerConfig {
+            clamd,
+            timeout_secs: 5,
+        })
+    }
+
+    #[tokio::test]
+    async fn clamd_scanner_should_work_over_tcp() -> Result<()> {
+        let scanner = scanner(start_fake_clamd().await?);
+        let clean = bytes_stream(Bytes::from(vec![b'a'; MAX_CHUNK * 2 + 1]));
+        assert_eq!(scanner.scan(clean).await, ScanOutcome::Clean);
+        let infected = bytes_stream(Bytes::from_static(b"X5O!P%@AP EICAR-STANDARD"));
+        assert_eq!(
+            scanner.scan(infected).await,
+            ScanOutcome::Infected("Eicar-Test-Signature".to_string())
+        );
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn clamd_scanner_should_work_over_unix_socket() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("clamd-{}.ctl", random_string(8)));
+        let listener = tokio::net::UnixListener::bind(&path)?;
+        tokio::spawn(async move {
+            while let Ok((conn, _)) = listener.accept().await {
+                tokio::spawn(serve_clamd(conn));
+            }
+        });
+
+        let scanner = scanner(format!("unix://{}", path.display()));
+        let body = bytes_stream(Bytes::from_static(b"EICAR"));
+        assert!(matches!(scanner.scan(body).await, ScanOutcome::Infected(_)));
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn clamd_scanner_should_report_errors() -> Result<()> {
+        // nothing listens there
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        drop(listener);
+        let scanner = scanner(addr.to_string());
+        let body = bytes_stream(Bytes::from_static(b"hello"));
+        assert!(matches!(scanner.scan(body).await, ScanOutcome::Error(_)));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_reply_should_work() {
+        assert_eq!(parse_reply("stream: OK"), ScanOutcome::Clean);
+        assert_eq!(
+            parse_reply("stream: Win.Test.EICAR_HDB-1 FOUND"),
+            ScanOutcome::Infected("Win.Test.EICAR_HDB-1".to_string())
+        );
+        assert!(matches!(
+            parse_reply("INSTREAM size limit exceeded. ERROR"),
+            ScanOutcome::Error(_)
+        ));
+        assert_eq!(
+            ClamdAddr::parse("/run/clamav/clamd.ctl"),
+            ClamdAddr::Unix("/run/clamav/clamd.ctl".to_string())
+        );
+        assert_eq!(
+            ClamdAddr::parse("localhost:3310"),
+            ClamdAddr::Tcp("localhost:3310".to_string())
+        );
+    }
+}
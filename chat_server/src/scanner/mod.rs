@@ -0,0 +1,33 @@
+mod clamd;
+
+use std::{future::Future, pin::Pin, sync::Arc};
+
+use crate::{storage::ByteStream, AppConfig};
+
+pub use clamd::ClamdScanner;
+
+#[cfg(test)]
+pub(crate) use clamd::tests::start_fake_clamd;
+
+pub type ScanFuture<'a> = Pin<Box<dyn Future<Output = ScanOutcome> + Send + 'a>>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScanOutcome {
+    Clean,
+    // name of the signature that matched
+    Infected(String),
+    // the scan couldn't finish, the file stays quarantined
+    Error(String),
+}
+
+/// Checks uploaded content before anyone else may download it
+pub trait FileScanner: Send + Sync {
+    fn scan<'a>(&'a self, body: ByteStream) -> ScanFuture<'a>;
+}
+
+pub(crate) fn build_scanner(config: &AppConfig) -> Option<Arc<dyn FileScanner>> {
+    config
+        .scanner
+        .as_ref()
+        .map(|scanner| Arc::new(ClamdScanner::new(scanner)) as Arc<dyn FileScanner>)
+}
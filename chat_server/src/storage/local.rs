@@ -0,0 +1,198 @@
+use std::{
+    io,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use futures::TryStreamExt;
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom},
+};
+use tokio_util::io::ReaderStream;
+
+use crate::{error::AppError, utils::random_string};
+
+use super::{validate_key, ByteStream, FileStore, ObjectMeta, StoreFuture, StoredObject};
+
+/// Files on the local disk under `server.base_dir`
+#[derive(Debug, Clone)]
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf, AppError> {
+        validate_key(key)?;
+        Ok(self.root.join(key))
+    }
+}
+
+impl FileStore for LocalStore {
+    fn put<'a>(
+        &'a self,
+        key: &'a str,
+        mut body: ByteStream,
+        len: u64,
+    ) -> StoreFuture<'a, ObjectMeta> {
+        Box::pin(async move {
+            let path = self.path(key)?;
+            let dir = path.parent().expect("file path parent should exists");
+            fs::create_dir_all(dir).await?;
+
+            // write next to the target and rename, readers never see a partial file
+            let tmp = dir.join(format!(".{}.tmp", random_string(12)));
+            let mut file = fs::File::create(&tmp).await?;
+            let mut written = 0;
+            let ret: Result<(), io::Error> = async {
+                while let Some(chunk) = body.try_next().await? {
+                    written += chunk.len() as u64;
+                    file.write_all(&chunk).await?;
+                }
+                file.flush().await
+            }
+            .await;
+
+            if ret.is_err() || written != len {
+                let _ = fs::remove_file(&tmp).await;
+                ret?;
+                return Err(AppError::StorageError(format!(
+                    "Expected {len} bytes for {key}, got {written}"
+                )));
+            }
+            fs::rename(&tmp, &path).await?;
+
+            Ok(ObjectMeta {
+                size: written,
+                etag: None,
+                last_modified: None,
+            })
+        })
+    }
+
+    // same disk as the upload temp dir, a rename is enough
+    fn put_file<'a>(&'a self, key: &'a str, src: &'a Path) -> StoreFuture<'a, ObjectMeta> {
+        Box::pin(async move {
+            let path = self.path(key)?;
+            fs::create_dir_all(path.parent().expect("file path parent should exists")).await?;
+            fs::rename(src, &path).await?;
+            Ok(to_meta(fs::metadata(&path).await?))
+        })
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> StoreFuture<'a, Option<StoredObject>> {
+        Box::pin(async move {
+            let path = self.path(key)?;
+            let file = match fs::File::open(&path).await {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+            let meta = to_meta(file.metadata().await?);
+            Ok(Some(StoredObject {
+                meta,
+                body: Box::pin(ReaderStream::new(file)),
+            }))
+        })
+    }
+
+    fn get_range<'a>(
+        &'a self,
+        key: &'a str,
+        range: Range<u64>,
+    ) -> StoreFuture<'a, Option<StoredObject>> {
+        Box::pin(async move {
+            let path = self.path(key)?;
+            let mut file = match fs::File::open(&path).await {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+            let meta = to_meta(file.metadata().await?);
+            file.seek(SeekFrom::Start(range.start)).await?;
+            let body = ReaderStream::new(file.take(range.end - range.start));
+            Ok(Some(StoredObject {
+                meta,
+                body: Box::pin(body),
+            }))
+        })
+    }
+
+    fn head<'a>(&'a self, key: &'a str) -> StoreFuture<'a, Option<ObjectMeta>> {
+        Box::pin(async move {
+            let path = self.path(key)?;
+            match fs::metadata(&path).await {
+                Ok(meta) if meta.is_file() => Ok(Some(to_meta(meta))),
+                Ok(_) => Ok(None),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            let path = self.path(key)?;
+            match fs::remove_file(&path).await {
+                Ok(()) => Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+}
+
+fn to_meta(meta: std::fs::Metadata) -> ObjectMeta {
+    ObjectMeta {
+        size: meta.len(),
+        etag: None,
+        last_modified: meta.modified().ok().map(Into::into),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::bytes_stream;
+    use anyhow::Result;
+    use bytes::Bytes;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn local_store_should_work() -> Result<()> {
+        let root = std::env::temp_dir().join(format!("chat-store-{}", random_string(8)));
+        let store: Arc<dyn FileStore> = Arc::new(LocalStore::new(&root));
+        let key = "1/2aa/e6c/35c94f.txt";
+
+        assert!(store.head(key).await?.is_none());
+        let meta = store.put_bytes(key, Bytes::from("hello world")).await?;
+        assert_eq!(meta.size, 11);
+        assert_eq!(store.head(key).await?.unwrap().size, 11);
+        assert_eq!(store.get_bytes(key).await?.unwrap(), "hello world");
+        let part = store.get_range(key, 6..9).await?.unwrap();
+        assert_eq!(part.meta.size, 11);
+        let chunks: Vec<Bytes> = part.body.try_collect().await?;
+        assert_eq!(chunks.concat(), b"wor");
+
+        // a short body doesn't leave a file behind
+        let ret = store
+            .put("1/aaa/bbb/short.txt", bytes_stream(Bytes::from("abc")), 10)
+            .await;
+        assert!(ret.is_err());
+        assert!(!store.exists("1/aaa/bbb/short.txt").await?);
+
+        store.delete(key).await?;
+        store.delete(key).await?;
+        assert!(store.get(key).await?.is_none());
+        assert!(store.head("../outside.txt").await.is_err());
+
+        fs::remove_dir_all(root).await?;
+        Ok(())
+    }
+}
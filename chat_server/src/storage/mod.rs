@@ -0,0 +1,121 @@
+mod local;
+mod s3;
+
+use std::{future::Future, io, ops::Range, path::Path, pin::Pin, sync::Arc};
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{stream, Stream, TryStreamExt};
+use tokio::fs;
+use tokio_util::io::ReaderStream;
+
+use crate::{config::StorageConfig, error::AppError, AppConfig};
+
+pub use local::LocalStore;
+pub use s3::S3Store;
+
+pub type ByteStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>;
+
+pub type StoreFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, AppError>> + Send + 'a>>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectMeta {
+    pub size: u64,
+    pub etag: Option<String>,
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+pub struct StoredObject {
+    pub meta: ObjectMeta,
+    pub body: ByteStream,
+}
+
+/// Where uploaded files live. Keys are relative paths like `1/2aa/e6c/35c94f.txt`.
+pub trait FileStore: Send + Sync {
+    /// Store the object, `len` must be the exact length of the body
+    fn put<'a>(&'a self, key: &'a str, body: ByteStream, len: u64) -> StoreFuture<'a, ObjectMeta>;
+
+    fn get<'a>(&'a self, key: &'a str) -> StoreFuture<'a, Option<StoredObject>>;
+
+    /// Only the bytes in `range`, which must be within the object. `meta` is still about the whole object.
+    fn get_range<'a>(
+        &'a self,
+        key: &'a str,
+        range: Range<u64>,
+    ) -> StoreFuture<'a, Option<StoredObject>>;
+
+    fn head<'a>(&'a self, key: &'a str) -> StoreFuture<'a, Option<ObjectMeta>>;
+
+    /// Deleting a missing object is not an error
+    fn delete<'a>(&'a self, key: &'a str) -> StoreFuture<'a, ()>;
+
+    /// Move a finished local file into the store, the file is gone afterwards
+    fn put_file<'a>(&'a self, key: &'a str, path: &'a Path) -> StoreFuture<'a, ObjectMeta> {
+        Box::pin(async move {
+            let file = fs::File::open(path).await?;
+            let len = file.metadata().await?.len();
+            let meta = self
+                .put(key, Box::pin(ReaderStream::new(file)), len)
+                .await?;
+            fs::remove_file(path).await?;
+            Ok(meta)
+        })
+    }
+}
+
+impl dyn FileStore {
+    pub async fn put_bytes(&self, key: &str, data: Bytes) -> Result<ObjectMeta, AppError> {
+        let len = data.len() as u64;
+        self.put(key, bytes_stream(data), len).await
+    }
+
+    pub async fn get_bytes(&self, key: &str) -> Result<Option<Bytes>, AppError> {
+        let Some(obj) = self.get(key).await? else {
+            return Ok(None);
+        };
+        let chunks: Vec<Bytes> = obj.body.try_collect().await?;
+        Ok(Some(chunks.concat().into()))
+    }
+
+    pub async fn exists(&self, key: &str) -> Result<bool, AppError> {
+        Ok(self.head(key).await?.is_some())
+    }
+}
+
+pub fn bytes_stream(data: Bytes) -> ByteStream {
+    Box::pin(stream::once(async move { Ok(data) }))
+}
+
+pub(crate) fn build_store(config: &AppConfig) -> Arc<dyn FileStore> {
+    match &config.storage {
+        StorageConfig::Local => Arc::new(LocalStore::new(&config.server.base_dir)),
+        StorageConfig::S3(s3) => Arc::new(S3Store::new(s3.clone())),
+    }
+}
+
+/// Keys are only ever built from ChatFile, still refuse anything that could leave the root
+fn validate_key(key: &str) -> Result<(), AppError> {
+    let valid = !key.is_empty()
+        && key
+            .split('/')
+            .all(|part| !part.is_empty() && part != "." && part != "..");
+    if valid {
+        Ok(())
+    } else {
+        Err(AppError::StorageError(format!("Invalid file key: {key}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_key_should_work() {
+        assert!(validate_key("1/2aa/e6c/35c94f.txt").is_ok());
+        assert!(validate_key("1/../etc/passwd").is_err());
+        assert!(validate_key("/etc/passwd").is_err());
+        assert!(validate_key("1//a.txt").is_err());
+        assert!(validate_key("").is_err());
+    }
+}
@@ -0,0 +1,73 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use rand::{distributions::Alphanumeric, Rng};
+
+pub(crate) fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+/// Fixed window rate limiter keyed by id
+#[derive(Debug, Default)]
+pub(crate) struct RateLimiter {
+    windows: DashMap<i64, (Instant, u32)>,
+    last_sweep: Mutex<Option<Instant>>,
+}
+
+impl RateLimiter {
+    /// Count a hit for the key, returning how long to wait when the limit per window is exceeded
+    pub(crate) fn check(&self, key: i64, limit: u32, window: Duration) -> Result<(), Duration> {
+        let now = Instant::now();
+        self.sweep(now, window);
+        let mut entry = self.windows.entry(key).or_insert((now, 0));
+        let (start, count) = entry.value_mut();
+        if now.duration_since(*start) >= window {
+            *start = now;
+            *count = 0;
+        }
+        if *count >= limit {
+            return Err(window - now.duration_since(*start));
+        }
+        *count += 1;
+        Ok(())
+    }
+
+    // drop finished windows once per window, so keys that stop hitting don't pile up
+    fn sweep(&self, now: Instant, window: Duration) {
+        {
+            let mut last = self.last_sweep.lock().unwrap();
+            match *last {
+                Some(at) if now.duration_since(at) < window => return,
+                _ => *last = Some(now),
+            }
+        }
+        self.windows
+            .retain(|_, (start, _)| now.duration_since(*start) < window);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limiter_should_limit_and_drop_finished_windows() {
+        let limiter = RateLimiter::default();
+        let window = Duration::from_millis(50);
+        assert!(limiter.check(1, 2, window).is_ok());
+        assert!(limiter.check(1, 2, window).is_ok());
+        assert!(limiter.check(1, 2, window).is_err());
+        assert!(limiter.check(2, 2, window).is_ok());
+
+        std::thread::sleep(window);
+        assert!(limiter.check(3, 2, window).is_ok());
+        assert_eq!(limiter.windows.len(), 1);
+    }
+}
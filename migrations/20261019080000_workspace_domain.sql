@@ -0,0 +1,4 @@
+-- Add migration script here
+-- email domain used to auto-provision sso users into a workspace
+ALTER TABLE workspaces
+  ADD COLUMN domain varchar(64) UNIQUE;
@@ -0,0 +1,33 @@
+-- Add migration script here
+-- bot users are owned by a workspace and can only authenticate with api tokens
+ALTER TABLE users
+  ADD COLUMN is_bot boolean NOT NULL DEFAULT FALSE;
+
+ALTER TABLE messages
+  ADD COLUMN is_bot boolean NOT NULL DEFAULT FALSE;
+
+-- create token scope: read_chats, write_chats, post_messages, read_files, upload_files
+CREATE TYPE token_scope AS ENUM(
+  'read_chats',
+  'write_chats',
+  'post_messages',
+  'read_files',
+  'upload_files'
+);
+
+-- long lived personal access tokens, for humans and bots
+CREATE TABLE IF NOT EXISTS api_tokens(
+  id bigserial PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id),
+  created_by bigint NOT NULL REFERENCES users(id),
+  name varchar(64) NOT NULL,
+  -- sha256 of the token, the token itself is only shown once
+  token_hash char(64) NOT NULL UNIQUE,
+  scopes token_scope[] NOT NULL,
+  expires_at timestamptz,
+  last_used_at timestamptz,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create index for api tokens for user_id
+CREATE INDEX IF NOT EXISTS api_tokens_user_id_index ON api_tokens(user_id);
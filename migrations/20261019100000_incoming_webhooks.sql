@@ -0,0 +1,17 @@
+-- Add migration script here
+-- incoming webhooks post into a chat as their own bot user
+CREATE TABLE IF NOT EXISTS incoming_webhooks(
+  id bigserial PRIMARY KEY,
+  chat_id bigint NOT NULL REFERENCES chats(id),
+  bot_id bigint NOT NULL REFERENCES users(id),
+  name varchar(64) NOT NULL,
+  -- sha256 of the secret token in the webhook url
+  token_hash char(64) NOT NULL,
+  -- max messages per minute
+  rate_limit int NOT NULL DEFAULT 60,
+  created_by bigint NOT NULL REFERENCES users(id),
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create index for incoming webhooks for chat_id
+CREATE INDEX IF NOT EXISTS incoming_webhooks_chat_id_index ON incoming_webhooks(chat_id);
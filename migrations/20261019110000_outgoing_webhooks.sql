@@ -0,0 +1,54 @@
+-- Add migration script here
+-- create webhook match: all messages, messages containing a keyword, or starting with a trigger word
+CREATE TYPE webhook_match AS ENUM(
+  'all',
+  'keyword',
+  'trigger_word'
+);
+
+-- outgoing webhooks forward new messages of a chat, or of the whole workspace, to an external url
+CREATE TABLE IF NOT EXISTS outgoing_webhooks(
+  id bigserial PRIMARY KEY,
+  ws_id bigint NOT NULL REFERENCES workspaces(id),
+  -- NULL means every chat in the workspace
+  chat_id bigint REFERENCES chats(id),
+  name varchar(64) NOT NULL,
+  url text NOT NULL,
+  -- hmac-sha256 key used to sign deliveries
+  secret varchar(64) NOT NULL,
+  match webhook_match NOT NULL DEFAULT 'all',
+  words text[] NOT NULL DEFAULT '{}',
+  created_by bigint NOT NULL REFERENCES users(id),
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create index for outgoing webhooks for ws_id
+CREATE INDEX IF NOT EXISTS outgoing_webhooks_ws_id_index ON outgoing_webhooks(ws_id);
+
+-- create delivery status: pending, delivered, dead
+CREATE TYPE delivery_status AS ENUM(
+  'pending',
+  'delivered',
+  'dead'
+);
+
+-- durable delivery queue and log, written in the same transaction as the message
+CREATE TABLE IF NOT EXISTS webhook_deliveries(
+  id bigserial PRIMARY KEY,
+  webhook_id bigint NOT NULL REFERENCES outgoing_webhooks(id) ON DELETE CASCADE,
+  message_id bigint NOT NULL REFERENCES messages(id),
+  payload jsonb NOT NULL,
+  status delivery_status NOT NULL DEFAULT 'pending',
+  attempts int NOT NULL DEFAULT 0,
+  next_attempt_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  last_status_code int,
+  last_error text,
+  delivered_at timestamptz,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP,
+  UNIQUE (webhook_id, message_id)
+);
+
+-- create index for pending deliveries for next_attempt_at
+CREATE INDEX IF NOT EXISTS webhook_deliveries_pending_index ON webhook_deliveries(next_attempt_at)
+WHERE
+  status = 'pending';
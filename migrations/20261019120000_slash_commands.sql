@@ -0,0 +1,39 @@
+-- chat topic, set with /topic
+ALTER TABLE chats
+  ADD COLUMN topic varchar(250);
+
+-- workspace commands forwarded to an external url
+CREATE TABLE IF NOT EXISTS slash_commands(
+  id bigserial PRIMARY KEY,
+  ws_id bigint NOT NULL REFERENCES workspaces(id),
+  -- without the leading slash
+  name varchar(32) NOT NULL,
+  description varchar(250) NOT NULL DEFAULT '',
+  url text NOT NULL,
+  -- signs the requests, same scheme as outgoing webhooks
+  secret varchar(64) NOT NULL,
+  -- in channel replies are posted by this bot
+  bot_id bigint NOT NULL REFERENCES users(id),
+  created_by bigint NOT NULL REFERENCES users(id),
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP,
+  UNIQUE (ws_id, name)
+);
+
+-- muted chats, until NULL means muted until turned off
+CREATE TABLE IF NOT EXISTS chat_mutes(
+  chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  user_id bigint NOT NULL REFERENCES users(id),
+  until timestamptz,
+  PRIMARY KEY (chat_id, user_id)
+);
+
+CREATE TABLE IF NOT EXISTS reminders(
+  id bigserial PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id),
+  chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  content text NOT NULL,
+  remind_at timestamptz NOT NULL,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS reminders_remind_at_index ON reminders(remind_at);
@@ -0,0 +1,15 @@
+-- resumable uploads (tus 1.0), the data lives in base_dir/.tus until completed
+CREATE TABLE IF NOT EXISTS tus_uploads(
+  id varchar(32) PRIMARY KEY,
+  ws_id bigint NOT NULL REFERENCES workspaces(id),
+  user_id bigint NOT NULL REFERENCES users(id),
+  filename varchar(255) NOT NULL,
+  upload_length bigint NOT NULL,
+  upload_offset bigint NOT NULL DEFAULT 0,
+  -- chat file url once all bytes arrived
+  file_url text,
+  expires_at timestamptz NOT NULL,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS tus_uploads_expires_at_index ON tus_uploads(expires_at);
@@ -0,0 +1,14 @@
+-- metadata of every upload, several rows can share a url when the content is identical
+CREATE TABLE IF NOT EXISTS files(
+  id bigserial PRIMARY KEY,
+  ws_id bigint NOT NULL REFERENCES workspaces(id),
+  uploader_id bigint NOT NULL REFERENCES users(id),
+  -- chat file url, e.g. /files/1/2aa/e6c/35c94f.txt
+  url text NOT NULL,
+  filename varchar(255) NOT NULL,
+  size bigint NOT NULL,
+  mime varchar(128) NOT NULL,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS files_url_index ON files(url);
@@ -0,0 +1,6 @@
+-- image dimensions and previews, filled in by a background task after the upload
+ALTER TABLE files ADD COLUMN IF NOT EXISTS width int;
+ALTER TABLE files ADD COLUMN IF NOT EXISTS height int;
+ALTER TABLE files ADD COLUMN IF NOT EXISTS blurhash varchar(64);
+-- [{"size": 256, "url": "/files/1/..."}]
+ALTER TABLE files ADD COLUMN IF NOT EXISTS thumbnails jsonb NOT NULL DEFAULT '[]';
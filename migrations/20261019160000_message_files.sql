@@ -0,0 +1,17 @@
+-- chats a file was posted in, members of any of them may download it
+CREATE TABLE IF NOT EXISTS message_files(
+  message_id bigint NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+  chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  url text NOT NULL,
+  PRIMARY KEY (message_id, url)
+);
+
+CREATE INDEX IF NOT EXISTS message_files_url_index ON message_files(url);
+
+-- thumbnail downloads are checked against the image they were made from
+CREATE INDEX IF NOT EXISTS files_thumbnails_index ON files USING gin(thumbnails jsonb_path_ops);
+
+INSERT INTO message_files(message_id, chat_id, url)
+SELECT id, chat_id, unnest(files)
+FROM messages
+ON CONFLICT DO NOTHING;
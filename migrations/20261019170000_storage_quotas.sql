@@ -0,0 +1,6 @@
+-- bytes a workspace may store, NULL falls back to upload.workspace_quota in the config
+ALTER TABLE workspaces ADD COLUMN IF NOT EXISTS storage_quota bigint;
+
+CREATE INDEX IF NOT EXISTS files_ws_id_index ON files(ws_id);
+-- the garbage collector looks for old uploads
+CREATE INDEX IF NOT EXISTS files_created_at_index ON files(created_at);
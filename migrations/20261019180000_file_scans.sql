@@ -0,0 +1,14 @@
+CREATE TYPE scan_status AS ENUM(
+  'pending',
+  'clean',
+  'infected',
+  'error'
+);
+
+-- files uploaded before scanning existed are trusted
+ALTER TABLE files ADD COLUMN IF NOT EXISTS scan_status scan_status NOT NULL DEFAULT 'clean';
+-- the signature that was found or why the scan failed
+ALTER TABLE files ADD COLUMN IF NOT EXISTS scan_detail text;
+
+CREATE INDEX IF NOT EXISTS files_scan_status_index ON files(scan_status)
+  WHERE scan_status IN ('pending', 'error');
@@ -0,0 +1,8 @@
+-- how far each member has read a chat, acked by clients over notify_server
+CREATE TABLE IF NOT EXISTS chat_reads(
+  chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  last_read_id bigint NOT NULL,
+  updated_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (chat_id, user_id)
+);
@@ -0,0 +1,60 @@
+-- events are written in the transaction that caused them, pg_notify only carries the id.
+-- whole rows in the payload hit the 8000 byte limit and made the insert fail.
+CREATE TABLE IF NOT EXISTS events(
+  id bigserial PRIMARY KEY,
+  kind varchar(64) NOT NULL,
+  payload jsonb NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS events_created_at_index ON events(created_at);
+
+-- how far each notify_server has dispatched
+CREATE TABLE IF NOT EXISTS notify_cursors(
+  consumer varchar(64) PRIMARY KEY,
+  last_id bigint NOT NULL,
+  updated_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE OR REPLACE FUNCTION add_to_chat()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  event_id bigint;
+BEGIN
+  INSERT INTO events(kind, payload)
+    VALUES ('chat_updated', json_build_object('op', TG_OP, 'old', OLD, 'new', NEW))
+  RETURNING
+    id INTO event_id;
+  PERFORM
+    pg_notify('event_created', event_id::text);
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION add_to_message()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+  event_id bigint;
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    SELECT
+      members INTO USERS
+    FROM
+      chats
+    WHERE
+      id = NEW.chat_id;
+    INSERT INTO events(kind, payload)
+      VALUES ('chat_message_created', json_build_object('message', NEW, 'members', USERS))
+    RETURNING
+      id INTO event_id;
+    PERFORM
+      pg_notify('event_created', event_id::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
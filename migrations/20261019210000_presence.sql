@@ -0,0 +1,62 @@
+CREATE TYPE presence_status AS ENUM(
+  'online',
+  'away',
+  'offline'
+);
+
+-- one row per notify_server instance a user is connected to, refreshed by its heartbeat
+CREATE TABLE IF NOT EXISTS presence_sessions(
+  instance varchar(64) NOT NULL,
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  connected_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  last_seen_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (instance, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS presence_sessions_user_id_index ON presence_sessions(user_id);
+
+-- what users say about themselves, and when they last did something
+CREATE TABLE IF NOT EXISTS user_status(
+  user_id bigint PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+  status_text varchar(100),
+  status_emoji varchar(32),
+  -- the text and emoji are cleared after this
+  status_expires_at timestamptz,
+  -- daily do-not-disturb window in UTC, it may wrap past midnight
+  dnd_start time,
+  dnd_end time,
+  last_active_at timestamptz,
+  updated_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- offline without a fresh session, away after 10 minutes without activity
+CREATE OR REPLACE VIEW user_presence AS
+SELECT
+  u.id AS user_id,
+  u.ws_id,
+  CASE
+    WHEN NOT EXISTS (
+      SELECT 1 FROM presence_sessions ps
+      WHERE ps.user_id = u.id AND ps.last_seen_at > NOW() - INTERVAL '90 seconds'
+    ) THEN 'offline'::presence_status
+    WHEN st.last_active_at IS NULL OR st.last_active_at < NOW() - INTERVAL '10 minutes'
+      THEN 'away'::presence_status
+    ELSE 'online'::presence_status
+  END AS presence,
+  CASE WHEN st.status_expires_at IS NULL OR st.status_expires_at > NOW()
+    THEN st.status_text END AS status_text,
+  CASE WHEN st.status_expires_at IS NULL OR st.status_expires_at > NOW()
+    THEN st.status_emoji END AS status_emoji,
+  CASE WHEN st.status_expires_at > NOW() THEN st.status_expires_at END AS status_expires_at,
+  COALESCE(
+    CASE
+      WHEN st.dnd_start <= st.dnd_end
+        THEN (NOW() AT TIME ZONE 'UTC')::time >= st.dnd_start
+          AND (NOW() AT TIME ZONE 'UTC')::time < st.dnd_end
+      ELSE (NOW() AT TIME ZONE 'UTC')::time >= st.dnd_start
+        OR (NOW() AT TIME ZONE 'UTC')::time < st.dnd_end
+    END,
+    false
+  ) AS dnd
+FROM users u
+LEFT JOIN user_status st ON st.user_id = u.id;
@@ -0,0 +1,18 @@
+CREATE TYPE notify_level AS ENUM(
+  'all',
+  'mentions',
+  'none'
+);
+
+-- what members get pushed when they haven't configured a chat, set by the workspace owner
+ALTER TABLE workspaces ADD COLUMN IF NOT EXISTS notify_level notify_level NOT NULL DEFAULT 'all';
+
+-- a member's own settings for a chat, a null level falls back to the workspace
+CREATE TABLE IF NOT EXISTS chat_notification_settings(
+  chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  level notify_level,
+  muted_until timestamptz,
+  updated_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (chat_id, user_id)
+);
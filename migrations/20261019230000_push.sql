@@ -0,0 +1,20 @@
+-- one row per browser or device a user enabled Web Push on
+CREATE TABLE IF NOT EXISTS push_subscriptions(
+  id bigserial PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  endpoint text NOT NULL UNIQUE,
+  -- keys from PushSubscription.getKey(), base64url
+  p256dh varchar(128) NOT NULL,
+  auth varchar(64) NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  last_used_at timestamptz
+);
+
+CREATE INDEX IF NOT EXISTS push_subscriptions_user_id_index ON push_subscriptions(user_id);
+
+-- the notify_server instance that claims an event for a user is the one pushing it
+CREATE TABLE IF NOT EXISTS push_claims(
+  event_id bigint NOT NULL REFERENCES events(id) ON DELETE CASCADE,
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  PRIMARY KEY (event_id, user_id)
+);
@@ -0,0 +1,6 @@
+-- newest message each user has been mailed about, digests only pick up later ones
+CREATE TABLE IF NOT EXISTS email_digests(
+  user_id bigint PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+  last_message_id bigint NOT NULL DEFAULT 0,
+  sent_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
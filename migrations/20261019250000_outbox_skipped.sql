@@ -0,0 +1,3 @@
+-- ids passed over while missing, a transaction that commits late is still dispatched
+ALTER TABLE notify_cursors
+  ADD COLUMN IF NOT EXISTS skipped bigint[] NOT NULL DEFAULT '{}';
@@ -0,0 +1,17 @@
+-- /mute wrote chat_mutes, which nothing sending notifications reads. Its mutes move into
+-- chat_notification_settings, one without an end is kept until the end of year 9999
+INSERT INTO chat_notification_settings(chat_id, user_id, muted_until)
+SELECT
+  chat_id,
+  user_id,
+  COALESCE(until, '9999-12-31 23:59:59+00')
+FROM
+  chat_mutes
+WHERE
+  until IS NULL
+  OR until > CURRENT_TIMESTAMP
+ON CONFLICT (chat_id, user_id)
+  DO UPDATE SET
+    muted_until = GREATEST(chat_notification_settings.muted_until, EXCLUDED.muted_until), updated_at = CURRENT_TIMESTAMP;
+
+DROP TABLE IF EXISTS chat_mutes;
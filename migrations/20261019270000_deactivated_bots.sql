@@ -0,0 +1,3 @@
+-- bots of deleted integrations stay as the senders of their messages, but can't be used again
+ALTER TABLE users
+  ADD COLUMN deactivated_at timestamptz;
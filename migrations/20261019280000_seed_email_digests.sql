@@ -0,0 +1,7 @@
+-- start existing users at the newest message, so the first digest run doesn't mail
+-- them all of their history
+INSERT INTO email_digests(user_id, last_message_id)
+SELECT u.id, (SELECT COALESCE(MAX(id), 0) FROM messages)
+FROM users u
+WHERE NOT u.is_bot
+ON CONFLICT (user_id) DO NOTHING;
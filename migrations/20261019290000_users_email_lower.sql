@@ -0,0 +1,2 @@
+-- sso sign-ins look users up by email case-insensitively
+CREATE INDEX IF NOT EXISTS users_email_lower_idx ON users(lower(email));
@@ -0,0 +1,69 @@
+//! Holds idle in-memory connections and fans one event out to all of them.
+//!
+//! cargo run --release --bin load_test -- 50000
+
+use std::{env, sync::Arc, time::Instant};
+
+use anyhow::{ensure, Result};
+use notify_server::{AppEvent, ConnectionManager, Typing};
+use tokio::sync::mpsc;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let count: usize = match env::args().nth(1) {
+        Some(n) => n.parse()?,
+        None => 50_000,
+    };
+    let manager = Arc::new(ConnectionManager::new(64, 256, 1));
+    let rss_before = rss_kb();
+
+    let start = Instant::now();
+    let (done_tx, mut done_rx) = mpsc::unbounded_channel();
+    for user_id in 0..count as i64 {
+        let mut sub = manager.subscribe(user_id, None);
+        let done_tx = done_tx.clone();
+        tokio::spawn(async move {
+            if sub.recv().await.is_some() {
+                let _ = done_tx.send(());
+            }
+        });
+    }
+    drop(done_tx);
+    let stats = manager.stats();
+    ensure!(
+        stats.connections == count,
+        "only {} connected",
+        stats.connections
+    );
+    let rss_after = rss_kb();
+    println!(
+        "{} connections in {:?}, rss {} KiB -> {} KiB (~{} bytes each)",
+        stats.connections,
+        start.elapsed(),
+        rss_before,
+        rss_after,
+        (rss_after.saturating_sub(rss_before) * 1024) / count.max(1) as u64
+    );
+
+    let start = Instant::now();
+    let event = AppEvent::Typing(Typing {
+        chat_id: 1,
+        user_id: 1,
+    });
+    manager.send(0..count as i64, event);
+    let mut received = 0;
+    while done_rx.recv().await.is_some() {
+        received += 1;
+    }
+    ensure!(received == count, "only {received} received the event");
+    println!("fan-out to {received} connections in {:?}", start.elapsed());
+    Ok(())
+}
+
+// resident set size from /proc, 0 where that's not available
+fn rss_kb() -> u64 {
+    std::fs::read_to_string("/proc/self/statm")
+        .ok()
+        .and_then(|statm| statm.split_whitespace().nth(1)?.parse::<u64>().ok())
+        .map_or(0, |pages| pages * 4)
+}
@@ -0,0 +1,190 @@
+use std::{env, fs::File};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AppConfig {
+    pub server: ServerConfig,
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub ws: WsConfig,
+    #[serde(default)]
+    pub connections: ConnectionsConfig,
+    #[serde(default)]
+    pub replay: ReplayConfig,
+    #[serde(default)]
+    pub outbox: OutboxConfig,
+    #[serde(default)]
+    pub typing: TypingConfig,
+    #[serde(default)]
+    pub presence: PresenceConfig,
+    #[serde(default)]
+    pub push: PushConfig,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuthConfig {
+    pub pk: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServerConfig {
+    pub port: u16,
+    pub db_url: String,
+    // unique per replica, it keys presence sessions and shows up in `/connections`
+    #[serde(default = "default_instance")]
+    pub instance: String,
+}
+
+fn default_instance() -> String {
+    env::var("HOSTNAME").unwrap_or_else(|_| "notify_server".to_string())
+}
+
+/// Heartbeats of `/ws`, a connection silent for `timeout_secs` is dropped
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WsConfig {
+    pub heartbeat_secs: u64,
+    pub timeout_secs: u64,
+}
+
+impl Default for WsConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_secs: 15,
+            timeout_secs: 45,
+        }
+    }
+}
+
+/// Events queued per connection, a connection that falls this far behind is dropped
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionsConfig {
+    pub queue_size: usize,
+}
+
+impl Default for ConnectionsConfig {
+    fn default() -> Self {
+        Self { queue_size: 64 }
+    }
+}
+
+/// Recent events kept per user so a reconnect can catch up
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplayConfig {
+    pub capacity: usize,
+    // history of a user without connections is dropped after this long
+    pub idle_secs: u64,
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 256,
+            idle_secs: 10 * 60,
+        }
+    }
+}
+
+/// Reading the `events` table the triggers write
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxConfig {
+    // name the cursor is saved under, every replica needs its own. Defaults to `server.instance`,
+    // set it to a name that survives restarts or a restarted replica starts from the latest event
+    pub consumer: Option<String>,
+    // a missing id may still be committed, later events wait this long for it
+    pub gap_grace_ms: u64,
+    // after the grace a missing id is looked up again until this long, then taken as rolled back
+    pub gap_retry_secs: u64,
+    // dispatched events are removed after this long
+    pub retention_secs: u64,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        Self {
+            consumer: None,
+            gap_grace_ms: 500,
+            gap_retry_secs: 10 * 60,
+            retention_secs: 24 * 60 * 60,
+        }
+    }
+}
+
+/// A typing indicator not refreshed within `ttl_ms` is cleared
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TypingConfig {
+    pub ttl_ms: u64,
+}
+
+impl Default for TypingConfig {
+    fn default() -> Self {
+        Self { ttl_ms: 5000 }
+    }
+}
+
+/// Sessions and activity are written every `sync_ms`. A session counts for 90 seconds,
+/// so `heartbeat_secs` has to stay well below that
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PresenceConfig {
+    pub sync_ms: u64,
+    pub heartbeat_secs: u64,
+}
+
+impl Default for PresenceConfig {
+    fn default() -> Self {
+        Self {
+            sync_ms: 2000,
+            heartbeat_secs: 30,
+        }
+    }
+}
+
+/// Web Push for users without a live connection, off without a VAPID key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PushConfig {
+    // P-256 private key, PKCS#8 PEM
+    pub vapid_key: Option<String>,
+    // contact for push services, a mailto: or https: url
+    pub subject: String,
+    // how long push services hold a message for an unreachable device
+    pub ttl_secs: u64,
+    // lets endpoints use http and private hosts, only for tests against a local push service
+    pub allow_private_endpoints: bool,
+}
+
+impl Default for PushConfig {
+    fn default() -> Self {
+        Self {
+            vapid_key: None,
+            subject: "mailto:admin@acme.org".to_string(),
+            ttl_secs: 24 * 60 * 60,
+            allow_private_endpoints: false,
+        }
+    }
+}
+
+impl AppConfig {
+    /// Name of this replica's outbox cursor
+    pub fn consumer(&self) -> &str {
+        self.outbox
+            .consumer
+            .as_deref()
+            .unwrap_or(&self.server.instance)
+    }
+
+    pub fn load() -> Result<Self> {
+        // read from ./notify.yml, or /etc/config/notify.yml, or from env NOTIFY_CONFIG
+        let ret = match (
+            File::open("notify.yml"),
+            File::open("/etc/config/notify.yml"),
+            env::var("NOTIFY_CONFIG"),
+        ) {
+            (Ok(reader), _, _) => serde_yaml::from_reader(reader),
+            (_, Ok(reader), _) => serde_yaml::from_reader(reader),
+            (_, _, Ok(path)) => serde_yaml::from_reader(File::open(path)?),
+            _ => bail!("Config file not found"),
+        };
+        Ok(ret?)
+    }
+}
@@ -0,0 +1,421 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{
+    self,
+    error::{TryRecvError, TrySendError},
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::warn;
+
+use crate::{AppEvent, EventRecord};
+
+/// The users connected to this instance. Each connection has its own bounded queue,
+/// one that falls behind is disconnected rather than holding up everyone else.
+pub struct ConnectionManager {
+    users: DashMap<i64, UserChannel>,
+    // tags the event ids of this process, ids are only comparable within one run of one instance
+    stream: u64,
+    next_id: AtomicU64,
+    next_conn: AtomicU64,
+    queue_size: usize,
+    history_size: usize,
+    slow_disconnects: AtomicU64,
+}
+
+/// Live connections of one user plus the most recent events for reconnects
+struct UserChannel {
+    connections: Vec<Connection>,
+    events: VecDeque<Arc<EventRecord>>,
+    // everything after this id is in `events`
+    since: u64,
+    // when the last connection went away
+    idle_since: Option<Instant>,
+}
+
+struct Connection {
+    id: u64,
+    tx: mpsc::Sender<Arc<EventRecord>>,
+}
+
+/// Where a reconnecting client left off, parsed from its Last-Event-ID
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LastEvent {
+    Id(u64),
+    // sent by another instance or an earlier run of this one, nothing kept here can fill the gap
+    Foreign,
+}
+
+/// What a reconnect gets before the live events
+#[derive(Debug, Default, PartialEq)]
+pub enum Replay {
+    #[default]
+    Nothing,
+    Events(Vec<Arc<EventRecord>>),
+    // the client missed events that are no longer kept, it can resume after the id once refetched
+    Resync(u64),
+}
+
+/// One open connection, it's unregistered when dropped
+pub struct Subscription {
+    pub replay: Replay,
+    rx: mpsc::Receiver<Arc<EventRecord>>,
+    guard: ConnectionGuard,
+}
+
+struct ConnectionGuard {
+    manager: Arc<ConnectionManager>,
+    user_id: i64,
+    id: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ConnectionStats {
+    pub users: usize,
+    pub connections: usize,
+    // connections dropped for not keeping up
+    pub slow_disconnects: u64,
+}
+
+impl ConnectionManager {
+    /// `first_id` is the id of the first event sent, later ones count up from there
+    pub fn new(queue_size: usize, history_size: usize, first_id: u64) -> Self {
+        Self {
+            users: DashMap::new(),
+            stream: rand::random(),
+            next_id: AtomicU64::new(first_id),
+            next_conn: AtomicU64::new(0),
+            queue_size,
+            history_size,
+            slow_disconnects: AtomicU64::new(0),
+        }
+    }
+
+    /// The id clients see, `{stream}-{id}` so a load balancer moving them to another instance
+    /// can't make them skip events
+    pub fn event_id(&self, id: u64) -> String {
+        format!("{:016x}-{id}", self.stream)
+    }
+
+    pub fn parse_event_id(&self, value: &str) -> LastEvent {
+        match value.split_once('-') {
+            Some((stream, id)) if u64::from_str_radix(stream, 16) == Ok(self.stream) => {
+                id.parse().map_or(LastEvent::Foreign, LastEvent::Id)
+            }
+            _ => LastEvent::Foreign,
+        }
+    }
+
+    /// Register a connection, preceded by the events missed since `last` on a reconnect
+    pub fn subscribe(self: &Arc<Self>, user_id: i64, last: Option<LastEvent>) -> Subscription {
+        let since = self.next_id.load(Ordering::SeqCst) - 1;
+        let (tx, rx) = mpsc::channel(self.queue_size);
+        let id = self.next_conn.fetch_add(1, Ordering::Relaxed);
+        // sends wait for the entry, so nothing falls between the replay and the live events
+        let mut channel = self
+            .users
+            .entry(user_id)
+            .or_insert_with(|| UserChannel::new(since));
+        channel.connections.push(Connection { id, tx });
+        channel.idle_since = None;
+        let replay = channel.replay(last);
+        drop(channel);
+
+        Subscription {
+            replay,
+            rx,
+            guard: ConnectionGuard {
+                manager: self.clone(),
+                user_id,
+                id,
+            },
+        }
+    }
+
+    /// Queue the event for the users that are or were recently connected here, the others are skipped
+    pub fn send(
+        &self,
+        user_ids: impl IntoIterator<Item = i64>,
+        event: AppEvent,
+    ) -> Arc<EventRecord> {
+        self.send_to(user_ids, &HashSet::new(), event)
+    }
+
+    /// Like `send`, the users in `silent` get it flagged as a sync rather than a notification
+    pub fn send_to(
+        &self,
+        user_ids: impl IntoIterator<Item = i64>,
+        silent: &HashSet<i64>,
+        event: AppEvent,
+    ) -> Arc<EventRecord> {
+        let record = Arc::new(EventRecord {
+            id: self.next_id.fetch_add(1, Ordering::SeqCst),
+            event,
+            silent: false,
+        });
+        let silent_record = Arc::new(EventRecord {
+            silent: true,
+            ..EventRecord::clone(&record)
+        });
+        for user_id in user_ids {
+            let Some(mut channel) = self.users.get_mut(&user_id) else {
+                continue;
+            };
+            let record = if silent.contains(&user_id) {
+                &silent_record
+            } else {
+                &record
+            };
+            channel.push(record.clone(), self.history_size);
+            channel
+                .connections
+                .retain(|conn| match conn.tx.try_send(record.clone()) {
+                    Ok(()) => true,
+                    Err(TrySendError::Full(_)) => {
+                        warn!(
+                            "Disconnected slow connection {} of user {}",
+                            conn.id, user_id
+                        );
+                        self.slow_disconnects.fetch_add(1, Ordering::Relaxed);
+                        false
+                    }
+                    Err(TrySendError::Closed(_)) => false,
+                });
+            if channel.connections.is_empty() && channel.idle_since.is_none() {
+                channel.idle_since = Some(Instant::now());
+            }
+        }
+        record
+    }
+
+    pub fn connection_count(&self, user_id: i64) -> usize {
+        self.users
+            .get(&user_id)
+            .map_or(0, |channel| channel.connections.len())
+    }
+
+    /// Users with at least one live connection
+    pub fn connected_users(&self) -> Vec<i64> {
+        self.users
+            .iter()
+            .filter(|channel| !channel.connections.is_empty())
+            .map(|channel| *channel.key())
+            .collect()
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        let (mut users, mut connections) = (0, 0);
+        for channel in self.users.iter() {
+            users += usize::from(!channel.connections.is_empty());
+            connections += channel.connections.len();
+        }
+        ConnectionStats {
+            users,
+            connections,
+            slow_disconnects: self.slow_disconnects.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Forget the history of users without connections for longer than `idle`
+    pub fn prune_idle(&self, idle: Duration) {
+        self.users.retain(|_, channel| {
+            !channel.connections.is_empty()
+                || channel
+                    .idle_since
+                    .is_none_or(|since| since.elapsed() < idle)
+        });
+    }
+
+    fn unregister(&self, user_id: i64, id: u64) {
+        if let Some(mut channel) = self.users.get_mut(&user_id) {
+            channel.connections.retain(|conn| conn.id != id);
+            if channel.connections.is_empty() {
+                channel.idle_since = Some(Instant::now());
+            }
+        }
+    }
+}
+
+impl UserChannel {
+    fn new(since: u64) -> Self {
+        Self {
+            connections: vec![],
+            events: VecDeque::new(),
+            since,
+            idle_since: None,
+        }
+    }
+
+    fn push(&mut self, record: Arc<EventRecord>, history_size: usize) {
+        self.events.push_back(record);
+        while self.events.len() > history_size {
+            if let Some(evicted) = self.events.pop_front() {
+                self.since = evicted.id;
+            }
+        }
+    }
+
+    fn replay(&self, last: Option<LastEvent>) -> Replay {
+        let latest = self.events.back().map_or(self.since, |e| e.id);
+        match last {
+            None => Replay::Nothing,
+            Some(LastEvent::Foreign) => Replay::Resync(latest),
+            Some(LastEvent::Id(id)) if id < self.since => Replay::Resync(latest),
+            Some(LastEvent::Id(id)) => {
+                Replay::Events(self.events.iter().filter(|e| e.id > id).cloned().collect())
+            }
+        }
+    }
+}
+
+impl Subscription {
+    /// The next live event, None once the connection was dropped for being too slow
+    pub async fn recv(&mut self) -> Option<Arc<EventRecord>> {
+        self.rx.recv().await
+    }
+
+    pub fn try_recv(&mut self) -> Result<Arc<EventRecord>, TryRecvError> {
+        self.rx.try_recv()
+    }
+
+    /// Live events, the connection stays registered while the stream is alive
+    pub fn into_stream(self) -> impl Stream<Item = Arc<EventRecord>> + Send {
+        let Self { rx, guard, .. } = self;
+        ReceiverStream::new(rx).map(move |record| {
+            let _ = &guard;
+            record
+        })
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.manager.unregister(self.user_id, self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Typing;
+
+    fn typing(chat_id: i64) -> AppEvent {
+        AppEvent::Typing(Typing {
+            chat_id,
+            user_id: 1,
+        })
+    }
+
+    fn after(id: u64) -> Option<LastEvent> {
+        Some(LastEvent::Id(id))
+    }
+
+    fn ids(replay: Replay) -> Vec<u64> {
+        match replay {
+            Replay::Events(events) => events.iter().map(|e| e.id).collect(),
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn subscribe_should_replay_missed_events() {
+        let manager = Arc::new(ConnectionManager::new(8, 3, 11));
+        drop(manager.subscribe(1, None));
+        for chat_id in 11..=15 {
+            manager.send([1], typing(chat_id));
+        }
+        // 11 and 12 were evicted
+        assert_eq!(manager.subscribe(1, after(11)).replay, Replay::Resync(15));
+        assert_eq!(ids(manager.subscribe(1, after(12)).replay), [13, 14, 15]);
+        assert_eq!(ids(manager.subscribe(1, after(14)).replay), [15]);
+        assert_eq!(manager.subscribe(1, None).replay, Replay::Nothing);
+        // from before the user was known here
+        assert_eq!(manager.subscribe(2, after(5)).replay, Replay::Resync(15));
+    }
+
+    #[test]
+    fn event_ids_of_other_instances_should_resync() {
+        let manager = Arc::new(ConnectionManager::new(8, 8, 1));
+        let other = ConnectionManager::new(8, 8, 1);
+        drop(manager.subscribe(1, None));
+        for chat_id in 1..=3 {
+            manager.send([1], typing(chat_id));
+        }
+
+        let ours = manager.parse_event_id(&manager.event_id(2));
+        assert_eq!(ours, LastEvent::Id(2));
+        assert_eq!(ids(manager.subscribe(1, Some(ours)).replay), [3]);
+
+        // the same number from another instance says nothing about what was missed here
+        for id in [other.event_id(2), "2".to_string(), "x-2".to_string()] {
+            let last = manager.parse_event_id(&id);
+            assert_eq!(last, LastEvent::Foreign);
+            assert_eq!(manager.subscribe(1, Some(last)).replay, Replay::Resync(3));
+        }
+    }
+
+    #[test]
+    fn silent_users_should_get_a_flagged_copy() {
+        let manager = Arc::new(ConnectionManager::new(8, 8, 1));
+        let mut loud = manager.subscribe(1, None);
+        let mut quiet = manager.subscribe(2, None);
+        let record = manager.send_to([1, 2], &HashSet::from([2]), typing(1));
+        let (loud, quiet) = (loud.try_recv().unwrap(), quiet.try_recv().unwrap());
+        assert!(!loud.silent);
+        assert!(quiet.silent);
+        assert_eq!(quiet.id, record.id);
+        // a reconnect replays the same flag
+        assert!(ids_silent(manager.subscribe(2, after(0)).replay));
+    }
+
+    fn ids_silent(replay: Replay) -> bool {
+        match replay {
+            Replay::Events(events) => events.iter().all(|e| e.silent),
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn connections_should_be_tracked_and_slow_ones_dropped() {
+        let manager = Arc::new(ConnectionManager::new(2, 8, 1));
+        let mut fast = manager.subscribe(1, None);
+        let mut slow = manager.subscribe(1, None);
+        let other = manager.subscribe(2, None);
+        assert_eq!(manager.connection_count(1), 2);
+
+        for chat_id in 1..=3 {
+            manager.send([1], typing(chat_id));
+            assert!(fast.try_recv().is_ok());
+        }
+        // the third event didn't fit, the slow connection is closed after the queued ones
+        assert_eq!(manager.connection_count(1), 1);
+        assert!(slow.try_recv().is_ok());
+        assert!(slow.try_recv().is_ok());
+        assert_eq!(slow.try_recv(), Err(TryRecvError::Disconnected));
+        assert_eq!(
+            manager.stats(),
+            ConnectionStats {
+                users: 2,
+                connections: 2,
+                slow_disconnects: 1
+            }
+        );
+
+        drop(other);
+        assert_eq!(manager.connection_count(2), 0);
+        manager.prune_idle(Duration::from_secs(60));
+        assert!(manager.users.contains_key(&2));
+        manager.prune_idle(Duration::ZERO);
+        assert!(!manager.users.contains_key(&2));
+        assert!(manager.users.contains_key(&1));
+    }
+}
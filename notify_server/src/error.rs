@@ -0,0 +1,45 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum AppError {
+    #[error("jwt error: {0}")]
+    JwtError(#[from] jwt_simple::Error),
+
+    #[error("sql error: {0}")]
+    SqlxError(#[from] sqlx::Error),
+
+    #[error("io error: {0}")]
+    IoError(#[from] std::io::Error),
+
+    #[error("invalid notification: {0}")]
+    InvalidNotification(#[from] serde_json::Error),
+
+    #[error("permission denied: {0}")]
+    PermissionDenied(String),
+
+    #[error("push error: {0}")]
+    PushError(String),
+
+    #[error("http error: {0}")]
+    HttpError(#[from] reqwest::Error),
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            AppError::JwtError(_) => StatusCode::FORBIDDEN,
+            AppError::PermissionDenied(_) => StatusCode::FORBIDDEN,
+            AppError::PushError(_) => StatusCode::BAD_REQUEST,
+            AppError::InvalidNotification(_) => StatusCode::BAD_REQUEST,
+            AppError::SqlxError(_) | AppError::IoError(_) | AppError::HttpError(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+        (status, Json(self.to_string())).into_response()
+    }
+}
@@ -0,0 +1,236 @@
+use std::collections::HashSet;
+
+use chat_core::{Chat, Message, UserPresence};
+use serde::{Deserialize, Serialize};
+
+use crate::AppError;
+
+/// What clients receive, the variant is the SSE event name
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "event")]
+pub enum AppEvent {
+    NewChat(Chat),
+    UpdateChat(Chat),
+    RemoveChat(Chat),
+    // to members added to a chat that already existed
+    ChatJoined(Chat),
+    // to members removed from a chat, carries the chat as they last saw it
+    ChatLeft(Chat),
+    NewMessage(Message),
+    Typing(Typing),
+    TypingStopped(Typing),
+    PresenceChanged(UserPresence),
+}
+
+/// An event as sent, ids only ever grow so clients can resume after the last one they saw
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct EventRecord {
+    // the events carry their own `id`
+    #[serde(rename = "event_id")]
+    pub id: u64,
+    #[serde(flatten)]
+    pub event: AppEvent,
+    // to be synced without notifying the user, following their notification settings
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub silent: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Typing {
+    pub chat_id: i64,
+    pub user_id: i64,
+}
+
+/// An event and the users it goes to
+#[derive(Debug)]
+pub(crate) struct Notification {
+    pub(crate) user_ids: HashSet<i64>,
+    pub(crate) event: AppEvent,
+    // recipients that only sync it
+    pub(crate) silent: HashSet<i64>,
+}
+
+// payload of the `chat_updated` trigger
+#[derive(Debug, Deserialize)]
+struct ChatUpdated {
+    op: String,
+    old: Option<Chat>,
+    new: Option<Chat>,
+}
+
+// payload of the `chat_message_created` trigger
+#[derive(Debug, Deserialize)]
+struct ChatMessageCreated {
+    message: Message,
+    members: Vec<i64>,
+}
+
+impl AppEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AppEvent::NewChat(_) => "NewChat",
+            AppEvent::UpdateChat(_) => "UpdateChat",
+            AppEvent::RemoveChat(_) => "RemoveChat",
+            AppEvent::ChatJoined(_) => "ChatJoined",
+            AppEvent::ChatLeft(_) => "ChatLeft",
+            AppEvent::NewMessage(_) => "NewMessage",
+            AppEvent::Typing(_) => "Typing",
+            AppEvent::TypingStopped(_) => "TypingStopped",
+            AppEvent::PresenceChanged(_) => "PresenceChanged",
+        }
+    }
+
+    /// None for events about the workspace rather than a chat
+    pub fn chat_id(&self) -> Option<i64> {
+        match self {
+            AppEvent::NewChat(chat)
+            | AppEvent::UpdateChat(chat)
+            | AppEvent::RemoveChat(chat)
+            | AppEvent::ChatJoined(chat)
+            | AppEvent::ChatLeft(chat) => Some(chat.id),
+            AppEvent::NewMessage(message) => Some(message.chat_id),
+            AppEvent::Typing(typing) | AppEvent::TypingStopped(typing) => Some(typing.chat_id),
+            AppEvent::PresenceChanged(_) => None,
+        }
+    }
+
+    /// Whether the user gained or lost the chat, which matters before they follow it
+    pub fn changes_membership(&self) -> bool {
+        matches!(
+            self,
+            AppEvent::NewChat(_)
+                | AppEvent::RemoveChat(_)
+                | AppEvent::ChatJoined(_)
+                | AppEvent::ChatLeft(_)
+        )
+    }
+}
+
+impl Notification {
+    pub(crate) fn new(user_ids: impl IntoIterator<Item = i64>, event: AppEvent) -> Self {
+        Self {
+            user_ids: user_ids.into_iter().collect(),
+            event,
+            silent: HashSet::new(),
+        }
+    }
+
+    // empty for events nobody needs to hear about
+    pub(crate) fn load(kind: &str, payload: &str) -> Result<Vec<Self>, AppError> {
+        match kind {
+            "chat_updated" => {
+                let ChatUpdated { op, old, new } = serde_json::from_str(payload)?;
+                Ok(match (op.as_str(), old, new) {
+                    ("INSERT", _, Some(chat)) => {
+                        vec![Self::new(chat.members.clone(), AppEvent::NewChat(chat))]
+                    }
+                    ("UPDATE", Some(old), Some(new)) => Self::chat_changed(old, new),
+                    ("DELETE", Some(chat), _) => {
+                        vec![Self::new(chat.members.clone(), AppEvent::RemoveChat(chat))]
+                    }
+                    _ => vec![],
+                })
+            }
+            "chat_message_created" => {
+                let ChatMessageCreated { message, members } = serde_json::from_str(payload)?;
+                Ok(vec![Self::new(members, AppEvent::NewMessage(message))])
+            }
+            _ => Ok(vec![]),
+        }
+    }
+
+    // members diffed between the rows, everyone hears about the chat in their own terms
+    fn chat_changed(old: Chat, new: Chat) -> Vec<Self> {
+        let before: HashSet<i64> = old.members.iter().copied().collect();
+        let after: HashSet<i64> = new.members.iter().copied().collect();
+        let joined: Vec<i64> = after.difference(&before).copied().collect();
+        let left: Vec<i64> = before.difference(&after).copied().collect();
+        let stayed: Vec<i64> = after.intersection(&before).copied().collect();
+
+        let mut notifications = vec![];
+        if !joined.is_empty() {
+            notifications.push(Self::new(joined, AppEvent::ChatJoined(new.clone())));
+        }
+        if !left.is_empty() {
+            notifications.push(Self::new(left, AppEvent::ChatLeft(old)));
+        }
+        if !stayed.is_empty() {
+            notifications.push(Self::new(stayed, AppEvent::UpdateChat(new)));
+        }
+        notifications
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn message_notification_should_go_to_members() -> Result<()> {
+        let payload = r#"{"message": {"id": 1, "chat_id": 2, "sender_id": 3, "content": "hi",
+            "files": [], "is_bot": false, "created_at": "2026-10-19T18:00:00.123456+00:00"},
+            "members": [1, 3]}"#;
+        let notification = &Notification::load("chat_message_created", payload)?[0];
+        assert_eq!(notification.user_ids, HashSet::from([1, 3]));
+        assert_eq!(notification.event.name(), "NewMessage");
+        assert_eq!(notification.event.chat_id(), Some(2));
+        Ok(())
+    }
+
+    fn chat_payload(op: &str, old: Option<&str>, new: Option<&str>) -> String {
+        let chat = |members: Option<&str>| match members {
+            Some(members) => format!(
+                r#"{{"id": 1, "ws_id": 1, "name": "general", "type": "public_channel",
+                "members": {members}, "created_at": "2026-10-19T18:00:00+00:00"}}"#
+            ),
+            None => "null".to_string(),
+        };
+        format!(
+            r#"{{"op": "{op}", "old": {}, "new": {}}}"#,
+            chat(old),
+            chat(new)
+        )
+    }
+
+    #[test]
+    fn chat_update_should_be_split_by_membership() -> Result<()> {
+        let payload = chat_payload("UPDATE", Some("[1, 2]"), Some("[2, 3, 4]"));
+        let notifications = Notification::load("chat_updated", &payload)?;
+        assert_eq!(notifications.len(), 3);
+        let AppEvent::ChatJoined(chat) = &notifications[0].event else {
+            anyhow::bail!("expected a join");
+        };
+        assert_eq!(notifications[0].user_ids, HashSet::from([3, 4]));
+        assert_eq!(chat.members, vec![2, 3, 4]);
+        let AppEvent::ChatLeft(chat) = &notifications[1].event else {
+            anyhow::bail!("expected a leave");
+        };
+        assert_eq!(notifications[1].user_ids, HashSet::from([1]));
+        assert_eq!(chat.members, vec![1, 2]);
+        assert!(matches!(notifications[2].event, AppEvent::UpdateChat(_)));
+        assert_eq!(notifications[2].user_ids, HashSet::from([2]));
+
+        // a rename keeps everyone where they were
+        let payload = chat_payload("UPDATE", Some("[1, 2]"), Some("[2, 1]"));
+        let notifications = Notification::load("chat_updated", &payload)?;
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].user_ids, HashSet::from([1, 2]));
+        assert!(matches!(notifications[0].event, AppEvent::UpdateChat(_)));
+        Ok(())
+    }
+
+    #[test]
+    fn chat_insert_and_delete_should_reach_members() -> Result<()> {
+        let payload = chat_payload("INSERT", None, Some("[1, 2]"));
+        let notifications = Notification::load("chat_updated", &payload)?;
+        assert_eq!(notifications[0].user_ids, HashSet::from([1, 2]));
+        assert!(matches!(notifications[0].event, AppEvent::NewChat(_)));
+
+        let payload = chat_payload("DELETE", Some("[1]"), None);
+        let notifications = Notification::load("chat_updated", &payload)?;
+        assert_eq!(notifications[0].user_ids, HashSet::from([1]));
+        assert!(matches!(notifications[0].event, AppEvent::RemoveChat(_)));
+        Ok(())
+    }
+}
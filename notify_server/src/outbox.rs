@@ -0,0 +1,405 @@
+use std::{
+    collections::BTreeMap,
+    ops::Range,
+    time::{Duration, Instant},
+};
+
+use chat_core::typing::TYPING_CHANNEL;
+use futures::StreamExt;
+use sqlx::{postgres::PgListener, FromRow};
+use tracing::{info, warn};
+
+use crate::{notif::Notification, presence::PRESENCE_CHANNEL, AppError, AppState};
+
+const CHANNEL: &str = "event_created";
+// notifications are lost while the listener reconnects, the table is polled as well
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const BATCH_SIZE: i64 = 500;
+// more missing ids than this come from a sequence jump, not transactions in flight
+const MAX_SKIPPED: usize = 1000;
+
+#[derive(Debug, FromRow)]
+struct OutboxEvent {
+    id: i64,
+    kind: String,
+    payload: String,
+}
+
+/// How far this consumer got in the outbox
+#[derive(Debug)]
+pub(crate) struct Cursor {
+    pub(crate) last_id: i64,
+    // when the id after `last_id` was first found missing
+    pub(crate) gap_since: Option<Instant>,
+    // ids passed over while missing and when, looked up again until `gap_retry_secs`
+    pub(crate) skipped: BTreeMap<i64, Instant>,
+}
+
+impl AppState {
+    /// The saved cursor, a new consumer starts from the latest event instead of replaying all
+    pub(crate) async fn load_cursor(&self) -> Result<Cursor, AppError> {
+        sqlx::query(
+            r#"
+            INSERT INTO notify_cursors (consumer, last_id)
+            SELECT $1, COALESCE(MAX(id), 0) FROM events
+            ON CONFLICT (consumer) DO NOTHING
+            "#,
+        )
+        .bind(self.config.consumer())
+        .execute(&self.pool)
+        .await?;
+        let (last_id, skipped): (i64, Vec<i64>) =
+            sqlx::query_as("SELECT last_id, skipped FROM notify_cursors WHERE consumer = $1")
+                .bind(self.config.consumer())
+                .fetch_one(&self.pool)
+                .await?;
+        let now = Instant::now();
+        Ok(Cursor {
+            last_id,
+            gap_since: None,
+            skipped: skipped.into_iter().map(|id| (id, now)).collect(),
+        })
+    }
+
+    /// Dispatch the events after the cursor in id order, then the skipped ones that showed up,
+    /// returning how many were handled
+    pub(crate) async fn dispatch_outbox(&self, cursor: &mut Cursor) -> Result<usize, AppError> {
+        let grace = Duration::from_millis(self.config.outbox.gap_grace_ms);
+        let mut handled = self.dispatch_skipped(cursor).await?;
+        loop {
+            let events: Vec<OutboxEvent> = sqlx::query_as(
+                r#"
+                SELECT id, kind, payload::text AS payload FROM events
+                WHERE id > $1
+                ORDER BY id
+                LIMIT $2
+                "#,
+            )
+            .bind(cursor.last_id)
+            .bind(BATCH_SIZE)
+            .fetch_all(&self.pool)
+            .await?;
+            let fetched = events.len();
+            let start = cursor.last_id;
+
+            for event in events {
+                if event.id != cursor.last_id + 1 {
+                    // ids are taken before commit, the transaction holding the missing one may
+                    // still be running. Once the grace is over later events go first and the
+                    // missing ids are looked up again on every round.
+                    let since = *cursor.gap_since.get_or_insert_with(Instant::now);
+                    if since.elapsed() < grace {
+                        break;
+                    }
+                    cursor.skip(cursor.last_id + 1..event.id);
+                }
+                cursor.gap_since = None;
+                self.dispatch_event(&event).await;
+                cursor.last_id = event.id;
+                handled += 1;
+            }
+
+            if cursor.last_id != start {
+                self.save_cursor(cursor).await?;
+            }
+            if fetched < BATCH_SIZE as usize || cursor.gap_since.is_some() {
+                return Ok(handled);
+            }
+        }
+    }
+
+    // skipped ids that committed since, the ones still missing after the retry time rolled back
+    async fn dispatch_skipped(&self, cursor: &mut Cursor) -> Result<usize, AppError> {
+        let retry = Duration::from_secs(self.config.outbox.gap_retry_secs);
+        let before = cursor.skipped.len();
+        cursor.skipped.retain(|id, since| {
+            let keep = since.elapsed() < retry;
+            if !keep {
+                info!("Outbox id {} never showed up, taken as rolled back", id);
+            }
+            keep
+        });
+        if cursor.skipped.is_empty() {
+            if before > 0 {
+                self.save_cursor(cursor).await?;
+            }
+            return Ok(0);
+        }
+
+        let ids: Vec<i64> = cursor.skipped.keys().copied().collect();
+        let events: Vec<OutboxEvent> = sqlx::query_as(
+            r#"
+            SELECT id, kind, payload::text AS payload FROM events
+            WHERE id = ANY($1)
+            ORDER BY id
+            "#,
+        )
+        .bind(&ids)
+        .fetch_all(&self.pool)
+        .await?;
+        for event in &events {
+            info!("Outbox id {} committed late", event.id);
+            cursor.skipped.remove(&event.id);
+            self.dispatch_event(event).await;
+        }
+        if cursor.skipped.len() != before {
+            self.save_cursor(cursor).await?;
+        }
+        Ok(events.len())
+    }
+
+    async fn dispatch_event(&self, event: &OutboxEvent) {
+        match Notification::load(&event.kind, &event.payload) {
+            Ok(notifications) => {
+                for mut notification in notifications {
+                    // better a push too many than a lost event
+                    if let Err(e) = self.apply_preferences(&mut notification).await {
+                        warn!("Apply notification settings failed: {}", e);
+                    }
+                    if let Err(e) = self.queue_pushes(event.id, &notification).await {
+                        warn!("Queue pushes for event {} failed: {}", event.id, e);
+                    }
+                    self.dispatch(notification)
+                }
+            }
+            Err(e) => warn!("Load event {} failed: {}", event.id, e),
+        }
+    }
+
+    async fn save_cursor(&self, cursor: &Cursor) -> Result<(), AppError> {
+        let skipped: Vec<i64> = cursor.skipped.keys().copied().collect();
+        sqlx::query(
+            r#"
+            UPDATE notify_cursors SET last_id = $2, skipped = $3, updated_at = CURRENT_TIMESTAMP
+            WHERE consumer = $1
+            "#,
+        )
+        .bind(self.config.consumer())
+        .bind(cursor.last_id)
+        .bind(skipped)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Remove events every consumer is done with, and consumers that are gone for good
+    pub(crate) async fn prune_outbox(&self) -> Result<u64, AppError> {
+        let retention = self.config.outbox.retention_secs as f64;
+        // a quiet consumer is still around
+        sqlx::query("UPDATE notify_cursors SET updated_at = CURRENT_TIMESTAMP WHERE consumer = $1")
+            .bind(self.config.consumer())
+            .execute(&self.pool)
+            .await?;
+        sqlx::query(
+            "DELETE FROM notify_cursors WHERE updated_at < CURRENT_TIMESTAMP - make_interval(secs => $1)",
+        )
+        .bind(retention)
+        .execute(&self.pool)
+        .await?;
+        let ret = sqlx::query(
+            r#"
+            DELETE FROM events
+            WHERE created_at < CURRENT_TIMESTAMP - make_interval(secs => $1)
+              AND id <= (SELECT COALESCE(MIN(last_id), 0) FROM notify_cursors)
+            "#,
+        )
+        .bind(retention)
+        .execute(&self.pool)
+        .await?;
+        Ok(ret.rows_affected())
+    }
+}
+
+impl Cursor {
+    fn skip(&mut self, ids: Range<i64>) {
+        let now = Instant::now();
+        let room = MAX_SKIPPED.saturating_sub(self.skipped.len());
+        if ids.end - ids.start > room as i64 {
+            warn!(
+                "Too many missing outbox ids in {:?}, only the last {} are kept",
+                ids, room
+            );
+        }
+        let start = ids.start.max(ids.end - room as i64);
+        self.skipped.extend((start..ids.end).map(|id| (id, now)));
+    }
+}
+
+/// Follow the outbox, woken up by the triggers and polling in case a notification got lost
+pub(crate) async fn setup_pg_listener(state: AppState) -> Result<(), AppError> {
+    let mut listener = PgListener::connect(&state.config.server.db_url).await?;
+    listener
+        .listen_all([CHANNEL, TYPING_CHANNEL, PRESENCE_CHANNEL])
+        .await?;
+    let mut cursor = state.load_cursor().await?;
+    info!("Dispatching events after {}", cursor.last_id);
+
+    let mut stream = listener.into_stream();
+    tokio::spawn(async move {
+        let mut pruned_at: Option<Instant> = None;
+        loop {
+            if let Err(e) = state.dispatch_outbox(&mut cursor).await {
+                warn!("Dispatch events failed: {}", e);
+            }
+            if pruned_at.is_none_or(|at| at.elapsed() >= PRUNE_INTERVAL) {
+                match state.prune_outbox().await {
+                    Ok(n) => info!("Pruned {} dispatched events", n),
+                    Err(e) => warn!("Prune events failed: {}", e),
+                }
+                pruned_at = Some(Instant::now());
+            }
+            // outbox payloads are only ids, the table is read either way
+            tokio::select! {
+                notif = stream.next() => match notif {
+                    Some(Ok(notif)) if notif.channel() == TYPING_CHANNEL => {
+                        if let Err(e) = state.handle_typing(notif.payload()).await {
+                            warn!("Handle typing failed: {}", e);
+                        }
+                    }
+                    Some(Ok(notif)) if notif.channel() == PRESENCE_CHANNEL => {
+                        if let Err(e) = state.handle_presence(notif.payload()).await {
+                            warn!("Handle presence failed: {}", e);
+                        }
+                    }
+                    Some(Err(e)) => {
+                        warn!("Receive notification failed: {}", e);
+                        tokio::time::sleep(POLL_INTERVAL).await;
+                    }
+                    _ => {}
+                },
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AppConfig, AppEvent};
+    use anyhow::Result;
+
+    async fn insert_message(state: &AppState, content: &str) -> Result<()> {
+        sqlx::query("INSERT INTO messages (chat_id, sender_id, content) VALUES (1, 1, $1)")
+            .bind(content)
+            .execute(&state.pool)
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn outbox_should_dispatch_large_events_and_keep_cursor() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        let mut rx = state.subscribe(2);
+        let mut cursor = state.load_cursor().await?;
+        assert_eq!(state.dispatch_outbox(&mut cursor).await?, 0);
+
+        // far beyond what pg_notify can carry
+        let content = "a".repeat(10_000);
+        insert_message(&state, &content).await?;
+        assert_eq!(state.dispatch_outbox(&mut cursor).await?, 1);
+        let AppEvent::NewMessage(message) = &rx.try_recv()?.event else {
+            anyhow::bail!("expected a message");
+        };
+        assert_eq!(message.content, content);
+
+        // a restart picks up where the last run stopped
+        insert_message(&state, "while restarting").await?;
+        let mut restarted = state.load_cursor().await?;
+        assert_eq!(restarted.last_id, cursor.last_id);
+        assert_eq!(state.dispatch_outbox(&mut restarted).await?, 1);
+
+        sqlx::query("UPDATE events SET created_at = created_at - interval '2 days'")
+            .execute(&state.pool)
+            .await?;
+        assert!(state.prune_outbox().await? > 0);
+        let (left,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM events")
+            .fetch_one(&state.pool)
+            .await?;
+        assert_eq!(left, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn outbox_should_tell_members_about_membership_changes() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        let mut cursor = state.load_cursor().await?;
+        let (mut stayed, mut left, mut joined) =
+            (state.subscribe(1), state.subscribe(3), state.subscribe(4));
+
+        // chat 2 goes from {1, 2, 3} to {1, 2, 4}
+        sqlx::query("UPDATE chats SET members = '{1,2,4}' WHERE id = 2")
+            .execute(&state.pool)
+            .await?;
+        assert_eq!(state.dispatch_outbox(&mut cursor).await?, 1);
+
+        let AppEvent::ChatJoined(chat) = &joined.try_recv()?.event else {
+            anyhow::bail!("expected a join");
+        };
+        assert_eq!(chat.members, vec![1, 2, 4]);
+        let AppEvent::ChatLeft(chat) = &left.try_recv()?.event else {
+            anyhow::bail!("expected a leave");
+        };
+        assert_eq!(chat.id, 2);
+        assert!(matches!(stayed.try_recv()?.event, AppEvent::UpdateChat(_)));
+        assert!(left.try_recv().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn outbox_should_wait_for_gaps() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        let mut cursor = state.load_cursor().await?;
+
+        // an id taken by a transaction that hasn't committed
+        let (late_id,): (i64,) = sqlx::query_as("SELECT nextval('events_id_seq')")
+            .fetch_one(&state.pool)
+            .await?;
+        insert_message(&state, "after the gap").await?;
+        assert_eq!(state.dispatch_outbox(&mut cursor).await?, 0);
+        assert!(cursor.gap_since.is_some());
+
+        cursor.gap_since = Some(Instant::now() - Duration::from_secs(10));
+        assert_eq!(state.dispatch_outbox(&mut cursor).await?, 1);
+        assert!(cursor.gap_since.is_none());
+        assert_eq!(cursor.skipped.keys().collect::<Vec<_>>(), [&late_id]);
+
+        // the skipped id survives a restart and is dispatched once it commits
+        let mut restarted = state.load_cursor().await?;
+        assert!(restarted.skipped.contains_key(&late_id));
+        sqlx::query(
+            "INSERT INTO events (id, kind, payload) SELECT $1, kind, payload FROM events WHERE id = $2",
+        )
+        .bind(late_id)
+        .bind(restarted.last_id)
+        .execute(&state.pool)
+        .await?;
+        assert_eq!(state.dispatch_outbox(&mut restarted).await?, 1);
+        assert!(restarted.skipped.is_empty());
+        assert_eq!(state.dispatch_outbox(&mut restarted).await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn outbox_should_give_up_on_rolled_back_ids() -> Result<()> {
+        let mut config = AppConfig::load()?;
+        config.outbox.gap_retry_secs = 0;
+        let (_tdb, state) = AppState::new_for_test(config).await?;
+        let mut cursor = state.load_cursor().await?;
+        sqlx::query("SELECT nextval('events_id_seq')")
+            .execute(&state.pool)
+            .await?;
+        insert_message(&state, "after the gap").await?;
+        cursor.gap_since = Some(Instant::now() - Duration::from_secs(10));
+        assert_eq!(state.dispatch_outbox(&mut cursor).await?, 1);
+        assert_eq!(cursor.skipped.len(), 1);
+
+        // still missing on the next round
+        assert_eq!(state.dispatch_outbox(&mut cursor).await?, 0);
+        assert!(cursor.skipped.is_empty());
+        assert!(state.load_cursor().await?.skipped.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,109 @@
+use chat_core::{
+    mention::{is_mentioned, mentions},
+    NotifyLevel,
+};
+use sqlx::FromRow;
+
+use crate::{notif::Notification, AppError, AppEvent, AppState};
+
+// a member's settings for the chat of a message
+#[derive(Debug, FromRow)]
+struct MemberSettings {
+    id: i64,
+    email: String,
+    level: NotifyLevel,
+    muted: bool,
+}
+
+impl AppState {
+    /// Mark the members a message shouldn't notify, they still get it to stay in sync
+    pub(crate) async fn apply_preferences(
+        &self,
+        notification: &mut Notification,
+    ) -> Result<(), AppError> {
+        let AppEvent::NewMessage(message) = &notification.event else {
+            return Ok(());
+        };
+        let user_ids: Vec<i64> = notification.user_ids.iter().copied().collect();
+        let members: Vec<MemberSettings> = sqlx::query_as(
+            r#"
+            SELECT u.id, u.email, COALESCE(s.level, w.notify_level) AS level,
+              COALESCE(s.muted_until > NOW(), false) AS muted
+            FROM users u
+            JOIN workspaces w ON w.id = u.ws_id
+            LEFT JOIN chat_notification_settings s ON s.chat_id = $1 AND s.user_id = u.id
+            WHERE u.id = ANY($2)
+            "#,
+        )
+        .bind(message.chat_id)
+        .bind(&user_ids)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let handles = mentions(&message.content);
+        notification.silent = members
+            .into_iter()
+            .filter(|member| {
+                // nobody is notified of their own message
+                member.id == message.sender_id
+                    || member.muted
+                    || match member.level {
+                        NotifyLevel::All => false,
+                        NotifyLevel::Mentions => !is_mentioned(&handles, &member.email),
+                        NotifyLevel::None => true,
+                    }
+            })
+            .map(|member| member.id)
+            .collect();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AppConfig;
+    use anyhow::Result;
+    use chat_core::Message;
+    use std::collections::HashSet;
+
+    fn message(content: &str) -> Result<Message> {
+        let message = serde_json::json!({"id": 1, "chat_id": 1, "sender_id": 1,
+            "content": content, "files": [], "created_at": "2026-10-19T18:00:00Z"});
+        Ok(serde_json::from_value(message)?)
+    }
+
+    #[tokio::test]
+    async fn preferences_should_pick_silent_members() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        // chat 1 has users 1 to 5, bob (3) only wants mentions, 4 muted the chat, 5 wants nothing
+        sqlx::query(
+            r#"
+            INSERT INTO chat_notification_settings (chat_id, user_id, level, muted_until)
+            VALUES (1, 3, 'mentions', NULL), (1, 4, 'all', NOW() + INTERVAL '1 hour'),
+              (1, 5, 'none', NULL)
+            "#,
+        )
+        .execute(&state.pool)
+        .await?;
+
+        let event = AppEvent::NewMessage(message("lunch?")?);
+        let mut notification = Notification::new(1..=5, event);
+        state.apply_preferences(&mut notification).await?;
+        assert_eq!(notification.silent, HashSet::from([1, 3, 4, 5]));
+
+        let event = AppEvent::NewMessage(message("lunch @bob?")?);
+        let mut notification = Notification::new(1..=5, event);
+        state.apply_preferences(&mut notification).await?;
+        assert_eq!(notification.silent, HashSet::from([1, 4, 5]));
+
+        // the workspace default applies to the rest
+        sqlx::query("UPDATE workspaces SET notify_level = 'none' WHERE id = 1")
+            .execute(&state.pool)
+            .await?;
+        let mut notification = Notification::new(1..=5, AppEvent::NewMessage(message("hi")?));
+        state.apply_preferences(&mut notification).await?;
+        assert_eq!(notification.silent, HashSet::from([1, 2, 3, 4, 5]));
+        Ok(())
+    }
+}
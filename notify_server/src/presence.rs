@@ -0,0 +1,266 @@
+use std::{
+    collections::HashSet,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use chat_core::{PresenceStatus, UserPresence};
+use dashmap::DashMap;
+use sqlx::FromRow;
+use tracing::{info, warn};
+
+use crate::{notif::Notification, AppError, AppEvent, AppState};
+
+/// A user id whose presence or status may have changed, sent by any instance and chat_server
+pub(crate) const PRESENCE_CHANNEL: &str = "presence_changed";
+
+/// Presence as last broadcast, and who did something since the last sync
+#[derive(Default)]
+pub(crate) struct PresenceTracker {
+    // offline users without a status aren't kept
+    known: DashMap<i64, UserPresence>,
+    active: Mutex<HashSet<i64>>,
+}
+
+// the users this instance has sessions for
+#[derive(Debug, Default)]
+pub(crate) struct Sessions {
+    users: HashSet<i64>,
+    beat_at: Option<Instant>,
+}
+
+#[derive(Debug, FromRow)]
+struct PresenceRow {
+    ws_id: i64,
+    #[sqlx(flatten)]
+    presence: UserPresence,
+}
+
+impl PresenceTracker {
+    /// Activity keeps the user from turning away
+    pub(crate) fn touch(&self, user_id: i64) {
+        self.active
+            .lock()
+            .expect("presence lock poisoned")
+            .insert(user_id);
+    }
+
+    fn take_active(&self) -> Vec<i64> {
+        let mut active = self.active.lock().expect("presence lock poisoned");
+        active.drain().collect()
+    }
+
+    // true when it differs from what was broadcast last
+    fn update(&self, presence: &UserPresence) -> bool {
+        let is_default = *presence == offline(presence.user_id);
+        match self.known.get(&presence.user_id) {
+            Some(known) if *known == *presence => return false,
+            None if is_default => return false,
+            _ => {}
+        }
+        if is_default {
+            self.known.remove(&presence.user_id);
+        } else {
+            self.known.insert(presence.user_id, presence.clone());
+        }
+        true
+    }
+}
+
+impl AppState {
+    /// Write this instance's sessions and activity, announcing the users it affects.
+    /// Time based changes like turning away are picked up with the heartbeat
+    pub(crate) async fn sync_presence(&self, sessions: &mut Sessions) -> Result<(), AppError> {
+        let instance = &self.config.server.instance;
+        let local: HashSet<i64> = self.connections.connected_users().into_iter().collect();
+        let joined: Vec<i64> = local.difference(&sessions.users).copied().collect();
+        let left: Vec<i64> = sessions.users.difference(&local).copied().collect();
+        let active = self.presence.take_active();
+
+        if !joined.is_empty() {
+            sqlx::query(
+                r#"
+                INSERT INTO presence_sessions (instance, user_id)
+                SELECT $1, id FROM UNNEST($2::bigint[]) id
+                ON CONFLICT (instance, user_id) DO UPDATE SET last_seen_at = CURRENT_TIMESTAMP
+                "#,
+            )
+            .bind(instance)
+            .bind(&joined)
+            .execute(&self.pool)
+            .await?;
+        }
+        if !left.is_empty() {
+            sqlx::query("DELETE FROM presence_sessions WHERE instance = $1 AND user_id = ANY($2)")
+                .bind(instance)
+                .bind(&left)
+                .execute(&self.pool)
+                .await?;
+        }
+        if !active.is_empty() {
+            sqlx::query(
+                r#"
+                INSERT INTO user_status (user_id, last_active_at)
+                SELECT id, CURRENT_TIMESTAMP FROM UNNEST($1::bigint[]) id
+                ON CONFLICT (user_id) DO UPDATE SET last_active_at = EXCLUDED.last_active_at
+                "#,
+            )
+            .bind(&active)
+            .execute(&self.pool)
+            .await?;
+        }
+        sessions.users = local;
+
+        // activity of users already online changes nothing
+        let changed: Vec<i64> = joined
+            .into_iter()
+            .chain(left)
+            .chain(active.into_iter().filter(|id| {
+                self.presence
+                    .known
+                    .get(id)
+                    .is_none_or(|p| p.presence != PresenceStatus::Online)
+            }))
+            .collect();
+        if !changed.is_empty() {
+            sqlx::query("SELECT pg_notify($1, id::text) FROM UNNEST($2::bigint[]) id")
+                .bind(PRESENCE_CHANNEL)
+                .bind(&changed)
+                .execute(&self.pool)
+                .await?;
+        }
+
+        let heartbeat = Duration::from_secs(self.config.presence.heartbeat_secs);
+        if sessions.beat_at.is_none_or(|at| at.elapsed() >= heartbeat) {
+            sqlx::query(
+                "UPDATE presence_sessions SET last_seen_at = CURRENT_TIMESTAMP WHERE instance = $1",
+            )
+            .bind(instance)
+            .execute(&self.pool)
+            .await?;
+            let known: Vec<i64> = self.presence.known.iter().map(|p| *p.key()).collect();
+            self.broadcast_presence(&known).await?;
+            sessions.beat_at = Some(Instant::now());
+        }
+        Ok(())
+    }
+
+    /// A `presence_changed` notification
+    pub(crate) async fn handle_presence(&self, payload: &str) -> Result<(), AppError> {
+        let user_id = serde_json::from_str(payload)?;
+        self.broadcast_presence(&[user_id]).await
+    }
+
+    // tell the workspace about the users whose presence differs from what it last heard
+    async fn broadcast_presence(&self, user_ids: &[i64]) -> Result<(), AppError> {
+        if user_ids.is_empty() {
+            return Ok(());
+        }
+        let rows: Vec<PresenceRow> = sqlx::query_as(
+            r#"
+            SELECT ws_id, user_id, presence, status_text, status_emoji, status_expires_at, dnd
+            FROM user_presence
+            WHERE user_id = ANY($1)
+            "#,
+        )
+        .bind(user_ids)
+        .fetch_all(&self.pool)
+        .await?;
+
+        for row in rows {
+            if !self.presence.update(&row.presence) {
+                continue;
+            }
+            let members: Vec<(i64,)> = sqlx::query_as("SELECT id FROM users WHERE ws_id = $1")
+                .bind(row.ws_id)
+                .fetch_all(&self.pool)
+                .await?;
+            let event = AppEvent::PresenceChanged(row.presence);
+            self.dispatch(Notification::new(
+                members.into_iter().map(|(id,)| id),
+                event,
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn run_presence_sync(self) {
+        // sessions left behind by an earlier run of this instance
+        let ret = sqlx::query("DELETE FROM presence_sessions WHERE instance = $1")
+            .bind(&self.config.server.instance)
+            .execute(&self.pool)
+            .await;
+        if let Err(e) = ret {
+            warn!("Clear presence sessions failed: {}", e);
+        }
+        info!("Syncing presence as {}", self.config.server.instance);
+
+        let mut sessions = Sessions::default();
+        let mut interval =
+            tokio::time::interval(Duration::from_millis(self.config.presence.sync_ms));
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.sync_presence(&mut sessions).await {
+                warn!("Sync presence failed: {}", e);
+            }
+        }
+    }
+}
+
+fn offline(user_id: i64) -> UserPresence {
+    UserPresence {
+        user_id,
+        presence: PresenceStatus::Offline,
+        status_text: None,
+        status_emoji: None,
+        status_expires_at: None,
+        dnd: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AppConfig;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn presence_should_follow_connections_and_activity() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        let mut sessions = Sessions::default();
+        let mut rx = state.subscribe(2);
+        // only connecting, not active
+        state.presence.take_active();
+
+        let sub = state.connections.subscribe(1, None);
+        state.sync_presence(&mut sessions).await?;
+        state.handle_presence("1").await?;
+        let AppEvent::PresenceChanged(presence) = &rx.try_recv()?.event else {
+            anyhow::bail!("expected presence");
+        };
+        assert_eq!(presence.user_id, 1);
+        assert_eq!(presence.presence, PresenceStatus::Away);
+
+        state.presence.touch(1);
+        state.sync_presence(&mut sessions).await?;
+        state.handle_presence("1").await?;
+        let AppEvent::PresenceChanged(presence) = &rx.try_recv()?.event else {
+            anyhow::bail!("expected presence");
+        };
+        assert_eq!(presence.presence, PresenceStatus::Online);
+
+        // nothing changed
+        state.handle_presence("1").await?;
+        assert!(rx.try_recv().is_err());
+
+        drop(sub);
+        state.sync_presence(&mut sessions).await?;
+        state.handle_presence("1").await?;
+        let AppEvent::PresenceChanged(presence) = &rx.try_recv()?.event else {
+            anyhow::bail!("expected presence");
+        };
+        assert_eq!(presence.presence, PresenceStatus::Offline);
+        assert!(state.presence.known.is_empty());
+        Ok(())
+    }
+}
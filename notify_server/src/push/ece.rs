@@ -0,0 +1,126 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit},
+    Aes128Gcm, Nonce,
+};
+use hkdf::Hkdf;
+use p256::{ecdh::EphemeralSecret, elliptic_curve::sec1::ToEncodedPoint, PublicKey};
+use rand::{rngs::OsRng, RngCore};
+use sha2::Sha256;
+
+use crate::AppError;
+
+// a push message is a single record
+const RECORD_SIZE: u32 = 4096;
+// salt, record size, key id length and the 65 byte key id
+const HEADER_SIZE: usize = 16 + 4 + 1 + 65;
+// the padding delimiter and the tag
+const OVERHEAD: usize = 1 + 16;
+/// Largest payload `encrypt` accepts
+pub(crate) const MAX_PAYLOAD_SIZE: usize = RECORD_SIZE as usize - HEADER_SIZE - OVERHEAD;
+
+/// Encrypt a payload for a subscription's keys, RFC 8291 on the `aes128gcm` coding of RFC 8188
+pub(crate) fn encrypt(
+    ua_public: &[u8],
+    auth_secret: &[u8],
+    payload: &[u8],
+) -> Result<Vec<u8>, AppError> {
+    if payload.len() > MAX_PAYLOAD_SIZE {
+        return Err(AppError::PushError("payload too large".to_string()));
+    }
+    let ua_key = PublicKey::from_sec1_bytes(ua_public)
+        .map_err(|_| AppError::PushError("invalid p256dh key".to_string()))?;
+    let as_secret = EphemeralSecret::random(&mut OsRng);
+    let as_public = as_secret.public_key().to_encoded_point(false);
+    let shared = as_secret.diffie_hellman(&ua_key);
+
+    let mut key_info = b"WebPush: info\0".to_vec();
+    key_info.extend_from_slice(ua_public);
+    key_info.extend_from_slice(as_public.as_bytes());
+    let mut ikm = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(auth_secret), shared.raw_secret_bytes())
+        .expand(&key_info, &mut ikm)
+        .expect("32 bytes is a valid hkdf length");
+
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    let (cek, nonce) = derive_keys(&salt, &ikm);
+    // a single, last record without padding
+    let mut plaintext = payload.to_vec();
+    plaintext.push(2);
+    let ciphertext = Aes128Gcm::new(&cek.into())
+        .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+        .map_err(|_| AppError::PushError("encryption failed".to_string()))?;
+
+    let mut body = Vec::with_capacity(HEADER_SIZE + ciphertext.len());
+    body.extend_from_slice(&salt);
+    body.extend_from_slice(&RECORD_SIZE.to_be_bytes());
+    body.push(as_public.len() as u8);
+    body.extend_from_slice(as_public.as_bytes());
+    body.extend_from_slice(&ciphertext);
+    Ok(body)
+}
+
+// content encryption key and nonce of the record
+fn derive_keys(salt: &[u8], ikm: &[u8]) -> ([u8; 16], [u8; 12]) {
+    let hk = Hkdf::<Sha256>::new(Some(salt), ikm);
+    let (mut cek, mut nonce) = ([0u8; 16], [0u8; 12]);
+    hk.expand(b"Content-Encoding: aes128gcm\0", &mut cek)
+        .expect("16 bytes is a valid hkdf length");
+    hk.expand(b"Content-Encoding: nonce\0", &mut nonce)
+        .expect("12 bytes is a valid hkdf length");
+    (cek, nonce)
+}
+
+/// What a browser does with a push message, for the stand-in push service of the tests
+#[cfg(test)]
+pub(crate) fn decrypt(
+    ua_secret: &p256::SecretKey,
+    auth_secret: &[u8],
+    body: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    let (salt, rest) = body.split_at(16);
+    let id_len = rest[4] as usize;
+    let (as_public, ciphertext) = rest[5..].split_at(id_len);
+    let shared = p256::ecdh::diffie_hellman(
+        ua_secret.to_nonzero_scalar(),
+        PublicKey::from_sec1_bytes(as_public)?.as_affine(),
+    );
+    let ua_public = ua_secret.public_key().to_encoded_point(false);
+
+    let mut key_info = b"WebPush: info\0".to_vec();
+    key_info.extend_from_slice(ua_public.as_bytes());
+    key_info.extend_from_slice(as_public);
+    let mut ikm = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(auth_secret), shared.raw_secret_bytes())
+        .expand(&key_info, &mut ikm)
+        .map_err(|e| anyhow::anyhow!("{e}"))?;
+    let (cek, nonce) = derive_keys(salt, &ikm);
+    let mut plaintext = Aes128Gcm::new(&cek.into())
+        .decrypt(Nonce::from_slice(&nonce), ciphertext)
+        .map_err(|_| anyhow::anyhow!("decryption failed"))?;
+    anyhow::ensure!(plaintext.pop() == Some(2), "not a last record");
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn encrypted_payload_should_decrypt() -> Result<()> {
+        let ua_secret = p256::SecretKey::random(&mut OsRng);
+        let ua_public = ua_secret.public_key().to_encoded_point(false);
+        let auth = [7u8; 16];
+
+        let body = encrypt(ua_public.as_bytes(), &auth, b"hello")?;
+        assert_eq!(&body[16..20], &4096u32.to_be_bytes());
+        assert_eq!(body[20], 65);
+        assert_eq!(decrypt(&ua_secret, &auth, &body)?, b"hello");
+
+        // another auth secret can't read it
+        assert!(decrypt(&ua_secret, &[8u8; 16], &body).is_err());
+        assert!(encrypt(ua_public.as_bytes(), &auth, &[0u8; 4096]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,521 @@
+mod ece;
+
+use std::time::Duration as StdDuration;
+
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Extension, Json};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chat_core::{
+    utils::{check_public_url, public_client},
+    User,
+};
+use jwt_simple::prelude::*;
+use sqlx::FromRow;
+use tracing::{info, warn};
+
+use crate::{notif::Notification, AppError, AppEvent, AppState, PushConfig};
+
+// what fits in a push message next to the rest of the payload
+const MAX_BODY_CHARS: usize = 1000;
+
+/// Sends VAPID signed, encrypted Web Push messages
+pub(crate) struct PushClient {
+    http: reqwest::Client,
+    key: ES256KeyPair,
+    // uncompressed point, base64url, the `applicationServerKey` of browsers
+    public_key: String,
+    subject: String,
+    ttl_secs: u64,
+    allow_private: bool,
+}
+
+/// A browser's `PushSubscription.toJSON()`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PushSubscription {
+    pub endpoint: String,
+    pub keys: PushKeys,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PushKeys {
+    pub p256dh: String,
+    pub auth: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemovePushSubscription {
+    pub endpoint: String,
+}
+
+/// What a push message carries, the client fetches the rest when opened
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PushMessage {
+    pub chat_id: i64,
+    pub message_id: i64,
+    pub sender_id: i64,
+    pub title: String,
+    pub body: String,
+}
+
+#[derive(Debug, FromRow)]
+struct StoredSubscription {
+    id: i64,
+    endpoint: String,
+    p256dh: String,
+    auth: String,
+}
+
+#[derive(Debug, PartialEq)]
+enum PushOutcome {
+    Delivered,
+    // the push service no longer knows the subscription
+    Gone,
+}
+
+impl PushClient {
+    /// None when no VAPID key is configured
+    pub(crate) fn new(config: &PushConfig) -> anyhow::Result<Option<Self>> {
+        let Some(pem) = &config.vapid_key else {
+            return Ok(None);
+        };
+        let key = ES256KeyPair::from_pem(pem)?;
+        let public_key =
+            URL_SAFE_NO_PAD.encode(key.public_key().public_key().to_bytes_uncompressed());
+        Ok(Some(Self {
+            http: public_client(config.allow_private_endpoints),
+            key,
+            public_key,
+            subject: config.subject.clone(),
+            ttl_secs: config.ttl_secs,
+            allow_private: config.allow_private_endpoints,
+        }))
+    }
+
+    async fn send(
+        &self,
+        sub: &StoredSubscription,
+        payload: &[u8],
+    ) -> Result<PushOutcome, AppError> {
+        let body = ece::encrypt(&decode_key(&sub.p256dh)?, &decode_key(&sub.auth)?, payload)?;
+        let url = check_public_url(&sub.endpoint, self.allow_private)
+            .await
+            .map_err(AppError::PushError)?;
+        // the token is only good for the push service it was made for
+        let claims = Claims::create(Duration::from_hours(12))
+            .with_audience(url.origin().ascii_serialization())
+            .with_subject(&self.subject);
+        let token = self.key.sign(claims)?;
+
+        let res = self
+            .http
+            .post(url)
+            .timeout(StdDuration::from_secs(10))
+            .header("TTL", self.ttl_secs)
+            .header("Content-Encoding", "aes128gcm")
+            .header("Content-Type", "application/octet-stream")
+            .header(
+                "Authorization",
+                format!("vapid t={token}, k={}", self.public_key),
+            )
+            .body(body)
+            .send()
+            .await?;
+        match res.status() {
+            status if status.is_success() => Ok(PushOutcome::Delivered),
+            StatusCode::NOT_FOUND | StatusCode::GONE => Ok(PushOutcome::Gone),
+            status => Err(AppError::PushError(format!(
+                "push service returned {status}"
+            ))),
+        }
+    }
+}
+
+// browsers hand out base64url, sometimes padded
+fn decode_key(key: &str) -> Result<Vec<u8>, AppError> {
+    URL_SAFE_NO_PAD
+        .decode(key.trim_end_matches('='))
+        .map_err(|_| AppError::PushError("invalid subscription key".to_string()))
+}
+
+impl AppState {
+    /// Register a device, an endpoint already known moves to this user
+    pub(crate) async fn save_push_subscription(
+        &self,
+        user_id: i64,
+        sub: &PushSubscription,
+    ) -> Result<(), AppError> {
+        if decode_key(&sub.keys.p256dh)?.len() != 65 || decode_key(&sub.keys.auth)?.len() != 16 {
+            return Err(AppError::PushError("invalid subscription key".to_string()));
+        }
+        // notify_server posts to it, so it must not lead into the internal network
+        check_public_url(&sub.endpoint, self.config.push.allow_private_endpoints)
+            .await
+            .map_err(AppError::PushError)?;
+        sqlx::query(
+            r#"
+            INSERT INTO push_subscriptions (user_id, endpoint, p256dh, auth)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (endpoint) DO UPDATE
+            SET user_id = EXCLUDED.user_id, p256dh = EXCLUDED.p256dh, auth = EXCLUDED.auth
+            "#,
+        )
+        .bind(user_id)
+        .bind(&sub.endpoint)
+        .bind(&sub.keys.p256dh)
+        .bind(&sub.keys.auth)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub(crate) async fn delete_push_subscription(
+        &self,
+        user_id: i64,
+        endpoint: &str,
+    ) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM push_subscriptions WHERE user_id = $1 AND endpoint = $2")
+            .bind(user_id)
+            .bind(endpoint)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Push a message to the recipients it should notify that aren't connected anywhere.
+    /// Every instance sees the event, the one claiming it for a user does the pushing
+    pub(crate) async fn queue_pushes(
+        &self,
+        event_id: i64,
+        notification: &Notification,
+    ) -> Result<(), AppError> {
+        if self.push.is_none() {
+            return Ok(());
+        }
+        let AppEvent::NewMessage(message) = &notification.event else {
+            return Ok(());
+        };
+        let user_ids: Vec<i64> = notification
+            .user_ids
+            .difference(&notification.silent)
+            .copied()
+            .filter(|&id| self.connections.connection_count(id) == 0)
+            .collect();
+        if user_ids.is_empty() {
+            return Ok(());
+        }
+
+        let claimed: Vec<(i64,)> = sqlx::query_as(
+            r#"
+            INSERT INTO push_claims (event_id, user_id)
+            SELECT $1, u.id FROM UNNEST($2::bigint[]) AS u(id)
+            WHERE EXISTS (SELECT 1 FROM push_subscriptions s WHERE s.user_id = u.id)
+              AND NOT EXISTS (
+                SELECT 1 FROM presence_sessions ps
+                WHERE ps.user_id = u.id AND ps.last_seen_at > NOW() - INTERVAL '90 seconds'
+              )
+            ON CONFLICT DO NOTHING
+            RETURNING user_id
+            "#,
+        )
+        .bind(event_id)
+        .bind(&user_ids)
+        .fetch_all(&self.pool)
+        .await?;
+        if claimed.is_empty() {
+            return Ok(());
+        }
+        let claimed: Vec<i64> = claimed.into_iter().map(|(id,)| id).collect();
+        let subs: Vec<StoredSubscription> = sqlx::query_as(
+            "SELECT id, endpoint, p256dh, auth FROM push_subscriptions WHERE user_id = ANY($1)",
+        )
+        .bind(&claimed)
+        .fetch_all(&self.pool)
+        .await?;
+        let (title,): (String,) = sqlx::query_as("SELECT fullname FROM users WHERE id = $1")
+            .bind(message.sender_id)
+            .fetch_one(&self.pool)
+            .await?;
+
+        let body = if message.content.is_empty() {
+            "sent a file".to_string()
+        } else {
+            message.content.chars().take(MAX_BODY_CHARS).collect()
+        };
+        let payload = encode_payload(PushMessage {
+            chat_id: message.chat_id,
+            message_id: message.id,
+            sender_id: message.sender_id,
+            title,
+            body,
+        })?;
+        info!("Pushing message {} to {} devices", message.id, subs.len());
+        let state = self.clone();
+        // push services can be slow, the outbox moves on
+        tokio::spawn(async move {
+            let Some(client) = &state.push else {
+                return;
+            };
+            for sub in subs {
+                match client.send(&sub, &payload).await {
+                    Ok(outcome) => state.record_push(&sub, outcome).await,
+                    Err(e) => warn!("Push to subscription {} failed: {}", sub.id, e),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    async fn record_push(&self, sub: &StoredSubscription, outcome: PushOutcome) {
+        let sql = match outcome {
+            PushOutcome::Delivered => {
+                "UPDATE push_subscriptions SET last_used_at = CURRENT_TIMESTAMP WHERE id = $1"
+            }
+            PushOutcome::Gone => "DELETE FROM push_subscriptions WHERE id = $1",
+        };
+        if let Err(e) = sqlx::query(sql).bind(sub.id).execute(&self.pool).await {
+            warn!("Update subscription {} failed: {}", sub.id, e);
+        }
+    }
+}
+
+// the serialized message must fit in one record, multibyte chars and json escapes take
+// more room than the char count suggests, so the body is cut until it does
+fn encode_payload(mut message: PushMessage) -> Result<Vec<u8>, AppError> {
+    loop {
+        let payload = serde_json::to_vec(&message)?;
+        let excess = payload.len().saturating_sub(ece::MAX_PAYLOAD_SIZE);
+        if excess == 0 {
+            return Ok(payload);
+        }
+        if message.body.is_empty() {
+            return Err(AppError::PushError("payload too large".to_string()));
+        }
+        let mut end = message.body.len().saturating_sub(excess);
+        while !message.body.is_char_boundary(end) {
+            end -= 1;
+        }
+        message.body.truncate(end);
+    }
+}
+
+// Path: GET /push/key
+// VAPID 公钥, 浏览器订阅时作为 applicationServerKey
+pub(crate) async fn push_key_handler(State(state): State<AppState>) -> impl IntoResponse {
+    match &state.push {
+        Some(client) => {
+            Json(serde_json::json!({ "public_key": client.public_key })).into_response()
+        }
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+// Path: POST /push/subscriptions
+// 保存当前设备的推送订阅, 用户没有在线连接时通过 Web Push 通知新消息
+pub(crate) async fn create_push_subscription_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<PushSubscription>,
+) -> Result<impl IntoResponse, AppError> {
+    state.save_push_subscription(user.id, &input).await?;
+    Ok(StatusCode::CREATED)
+}
+
+// Path: DELETE /push/subscriptions
+// 删除当前设备的推送订阅
+pub(crate) async fn delete_push_subscription_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<RemovePushSubscription>,
+) -> Result<impl IntoResponse, AppError> {
+    state
+        .delete_push_subscription(user.id, &input.endpoint)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AppConfig;
+    use anyhow::Result;
+    use axum::{body::Bytes, extract::Path, http::HeaderMap, routing::post, Router};
+    use p256::elliptic_curve::sec1::ToEncodedPoint;
+    use rand::{rngs::OsRng, RngCore};
+    use tokio::{net::TcpListener, sync::mpsc};
+
+    // a push service on localhost, forwarding what it gets
+    async fn push_service() -> Result<(String, mpsc::UnboundedReceiver<(String, HeaderMap, Bytes)>)>
+    {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let app = Router::new().route(
+            "/push/:device",
+            post(
+                move |Path(device): Path<String>, headers: HeaderMap, body: Bytes| {
+                    let tx = tx.clone();
+                    async move {
+                        let gone = device == "gone";
+                        let _ = tx.send((device, headers, body));
+                        if gone {
+                            StatusCode::GONE
+                        } else {
+                            StatusCode::CREATED
+                        }
+                    }
+                },
+            ),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        Ok((format!("http://{addr}"), rx))
+    }
+
+    fn device(origin: &str, name: &str) -> (p256::SecretKey, [u8; 16], PushSubscription) {
+        let secret = p256::SecretKey::random(&mut OsRng);
+        let mut auth = [0u8; 16];
+        OsRng.fill_bytes(&mut auth);
+        let sub = PushSubscription {
+            endpoint: format!("{origin}/push/{name}"),
+            keys: PushKeys {
+                p256dh: URL_SAFE_NO_PAD.encode(secret.public_key().to_encoded_point(false)),
+                auth: URL_SAFE_NO_PAD.encode(auth),
+            },
+        };
+        (secret, auth, sub)
+    }
+
+    #[test]
+    fn long_multibyte_bodies_should_fit_in_a_record() -> Result<()> {
+        for text in ["😀", "中", "\u{1}"] {
+            let body: String = text.repeat(MAX_BODY_CHARS);
+            let message = PushMessage {
+                chat_id: 1,
+                message_id: 1,
+                sender_id: 1,
+                title: "Tyr Chen".to_string(),
+                body: body.clone(),
+            };
+            let payload = encode_payload(message)?;
+            assert!(payload.len() <= ece::MAX_PAYLOAD_SIZE);
+            let decoded: PushMessage = serde_json::from_slice(&payload)?;
+            assert!(!decoded.body.is_empty());
+            assert!(body.starts_with(&decoded.body));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn offline_members_should_get_encrypted_pushes() -> Result<()> {
+        let mut config = AppConfig::load()?;
+        config.push.vapid_key = Some(ES256KeyPair::generate().to_pem()?);
+        config.push.allow_private_endpoints = true;
+        let (_tdb, state) = AppState::new_for_test(config).await?;
+        let (origin, mut pushes) = push_service().await?;
+
+        // 2 is offline, 3 moved on from its device, 4 is connected here and 5 muted the chat
+        let (secret, auth, sub) = device(&origin, "alice");
+        state.save_push_subscription(2, &sub).await?;
+        state
+            .save_push_subscription(3, &device(&origin, "gone").2)
+            .await?;
+        state
+            .save_push_subscription(4, &device(&origin, "four").2)
+            .await?;
+        state
+            .save_push_subscription(5, &device(&origin, "five").2)
+            .await?;
+        let _conn = state.subscribe(4);
+
+        let message = serde_json::json!({"id": 9, "chat_id": 1, "sender_id": 1,
+            "content": "lunch?", "files": [], "created_at": "2026-10-19T18:00:00Z"});
+        let mut notification = Notification::new(
+            1..=5,
+            AppEvent::NewMessage(serde_json::from_value(message)?),
+        );
+        notification.silent = HashSet::from([1, 5]);
+        let (event_id,): (i64,) =
+            sqlx::query_as("INSERT INTO events (kind, payload) VALUES ('test', '{}') RETURNING id")
+                .fetch_one(&state.pool)
+                .await?;
+        state.queue_pushes(event_id, &notification).await?;
+        // another instance seeing the same event leaves it
+        state.queue_pushes(event_id, &notification).await?;
+
+        let mut received = vec![];
+        for _ in 0..2 {
+            received.push(
+                tokio::time::timeout(StdDuration::from_secs(5), pushes.recv())
+                    .await?
+                    .expect("push"),
+            );
+        }
+        received.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(received[0].0, "alice");
+        assert_eq!(received[1].0, "gone");
+        let (_, headers, body) = &received[0];
+        assert_eq!(headers["content-encoding"], "aes128gcm");
+        assert_eq!(headers["ttl"], "86400");
+
+        let payload: PushMessage = serde_json::from_slice(&ece::decrypt(&secret, &auth, body)?)?;
+        assert_eq!(payload.title, "Tyr Chen");
+        assert_eq!(payload.body, "lunch?");
+
+        // signed for the push service with the key the server hands out
+        let vapid = headers["authorization"].to_str()?;
+        let (token, key) = vapid
+            .strip_prefix("vapid t=")
+            .and_then(|v| v.split_once(", k="))
+            .expect("vapid header");
+        assert_eq!(key, state.push.as_ref().unwrap().public_key);
+        let pk = ES256PublicKey::from_bytes(&URL_SAFE_NO_PAD.decode(key)?)?;
+        let options = VerificationOptions {
+            allowed_audiences: Some(HashSet::from([origin.clone()])),
+            ..Default::default()
+        };
+        let claims = pk.verify_token::<NoCustomClaims>(token, Some(options))?;
+        assert_eq!(claims.subject.as_deref(), Some("mailto:admin@acme.org"));
+
+        // a gone device is forgotten
+        for _ in 0..50 {
+            let (left,): (i64,) =
+                sqlx::query_as("SELECT COUNT(*) FROM push_subscriptions WHERE user_id = 3")
+                    .fetch_one(&state.pool)
+                    .await?;
+            if left == 0 {
+                return Ok(());
+            }
+            tokio::time::sleep(StdDuration::from_millis(50)).await;
+        }
+        anyhow::bail!("gone subscription still there")
+    }
+
+    #[tokio::test]
+    async fn subscriptions_should_be_validated() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        let (_, _, mut sub) = device("https://push.example.com", "a");
+        sub.keys.auth = URL_SAFE_NO_PAD.encode([1u8; 4]);
+        assert!(matches!(
+            state.save_push_subscription(1, &sub).await,
+            Err(AppError::PushError(_))
+        ));
+        // valid keys, but nothing a push service would be reached at
+        let (_, _, mut sub) = device("https://push.example.com", "a");
+        for endpoint in [
+            "ftp://push.example.com/a",
+            "http://push.example.com/a",
+            "https://127.0.0.1/a",
+            "https://[::1]/a",
+            "https://10.0.0.8/a",
+            "https://localhost/a",
+        ] {
+            sub.endpoint = endpoint.to_string();
+            assert!(
+                matches!(
+                    state.save_push_subscription(1, &sub).await,
+                    Err(AppError::PushError(_))
+                ),
+                "{endpoint}"
+            );
+        }
+        Ok(())
+    }
+}
@@ -1,22 +1,46 @@
-use axum::response::{sse::Event, Sse};
-use axum_extra::{headers, TypedHeader};
-use futures::{stream, Stream};
 use std::{convert::Infallible, time::Duration};
+
+use axum::{
+    extract::State,
+    http::HeaderMap,
+    response::{sse::Event, Sse},
+    Extension,
+};
+use chat_core::User;
+use futures::{stream, Stream};
 use tokio_stream::StreamExt;
 use tracing::info;
 
+use crate::{AppState, ConnectionManager, EventRecord, Replay};
+
+const LAST_EVENT_ID: &str = "Last-Event-ID";
+
+// Path: notify_server/src/sse.rs
+// 推送当前用户相关的事件, event 为事件类型, data 为 json
+// 断线重连时浏览器带上 Last-Event-ID, 补发错过的事件, 太久远的返回 ResyncRequired
 pub(crate) async fn sse_handler(
-    TypedHeader(user_agent): TypedHeader<headers::UserAgent>,
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
 ) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
-    info!("`{}` connected", user_agent.as_str());
+    let connections = state.connections.clone();
+    let last = headers
+        .get(LAST_EVENT_ID)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| connections.parse_event_id(v));
+    info!("User {} subscribed, last event {:?}", user.id, last);
 
-    // A `Stream` that repeats an event every second
-    //
-    // You can also create streams from tokio channels using the wrappers in
-    // https://docs.rs/tokio-stream
-    let stream = stream::repeat_with(|| Event::default().data("hi!"))
-        .map(Ok)
-        .throttle(Duration::from_secs(1));
+    let mut sub = state.connect(user.id, last);
+    let replay = match std::mem::take(&mut sub.replay) {
+        Replay::Nothing => vec![],
+        Replay::Events(records) => records.iter().map(|r| sse_event(&connections, r)).collect(),
+        Replay::Resync(id) => vec![resync_event().id(connections.event_id(id))],
+    };
+    // a connection that fell behind is closed, the browser reconnects with its Last-Event-ID
+    let live = sub
+        .into_stream()
+        .map(move |record| sse_event(&connections, &record));
+    let stream = stream::iter(replay).chain(live).map(Ok);
 
     Sse::new(stream).keep_alive(
         axum::response::sse::KeepAlive::new()
@@ -24,3 +48,92 @@ pub(crate) async fn sse_handler(
             .text("keep-alive-text"),
     )
 }
+
+fn sse_event(connections: &ConnectionManager, record: &EventRecord) -> Event {
+    let mut data = serde_json::to_value(&record.event).expect("events serialize");
+    if record.silent {
+        data["silent"] = true.into();
+    }
+    Event::default()
+        .id(connections.event_id(record.id))
+        .event(record.event.name())
+        .data(data.to_string())
+}
+
+fn resync_event() -> Event {
+    Event::default()
+        .event("ResyncRequired")
+        .data(r#"{"event":"ResyncRequired"}"#)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{notif::Notification, router, AppConfig, AppEvent, Typing};
+    use anyhow::Result;
+    use axum::{body::Body, http::Request};
+    use chat_core::utils::EncodingKey;
+    use tower::ServiceExt;
+
+    fn typing(chat_id: i64) -> Notification {
+        let event = AppEvent::Typing(Typing {
+            chat_id,
+            user_id: 2,
+        });
+        Notification::new([1], event)
+    }
+
+    // the first `n` data chunks of the stream
+    async fn read_events(app: axum::Router, last_id: Option<String>, n: usize) -> Result<String> {
+        let ek = EncodingKey::load(include_str!("../../chat_core/fixtures/encoding.pem"))?;
+        let token = ek.sign(User::new(1, "Tyr Chen", "tchen@acme.org"))?;
+        let mut req = Request::builder().uri(format!("/events?access_token={token}"));
+        if let Some(id) = last_id {
+            req = req.header(LAST_EVENT_ID, id);
+        }
+        let res = app.oneshot(req.body(Body::empty())?).await?;
+        let body = res.into_body().into_data_stream().take(n);
+        let chunks: Vec<_> = body.collect::<Result<_, _>>().await?;
+        Ok(String::from_utf8(chunks.concat())?)
+    }
+
+    #[tokio::test]
+    async fn sse_should_replay_missed_events() -> Result<()> {
+        let mut config = AppConfig::load()?;
+        config.auth.pk = include_str!("../../chat_core/fixtures/decoding.pem").to_string();
+        config.replay.capacity = 2;
+        let state = AppState::try_new(config)?;
+        // the user was connected, the events sent while away are kept
+        drop(state.subscribe(1));
+        let mut latest = 0;
+        for chat_id in 1..=3 {
+            let notification = typing(chat_id);
+            latest = state
+                .connections
+                .send(notification.user_ids, notification.event)
+                .id;
+        }
+        let connections = state.connections.clone();
+        let after = |id| Some(connections.event_id(id));
+        let app = router(state);
+
+        let body = read_events(app.clone(), after(latest - 1), 1).await?;
+        assert!(body.contains(&format!("id: {}", connections.event_id(latest))));
+        assert!(body.contains(r#""chat_id":3"#));
+
+        let body = read_events(app.clone(), after(latest - 2), 2).await?;
+        assert!(body.contains(r#""chat_id":2"#));
+        assert!(body.contains(r#""chat_id":3"#));
+
+        // the event for chat 1 is gone
+        let body = read_events(app.clone(), after(latest - 3), 1).await?;
+        assert!(body.contains("event: ResyncRequired"));
+        assert!(body.contains(&format!("id: {}", connections.event_id(latest))));
+
+        // an id handed out by another instance can't be resumed here
+        let other = ConnectionManager::new(8, 8, 1);
+        let body = read_events(app, Some(other.event_id(latest)), 1).await?;
+        assert!(body.contains("event: ResyncRequired"));
+        Ok(())
+    }
+}
@@ -0,0 +1,168 @@
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant},
+};
+
+use chat_core::typing::publish_typing;
+use dashmap::DashMap;
+
+use crate::{notif::Notification, AppError, AppEvent, AppState, Typing};
+
+/// Who is typing where. Never stored, an entry goes away unless refreshed within the ttl
+#[derive(Default)]
+pub(crate) struct TypingTracker {
+    active: DashMap<(i64, i64), TypingEntry>,
+}
+
+struct TypingEntry {
+    expires_at: Instant,
+    // the other members, told again once the user stops
+    recipients: HashSet<i64>,
+}
+
+impl TypingTracker {
+    /// Push back the ttl of someone already typing, false when they weren't
+    fn refresh(&self, typing: &Typing, ttl: Duration) -> bool {
+        match self.active.get_mut(&(typing.chat_id, typing.user_id)) {
+            Some(mut entry) => {
+                entry.expires_at = Instant::now() + ttl;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// True when the user just started, a refresh only extends the ttl
+    fn start(&self, typing: &Typing, recipients: HashSet<i64>, ttl: Duration) -> bool {
+        let entry = TypingEntry {
+            expires_at: Instant::now() + ttl,
+            recipients,
+        };
+        self.active
+            .insert((typing.chat_id, typing.user_id), entry)
+            .is_none()
+    }
+
+    fn stop(&self, chat_id: i64, user_id: i64) {
+        self.active.remove(&(chat_id, user_id));
+    }
+
+    // removes and returns the entries past their ttl
+    fn expire(&self, now: Instant) -> Vec<(Typing, HashSet<i64>)> {
+        let expired: Vec<_> = self
+            .active
+            .iter()
+            .filter(|entry| entry.expires_at <= now)
+            .map(|entry| *entry.key())
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|key| {
+                let ((chat_id, user_id), entry) = self
+                    .active
+                    .remove_if(&key, |_, entry| entry.expires_at <= now)?;
+                Some((Typing { chat_id, user_id }, entry.recipients))
+            })
+            .collect()
+    }
+}
+
+impl AppState {
+    /// Publish that the user is typing, only members of the chat may
+    pub(crate) async fn publish_typing(&self, user_id: i64, chat_id: i64) -> Result<(), AppError> {
+        if publish_typing(&self.pool, chat_id, user_id).await? {
+            return Ok(());
+        }
+        Err(AppError::PermissionDenied(format!(
+            "not a member of chat {chat_id}"
+        )))
+    }
+
+    /// A `chat_typing` notification, the other members hear of it unless it's a refresh
+    pub(crate) async fn handle_typing(&self, payload: &str) -> Result<(), AppError> {
+        let typing: Typing = serde_json::from_str(payload)?;
+        let ttl = Duration::from_millis(self.config.typing.ttl_ms);
+        if self.typing.refresh(&typing, ttl) {
+            return Ok(());
+        }
+        // the payload only has the ids, a member list could be too large for pg_notify
+        let members: Option<Vec<i64>> =
+            sqlx::query_scalar("SELECT members FROM chats WHERE id = $1")
+                .bind(typing.chat_id)
+                .fetch_optional(&self.pool)
+                .await?;
+        let others: HashSet<_> = members
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|&id| id != typing.user_id)
+            .collect();
+        if self.typing.start(&typing, others.clone(), ttl) {
+            self.dispatch(Notification::new(others, AppEvent::Typing(typing)));
+        }
+        Ok(())
+    }
+
+    /// The message itself ends the typing, no extra event needed
+    pub(crate) fn clear_typing(&self, event: &AppEvent) {
+        if let AppEvent::NewMessage(message) = event {
+            self.typing.stop(message.chat_id, message.sender_id);
+        }
+    }
+
+    /// Tell the members when someone stopped typing without sending
+    pub(crate) fn expire_typing(&self) {
+        for (typing, recipients) in self.typing.expire(Instant::now()) {
+            self.dispatch(Notification::new(
+                recipients,
+                AppEvent::TypingStopped(typing),
+            ));
+        }
+    }
+
+    pub(crate) async fn run_typing_janitor(self) {
+        let mut interval = tokio::time::interval(Duration::from_millis(250));
+        loop {
+            interval.tick().await;
+            self.expire_typing();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AppConfig;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn typing_should_be_announced_once_and_expire() -> Result<()> {
+        let mut config = AppConfig::load()?;
+        config.typing.ttl_ms = 0;
+        // chat 3 is between users 1 and 2
+        let (_tdb, state) = AppState::new_for_test(config).await?;
+        let mut rx = state.subscribe(2);
+        let payload = r#"{"chat_id": 3, "user_id": 1}"#;
+
+        state.handle_typing(payload).await?;
+        state.handle_typing(payload).await?;
+        let typing = Typing {
+            chat_id: 3,
+            user_id: 1,
+        };
+        assert_eq!(rx.try_recv()?.event, AppEvent::Typing(typing.clone()));
+        assert!(rx.try_recv().is_err());
+
+        state.expire_typing();
+        assert_eq!(rx.try_recv()?.event, AppEvent::TypingStopped(typing));
+        assert!(state.typing.active.is_empty());
+
+        // a message ends it quietly
+        state.handle_typing(payload).await?;
+        assert!(rx.try_recv().is_ok());
+        let message = r#"{"id": 1, "chat_id": 3, "sender_id": 1, "content": "hi", "files": [], "created_at": "2024-01-01T00:00:00Z"}"#;
+        state.clear_typing(&AppEvent::NewMessage(serde_json::from_str(message)?));
+        state.expire_typing();
+        assert!(rx.try_recv().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,400 @@
+use std::{collections::HashSet, time::Duration};
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket},
+        State, WebSocketUpgrade,
+    },
+    response::Response,
+    Extension,
+};
+use chat_core::{middlewares::WS_PROTOCOL, User};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::{notif::EventRecord, AppError, AppState};
+
+/// Frames a client sends over `/ws`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum ClientFrame {
+    // only receive events of these chats, empty means all of them
+    Subscribe {
+        chat_ids: Vec<i64>,
+    },
+    Typing {
+        chat_id: i64,
+    },
+    // everything up to the message has been read
+    Ack {
+        chat_id: i64,
+        message_id: i64,
+    },
+    Ping {
+        #[serde(default)]
+        nonce: Option<String>,
+    },
+}
+
+/// Frames the server sends over `/ws`, events carry the same fields as on `/events`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum ServerFrame {
+    Event(EventRecord),
+    Subscribed {
+        chat_ids: Vec<i64>,
+    },
+    Pong {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        nonce: Option<String>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+// Path: notify_server/src/ws.rs
+// 和 /events 相同的事件, 另外支持 subscribe / typing / ack / ping
+pub(crate) async fn ws_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    // the token subprotocol is never echoed back
+    ws.protocols([WS_PROTOCOL])
+        .on_upgrade(move |socket| handle_socket(state, user, socket))
+}
+
+async fn handle_socket(state: AppState, user: User, mut socket: WebSocket) {
+    info!("User {} connected over websocket", user.id);
+    let mut events = state.subscribe(user.id);
+    let mut chat_ids = HashSet::new();
+    let timeout = Duration::from_secs(state.config.ws.timeout_secs);
+    let mut heartbeat = tokio::time::interval(Duration::from_secs(state.config.ws.heartbeat_secs));
+    let mut last_seen = Instant::now();
+
+    loop {
+        let reply = tokio::select! {
+            msg = socket.recv() => {
+                let Some(Ok(msg)) = msg else {
+                    break;
+                };
+                // any frame, pongs included, shows the client is alive
+                last_seen = Instant::now();
+                match msg {
+                    Message::Text(text) => state.handle_frame(&user, &text, &mut chat_ids).await,
+                    Message::Close(_) => break,
+                    _ => None,
+                }
+            }
+            event = events.recv() => match event {
+                // workspace events go to everyone, and so do chats coming and going
+                Some(record)
+                    if chat_ids.is_empty()
+                        || record.event.changes_membership()
+                        || record.event.chat_id().is_none_or(|id| chat_ids.contains(&id)) =>
+                {
+                    Some(ServerFrame::Event(EventRecord::clone(&record)))
+                }
+                Some(_) => None,
+                None => {
+                    // dropped for falling behind, the client reconnects and refetches
+                    let frame = ServerFrame::Error {
+                        message: "too slow, resync required".to_string(),
+                    };
+                    let text = serde_json::to_string(&frame).expect("server frames serialize");
+                    let _ = socket.send(Message::Text(text)).await;
+                    let _ = socket.send(Message::Close(None)).await;
+                    break;
+                }
+            },
+            _ = heartbeat.tick() => {
+                if last_seen.elapsed() > timeout {
+                    info!("User {} timed out", user.id);
+                    let _ = socket.send(Message::Close(None)).await;
+                    break;
+                }
+                if socket.send(Message::Ping(vec![])).await.is_err() {
+                    break;
+                }
+                None
+            }
+        };
+
+        let Some(reply) = reply else {
+            continue;
+        };
+        let text = serde_json::to_string(&reply).expect("server frames serialize");
+        if socket.send(Message::Text(text)).await.is_err() {
+            break;
+        }
+    }
+    info!("User {} disconnected from websocket", user.id);
+}
+
+impl AppState {
+    async fn handle_frame(
+        &self,
+        user: &User,
+        text: &str,
+        chat_ids: &mut HashSet<i64>,
+    ) -> Option<ServerFrame> {
+        let frame = match serde_json::from_str(text) {
+            Ok(frame) => frame,
+            Err(e) => {
+                return Some(ServerFrame::Error {
+                    message: format!("invalid frame: {e}"),
+                })
+            }
+        };
+        // pings only keep the connection, the rest shows the user is around
+        if !matches!(frame, ClientFrame::Ping { .. }) {
+            self.presence.touch(user.id);
+        }
+        let ret = match frame {
+            ClientFrame::Subscribe { chat_ids: ids } => {
+                *chat_ids = ids.iter().copied().collect();
+                return Some(ServerFrame::Subscribed { chat_ids: ids });
+            }
+            ClientFrame::Ping { nonce } => return Some(ServerFrame::Pong { nonce }),
+            ClientFrame::Typing { chat_id } => self.publish_typing(user.id, chat_id).await,
+            ClientFrame::Ack {
+                chat_id,
+                message_id,
+            } => self.mark_read(user.id, chat_id, message_id).await,
+        };
+        // typing and acks are only answered when they fail
+        let e = ret.err()?;
+        warn!("Frame from user {} failed: {}", user.id, e);
+        Some(ServerFrame::Error {
+            message: e.to_string(),
+        })
+    }
+
+    // the read position only moves forward
+    async fn mark_read(&self, user_id: i64, chat_id: i64, message_id: i64) -> Result<(), AppError> {
+        let ret = sqlx::query(
+            r#"
+            INSERT INTO chat_reads (chat_id, user_id, last_read_id)
+            SELECT id, $2, $3 FROM chats WHERE id = $1 AND $2 = ANY(members)
+            ON CONFLICT (chat_id, user_id) DO UPDATE
+            SET last_read_id = GREATEST(chat_reads.last_read_id, EXCLUDED.last_read_id),
+              updated_at = CURRENT_TIMESTAMP
+            "#,
+        )
+        .bind(chat_id)
+        .bind(user_id)
+        .bind(message_id)
+        .execute(&self.pool)
+        .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::PermissionDenied(format!(
+                "not a member of chat {chat_id}"
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{notif::Notification, router, AppConfig, AppEvent, Typing};
+    use anyhow::Result;
+    use chat_core::{middlewares::WS_TOKEN_PREFIX, utils::EncodingKey, Message as ChatMessage};
+    use futures::{SinkExt, StreamExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::{
+        connect_async,
+        tungstenite::{client::IntoClientRequest, Message as WsMessage},
+        MaybeTlsStream, WebSocketStream,
+    };
+
+    type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    async fn start_server(config: AppConfig) -> Result<(AppState, Client)> {
+        let mut config = config;
+        config.auth.pk = include_str!("../../chat_core/fixtures/decoding.pem").to_string();
+        let state = AppState::try_new(config)?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let app = router(state.clone());
+        tokio::spawn(async move { axum::serve(listener, app.into_make_service()).await });
+
+        let ek = EncodingKey::load(include_str!("../../chat_core/fixtures/encoding.pem"))?;
+        let token = ek.sign(User::new(1, "Tyr Chen", "tchen@acme.org"))?;
+        let mut req = format!("ws://{addr}/ws").into_client_request()?;
+        req.headers_mut().insert(
+            "Sec-WebSocket-Protocol",
+            format!("{WS_PROTOCOL}, {WS_TOKEN_PREFIX}{token}").parse()?,
+        );
+        let (client, res) = connect_async(req).await?;
+        assert_eq!(res.headers()["Sec-WebSocket-Protocol"], WS_PROTOCOL);
+        Ok((state, client))
+    }
+
+    async fn request(client: &mut Client, frame: ClientFrame) -> Result<ServerFrame> {
+        client
+            .send(WsMessage::Text(serde_json::to_string(&frame)?))
+            .await?;
+        next_frame(client).await
+    }
+
+    async fn next_frame(client: &mut Client) -> Result<ServerFrame> {
+        loop {
+            match client.next().await {
+                Some(Ok(WsMessage::Text(text))) => return Ok(serde_json::from_str(&text)?),
+                Some(Ok(_)) => continue,
+                other => anyhow::bail!("connection closed: {:?}", other),
+            }
+        }
+    }
+
+    fn new_message(id: i64, chat_id: i64) -> AppEvent {
+        AppEvent::NewMessage(ChatMessage {
+            id,
+            chat_id,
+            sender_id: 2,
+            content: "hello".to_string(),
+            files: vec![],
+            is_bot: false,
+            attachments: vec![],
+            created_at: Default::default(),
+        })
+    }
+
+    #[tokio::test]
+    async fn ws_should_route_events_and_answer_frames() -> Result<()> {
+        let (state, mut client) = start_server(AppConfig::load()?).await?;
+
+        let nonce = Some("1".to_string());
+        let ret = request(
+            &mut client,
+            ClientFrame::Ping {
+                nonce: nonce.clone(),
+            },
+        )
+        .await?;
+        assert_eq!(ret, ServerFrame::Pong { nonce });
+        client
+            .send(WsMessage::Text("{\"op\":\"dance\"}".into()))
+            .await?;
+        assert!(matches!(
+            next_frame(&mut client).await?,
+            ServerFrame::Error { .. }
+        ));
+
+        let ret = request(&mut client, ClientFrame::Subscribe { chat_ids: vec![2] }).await?;
+        assert_eq!(ret, ServerFrame::Subscribed { chat_ids: vec![2] });
+        state.dispatch(Notification::new([1, 2], new_message(1, 3)));
+        state.dispatch(Notification::new([2], new_message(2, 2)));
+        state.dispatch(Notification::new([1, 2], new_message(3, 2)));
+        let ServerFrame::Event(record) = next_frame(&mut client).await? else {
+            anyhow::bail!("expected an event");
+        };
+        assert_eq!(record.event, new_message(3, 2));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ws_should_drop_silent_connections() -> Result<()> {
+        let mut config = AppConfig::load()?;
+        config.ws.heartbeat_secs = 1;
+        config.ws.timeout_secs = 1;
+        let (state, mut client) = start_server(config).await?;
+        assert_eq!(state.connections.connection_count(1), 1);
+
+        // the client doesn't read, so it never answers the pings
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        while let Some(Ok(msg)) = client.next().await {
+            if msg.is_close() {
+                break;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(state.connections.connection_count(1), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn typing_and_ack_should_require_membership() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        let user = User::new(2, "Alice", "alice@acme.org");
+        let mut chat_ids = HashSet::new();
+
+        // chat 2 is private to users 1, 2 and 3, typing goes around through postgres
+        crate::outbox::setup_pg_listener(state.clone()).await?;
+        let mut rx = state.subscribe(3);
+        let typing = r#"{"op": "typing", "chat_id": 2}"#;
+        assert_eq!(state.handle_frame(&user, typing, &mut chat_ids).await, None);
+        let record = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await?
+            .expect("typing event");
+        assert_eq!(
+            record.event,
+            AppEvent::Typing(Typing {
+                chat_id: 2,
+                user_id: 2
+            })
+        );
+
+        let ack =
+            |message_id| format!(r#"{{"op": "ack", "chat_id": 1, "message_id": {message_id}}}"#);
+        assert_eq!(
+            state.handle_frame(&user, &ack(5), &mut chat_ids).await,
+            None
+        );
+        assert_eq!(
+            state.handle_frame(&user, &ack(3), &mut chat_ids).await,
+            None
+        );
+        let (last_read_id,): (i64,) =
+            sqlx::query_as("SELECT last_read_id FROM chat_reads WHERE chat_id = 1 AND user_id = 2")
+                .fetch_one(&state.pool)
+                .await?;
+        assert_eq!(last_read_id, 5);
+
+        // chat 4 is a group of users 1, 3 and 4
+        for frame in [
+            r#"{"op": "typing", "chat_id": 4}"#,
+            r#"{"op": "ack", "chat_id": 4, "message_id": 1}"#,
+        ] {
+            let ret = state.handle_frame(&user, frame, &mut chat_ids).await;
+            assert!(matches!(ret, Some(ServerFrame::Error { .. })));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn frames_should_be_tagged_with_op() -> Result<()> {
+        let frame: ClientFrame =
+            serde_json::from_str(r#"{"op": "ack", "chat_id": 1, "message_id": 2}"#)?;
+        assert_eq!(
+            frame,
+            ClientFrame::Ack {
+                chat_id: 1,
+                message_id: 2
+            }
+        );
+        let frame: ClientFrame = serde_json::from_str(r#"{"op": "ping"}"#)?;
+        assert_eq!(frame, ClientFrame::Ping { nonce: None });
+
+        let record = EventRecord {
+            id: 7,
+            event: new_message(1, 2),
+            silent: false,
+        };
+        let event = serde_json::to_value(ServerFrame::Event(record.clone()))?;
+        assert_eq!(event["op"], "event");
+        assert_eq!(event["event_id"], 7);
+        assert_eq!(event["id"], 1);
+        assert_eq!(event["event"], "NewMessage");
+        assert_eq!(event["chat_id"], 2);
+        assert_eq!(
+            serde_json::from_value::<ServerFrame>(event)?,
+            ServerFrame::Event(record)
+        );
+        Ok(())
+    }
+}